@@ -1,3 +1,4 @@
+use async_compression::Level;
 use brotli::enc::BrotliEncoderParams;
 use bytes::Bytes;
 use bytes::BytesMut;
@@ -57,6 +58,46 @@ impl Compressor {
         None
     }
 
+    /// Same as `new`, but compresses with the provided level instead of the fast default.
+    /// Precise levels are clamped to the range supported by the selected algorithm.
+    pub(crate) fn with_level<'a, It: 'a>(it: It, level: Level) -> Option<Self>
+    where
+        It: Iterator<Item = &'a str>,
+    {
+        for s in it {
+            match s {
+                "gzip" => return Some(Compressor::Gzip(GzipEncoder::new(flate2_level(level)))),
+                "deflate" => {
+                    return Some(Compressor::Deflate(DeflateEncoder::new(flate2_level(
+                        level,
+                    ))))
+                }
+                "br" => {
+                    let mut params = BrotliEncoderParams::default();
+                    match level {
+                        Level::Fastest => params.quality = 0,
+                        Level::Best => params.quality = 11,
+                        Level::Precise(quality) => params.quality = quality.clamp(0, 11),
+                        _ => {}
+                    }
+                    return Some(Compressor::Brotli(Box::new(BrotliEncoder::new(params))));
+                }
+                "zstd" => {
+                    let (fastest, best) = (zstd_safe::min_c_level(), zstd_safe::max_c_level());
+                    let level = match level {
+                        Level::Fastest => fastest,
+                        Level::Best => best,
+                        Level::Precise(quality) => quality.clamp(fastest, best),
+                        _ => zstd_safe::CLEVEL_DEFAULT,
+                    };
+                    return Some(Compressor::Zstd(ZstdEncoder::new(level)));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
     pub(crate) fn content_encoding(&self) -> &'static str {
         match self {
             Compressor::Deflate(_) => "deflate",
@@ -165,6 +206,21 @@ where {
     }
 }
 
+fn flate2_level(level: Level) -> Compression {
+    let (fastest, best) = (Compression::fast(), Compression::best());
+    match level {
+        Level::Fastest => fastest,
+        Level::Best => best,
+        // level 0 stores the data without compressing it
+        Level::Precise(quality) => Compression::new(
+            u32::try_from(quality)
+                .unwrap_or(0)
+                .clamp(Compression::none().level(), best.level()),
+        ),
+        _ => Compression::default(),
+    }
+}
+
 impl Encode for Compressor {
     fn encode(
         &mut self,
@@ -214,6 +270,14 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn flate2_levels() {
+        assert_eq!(flate2_level(Level::Precise(0)).level(), 0);
+        assert_eq!(flate2_level(Level::Precise(6)).level(), 6);
+        assert_eq!(flate2_level(Level::Precise(12)).level(), 9);
+        assert_eq!(flate2_level(Level::Fastest).level(), 1);
+    }
+
     #[tokio::test]
     async fn finish() {
         let compressor = Compressor::new(["gzip"].into_iter()).unwrap();
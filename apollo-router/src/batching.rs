@@ -457,7 +457,9 @@ pub(crate) async fn assemble_batch(
         .operation_name
         .clone()
         .unwrap_or_default();
-    let (parts, _) = first_request.into_parts();
+    let (mut parts, _) = first_request.into_parts();
+    // the queries sent as GET requests to a subgraph are batched in the body of a POST request
+    parts.method = http::Method::POST;
 
     // Generate the final request and pass it up
     let request = http::Request::from_parts(parts, Body::from(bytes));
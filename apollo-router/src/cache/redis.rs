@@ -25,6 +25,7 @@ use url::Url;
 
 use super::KeyType;
 use super::ValueType;
+use crate::configuration::Ocsp;
 use crate::configuration::RedisCache;
 use crate::services::generate_tls_client_config;
 
@@ -154,6 +155,25 @@ impl RedisCacheStorage {
         }
 
         if let Some(tls) = config.tls.as_ref() {
+            // these options are only implemented for subgraph connections
+            let unsupported = [
+                (
+                    "certificate_revocation_lists",
+                    tls.certificate_revocation_lists.is_some(),
+                ),
+                ("ocsp", tls.ocsp.unwrap_or_default() != Ocsp::Off),
+                ("min_version", tls.min_version.is_some()),
+                ("max_version", tls.max_version.is_some()),
+                ("cipher_suites", tls.cipher_suites.is_some()),
+                ("server_name", tls.server_name.is_some()),
+                ("pinned_public_keys", tls.pinned_public_keys.is_some()),
+            ];
+            if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+                return Err(format!(
+                    "the {option} option is not supported in the Redis TLS configuration"
+                )
+                .into());
+            }
             let tls_cert_store = tls.create_certificate_store().transpose()?;
             let client_cert_config = tls.client_authentication.as_ref();
             let tls_client_config = generate_tls_client_config(tls_cert_store, client_cert_config)?;
@@ -272,7 +272,7 @@ impl InstrumentData {
             opt.router.tls.server,
             "$.supergraph",
             opt.router.tls.subgraph.ca_override,
-            "$[?(@.subgraph..certificate_authorities)]",
+            "$[?(@.subgraph..certificate_authorities || @.subgraph..certificate_authorities_file)]",
             opt.router.tls.subgraph.client_authentication,
             "$.subgraph..client_authentication"
         );
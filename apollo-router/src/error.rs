@@ -87,6 +87,62 @@ pub(crate) enum FetchError {
         /// The reason the fetch failed.
         reason: String,
     },
+    /// HTTP fetch failed from '{service}': subgraph didn't return JSON (expected content-type: application/json or content-type: application/graphql-response+json; found content-type: {content_type})
+    ///
+    /// the response body is not parsed, its start is kept for diagnostics
+    SubrequestUnexpectedContentType {
+        status_code: u16,
+
+        /// The service that returned the response.
+        service: String,
+
+        /// The content-type of the response.
+        content_type: String,
+
+        /// The start of the response body.
+        body: String,
+    },
+    /// HTTP request to '{service}' timed out after {elapsed_ms}ms
+    ///
+    /// the request_timeout covers the connection, the TLS handshake and the whole response
+    SubrequestTimeout {
+        /// The service that timed out.
+        service: String,
+
+        /// Time spent on the request, in milliseconds.
+        elapsed_ms: u64,
+    },
+    /// HTTP request to '{service}' got no connection within {timeout_ms}ms
+    ///
+    /// the pool_acquire_timeout covers the wait for an idle connection of the pool or for a new
+    /// connection
+    SubrequestPoolTimeout {
+        /// The service no connection was acquired for.
+        service: String,
+
+        /// The pool_acquire_timeout of the service, in milliseconds.
+        timeout_ms: u64,
+    },
+    /// HTTP request to '{service}' was not sent because its circuit breaker is open
+    SubrequestCircuitOpen {
+        /// The service whose circuit breaker is open.
+        service: String,
+    },
+    /// HTTP request to '{service}' was not sent because its rate limit was exceeded
+    SubrequestRateLimited {
+        /// The service whose rate limit was exceeded.
+        service: String,
+    },
+    /// HTTP request to '{service}' was not sent because its bulkhead queue is full
+    SubrequestOverloaded {
+        /// The service whose bulkhead queue is full.
+        service: String,
+    },
+    /// HTTP request to '{service}' was not sent because all its endpoints are ejected
+    SubrequestEndpointsEjected {
+        /// The service whose endpoints are all ejected.
+        service: String,
+    },
     /// Websocket fetch failed from '{service}': {reason}
     ///
     /// note that this relates to a transport error and not a GraphQL error
@@ -135,9 +191,26 @@ impl FetchError {
                             .insert("http", serde_json_bytes::json!({ "status": status_code }));
                     }
                 }
+                FetchError::SubrequestUnexpectedContentType {
+                    service,
+                    status_code,
+                    ..
+                } => {
+                    extensions
+                        .entry("service")
+                        .or_insert_with(|| service.clone().into());
+                    extensions.remove("status_code");
+                    extensions.insert("http", serde_json_bytes::json!({ "status": status_code }));
+                }
                 FetchError::SubrequestMalformedResponse { service, .. }
                 | FetchError::SubrequestUnexpectedPatchResponse { service }
-                | FetchError::SubrequestWsError { service, .. } => {
+                | FetchError::SubrequestWsError { service, .. }
+                | FetchError::SubrequestTimeout { service, .. }
+                | FetchError::SubrequestPoolTimeout { service, .. }
+                | FetchError::SubrequestCircuitOpen { service }
+                | FetchError::SubrequestRateLimited { service }
+                | FetchError::SubrequestOverloaded { service }
+                | FetchError::SubrequestEndpointsEjected { service } => {
                     extensions
                         .entry("service")
                         .or_insert_with(|| service.clone().into());
@@ -177,8 +250,15 @@ impl ErrorExtension for FetchError {
             FetchError::SubrequestUnexpectedPatchResponse { .. } => {
                 "SUBREQUEST_UNEXPECTED_PATCH_RESPONSE"
             }
-            FetchError::SubrequestHttpError { .. } => "SUBREQUEST_HTTP_ERROR",
+            FetchError::SubrequestHttpError { .. }
+            | FetchError::SubrequestUnexpectedContentType { .. } => "SUBREQUEST_HTTP_ERROR",
             FetchError::SubrequestWsError { .. } => "SUBREQUEST_WEBSOCKET_ERROR",
+            FetchError::SubrequestTimeout { .. } => "SUBREQUEST_TIMEOUT",
+            FetchError::SubrequestPoolTimeout { .. } => "CONNECTION_POOL_TIMEOUT",
+            FetchError::SubrequestCircuitOpen { .. } => "SUBREQUEST_CIRCUIT_OPEN",
+            FetchError::SubrequestRateLimited { .. } => "SUBREQUEST_RATE_LIMITED",
+            FetchError::SubrequestOverloaded { .. } => "SUBREQUEST_OVERLOADED",
+            FetchError::SubrequestEndpointsEjected { .. } => "SUBREQUEST_ENDPOINTS_EJECTED",
             FetchError::ExecutionPathNotFound { .. } => "EXECUTION_PATH_NOT_FOUND",
             FetchError::MalformedRequest { .. } => "MALFORMED_REQUEST",
             FetchError::MalformedResponse { .. } => "MALFORMED_RESPONSE",
@@ -1,6 +1,5 @@
 //! Authentication plugin
 
-use std::collections::HashMap;
 use std::ops::ControlFlow;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -38,8 +37,8 @@ use tower::ServiceExt;
 use url::Url;
 
 use self::jwks::JwksManager;
+use self::subgraph::AuthorizationHeaders;
 use self::subgraph::SigningParams;
-use self::subgraph::SigningParamsConfig;
 use self::subgraph::SubgraphAuth;
 use crate::graphql;
 use crate::layers::ServiceBuilderExt;
@@ -412,24 +411,35 @@ impl Plugin for AuthenticationPlugin {
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
         let subgraph = if let Some(config) = init.config.subgraph {
-            let all = if let Some(config) = &config.all {
-                Some(Arc::new(
-                    subgraph::make_signing_params(config, "all").await?,
-                ))
-            } else {
-                None
-            };
+            let mut signing_params = SigningParams::default();
+            let mut authorization = AuthorizationHeaders::default();
+            if let Some(config) = &config.all {
+                signing_params.all = subgraph::make_signing_params(config, "all")
+                    .await?
+                    .map(Arc::new);
+                authorization.all = subgraph::make_authorization_header(config, "all")?;
+            }
 
-            let mut subgraphs: HashMap<String, Arc<SigningParamsConfig>> = Default::default();
             for (subgraph_name, config) in &config.subgraphs {
-                subgraphs.insert(
-                    subgraph_name.clone(),
-                    Arc::new(subgraph::make_signing_params(config, subgraph_name.as_str()).await?),
-                );
+                if let Some(params) =
+                    subgraph::make_signing_params(config, subgraph_name.as_str()).await?
+                {
+                    signing_params
+                        .subgraphs
+                        .insert(subgraph_name.clone(), Arc::new(params));
+                }
+                if let Some(header) =
+                    subgraph::make_authorization_header(config, subgraph_name.as_str())?
+                {
+                    authorization
+                        .subgraphs
+                        .insert(subgraph_name.clone(), header);
+                }
             }
 
             Some(SubgraphAuth {
-                signing_params: Arc::new(SigningParams { all, subgraphs }),
+                signing_params: Arc::new(signing_params),
+                authorization: Arc::new(authorization),
             })
         } else {
             None
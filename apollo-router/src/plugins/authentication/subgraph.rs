@@ -11,7 +11,9 @@ use aws_sigv4::http_request::SignableRequest;
 use aws_sigv4::http_request::SigningSettings;
 use aws_smithy_runtime_api::client::identity::Identity;
 use aws_types::region::Region;
+use base64::Engine as _;
 use http::HeaderMap;
+use http::HeaderValue;
 use http::Request;
 use hyper::Body;
 use schemars::JsonSchema;
@@ -163,11 +165,52 @@ impl AWSSigV4Config {
     }
 }
 
+/// Configure basic auth. The credentials can be read from environment variables with
+/// `${env.NAME}`.
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BasicAuthConfig {
+    /// The username sent to the subgraph.
+    username: String,
+    /// The password sent to the subgraph.
+    password: String,
+}
+
+impl std::fmt::Debug for BasicAuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuthConfig")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Configure bearer token auth. The token can be read from an environment variable with
+/// `${env.NAME}`.
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BearerAuthConfig {
+    /// The token sent to the subgraph.
+    token: String,
+}
+
+impl std::fmt::Debug for BearerAuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BearerAuthConfig")
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug, JsonSchema, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub(crate) enum AuthConfig {
     #[serde(rename = "aws_sig_v4")]
     AWSSigV4(AWSSigV4Config),
+    #[serde(rename = "basic")]
+    Basic(BasicAuthConfig),
+    #[serde(rename = "bearer")]
+    Bearer(BearerAuthConfig),
 }
 
 /// Configure subgraph authentication
@@ -189,6 +232,19 @@ pub(crate) struct SigningParams {
     pub(crate) subgraphs: HashMap<String, Arc<SigningParamsConfig>>,
 }
 
+/// `Authorization` header set on the requests to a subgraph
+///
+/// The header value is marked as sensitive, so that it is redacted when the request headers are
+/// logged.
+#[derive(Clone)]
+pub(crate) struct AuthorizationHeader(pub(crate) HeaderValue);
+
+#[derive(Clone, Default)]
+pub(crate) struct AuthorizationHeaders {
+    pub(crate) all: Option<AuthorizationHeader>,
+    pub(crate) subgraphs: HashMap<String, AuthorizationHeader>,
+}
+
 #[derive(Clone)]
 pub(crate) struct SigningParamsConfig {
     credentials_provider: Arc<dyn ProvideCredentials>,
@@ -319,10 +375,10 @@ fn increment_failure_counter(subgraph_name: &str) {
     );
 }
 
-pub(super) async fn make_signing_params(
+pub(crate) async fn make_signing_params(
     config: &AuthConfig,
     subgraph_name: &str,
-) -> Result<SigningParamsConfig, BoxError> {
+) -> Result<Option<SigningParamsConfig>, BoxError> {
     match config {
         AuthConfig::AWSSigV4(config) => {
             let credentials_provider = config.get_credentials_provider().await;
@@ -339,16 +395,43 @@ pub(super) async fn make_signing_params(
                 .into());
             }
 
-            Ok(SigningParamsConfig {
+            Ok(Some(SigningParamsConfig {
                 region: config.region(),
                 service_name: config.service_name(),
                 credentials_provider,
                 subgraph_name: subgraph_name.to_string(),
-            })
+            }))
         }
+        AuthConfig::Basic(_) | AuthConfig::Bearer(_) => Ok(None),
     }
 }
 
+pub(super) fn make_authorization_header(
+    config: &AuthConfig,
+    subgraph_name: &str,
+) -> Result<Option<AuthorizationHeader>, BoxError> {
+    let value = match config {
+        AuthConfig::AWSSigV4(_) => return Ok(None),
+        AuthConfig::Basic(config) => format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", config.username, config.password))
+        ),
+        AuthConfig::Bearer(config) => format!("Bearer {}", config.token),
+    };
+    // the value is not part of the error, as it contains the credentials
+    let mut value = HeaderValue::try_from(value).map_err(|_| {
+        let error_subgraph_name = if subgraph_name == "all" {
+            "all subgraphs".to_string()
+        } else {
+            format!("{} subgraph", subgraph_name)
+        };
+        format!("auth: {error_subgraph_name}: the credentials are not a valid header value")
+    })?;
+    value.set_sensitive(true);
+    Ok(Some(AuthorizationHeader(value)))
+}
+
 /// There are three possible cases
 /// https://github.com/awslabs/aws-sdk-rust/blob/9c3168dafa4fd8885ce4e1fd41cec55ce982a33c/sdk/aws-sigv4/src/http_request/sign.rs#L264C1-L271C6
 fn get_signing_settings(signing_params: &SigningParamsConfig) -> SigningSettings {
@@ -362,6 +445,7 @@ fn get_signing_settings(signing_params: &SigningParamsConfig) -> SigningSettings
 
 pub(super) struct SubgraphAuth {
     pub(super) signing_params: Arc<SigningParams>,
+    pub(super) authorization: Arc<AuthorizationHeaders>,
 }
 
 impl SubgraphAuth {
@@ -370,28 +454,50 @@ impl SubgraphAuth {
         name: &str,
         service: crate::services::subgraph::BoxService,
     ) -> crate::services::subgraph::BoxService {
-        if let Some(signing_params) = self.params_for_service(name) {
-            ServiceBuilder::new()
-                .map_request(move |req: SubgraphRequest| {
-                    let signing_params = signing_params.clone();
-                    req.context.extensions().lock().insert(signing_params);
-                    req
-                })
-                .service(service)
-                .boxed()
-        } else {
-            service
+        let signing_params = self.params_for_service(name);
+        let authorization = self.authorization_for_service(name);
+        if signing_params.is_none() && authorization.is_none() {
+            return service;
         }
+        ServiceBuilder::new()
+            .map_request(move |req: SubgraphRequest| {
+                {
+                    let mut extensions = req.context.extensions().lock();
+                    if let Some(signing_params) = &signing_params {
+                        extensions.insert(signing_params.clone());
+                    }
+                    if let Some(authorization) = &authorization {
+                        extensions.insert(authorization.clone());
+                    }
+                }
+                req
+            })
+            .service(service)
+            .boxed()
     }
 }
 
 impl SubgraphAuth {
+    // the configuration of a subgraph replaces the configuration of all subgraphs
+    fn is_configured(&self, service_name: &str) -> bool {
+        self.signing_params.subgraphs.contains_key(service_name)
+            || self.authorization.subgraphs.contains_key(service_name)
+    }
+
     fn params_for_service(&self, service_name: &str) -> Option<Arc<SigningParamsConfig>> {
-        self.signing_params
-            .subgraphs
-            .get(service_name)
-            .cloned()
-            .or_else(|| self.signing_params.all.clone())
+        if self.is_configured(service_name) {
+            self.signing_params.subgraphs.get(service_name).cloned()
+        } else {
+            self.signing_params.all.clone()
+        }
+    }
+
+    fn authorization_for_service(&self, service_name: &str) -> Option<AuthorizationHeader> {
+        if self.is_configured(service_name) {
+            self.authorization.subgraphs.get(service_name).cloned()
+        } else {
+            self.authorization.all.clone()
+        }
     }
 }
 
@@ -425,6 +531,7 @@ mod test {
             "all",
         )
         .await
+        .unwrap()
         .unwrap();
         get_signing_settings(&params)
     }
@@ -541,9 +648,11 @@ mod test {
                 )
                 .await
                 .ok()
+                .flatten()
                 .map(Arc::new),
                 subgraphs: Default::default(),
             }),
+            authorization: Default::default(),
         }
         .subgraph_service("test_subgraph", mock.boxed());
 
@@ -594,9 +703,11 @@ mod test {
                 )
                 .await
                 .ok()
+                .flatten()
                 .map(Arc::new),
                 subgraphs: Default::default(),
             }),
+            authorization: Default::default(),
         }
         .subgraph_service("test_subgraph", mock.boxed());
 
@@ -611,6 +722,82 @@ mod test {
         ))
     }
 
+    #[tokio::test]
+    async fn test_basic_and_bearer_authorization() -> Result<(), BoxError> {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          bearer:
+            token: "all-token"
+        subgraphs:
+          products:
+            basic:
+              username: "user"
+              password: "secret"
+        "#,
+        )
+        .unwrap();
+        let mut authorization = AuthorizationHeaders {
+            all: make_authorization_header(config.all.as_ref().unwrap(), "all")?,
+            subgraphs: Default::default(),
+        };
+        authorization.subgraphs.insert(
+            "products".to_string(),
+            make_authorization_header(&config.subgraphs["products"], "products")?.unwrap(),
+        );
+        let auth = SubgraphAuth {
+            signing_params: Default::default(),
+            authorization: Arc::new(authorization),
+        };
+
+        let call = |subgraph_name: &str, expected: &'static str| {
+            let mut mock = MockSubgraphService::new();
+            mock.expect_call()
+                .times(1)
+                .withf(move |request| {
+                    let authorization = request
+                        .context
+                        .extensions()
+                        .lock()
+                        .get::<AuthorizationHeader>()
+                        .cloned()
+                        .unwrap();
+                    assert_eq!(authorization.0, expected);
+                    // the credentials are not logged
+                    assert_eq!(format!("{:?}", authorization.0), "Sensitive");
+                    true
+                })
+                .returning(example_response);
+            auth.subgraph_service(subgraph_name, mock.boxed())
+                .oneshot(example_request())
+        };
+
+        call("reviews", "Bearer all-token").await?;
+        call("products", "Basic dXNlcjpzZWNyZXQ=").await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_auth_config_debug_is_redacted() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          basic:
+            username: "user"
+            password: "secret"
+        subgraphs:
+          products:
+            bearer:
+              token: "products-token"
+        "#,
+        )
+        .unwrap();
+        let debug = format!("{config:?}");
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("secret"));
+        assert!(!debug.contains("products-token"));
+    }
+
     fn example_request() -> SubgraphRequest {
         SubgraphRequest::builder()
             .supergraph_request(Arc::new(
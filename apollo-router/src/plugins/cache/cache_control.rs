@@ -271,7 +271,7 @@ impl CacheControl {
         ) {
             (None, _) => None,
             (Some(max_age), None) => Some(*max_age),
-            (Some(max_age), Some(age)) => Some(max_age - age),
+            (Some(max_age), Some(age)) => Some(max_age.saturating_sub(*age)),
         }
     }
 
@@ -285,6 +285,14 @@ impl CacheControl {
         self.private
     }
 
+    pub(crate) fn no_cache(&self) -> bool {
+        self.no_cache
+    }
+
+    pub(crate) fn stale_while_revalidate(&self) -> Option<u32> {
+        self.stale_while_revalidate
+    }
+
     // We don't support revalidation yet
     #[allow(dead_code)]
     pub(crate) fn should_revalidate(&self) -> bool {
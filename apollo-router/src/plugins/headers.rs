@@ -19,6 +19,7 @@ use http::header::TE;
 use http::header::TRAILER;
 use http::header::TRANSFER_ENCODING;
 use http::header::UPGRADE;
+use http::HeaderMap;
 use http::HeaderValue;
 use regex::Regex;
 use schemars::JsonSchema;
@@ -30,6 +31,7 @@ use tower::ServiceBuilder;
 use tower::ServiceExt;
 use tower_service::Service;
 
+use crate::error::ConfigurationError;
 use crate::plugin::serde::deserialize_header_name;
 use crate::plugin::serde::deserialize_header_value;
 use crate::plugin::serde::deserialize_json_query;
@@ -39,8 +41,10 @@ use crate::plugin::serde::deserialize_regex;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
+use crate::services::router;
 use crate::services::subgraph;
 use crate::services::SubgraphRequest;
+use crate::services::SubgraphResponse;
 
 register_plugin!("apollo", "headers", Headers);
 
@@ -48,9 +52,11 @@ register_plugin!("apollo", "headers", Headers);
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 struct HeadersLocation {
     /// Propagate/Insert/Remove headers from request
+    #[serde(default)]
     request: Vec<Operation>,
-    // Propagate/Insert/Remove headers from response
-    // response: Option<Operation>
+    /// Propagate headers from the subgraph response to the client response
+    #[serde(default)]
+    response: Vec<ResponseOperation>,
 }
 
 #[derive(Clone, JsonSchema, Deserialize)]
@@ -180,6 +186,43 @@ enum Propagate {
     },
 }
 
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum ResponseOperation {
+    Propagate(ResponsePropagate),
+}
+
+#[derive(Clone, JsonSchema, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+#[serde(untagged)]
+/// Propagate a subgraph response header to the client response
+enum ResponsePropagate {
+    /// Propagate a subgraph response header given its name
+    Named {
+        /// The subgraph response header name
+        #[schemars(with = "String")]
+        #[serde(deserialize_with = "deserialize_header_name")]
+        named: HeaderName,
+
+        /// An optional client response header name
+        #[schemars(with = "Option<String>", default)]
+        #[serde(deserialize_with = "deserialize_option_header_name", default)]
+        rename: Option<HeaderName>,
+    },
+    /// Propagate the subgraph response headers given a regex to match their name
+    Matching {
+        /// The regex on header name
+        #[schemars(schema_with = "propagate_matching")]
+        #[serde(deserialize_with = "deserialize_regex")]
+        matching: Regex,
+    },
+}
+
+/// Subgraph response headers propagated to the client response, collected in the context
+/// extensions as the subgraph responses are received
+#[derive(Default)]
+struct PropagatedResponseHeaders(HeaderMap);
+
 /// Configuration for header propagation
 #[derive(Clone, JsonSchema, Default, Deserialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields, default)]
@@ -193,6 +236,8 @@ struct Config {
 struct Headers {
     all_operations: Arc<Vec<Operation>>,
     subgraph_operations: HashMap<String, Arc<Vec<Operation>>>,
+    all_response_operations: Arc<Vec<ResponseOperation>>,
+    subgraph_response_operations: HashMap<String, Arc<Vec<ResponseOperation>>>,
 }
 
 #[async_trait::async_trait]
@@ -200,6 +245,41 @@ impl Plugin for Headers {
     type Config = Config;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        for location in init.config.all.iter().chain(init.config.subgraphs.values()) {
+            for operation in &location.request {
+                if let Operation::Propagate(Propagate::Named { named, rename, .. }) = operation {
+                    if let Some(name) = [Some(named), rename.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .find(|name| HOP_BY_HOP_HEADERS.contains(*name))
+                    {
+                        return Err(ConfigurationError::InvalidConfiguration {
+                            message: "bad configuration for headers plugin",
+                            error: format!("the hop-by-hop header '{name}' cannot be propagated"),
+                        }
+                        .into());
+                    }
+                }
+            }
+            for ResponseOperation::Propagate(propagate) in &location.response {
+                if let ResponsePropagate::Named { named, rename } = propagate {
+                    if let Some(name) = [Some(named), rename.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .find(|name| RESERVED_HEADERS.contains(*name))
+                    {
+                        return Err(ConfigurationError::InvalidConfiguration {
+                            message: "bad configuration for headers plugin",
+                            error: format!(
+                                "the header '{name}' cannot be propagated to the client response"
+                            ),
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+
         let operations: Vec<Operation> = init
             .config
             .all
@@ -217,13 +297,66 @@ impl Plugin for Headers {
             })
             .collect();
 
+        let response_operations: Vec<ResponseOperation> = init
+            .config
+            .all
+            .as_ref()
+            .map(|a| a.response.clone())
+            .unwrap_or_default();
+        let subgraph_response_operations = init
+            .config
+            .subgraphs
+            .iter()
+            .map(|(subgraph_name, op)| {
+                let mut operations = response_operations.clone();
+                operations.append(&mut op.response.clone());
+                (subgraph_name.clone(), Arc::new(operations))
+            })
+            .collect();
+
         Ok(Headers {
             all_operations: Arc::new(operations),
             subgraph_operations,
+            all_response_operations: Arc::new(response_operations),
+            subgraph_response_operations,
         })
     }
 
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        if self.all_response_operations.is_empty()
+            && self
+                .subgraph_response_operations
+                .values()
+                .all(|operations| operations.is_empty())
+        {
+            return service;
+        }
+        ServiceBuilder::new()
+            .map_response(|mut response: router::Response| {
+                let propagated = response
+                    .context
+                    .extensions()
+                    .lock()
+                    .remove::<PropagatedResponseHeaders>();
+                if let Some(PropagatedResponseHeaders(propagated)) = propagated {
+                    let headers = response.response.headers_mut();
+                    // every value is kept as its own header line, like each `set-cookie`
+                    for (name, value) in propagated.iter() {
+                        headers.append(name, value.clone());
+                    }
+                }
+                response
+            })
+            .service(service)
+            .boxed()
+    }
+
     fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let response_operations = self
+            .subgraph_response_operations
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| self.all_response_operations.clone());
         ServiceBuilder::new()
             .layer(HeadersLayer::new(
                 self.subgraph_operations
@@ -231,11 +364,60 @@ impl Plugin for Headers {
                     .cloned()
                     .unwrap_or_else(|| self.all_operations.clone()),
             ))
+            .map_response(move |response: SubgraphResponse| {
+                if !response_operations.is_empty() {
+                    propagate_response_headers(&response_operations, &response);
+                }
+                response
+            })
             .service(service)
             .boxed()
     }
 }
 
+/// Collects the subgraph response headers selected by the response operations, to add them to
+/// the client response
+fn propagate_response_headers(operations: &[ResponseOperation], response: &SubgraphResponse) {
+    let subgraph_headers = response.response.headers();
+    let mut propagated = HeaderMap::new();
+    let mut already_propagated: HashSet<&HeaderName> = HashSet::new();
+
+    for ResponseOperation::Propagate(propagate) in operations {
+        match propagate {
+            ResponsePropagate::Named { named, rename } => {
+                if already_propagated.insert(named) {
+                    for value in subgraph_headers.get_all(named) {
+                        propagated.append(rename.as_ref().unwrap_or(named), value.clone());
+                    }
+                }
+            }
+            ResponsePropagate::Matching { matching } => {
+                for name in subgraph_headers.keys() {
+                    if RESERVED_HEADERS.contains(name)
+                        || !matching.is_match(name.as_str())
+                        || !already_propagated.insert(name)
+                    {
+                        continue;
+                    }
+                    for value in subgraph_headers.get_all(name) {
+                        propagated.append(name, value.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    if propagated.is_empty() {
+        return;
+    }
+    let mut extensions = response.context.extensions().lock();
+    let PropagatedResponseHeaders(headers) =
+        extensions.get_or_default_mut::<PropagatedResponseHeaders>();
+    for (name, value) in propagated.iter() {
+        headers.append(name, value.clone());
+    }
+}
+
 struct HeadersLayer {
     operations: Arc<Vec<Operation>>,
     reserved_headers: Arc<HashSet<&'static HeaderName>>,
@@ -289,6 +471,19 @@ static RESERVED_HEADERS: [HeaderName; 14] = [
     HeaderName::from_static("keep-alive"),
 ];
 
+// Headers that only apply to the connection they are sent on, which are never propagated, even
+// when they are named explicitly
+static HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
 impl<S> Service<SubgraphRequest> for HeadersService<S>
 where
     S: Service<SubgraphRequest>,
@@ -568,6 +763,174 @@ mod test {
         .unwrap();
     }
 
+    #[tokio::test]
+    async fn test_propagate_hop_by_hop_is_rejected() {
+        for config in [
+            r#"
+        all:
+            request:
+                - propagate:
+                    named: "connection"
+        "#,
+            r#"
+        subgraphs:
+          products:
+            request:
+                - propagate:
+                    named: "test"
+                    rename: "transfer-encoding"
+        "#,
+        ] {
+            let config = serde_yaml::from_str::<Config>(config).unwrap();
+            let error = Headers::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .err()
+                .unwrap();
+            assert!(error.to_string().contains("cannot be propagated"));
+        }
+    }
+
+    #[test]
+    fn test_response_config() {
+        serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            response:
+                - propagate:
+                    named: "set-cookie"
+                - propagate:
+                    named: "x-version"
+                    rename: "x-subgraph-version"
+                - propagate:
+                    matching: "x-.*"
+        "#,
+        )
+        .unwrap();
+
+        assert!(serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            response:
+                - propagate:
+                    named: "set-cookie"
+                    default: "a=b"
+        "#,
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_propagate_reserved_response_header_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            response:
+                - propagate:
+                    named: "content-length"
+        "#,
+        )
+        .unwrap();
+        let error = Headers::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("cannot be propagated to the client response"));
+    }
+
+    #[tokio::test]
+    async fn test_propagate_response_headers() -> Result<(), BoxError> {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            response:
+                - propagate:
+                    named: "set-cookie"
+        subgraphs:
+          products:
+            response:
+                - propagate:
+                    matching: "x-.*"
+        "#,
+        )?;
+        let plugin = Headers::new(PluginInit::fake_builder().config(config).build()).await?;
+        let context = Context::new();
+
+        for (subgraph_name, cookies) in [
+            (
+                "products",
+                ["a=1; Path=/", "b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT"],
+            ),
+            ("reviews", ["a=1; Path=/", "c=3"]),
+        ] {
+            let mut mock = MockSubgraphService::new();
+            mock.expect_call().times(1).returning(move |request| {
+                let mut headers = http::HeaderMap::new();
+                for cookie in cookies {
+                    headers.append(http::header::SET_COOKIE, cookie.try_into()?);
+                }
+                headers.insert("x-version", "2".try_into()?);
+                headers.insert(CONTENT_LENGTH, "2".try_into()?);
+                Ok(SubgraphResponse::fake_builder()
+                    .headers(headers)
+                    .context(request.context)
+                    .build())
+            });
+            let mut request = example_request();
+            request.context = context.clone();
+            plugin
+                .subgraph_service(subgraph_name, mock.boxed())
+                .oneshot(request)
+                .await?;
+        }
+
+        let service = tower::service_fn(|request: router::Request| async move {
+            Ok::<_, BoxError>(router::Response {
+                response: http::Response::builder()
+                    .header(http::header::SET_COOKIE, "session=router")
+                    .header(CONTENT_LENGTH, "10")
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+                context: request.context,
+            })
+        })
+        .boxed();
+        let response = plugin
+            .router_service(service)
+            .oneshot(router::Request::fake_builder().context(context).build()?)
+            .await?;
+
+        let headers = response.response.headers();
+        // each value stays a separate header line, without merging or removing duplicates
+        assert_eq!(
+            headers
+                .get_all(http::header::SET_COOKIE)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "session=router",
+                "a=1; Path=/",
+                "b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT",
+                "a=1; Path=/",
+                "c=3",
+            ]
+        );
+        // only the products subgraph propagates the headers matching `x-.*`
+        assert_eq!(
+            headers.get_all("x-version").iter().collect::<Vec<_>>(),
+            vec!["2"]
+        );
+        // the reserved headers of the subgraph response are never propagated
+        assert_eq!(
+            headers.get_all(CONTENT_LENGTH).iter().collect::<Vec<_>>(),
+            vec!["10"]
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_insert_static() -> Result<(), BoxError> {
         let mut mock = MockSubgraphService::new();
@@ -738,6 +1101,32 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_propagate_exact_multiple_values() -> Result<(), BoxError> {
+        let service = HeadersService {
+            inner: MockSubgraphService::new(),
+            operations: Arc::new(vec![Operation::Propagate(Propagate::Named {
+                named: "db".try_into()?,
+                rename: Some("eb".try_into()?),
+                default: None,
+            })]),
+            reserved_headers: Arc::new(RESERVED_HEADERS.iter().collect()),
+        };
+
+        let mut request = example_request();
+        service.modify_request(&mut request);
+        let values = request
+            .subgraph_request
+            .headers()
+            .get_all("eb")
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec!["vdb", "vdb", "vdb2"]);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_propagate_exact_rename() -> Result<(), BoxError> {
         let mut mock = MockSubgraphService::new();
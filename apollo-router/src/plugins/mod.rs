@@ -34,6 +34,7 @@ mod include_subgraph_errors;
 pub(crate) mod override_url;
 pub(crate) mod progressive_override;
 mod record_replay;
+mod request_id;
 pub(crate) mod rhai;
 pub(crate) mod subscription;
 pub(crate) mod telemetry;
@@ -0,0 +1,244 @@
+//! Request ID plugin, correlating the logs of the router and of the subgraphs.
+
+use http::header::HeaderName;
+use http::HeaderMap;
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::plugin::serde::deserialize_header_name;
+use crate::plugin::PluginInit;
+use crate::plugin::PluginPrivate;
+use crate::register_private_plugin;
+use crate::services::http::HttpRequest;
+use crate::services::router;
+
+/// Context key of the ID of the client request, as a string
+pub(crate) const REQUEST_ID_CONTEXT_KEY: &str = "apollo_request_id::request_id";
+
+/// Longest request ID accepted from a client, longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Request ID configuration
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub(crate) struct RequestIdConfig {
+    /// Read the ID of the client requests from the `header_name` header, or generate a UUID when
+    /// it is absent, and send it back in the response and in the requests to the subgraphs.
+    /// Enabled by default
+    enabled: bool,
+    /// Name of the header carrying the request ID. Default value is `x-request-id`
+    #[schemars(with = "String")]
+    #[serde(deserialize_with = "deserialize_header_name")]
+    header_name: HeaderName,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            header_name: HeaderName::from_static("x-request-id"),
+        }
+    }
+}
+
+/// Propagates the ID of each client request to the subgraph requests it leads to.
+///
+/// The ID is stored in the context under the `apollo_request_id::request_id` key, where the
+/// other plugins can read it.
+#[derive(Debug, Clone)]
+struct RequestId {
+    config: RequestIdConfig,
+}
+
+#[async_trait::async_trait]
+impl PluginPrivate for RequestId {
+    type Config = RequestIdConfig;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(RequestId {
+            config: init.config,
+        })
+    }
+
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        if !self.config.enabled {
+            return service;
+        }
+        let header_name = self.config.header_name.clone();
+        let response_header_name = self.config.header_name.clone();
+        ServiceBuilder::new()
+            .map_request(move |request: router::Request| {
+                let request_id = request_id(request.router_request.headers(), &header_name);
+                if let Err(err) = request.context.insert(REQUEST_ID_CONTEXT_KEY, request_id) {
+                    tracing::error!("cannot store the request ID in the context: {err}");
+                }
+                request
+            })
+            .map_response(move |mut response: router::Response| {
+                if let Some(value) = context_request_id(&response.context) {
+                    response
+                        .response
+                        .headers_mut()
+                        .entry(response_header_name.clone())
+                        .or_insert(value);
+                }
+                response
+            })
+            .service(service)
+            .boxed()
+    }
+
+    fn http_client_service(
+        &self,
+        _subgraph_name: &str,
+        service: crate::services::http::BoxService,
+    ) -> crate::services::http::BoxService {
+        if !self.config.enabled {
+            return service;
+        }
+        let header_name = self.config.header_name.clone();
+        ServiceBuilder::new()
+            .map_request(move |mut request: HttpRequest| {
+                if let Some(value) = context_request_id(&request.context) {
+                    request
+                        .http_request
+                        .headers_mut()
+                        .insert(header_name.clone(), value);
+                }
+                request
+            })
+            .service(service)
+            .boxed()
+    }
+}
+
+/// Reads the ID of the client request, or generates one when it is absent or invalid
+fn request_id(headers: &HeaderMap, header_name: &HeaderName) -> String {
+    headers
+        .get(header_name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+fn context_request_id(context: &crate::Context) -> Option<HeaderValue> {
+    context
+        .get::<_, String>(REQUEST_ID_CONTEXT_KEY)
+        .ok()
+        .flatten()
+        .and_then(|id| HeaderValue::from_str(&id).ok())
+}
+
+register_private_plugin!("apollo", "request_id", RequestId);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::http::HttpResponse;
+    use crate::Context;
+
+    async fn call_router(config: RequestIdConfig, request: router::Request) -> router::Response {
+        let plugin = RequestId::new(PluginInit::fake_new(config, Default::default()))
+            .await
+            .unwrap();
+        let service = tower::service_fn(|request: router::Request| async move {
+            Ok::<_, BoxError>(router::Response {
+                response: http::Response::new(hyper::Body::empty()),
+                context: request.context,
+            })
+        })
+        .boxed();
+        plugin
+            .router_service(service)
+            .oneshot(request)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_client_request_id() {
+        let request = router::Request::fake_builder()
+            .header("x-request-id", "abc-123")
+            .build()
+            .unwrap();
+        let response = call_router(RequestIdConfig::default(), request).await;
+
+        assert_eq!(
+            response.response.headers().get("x-request-id").unwrap(),
+            "abc-123"
+        );
+        assert_eq!(
+            response
+                .context
+                .get::<_, String>(REQUEST_ID_CONTEXT_KEY)
+                .unwrap()
+                .unwrap(),
+            "abc-123"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_generates_a_request_id() {
+        let config = serde_json::from_value::<RequestIdConfig>(serde_json::json!({
+            "header_name": "x-correlation-id"
+        }))
+        .unwrap();
+        let request = router::Request::fake_builder()
+            .header("x-correlation-id", "")
+            .build()
+            .unwrap();
+        let response = call_router(config, request).await;
+
+        let request_id = response
+            .context
+            .get::<_, String>(REQUEST_ID_CONTEXT_KEY)
+            .unwrap()
+            .unwrap();
+        assert!(Uuid::parse_str(&request_id).is_ok());
+        assert_eq!(
+            response.response.headers().get("x-correlation-id").unwrap(),
+            request_id.as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_forwards_the_request_id_to_subgraphs() {
+        let plugin = RequestId::new(PluginInit::fake_new(
+            RequestIdConfig::default(),
+            Default::default(),
+        ))
+        .await
+        .unwrap();
+        let service = tower::service_fn(|request: HttpRequest| async move {
+            assert_eq!(
+                request.http_request.headers().get("x-request-id").unwrap(),
+                "abc-123"
+            );
+            Ok::<_, BoxError>(HttpResponse {
+                http_response: http::Response::new(hyper::Body::empty()),
+                context: request.context,
+            })
+        })
+        .boxed();
+        let context = Context::new();
+        context
+            .insert(REQUEST_ID_CONTEXT_KEY, "abc-123".to_string())
+            .unwrap();
+        plugin
+            .http_client_service("products", service)
+            .oneshot(HttpRequest {
+                http_request: http::Request::new(hyper::Body::empty()),
+                context,
+            })
+            .await
+            .unwrap();
+    }
+}
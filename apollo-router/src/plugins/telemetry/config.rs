@@ -262,6 +262,34 @@ pub(crate) struct Propagation {
     pub(crate) zipkin: bool,
     /// Propagate AWS X-Ray
     pub(crate) aws_xray: bool,
+    /// W3C trace context headers (`traceparent` and `tracestate`) of subgraph requests
+    pub(crate) subgraph_trace_context: SubgraphTraceContext,
+}
+
+/// W3C trace context headers of subgraph requests
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, Default)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct SubgraphTraceContext {
+    /// Send the trace context headers to subgraphs even when `trace_context` propagation is
+    /// disabled
+    pub(crate) enabled: bool,
+    /// Send the trace context headers received from the client to subgraphs as is, instead of
+    /// the trace context of the router
+    pub(crate) forward_incoming: bool,
+    /// What to do with a `traceparent` header already set on a subgraph request, for example by
+    /// header propagation or a coprocessor
+    pub(crate) existing_traceparent: ExistingTraceParent,
+}
+
+/// What to do with a `traceparent` header already set on a subgraph request
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExistingTraceParent {
+    /// Replace the existing headers with the trace context of the router
+    #[default]
+    Overwrite,
+    /// Keep the existing headers
+    Keep,
 }
 
 #[derive(Clone, Debug, Deserialize, JsonSchema, Default)]
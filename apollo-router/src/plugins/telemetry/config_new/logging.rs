@@ -1,7 +1,10 @@
 use std::collections::BTreeMap;
 use std::io::IsTerminal;
+use std::sync::Arc;
 use std::time::Duration;
 
+use http::HeaderMap;
+
 use schemars::gen::SchemaGenerator;
 use schemars::schema::InstanceType;
 use schemars::schema::Metadata;
@@ -21,6 +24,7 @@ use crate::plugins::telemetry::config::AttributeValue;
 use crate::plugins::telemetry::config_new::experimental_when_header::HeaderLoggingCondition;
 use crate::plugins::telemetry::resource::ConfigResource;
 use crate::services::SupergraphRequest;
+use crate::Context;
 
 /// Logging configuration.
 #[derive(Deserialize, JsonSchema, Clone, Default, Debug)]
@@ -38,6 +42,11 @@ pub(crate) struct Logging {
     /// Note that this will be removed when events are implemented.
     #[serde(rename = "experimental_when_header")]
     pub(crate) when_header: Vec<HeaderLoggingCondition>,
+
+    /// Headers whose values are redacted when the headers of the supergraph and subgraph
+    /// requests and responses are logged (default: authorization, proxy-authorization, cookie
+    /// and set-cookie)
+    pub(crate) redacted_headers: RedactedHeaders,
 }
 
 impl Logging {
@@ -73,6 +82,59 @@ impl Logging {
     }
 }
 
+/// Names of the headers whose values are redacted from the logs
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub(crate) struct RedactedHeaders(Vec<String>);
+
+impl Default for RedactedHeaders {
+    fn default() -> Self {
+        Self(
+            [
+                "authorization",
+                "proxy-authorization",
+                "cookie",
+                "set-cookie",
+            ]
+            .map(String::from)
+            .to_vec(),
+        )
+    }
+}
+
+impl RedactedHeaders {
+    /// Redacted headers of the logging configuration, added to the context by the telemetry
+    /// plugin. The default ones are redacted without it
+    pub(crate) fn from_context(context: &Context) -> Arc<Self> {
+        context
+            .extensions()
+            .lock()
+            .get::<Arc<Self>>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Marks the values of the redacted headers as sensitive, they are formatted as `Sensitive`
+    pub(crate) fn redact(&self, headers: &mut HeaderMap) {
+        for (name, value) in headers.iter_mut() {
+            if self
+                .0
+                .iter()
+                .any(|redacted| redacted.eq_ignore_ascii_case(name.as_str()))
+            {
+                value.set_sensitive(true);
+            }
+        }
+    }
+
+    /// Copy of the headers to log, with the values of the redacted ones
+    pub(crate) fn redacted(&self, headers: &HeaderMap) -> HeaderMap {
+        let mut headers = headers.clone();
+        self.redact(&mut headers);
+        headers
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, JsonSchema, Default)]
 #[serde(deny_unknown_fields, default)]
 pub(crate) struct LoggingCommon {
@@ -438,7 +500,32 @@ mod test {
     use crate::plugins::telemetry::config_new::experimental_when_header::HeaderLoggingCondition;
     use crate::plugins::telemetry::config_new::logging::Format;
     use crate::plugins::telemetry::config_new::logging::Logging;
+    use crate::plugins::telemetry::config_new::logging::RedactedHeaders;
     use crate::services::SupergraphRequest;
+
+    #[test]
+    fn redacted_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("x-api-key", "secret".parse().unwrap());
+        headers.insert("accept", "application/json".parse().unwrap());
+
+        let redacted = RedactedHeaders::default().redacted(&headers);
+        assert_eq!(
+            format!("{redacted:?}"),
+            r#"{"authorization": Sensitive, "x-api-key": "secret", "accept": "application/json"}"#
+        );
+
+        let redacted_headers =
+            serde_json::from_value::<RedactedHeaders>(json!(["Authorization", "X-Api-Key"]))
+                .unwrap();
+        let redacted = redacted_headers.redacted(&headers);
+        assert_eq!(
+            format!("{redacted:?}"),
+            r#"{"authorization": Sensitive, "x-api-key": Sensitive, "accept": "application/json"}"#
+        );
+    }
+
     #[test]
     fn format_de() {
         let format = serde_json::from_value::<Format>(json!("text")).unwrap();
@@ -65,6 +65,7 @@ use self::config_new::events::SupergraphEvents;
 use self::config_new::instruments::Instrumented;
 use self::config_new::instruments::RouterInstruments;
 use self::config_new::instruments::SubgraphInstruments;
+use self::config_new::logging::RedactedHeaders;
 use self::config_new::spans::Spans;
 use self::metrics::apollo::studio::SingleTypeStat;
 use self::metrics::AttributesForwardConf;
@@ -113,6 +114,7 @@ use crate::query_planner::OperationKind;
 use crate::register_plugin;
 use crate::router_factory::Endpoint;
 use crate::services::execution;
+use crate::services::http::trace_context::IncomingTraceContext;
 use crate::services::router;
 use crate::services::subgraph;
 use crate::services::subgraph::Request;
@@ -362,6 +364,8 @@ impl Plugin for Telemetry {
                         let _ = request.context.insert(CLIENT_VERSION, version.to_owned());
                     }
 
+                    Self::prepare_subgraph_trace_context(&config_request, request);
+
                     let mut custom_attributes = config_request
                         .instrumentation
                         .spans
@@ -553,9 +557,9 @@ impl Plugin for Telemetry {
                 }
 
                 if resp.context.contains_key(LOGGING_DISPLAY_HEADERS) {
-                    let sorted_headers = resp
-                        .response
-                        .headers()
+                    let headers = RedactedHeaders::from_context(&resp.context)
+                        .redacted(resp.response.headers());
+                    let sorted_headers = headers
                         .iter()
                         .map(|(k, v)| (k.as_str(), v))
                         .collect::<BTreeMap<_, _>>();
@@ -1011,11 +1015,13 @@ impl Telemetry {
         let http_request = &req.supergraph_request;
         let headers = http_request.headers();
 
+        // read by the services logging the headers of the subgraph requests and responses
+        let redacted_headers = Arc::new(config.exporters.logging.redacted_headers.clone());
+        context.extensions().lock().insert(redacted_headers.clone());
         let (should_log_headers, should_log_body) = config.exporters.logging.should_log(req);
         if should_log_headers {
-            let sorted_headers = req
-                .supergraph_request
-                .headers()
+            let headers = redacted_headers.redacted(req.supergraph_request.headers());
+            let sorted_headers = headers
                 .iter()
                 .map(|(k, v)| (k.as_str(), v))
                 .collect::<BTreeMap<_, _>>();
@@ -1054,6 +1060,21 @@ impl Telemetry {
         }
     }
 
+    /// Makes the subgraph trace context options, and the trace context headers of the client
+    /// request when they are forwarded, available to the HTTP clients of subgraphs
+    fn prepare_subgraph_trace_context(config: &Conf, request: &router::Request) {
+        let options = config.exporters.tracing.propagation.subgraph_trace_context;
+        let mut extensions = request.context.extensions().lock();
+        if options.forward_incoming {
+            if let Some(incoming) =
+                IncomingTraceContext::from_headers(request.router_request.headers())
+            {
+                extensions.insert(incoming);
+            }
+        }
+        extensions.insert(options);
+    }
+
     fn create_subgraph_metrics_conf(&self, name: &str) -> Arc<AttributesForwardConf> {
         let subgraph_cfg = &self.config.exporters.metrics.common.attributes.subgraph;
         macro_rules! extend_config {
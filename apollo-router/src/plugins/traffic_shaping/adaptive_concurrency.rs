@@ -0,0 +1,240 @@
+//! Adaptive limit of the requests in flight to a subgraph
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use opentelemetry_api::metrics::MeterProvider as _;
+use opentelemetry_api::metrics::ObservableGauge;
+use opentelemetry_api::KeyValue;
+use tokio::sync::Notify;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use crate::metrics::meter_provider;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_INITIAL_LIMIT: usize = 20;
+pub(super) const DEFAULT_MIN_LIMIT: usize = 1;
+pub(super) const DEFAULT_MAX_LIMIT: usize = 200;
+const DEFAULT_LATENCY_THRESHOLD: Duration = Duration::from_secs(1);
+const DEFAULT_BACKOFF_RATIO: f64 = 0.9;
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+}
+
+/// Limits the requests in flight to a subgraph, and adjusts the limit with an AIMD (additive
+/// increase, multiplicative decrease) algorithm: the limit grows by one when a request succeeds
+/// under the latency threshold while the limit is in use, and is multiplied by the backoff ratio
+/// when a request fails or is slower than the threshold. Requests over the limit wait for a
+/// request in flight to finish
+pub(crate) struct AdaptiveConcurrency {
+    min_limit: usize,
+    max_limit: usize,
+    latency_threshold: Duration,
+    backoff_ratio: f64,
+    state: Mutex<State>,
+    released: Notify,
+    // read by the gauge
+    current_limit: Arc<AtomicU64>,
+    _limit_gauge: ObservableGauge<u64>,
+}
+
+impl AdaptiveConcurrency {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        initial_limit: Option<usize>,
+        min_limit: Option<usize>,
+        max_limit: Option<usize>,
+        latency_threshold: Option<Duration>,
+        backoff_ratio: Option<f64>,
+    ) -> Self {
+        let min_limit = min_limit.unwrap_or(DEFAULT_MIN_LIMIT);
+        let max_limit = max_limit.unwrap_or(DEFAULT_MAX_LIMIT);
+        let initial_limit = initial_limit
+            .unwrap_or(DEFAULT_INITIAL_LIMIT)
+            .clamp(min_limit, max_limit);
+
+        let current_limit = Arc::new(AtomicU64::new(initial_limit as u64));
+        let limit_for_gauge = current_limit.clone();
+        let subgraph_name = subgraph_name.to_string();
+        let limit_gauge = meter_provider()
+            .meter("apollo/router")
+            .u64_observable_gauge("apollo.router.traffic_shaping.adaptive_concurrency.limit")
+            .with_description("Current limit of the requests in flight to a subgraph")
+            .with_callback(move |observer| {
+                observer.observe(
+                    limit_for_gauge.load(Ordering::Relaxed),
+                    &[KeyValue::new("subgraph.name", subgraph_name.clone())],
+                )
+            })
+            .init();
+
+        Self {
+            min_limit,
+            max_limit,
+            latency_threshold: latency_threshold.unwrap_or(DEFAULT_LATENCY_THRESHOLD),
+            backoff_ratio: backoff_ratio.unwrap_or(DEFAULT_BACKOFF_RATIO),
+            state: Mutex::new(State {
+                limit: initial_limit,
+                in_flight: 0,
+            }),
+            released: Notify::new(),
+            current_limit,
+            _limit_gauge: limit_gauge,
+        }
+    }
+
+    /// Current limit of the requests in flight
+    #[cfg(test)]
+    pub(crate) fn limit(&self) -> usize {
+        self.state.lock().expect("lock poisoned").limit
+    }
+
+    async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            // registered before checking the state so that a release in between is not missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().expect("lock poisoned");
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return Permit {
+                        limiter: self.clone(),
+                        start: Instant::now(),
+                        in_flight: state.in_flight,
+                        released: false,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn release(&self, sample: Option<(Duration, bool)>, in_flight: usize) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.in_flight -= 1;
+        let previous_limit = state.limit;
+        match sample {
+            Some((latency, failed)) if failed || latency > self.latency_threshold => {
+                state.limit = ((state.limit as f64 * self.backoff_ratio) as usize)
+                    .clamp(self.min_limit, self.max_limit);
+            }
+            // the limit only grows when it is actually reached, not when the subgraph gets
+            // little traffic
+            Some(_) if in_flight * 2 >= state.limit => {
+                state.limit = (state.limit + 1).min(self.max_limit);
+            }
+            _ => {}
+        }
+        if state.limit != previous_limit {
+            self.current_limit
+                .store(state.limit as u64, Ordering::Relaxed);
+        }
+        let increased = state.limit > previous_limit;
+        drop(state);
+
+        self.released.notify_one();
+        if increased {
+            self.released.notify_one();
+        }
+    }
+}
+
+/// A request in flight, counted in the limit until it is dropped
+struct Permit {
+    limiter: Arc<AdaptiveConcurrency>,
+    start: Instant,
+    // requests in flight when this one was sent, including itself
+    in_flight: usize,
+    released: bool,
+}
+
+impl Permit {
+    fn complete(mut self, failed: bool) {
+        self.released = true;
+        self.limiter
+            .release(Some((self.start.elapsed(), failed)), self.in_flight);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // cancelled requests do not change the limit
+        if !self.released {
+            self.limiter.release(None, self.in_flight);
+        }
+    }
+}
+
+/// Applies the adaptive concurrency limit of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct AdaptiveConcurrencyLayer {
+    limiter: Arc<AdaptiveConcurrency>,
+}
+
+impl AdaptiveConcurrencyLayer {
+    pub(crate) fn new(limiter: Arc<AdaptiveConcurrency>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for AdaptiveConcurrencyLayer {
+    type Service = AdaptiveConcurrencyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AdaptiveConcurrencyService {
+            // the inner service is called from the response future, once a permit is acquired
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+pub(crate) struct AdaptiveConcurrencyService<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    limiter: Arc<AdaptiveConcurrency>,
+}
+
+impl<S> Service<HttpRequest> for AdaptiveConcurrencyService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the limit is applied in the response future
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let permit = limiter.acquire().await;
+            let result = call_inner(&inner, request).await;
+            permit.complete(!matches!(
+                &result,
+                Ok(response) if !response.http_response.status().is_server_error()
+            ));
+            result
+        })
+    }
+}
@@ -0,0 +1,165 @@
+//! Bulkhead isolating the requests to a subgraph
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use opentelemetry_api::metrics::MeterProvider as _;
+use opentelemetry_api::metrics::ObservableGauge;
+use opentelemetry_api::KeyValue;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use crate::error::FetchError;
+use crate::metrics::meter_provider;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+/// Sends at most `max_concurrent` requests at once to a subgraph, and queues at most
+/// `max_queue` requests waiting for one of them to finish. Requests that do not fit in the
+/// queue fail immediately, so that a slow subgraph cannot accumulate requests in the router
+pub(crate) struct Bulkhead {
+    subgraph_name: String,
+    max_queue: usize,
+    in_flight: Arc<Semaphore>,
+    // read by the gauge
+    queued: Arc<AtomicUsize>,
+    _queue_gauge: ObservableGauge<u64>,
+}
+
+impl Bulkhead {
+    pub(crate) fn new(subgraph_name: &str, max_concurrent: usize, max_queue: usize) -> Self {
+        let queued = Arc::new(AtomicUsize::new(0));
+        let queued_for_gauge = queued.clone();
+        let subgraph_name_for_gauge = subgraph_name.to_string();
+        let queue_gauge = meter_provider()
+            .meter("apollo/router")
+            .u64_observable_gauge("apollo.router.traffic_shaping.bulkhead.queued")
+            .with_description("Number of requests waiting in the bulkhead queue of a subgraph")
+            .with_callback(move |observer| {
+                observer.observe(
+                    queued_for_gauge.load(Ordering::Relaxed) as u64,
+                    &[KeyValue::new(
+                        "subgraph.name",
+                        subgraph_name_for_gauge.clone(),
+                    )],
+                )
+            })
+            .init();
+
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            max_queue,
+            in_flight: Arc::new(Semaphore::new(max_concurrent)),
+            queued,
+            _queue_gauge: queue_gauge,
+        }
+    }
+
+    /// Number of requests waiting in the queue
+    #[cfg(test)]
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, FetchError> {
+        if let Ok(permit) = self.in_flight.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < self.max_queue).then_some(queued + 1)
+            })
+            .is_err()
+        {
+            u64_counter!(
+                "apollo.router.traffic_shaping.bulkhead.rejected",
+                "Number of subgraph requests rejected by a full bulkhead queue",
+                1,
+                "subgraph.name" = self.subgraph_name.clone()
+            );
+            return Err(FetchError::SubrequestOverloaded {
+                service: self.subgraph_name.clone(),
+            });
+        }
+        let _queued = Queued(&self.queued);
+        Ok(self
+            .in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed"))
+    }
+}
+
+/// A place in the queue, released when the request leaves it, even if it is cancelled
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Applies the bulkhead of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct BulkheadLayer {
+    bulkhead: Arc<Bulkhead>,
+}
+
+impl BulkheadLayer {
+    pub(crate) fn new(bulkhead: Arc<Bulkhead>) -> Self {
+        Self { bulkhead }
+    }
+}
+
+impl<S> Layer<S> for BulkheadLayer {
+    type Service = BulkheadService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BulkheadService {
+            // the inner service is called from the response future, once the request leaves the
+            // queue
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            bulkhead: self.bulkhead.clone(),
+        }
+    }
+}
+
+pub(crate) struct BulkheadService<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    bulkhead: Arc<Bulkhead>,
+}
+
+impl<S> Service<HttpRequest> for BulkheadService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the bulkhead is applied in the response future
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let bulkhead = self.bulkhead.clone();
+
+        Box::pin(async move {
+            let _permit = bulkhead.acquire().await?;
+            call_inner(&inner, request).await
+        })
+    }
+}
@@ -0,0 +1,397 @@
+//! Circuit breaker for subgraph requests
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use opentelemetry_api::metrics::MeterProvider as _;
+use opentelemetry_api::metrics::ObservableGauge;
+use opentelemetry_api::KeyValue;
+use serde::Deserialize;
+use serde::Serialize;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::is_rejected;
+use crate::error::FetchError;
+use crate::metrics::meter_provider;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+use crate::Context;
+
+/// State of the circuit breaker of each subgraph, when the requests of the current operation
+/// were sent
+pub(crate) const CIRCUIT_BREAKER_CONTEXT_KEY: &str = "apollo_traffic_shaping::circuit_breaker";
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitState {
+    /// Requests are sent to the subgraph
+    Closed,
+    /// Requests fail without being sent, until the end of the cooldown
+    Open,
+    /// A single request is sent to decide whether the circuit closes again
+    HalfOpen,
+}
+
+impl CircuitState {
+    const ALL: [CircuitState; 3] = [
+        CircuitState::Closed,
+        CircuitState::Open,
+        CircuitState::HalfOpen,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+enum State {
+    Closed { failures: VecDeque<Instant> },
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+impl State {
+    fn circuit_state(&self) -> CircuitState {
+        match self {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// Opens after too many failed requests to a subgraph within a window, so that requests fail
+/// fast instead of adding load to a failing subgraph. After the cooldown, a probe request
+/// decides whether the circuit closes again or stays open for another cooldown
+///
+/// A request fails when no response is received or when the response has a 5xx status.
+pub(crate) struct CircuitBreaker {
+    subgraph_name: String,
+    failure_threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+    // index of the current state in `CircuitState::ALL`, read by the gauge
+    current_state: Arc<AtomicU8>,
+    _state_gauge: ObservableGauge<u64>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        failure_threshold: Option<u32>,
+        window: Option<Duration>,
+        cooldown: Option<Duration>,
+    ) -> Self {
+        let current_state = Arc::new(AtomicU8::new(0));
+        let state_for_gauge = current_state.clone();
+        let subgraph_name_for_gauge = subgraph_name.to_string();
+        // one series per state, set to 1 for the current state. An open circuit becomes half
+        // open when a request is received after the cooldown
+        let state_gauge = meter_provider()
+            .meter("apollo/router")
+            .u64_observable_gauge("apollo.router.traffic_shaping.circuit_breaker.state")
+            .with_description("Current state of the circuit breaker of a subgraph")
+            .with_callback(move |observer| {
+                let current = state_for_gauge.load(Ordering::Relaxed) as usize;
+                for (index, state) in CircuitState::ALL.iter().enumerate() {
+                    observer.observe(
+                        u64::from(index == current),
+                        &[
+                            KeyValue::new("subgraph.name", subgraph_name_for_gauge.clone()),
+                            KeyValue::new("state", state.as_str()),
+                        ],
+                    )
+                }
+            })
+            .init();
+
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            failure_threshold: failure_threshold.unwrap_or(DEFAULT_FAILURE_THRESHOLD) as usize,
+            window: window.unwrap_or(DEFAULT_WINDOW),
+            cooldown: cooldown.unwrap_or(DEFAULT_COOLDOWN),
+            state: Mutex::new(State::Closed {
+                failures: VecDeque::new(),
+            }),
+            current_state,
+            _state_gauge: state_gauge,
+        }
+    }
+
+    /// State in which a request is sent, or the state rejecting it
+    fn admit(&self) -> Result<CircuitState, CircuitState> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        match &mut *state {
+            State::Closed { .. } => Ok(CircuitState::Closed),
+            State::Open { until } if Instant::now() < *until => Err(CircuitState::Open),
+            State::Open { .. } => {
+                self.transition(&mut state, State::HalfOpen { probing: true });
+                Ok(CircuitState::HalfOpen)
+            }
+            State::HalfOpen { probing: true } => Err(CircuitState::HalfOpen),
+            State::HalfOpen { probing } => {
+                *probing = true;
+                Ok(CircuitState::HalfOpen)
+            }
+        }
+    }
+
+    fn record(&self, admitted: CircuitState, success: bool) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        match &mut *state {
+            // only the probe decides whether the circuit closes
+            State::HalfOpen { .. } if admitted == CircuitState::HalfOpen => {
+                if success {
+                    self.transition(
+                        &mut state,
+                        State::Closed {
+                            failures: VecDeque::new(),
+                        },
+                    );
+                } else {
+                    self.open(&mut state);
+                }
+            }
+            State::Closed { failures } if !success => {
+                let now = Instant::now();
+                while failures
+                    .front()
+                    .is_some_and(|failure| now.duration_since(*failure) > self.window)
+                {
+                    failures.pop_front();
+                }
+                failures.push_back(now);
+                if failures.len() >= self.failure_threshold {
+                    self.open(&mut state);
+                }
+            }
+            // requests sent before the circuit opened do not change it
+            _ => {}
+        }
+    }
+
+    // the probe was cancelled before getting a response, the next request becomes the probe
+    fn cancel_probe(&self) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        if let State::HalfOpen { probing } = &mut *state {
+            *probing = false;
+        }
+    }
+
+    fn open(&self, state: &mut State) {
+        self.transition(
+            state,
+            State::Open {
+                until: Instant::now() + self.cooldown,
+            },
+        );
+    }
+
+    fn transition(&self, state: &mut State, new_state: State) {
+        let subgraph_name = &self.subgraph_name;
+        let new_circuit_state = new_state.circuit_state();
+        match new_circuit_state {
+            CircuitState::Open => tracing::warn!(
+                "the circuit breaker of subgraph '{subgraph_name}' is open for {:?}",
+                self.cooldown
+            ),
+            _ => tracing::info!(
+                "the circuit breaker of subgraph '{subgraph_name}' is {}",
+                new_circuit_state.as_str()
+            ),
+        }
+        u64_counter!(
+            "apollo.router.traffic_shaping.circuit_breaker.transitions",
+            "Number of state changes of subgraph circuit breakers",
+            1,
+            "subgraph.name" = subgraph_name.clone(),
+            "state" = new_circuit_state.as_str()
+        );
+        let index = CircuitState::ALL
+            .iter()
+            .position(|state| *state == new_circuit_state)
+            .expect("all the states are listed");
+        self.current_state.store(index as u8, Ordering::Relaxed);
+        *state = new_state;
+    }
+}
+
+/// Applies the circuit breaker of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct CircuitBreakerLayer {
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl CircuitBreakerLayer {
+    pub(crate) fn new(breaker: Arc<CircuitBreaker>) -> Self {
+        Self { breaker }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreakerService {
+            inner,
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+pub(crate) struct CircuitBreakerService<S> {
+    inner: S,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<S> Service<HttpRequest> for CircuitBreakerService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let admitted = self.breaker.admit();
+        let subgraph_name = &self.breaker.subgraph_name;
+        record_state(
+            &request.context,
+            subgraph_name,
+            admitted.unwrap_or_else(|state| state),
+        );
+
+        let admitted = match admitted {
+            Ok(admitted) => admitted,
+            Err(_) => {
+                u64_counter!(
+                    "apollo.router.traffic_shaping.circuit_breaker.rejected",
+                    "Number of subgraph requests rejected by an open circuit breaker",
+                    1,
+                    "subgraph.name" = subgraph_name.clone()
+                );
+                let error = FetchError::SubrequestCircuitOpen {
+                    service: subgraph_name.clone(),
+                };
+                return Box::pin(async move { Err::<HttpResponse, BoxError>(error.into()) });
+            }
+        };
+
+        let mut outcome = Outcome {
+            breaker: self.breaker.clone(),
+            admitted,
+            recorded: false,
+        };
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let result = response.await;
+            // a request rejected before being sent says nothing of the subgraph
+            if matches!(&result, Err(error) if is_rejected(error)) {
+                return result;
+            }
+            outcome.record(matches!(
+                &result,
+                Ok(response) if !response.http_response.status().is_server_error()
+            ));
+            result
+        })
+    }
+}
+
+/// Records the result of a request in the circuit breaker
+struct Outcome {
+    breaker: Arc<CircuitBreaker>,
+    admitted: CircuitState,
+    recorded: bool,
+}
+
+impl Outcome {
+    fn record(&mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(self.admitted, success);
+    }
+}
+
+impl Drop for Outcome {
+    fn drop(&mut self) {
+        if !self.recorded && self.admitted == CircuitState::HalfOpen {
+            self.breaker.cancel_probe();
+        }
+    }
+}
+
+fn record_state(context: &Context, subgraph_name: &str, state: CircuitState) {
+    if let Err(e) = context.upsert(
+        CIRCUIT_BREAKER_CONTEXT_KEY,
+        |mut states: HashMap<String, CircuitState>| {
+            states.insert(subgraph_name.to_string(), state);
+            states
+        },
+    ) {
+        tracing::error!(
+            "could not record the circuit breaker state of subgraph '{subgraph_name}': {e}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::FutureMetricsExt;
+
+    #[tokio::test]
+    async fn it_reports_the_state_of_the_circuit() {
+        async {
+            let breaker =
+                CircuitBreaker::new("products", Some(1), None, Some(Duration::from_millis(50)));
+            let assert_state = |current: CircuitState| {
+                for state in CircuitState::ALL {
+                    assert_gauge!(
+                        "apollo.router.traffic_shaping.circuit_breaker.state",
+                        u64::from(state == current),
+                        "subgraph.name" = "products",
+                        "state" = state.as_str()
+                    );
+                }
+            };
+            assert_state(CircuitState::Closed);
+
+            let admitted = breaker.admit().unwrap();
+            breaker.record(admitted, false);
+            assert_state(CircuitState::Open);
+
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            let admitted = breaker.admit().unwrap();
+            assert_state(CircuitState::HalfOpen);
+
+            breaker.record(admitted, true);
+            assert_state(CircuitState::Closed);
+        }
+        .with_metrics()
+        .await;
+    }
+}
@@ -0,0 +1,172 @@
+//! Active health checks of the endpoints of a subgraph
+
+use std::time::Duration;
+
+use http::header::CONTENT_TYPE;
+use http::header::USER_AGENT;
+use http::uri::PathAndQuery;
+use http::HeaderValue;
+use http::Method;
+use http::Uri;
+use hyper::Body;
+use tower::ServiceExt;
+
+use super::HealthCheckConfig;
+use crate::services::http::BoxCloneService;
+use crate::services::http::HttpRequest;
+use crate::Context;
+
+const DEFAULT_QUERY: &str = "query HealthCheck { __typename }";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_HEALTHY_THRESHOLD: u32 = 2;
+const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+
+/// Health check sent to each endpoint of a subgraph on an interval
+///
+/// The check is a `GET` request to `path` when it is set, and a GraphQL query sent to the endpoint
+/// otherwise. It passes when a 2xx response is received within the timeout.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct HealthCheck {
+    path: Option<PathAndQuery>,
+    query: String,
+    pub(super) interval: Duration,
+    timeout: Duration,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+}
+
+/// Results in a row of the health checks of an endpoint
+#[derive(Clone, Copy, Default)]
+pub(super) struct Streak {
+    passed: u32,
+    failed: u32,
+}
+
+impl HealthCheck {
+    pub(crate) fn new(config: &HealthCheckConfig) -> Self {
+        Self {
+            path: config
+                .path
+                .as_ref()
+                .map(|path| path.parse().expect("the path is validated")),
+            query: config
+                .query
+                .clone()
+                .unwrap_or_else(|| DEFAULT_QUERY.to_string()),
+            interval: config.interval.unwrap_or(DEFAULT_INTERVAL),
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            healthy_threshold: config
+                .healthy_threshold
+                .unwrap_or(DEFAULT_HEALTHY_THRESHOLD),
+            unhealthy_threshold: config
+                .unhealthy_threshold
+                .unwrap_or(DEFAULT_UNHEALTHY_THRESHOLD),
+        }
+    }
+
+    /// Whether the health check of the endpoint passes
+    pub(super) async fn check(&self, service: BoxCloneService, endpoint: &Uri) -> bool {
+        let request = HttpRequest {
+            http_request: self.request(endpoint),
+            context: Context::new(),
+        };
+        matches!(
+            tokio::time::timeout(self.timeout, service.oneshot(request)).await,
+            Ok(Ok(response)) if response.http_response.status().is_success()
+        )
+    }
+
+    fn request(&self, endpoint: &Uri) -> http::Request<Body> {
+        let mut request = match &self.path {
+            Some(path) => {
+                let mut parts = endpoint.clone().into_parts();
+                parts.path_and_query = Some(path.clone());
+                let mut request = http::Request::new(Body::empty());
+                *request.uri_mut() = Uri::from_parts(parts).unwrap_or_else(|_| endpoint.clone());
+                request
+            }
+            None => {
+                let body = serde_json::json!({ "query": self.query }).to_string();
+                let mut request = http::Request::new(Body::from(body));
+                *request.method_mut() = Method::POST;
+                *request.uri_mut() = endpoint.clone();
+                request
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                request
+            }
+        };
+        // the probes are not mistaken for client requests in the logs of the subgraph
+        request.headers_mut().insert(
+            USER_AGENT,
+            HeaderValue::from_static("apollo-router-health-check"),
+        );
+        request
+    }
+
+    /// Whether an endpoint is healthy after the result of its last health check
+    ///
+    /// An unhealthy endpoint becomes healthy after `healthy_threshold` checks in a row pass, and
+    /// a healthy endpoint becomes unhealthy after `unhealthy_threshold` checks in a row fail.
+    pub(super) fn update(&self, streak: &mut Streak, healthy: bool, passed: bool) -> bool {
+        if passed {
+            *streak = Streak {
+                passed: streak.passed + 1,
+                failed: 0,
+            };
+            healthy || streak.passed >= self.healthy_threshold
+        } else {
+            *streak = Streak {
+                passed: 0,
+                failed: streak.failed + 1,
+            };
+            healthy && streak.failed < self.unhealthy_threshold
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health_check(path: Option<&str>) -> HealthCheck {
+        HealthCheck::new(&HealthCheckConfig {
+            path: path.map(str::to_string),
+            query: None,
+            interval: None,
+            timeout: None,
+            healthy_threshold: None,
+            unhealthy_threshold: None,
+        })
+    }
+
+    #[test]
+    fn it_applies_the_thresholds() {
+        let health_check = health_check(None);
+        let mut streak = Streak::default();
+        let mut healthy = true;
+        let results: Vec<_> = [false, false, true, false, false, false, true, true]
+            .into_iter()
+            .map(|passed| {
+                healthy = health_check.update(&mut streak, healthy, passed);
+                healthy
+            })
+            .collect();
+        assert_eq!(results, [true, true, true, true, true, false, false, true]);
+    }
+
+    #[test]
+    fn it_sends_the_path_or_the_query() {
+        let endpoint = Uri::from_static("http://products-1:4001/graphql");
+
+        let request = health_check(Some("/health?ready")).request(&endpoint);
+        assert_eq!(request.method(), Method::GET);
+        assert_eq!(request.uri(), "http://products-1:4001/health?ready");
+
+        let request = health_check(None).request(&endpoint);
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri(), &endpoint);
+        assert_eq!(request.headers()[CONTENT_TYPE], "application/json");
+    }
+}
@@ -0,0 +1,266 @@
+//! Hedging of the subgraph HTTP requests that are slower than usual
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use super::http_retry::replay;
+use super::http_retry::take_extensions;
+use crate::query_planner::OperationKind;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_PERCENTILE: f64 = 95.0;
+const DEFAULT_MIN_DELAY: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_CONCURRENT: usize = 10;
+/// Number of recent latencies the delay is computed from
+const LATENCY_WINDOW: usize = 1000;
+/// Number of latencies recorded before the first request is hedged, and between two updates of
+/// the delay
+const MIN_SAMPLES: usize = 20;
+const NO_DELAY: u64 = u64::MAX;
+
+#[derive(Default)]
+struct Latencies {
+    samples: VecDeque<Duration>,
+    since_update: usize,
+}
+
+/// Sends a second request when the response of a query to a subgraph takes longer than a
+/// percentile of the latencies of its recent queries, and returns the first response received.
+/// The other request is cancelled.
+///
+/// Mutations are never hedged, as they may not be idempotent. At most `max_concurrent` hedged
+/// requests are in flight at once, the requests going over that limit only wait for their
+/// response.
+pub(crate) struct Hedging {
+    subgraph_name: String,
+    percentile: f64,
+    min_delay: Duration,
+    latencies: Mutex<Latencies>,
+    // delay before hedging, in nanoseconds, `NO_DELAY` until enough latencies are recorded
+    delay: AtomicU64,
+    hedges: Arc<Semaphore>,
+}
+
+impl Hedging {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        percentile: Option<f64>,
+        min_delay: Option<Duration>,
+        max_concurrent: Option<usize>,
+    ) -> Self {
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            percentile: percentile.unwrap_or(DEFAULT_PERCENTILE),
+            min_delay: min_delay.unwrap_or(DEFAULT_MIN_DELAY),
+            latencies: Mutex::new(Latencies::default()),
+            delay: AtomicU64::new(NO_DELAY),
+            hedges: Arc::new(Semaphore::new(
+                max_concurrent.unwrap_or(DEFAULT_MAX_CONCURRENT),
+            )),
+        }
+    }
+
+    /// Delay after which a query is hedged, if enough latencies were recorded
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        match self.delay.load(Ordering::Relaxed) {
+            NO_DELAY => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().expect("lock poisoned");
+        if latencies.samples.len() == LATENCY_WINDOW {
+            latencies.samples.pop_front();
+        }
+        latencies.samples.push_back(latency);
+        latencies.since_update += 1;
+        // sorting the window on each response would cost more than the requests it saves
+        if latencies.since_update < MIN_SAMPLES {
+            return;
+        }
+        latencies.since_update = 0;
+
+        let mut sorted: Vec<Duration> = latencies.samples.iter().copied().collect();
+        drop(latencies);
+        sorted.sort_unstable();
+        let rank = (self.percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        let delay = sorted[rank.clamp(1, sorted.len()) - 1].max(self.min_delay);
+        self.delay.store(
+            u64::try_from(delay.as_nanos()).unwrap_or(NO_DELAY - 1),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Takes a place for a hedged request, if the limit is not reached
+    fn try_hedge(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = self.hedges.clone().try_acquire_owned().ok()?;
+        u64_counter!(
+            "apollo.router.traffic_shaping.hedging.attempts",
+            "Number of hedged requests sent to a subgraph",
+            1,
+            "subgraph.name" = self.subgraph_name.clone()
+        );
+        Some(permit)
+    }
+
+    fn record_win(&self) {
+        u64_counter!(
+            "apollo.router.traffic_shaping.hedging.wins",
+            "Number of hedged requests to a subgraph answered before the original request",
+            1,
+            "subgraph.name" = self.subgraph_name.clone()
+        );
+    }
+
+    /// Records the latency of the response, up to its headers
+    async fn timed(
+        &self,
+        response: impl Future<Output = Result<HttpResponse, BoxError>>,
+    ) -> Result<HttpResponse, BoxError> {
+        let start = Instant::now();
+        let result = response.await;
+        if result.is_ok() {
+            self.record(start.elapsed());
+        }
+        result
+    }
+}
+
+/// Applies the hedging of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct HedgingLayer {
+    hedging: Arc<Hedging>,
+}
+
+impl HedgingLayer {
+    pub(crate) fn new(hedging: Arc<Hedging>) -> Self {
+        Self { hedging }
+    }
+}
+
+impl<S> Layer<S> for HedgingLayer {
+    type Service = HedgingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HedgingService {
+            // the inner service is called from the response future, once per request sent
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            hedging: self.hedging.clone(),
+        }
+    }
+}
+
+pub(crate) struct HedgingService<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    hedging: Arc<Hedging>,
+}
+
+impl<S> Service<HttpRequest> for HedgingService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is polled before each request sent
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let hedging = self.hedging.clone();
+
+        Box::pin(async move {
+            // requests without an operation kind are not known to be safe to send twice
+            if request.http_request.extensions().get::<OperationKind>()
+                != Some(&OperationKind::Query)
+            {
+                return call_inner(&inner, request).await;
+            }
+            let Some(delay) = hedging.delay() else {
+                return hedging.timed(call_inner(&inner, request)).await;
+            };
+
+            let HttpRequest {
+                http_request,
+                context,
+            } = request;
+            // the body is buffered to be sent again by the hedged request
+            let (mut parts, body) = http_request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            // the hedged request is sent with the replayed extensions, like a retry
+            let extensions = take_extensions(&mut parts);
+
+            let primary = hedging.timed(call_inner(
+                &inner,
+                HttpRequest {
+                    http_request: replay(&parts, Some(extensions), &body),
+                    context: context.clone(),
+                },
+            ));
+            tokio::pin!(primary);
+            tokio::select! {
+                result = &mut primary => return result,
+                _ = tokio::time::sleep(delay) => {}
+            }
+            let Some(permit) = hedging.try_hedge() else {
+                return primary.await;
+            };
+
+            let hedge = async {
+                let _permit = permit;
+                hedging
+                    .timed(call_inner(
+                        &inner,
+                        HttpRequest {
+                            http_request: replay(&parts, None, &body),
+                            context,
+                        },
+                    ))
+                    .await
+            };
+            tokio::pin!(hedge);
+            // the request that did not answer first is dropped, which cancels it. A request
+            // failing without a response still leaves the other one a chance to succeed
+            tokio::select! {
+                result = &mut primary => match result {
+                    Ok(_) => result,
+                    Err(_) => {
+                        let result = hedge.await;
+                        if result.is_ok() {
+                            hedging.record_win();
+                        }
+                        result
+                    }
+                },
+                result = &mut hedge => match result {
+                    Ok(_) => {
+                        hedging.record_win();
+                        result
+                    }
+                    Err(_) => primary.await,
+                },
+            }
+        })
+    }
+}
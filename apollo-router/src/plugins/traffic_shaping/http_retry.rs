@@ -0,0 +1,327 @@
+//! Retries of subgraph HTTP requests with an exponential backoff
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::header::RETRY_AFTER;
+use http::request::Parts;
+use http::Extensions;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::StatusCode;
+use hyper::Body;
+use rand::Rng;
+use tokio::sync::Mutex;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt;
+
+use super::is_rejected;
+use crate::query_planner::OperationKind;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+use crate::Context;
+
+/// Number of HTTP requests retried for the current operation, per subgraph
+pub(crate) const HTTP_RETRIES_CONTEXT_KEY: &str = "apollo_traffic_shaping::http_retries";
+/// Delay requested by the `Retry-After` header of the last retried response, per subgraph, in
+/// milliseconds
+pub(crate) const HTTP_RETRY_AFTER_CONTEXT_KEY: &str = "apollo_traffic_shaping::http_retry_after";
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+const DEFAULT_JITTER: f64 = 0.2;
+const DEFAULT_MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Delays between the attempts of a subgraph HTTP request
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Backoff {
+    max_attempts: u32,
+    base_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    queries_only: bool,
+    max_retry_after: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(
+        max_attempts: Option<u32>,
+        base_delay: Option<Duration>,
+        multiplier: Option<f64>,
+        jitter: Option<f64>,
+        queries_only: Option<bool>,
+        max_retry_after: Option<Duration>,
+    ) -> Self {
+        Self {
+            max_attempts: max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            base_delay: base_delay.unwrap_or(DEFAULT_BASE_DELAY),
+            multiplier: multiplier.unwrap_or(DEFAULT_MULTIPLIER),
+            jitter: jitter.unwrap_or(DEFAULT_JITTER),
+            queries_only: queries_only.unwrap_or(true),
+            max_retry_after: max_retry_after.unwrap_or(DEFAULT_MAX_RETRY_AFTER),
+        }
+    }
+
+    /// Delay before the given retry, starting at 1: the base delay multiplied by the multiplier
+    /// for each previous retry, randomly spread by the jitter
+    fn delay(&self, retry: u32) -> Duration {
+        let exponent = i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX);
+        let mut factor = self.multiplier.powi(exponent);
+        if self.jitter > 0.0 {
+            factor *= 1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        }
+        Duration::try_from_secs_f64(self.base_delay.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+    }
+
+    fn can_retry(&self, request: &http::Request<Body>) -> bool {
+        // requests without an operation kind are not known to be safe to replay
+        self.max_attempts > 1
+            && (!self.queries_only
+                || request.extensions().get::<OperationKind>() == Some(&OperationKind::Query))
+    }
+}
+
+/// Retries the subgraph HTTP requests that failed without a response (connection refused or
+/// reset, timeouts) or with a 502, 503 or 504 status, and the ones rejected with a 429 status
+/// and a `Retry-After` header
+///
+/// The `Retry-After` header of 429 and 503 responses replaces the backoff delay, up to
+/// `max_retry_after`.
+#[derive(Clone)]
+pub(crate) struct HttpRetryLayer {
+    backoff: Arc<Backoff>,
+    subgraph_name: Arc<String>,
+}
+
+impl HttpRetryLayer {
+    pub(crate) fn new(backoff: Backoff, subgraph_name: &str) -> Self {
+        Self {
+            backoff: Arc::new(backoff),
+            subgraph_name: Arc::new(subgraph_name.to_string()),
+        }
+    }
+}
+
+impl<S> Layer<S> for HttpRetryLayer {
+    type Service = HttpRetry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpRetry {
+            // the inner service is called once per attempt from the response future
+            inner: Arc::new(Mutex::new(inner)),
+            backoff: self.backoff.clone(),
+            subgraph_name: self.subgraph_name.clone(),
+        }
+    }
+}
+
+pub(crate) struct HttpRetry<S> {
+    inner: Arc<Mutex<S>>,
+    backoff: Arc<Backoff>,
+    subgraph_name: Arc<String>,
+}
+
+impl<S> Service<HttpRequest> for HttpRetry<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is polled before each attempt
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let backoff = self.backoff.clone();
+        let subgraph_name = self.subgraph_name.clone();
+
+        Box::pin(async move {
+            if !backoff.can_retry(&request.http_request) {
+                return call_inner(&inner, request).await;
+            }
+
+            let HttpRequest {
+                http_request,
+                context,
+            } = request;
+            // the body is buffered to be sent again on each attempt
+            let (mut parts, body) = http_request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            let mut extensions = Some(take_extensions(&mut parts));
+
+            let mut attempt = 1;
+            loop {
+                let result = call_inner(
+                    &inner,
+                    HttpRequest {
+                        http_request: replay(&parts, extensions.take(), &body),
+                        context: context.clone(),
+                    },
+                )
+                .await;
+
+                let (reason, retry_after) = match &result {
+                    Ok(response) => {
+                        let status = response.http_response.status();
+                        let retry_after = retry_after(status, response.http_response.headers());
+                        if !is_retryable_status(status) && retry_after.is_none() {
+                            return result;
+                        }
+                        (status.to_string(), retry_after)
+                    }
+                    // the request was not sent, retrying it would only add to the load
+                    Err(error) if is_rejected(error) => return result,
+                    Err(error) => (error.to_string(), None),
+                };
+                if attempt >= backoff.max_attempts {
+                    return result;
+                }
+                // releases the connection of the failed attempt
+                drop(result);
+
+                let delay = match retry_after {
+                    Some(retry_after) => {
+                        let delay = retry_after.min(backoff.max_retry_after);
+                        record_retry_after(&context, &subgraph_name, delay);
+                        delay
+                    }
+                    None => backoff.delay(attempt),
+                };
+                record_retry(&context, &subgraph_name, attempt);
+                tracing::info!(
+                    monotonic_counter.apollo_router_http_request_retry_total = 1u64,
+                    subgraph = %subgraph_name,
+                );
+                tracing::info!(
+                    "retrying the HTTP request to subgraph '{subgraph_name}' in {delay:?} after attempt {attempt} failed: {reason}"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        })
+    }
+}
+
+pub(super) async fn call_inner<S>(
+    inner: &Mutex<S>,
+    request: HttpRequest,
+) -> Result<HttpResponse, BoxError>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError>,
+{
+    // the lock is only held until the request is sent
+    let response = {
+        let mut inner = inner.lock().await;
+        inner.ready().await?.call(request)
+    };
+    response.await
+}
+
+/// Takes the extensions of a request that is sent several times
+///
+/// `Extensions` cannot be cloned: all of them are sent with the first attempt, and the ones
+/// replayed with the next attempts are kept in `parts`.
+pub(super) fn take_extensions(parts: &mut Parts) -> Extensions {
+    let extensions = std::mem::take(&mut parts.extensions);
+    copy_replayed_extensions(&extensions, &mut parts.extensions);
+    extensions
+}
+
+/// Builds an attempt of a request, with the extensions of the first attempt, or with the
+/// extensions kept in `parts` by `take_extensions` for the next attempts
+pub(super) fn replay(
+    parts: &Parts,
+    extensions: Option<Extensions>,
+    body: &Bytes,
+) -> http::Request<Body> {
+    let mut request = http::Request::new(Body::from(body.clone()));
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    match extensions {
+        Some(extensions) => *request.extensions_mut() = extensions,
+        None => copy_replayed_extensions(&parts.extensions, request.extensions_mut()),
+    }
+    request
+}
+
+/// Copies the extensions read by the traffic shaping layers: the `OperationKind`, which tells
+/// the queries that can be retried, hedged, batched or cached
+fn copy_replayed_extensions(from: &Extensions, to: &mut Extensions) {
+    if let Some(operation_kind) = from.get::<OperationKind>().copied() {
+        to.insert(operation_kind);
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Delay requested by a 429 or 503 response before the request is sent again
+fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if !matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    parse_retry_after(headers.get(RETRY_AFTER)?, SystemTime::now())
+}
+
+/// Parses a `Retry-After` value, either a number of seconds or an HTTP date
+pub(super) fn parse_retry_after(value: &HeaderValue, now: SystemTime) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // a date in the past allows an immediate retry
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+fn record_retry_after(context: &Context, subgraph_name: &str, delay: Duration) {
+    if let Err(e) = context.upsert(
+        HTTP_RETRY_AFTER_CONTEXT_KEY,
+        |mut delays: HashMap<String, u64>| {
+            delays.insert(
+                subgraph_name.to_string(),
+                u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+            );
+            delays
+        },
+    ) {
+        tracing::error!(
+            "could not record the Retry-After delay of subgraph '{subgraph_name}': {e}"
+        );
+    }
+}
+
+fn record_retry(context: &Context, subgraph_name: &str, resend_count: u32) {
+    if let Err(e) = context.upsert(
+        HTTP_RETRIES_CONTEXT_KEY,
+        |mut counts: HashMap<String, u32>| {
+            *counts.entry(subgraph_name.to_string()).or_default() += 1;
+            counts
+        },
+    ) {
+        tracing::error!("could not record the HTTP retries of subgraph '{subgraph_name}': {e}");
+    }
+    tracing::Span::current().record("http.request.resend_count", resend_count);
+}
@@ -0,0 +1,595 @@
+//! Client side load balancing of the requests to a subgraph across several endpoints
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use http::uri::PathAndQuery;
+use http::Uri;
+use opentelemetry_api::metrics::MeterProvider as _;
+use opentelemetry_api::KeyValue;
+use rand::Rng;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::health_check::HealthCheck;
+use super::health_check::Streak;
+use super::is_rejected;
+use super::LoadBalancingPolicy;
+use crate::error::FetchError;
+use crate::metrics::meter_provider;
+use crate::services::http::BoxCloneService;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_CONSECUTIVE_FAILURES: u32 = 5;
+const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+
+/// Ejection of the endpoints failing several requests in a row
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct OutlierDetection {
+    consecutive_failures: u32,
+    ejection_time: Duration,
+}
+
+impl OutlierDetection {
+    pub(crate) fn new(consecutive_failures: Option<u32>, ejection_time: Option<Duration>) -> Self {
+        Self {
+            consecutive_failures: consecutive_failures.unwrap_or(DEFAULT_CONSECUTIVE_FAILURES),
+            ejection_time: ejection_time.unwrap_or(DEFAULT_EJECTION_TIME),
+        }
+    }
+}
+
+/// Endpoints of a subgraph, with the number of requests in flight to each of them
+///
+/// With outlier detection, an endpoint failing several requests in a row is ejected: it receives
+/// no request until the end of the ejection time, then a single probe request decides whether it
+/// receives requests again or stays ejected for another ejection time. A request fails when no
+/// response is received or when the response has a 5xx status.
+///
+/// With health checks, an endpoint failing its health checks receives no request until they
+/// pass again.
+pub(crate) struct LoadBalancer {
+    subgraph_name: String,
+    endpoints: Vec<Endpoint>,
+    policy: LoadBalancingPolicy,
+    outlier_detection: Option<OutlierDetection>,
+    health_check: Option<HealthCheck>,
+    health_checks: Mutex<Option<JoinHandle<()>>>,
+    next: AtomicUsize,
+}
+
+struct Endpoint {
+    uri: Uri,
+    in_flight: Arc<AtomicUsize>,
+    health: Mutex<Health>,
+    passes_health_checks: Arc<AtomicBool>,
+}
+
+enum Health {
+    Healthy { failures: u32 },
+    Ejected { until: Instant },
+    Probing,
+}
+
+impl LoadBalancer {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        endpoints: Vec<Uri>,
+        policy: LoadBalancingPolicy,
+        outlier_detection: Option<OutlierDetection>,
+        health_check: Option<HealthCheck>,
+    ) -> Self {
+        assert!(!endpoints.is_empty(), "the endpoints are validated");
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            endpoints: endpoints
+                .into_iter()
+                .map(|uri| Endpoint {
+                    uri,
+                    in_flight: Default::default(),
+                    health: Mutex::new(Health::Healthy { failures: 0 }),
+                    // the endpoints receive requests until their health checks fail
+                    passes_health_checks: Arc::new(AtomicBool::new(true)),
+                })
+                .collect(),
+            policy,
+            outlier_detection,
+            health_check,
+            health_checks: Mutex::new(None),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Endpoint receiving a request, and whether the request is the probe of an ejected endpoint
+    fn select(&self) -> Result<(usize, bool), FetchError> {
+        if self.outlier_detection.is_some() {
+            if let Some(index) = self.start_probe() {
+                return Ok((index, true));
+            }
+        }
+
+        let len = self.endpoints.len();
+        let start = match self.policy {
+            LoadBalancingPolicy::RoundRobin | LoadBalancingPolicy::LeastConnections => {
+                self.next.fetch_add(1, Ordering::Relaxed) % len
+            }
+            LoadBalancingPolicy::Random => rand::thread_rng().gen_range(0..len),
+        };
+        // ejected endpoints are skipped, the next healthy endpoint is selected instead
+        let mut healthy = (0..len)
+            .map(|offset| (start + offset) % len)
+            .filter(|index| self.is_healthy(*index));
+        let index = match self.policy {
+            LoadBalancingPolicy::RoundRobin | LoadBalancingPolicy::Random => healthy.next(),
+            // the search starts from each endpoint in turn, so that endpoints with the same
+            // number of requests in flight share the load
+            LoadBalancingPolicy::LeastConnections => {
+                healthy.min_by_key(|index| self.endpoints[*index].in_flight.load(Ordering::Relaxed))
+            }
+        };
+        index
+            .map(|index| (index, false))
+            .ok_or_else(|| FetchError::SubrequestEndpointsEjected {
+                service: self.subgraph_name.clone(),
+            })
+    }
+
+    fn is_healthy(&self, index: usize) -> bool {
+        let endpoint = &self.endpoints[index];
+        endpoint.passes_health_checks.load(Ordering::Relaxed)
+            && (self.outlier_detection.is_none()
+                || matches!(
+                    *endpoint.health.lock().expect("lock poisoned"),
+                    Health::Healthy { .. }
+                ))
+    }
+
+    // the first endpoint at the end of its ejection time receives the next request as a probe
+    fn start_probe(&self) -> Option<usize> {
+        let now = Instant::now();
+        self.endpoints.iter().position(|endpoint| {
+            if !endpoint.passes_health_checks.load(Ordering::Relaxed) {
+                return false;
+            }
+            let mut health = endpoint.health.lock().expect("lock poisoned");
+            let expired = matches!(*health, Health::Ejected { until } if until <= now);
+            if expired {
+                *health = Health::Probing;
+            }
+            expired
+        })
+    }
+
+    fn record(&self, index: usize, probe: bool, success: bool) {
+        let Some(outlier_detection) = &self.outlier_detection else {
+            return;
+        };
+        let endpoint = &self.endpoints[index];
+        let mut health = endpoint.health.lock().expect("lock poisoned");
+        match &mut *health {
+            Health::Probing if probe => {
+                if success {
+                    tracing::info!(
+                        "endpoint {} of subgraph '{}' receives requests again",
+                        endpoint.uri,
+                        self.subgraph_name
+                    );
+                    *health = Health::Healthy { failures: 0 };
+                } else {
+                    self.eject(endpoint, &mut health, outlier_detection);
+                }
+            }
+            Health::Healthy { failures } if success => *failures = 0,
+            Health::Healthy { failures } => {
+                *failures += 1;
+                if *failures >= outlier_detection.consecutive_failures {
+                    self.eject(endpoint, &mut health, outlier_detection);
+                }
+            }
+            // requests sent before the endpoint was ejected do not change it
+            _ => {}
+        }
+    }
+
+    // the probe was cancelled before getting a response, the next request becomes the probe
+    fn cancel_probe(&self, index: usize) {
+        let mut health = self.endpoints[index].health.lock().expect("lock poisoned");
+        if let Health::Probing = *health {
+            *health = Health::Ejected {
+                until: Instant::now(),
+            };
+        }
+    }
+
+    fn eject(
+        &self,
+        endpoint: &Endpoint,
+        health: &mut Health,
+        outlier_detection: &OutlierDetection,
+    ) {
+        tracing::warn!(
+            "endpoint {} of subgraph '{}' is ejected for {:?}",
+            endpoint.uri,
+            self.subgraph_name,
+            outlier_detection.ejection_time
+        );
+        u64_counter!(
+            "apollo.router.traffic_shaping.load_balancing.ejections",
+            "Number of ejections of subgraph endpoints by the outlier detection",
+            1,
+            "subgraph.name" = self.subgraph_name.clone(),
+            "endpoint" = endpoint.uri.to_string()
+        );
+        *health = Health::Ejected {
+            until: Instant::now() + outlier_detection.ejection_time,
+        };
+    }
+
+    /// Whether health checks are configured but not sent yet
+    pub(crate) fn needs_health_checks(&self) -> bool {
+        self.health_check.is_some() && self.health_checks.lock().expect("lock poisoned").is_none()
+    }
+
+    /// Starts sending the health checks of the endpoints with the client of the subgraph, unless
+    /// they are already sent
+    pub(crate) fn start_health_checks(self: &Arc<Self>, service: BoxCloneService) {
+        let Some(health_check) = self.health_check.clone() else {
+            return;
+        };
+        let mut health_checks = self.health_checks.lock().expect("lock poisoned");
+        if health_checks.is_none() {
+            *health_checks = Some(tokio::spawn(check_health(
+                Arc::downgrade(self),
+                health_check,
+                service,
+            )));
+        }
+    }
+
+    fn record_health_check(&self, endpoint: &Endpoint, healthy: bool) {
+        if endpoint
+            .passes_health_checks
+            .swap(healthy, Ordering::Relaxed)
+            == healthy
+        {
+            return;
+        }
+        if healthy {
+            tracing::info!(
+                "endpoint {} of subgraph '{}' passes its health checks",
+                endpoint.uri,
+                self.subgraph_name
+            );
+        } else {
+            tracing::warn!(
+                "endpoint {} of subgraph '{}' fails its health checks",
+                endpoint.uri,
+                self.subgraph_name
+            );
+        }
+    }
+}
+
+impl Drop for LoadBalancer {
+    fn drop(&mut self) {
+        if let Ok(Some(health_checks)) = self.health_checks.get_mut() {
+            health_checks.abort();
+        }
+    }
+}
+
+/// Sends the health checks of the endpoints on an interval, until the load balancer is dropped
+async fn check_health(
+    load_balancer: Weak<LoadBalancer>,
+    health_check: HealthCheck,
+    service: BoxCloneService,
+) {
+    let Some(_health_gauge) = load_balancer.upgrade().map(|load_balancer| {
+        let subgraph_name = load_balancer.subgraph_name.clone();
+        let endpoints: Vec<_> = load_balancer
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                (
+                    endpoint.uri.to_string(),
+                    endpoint.passes_health_checks.clone(),
+                )
+            })
+            .collect();
+        meter_provider()
+            .meter("apollo/router")
+            .u64_observable_gauge("apollo.router.traffic_shaping.health_check.healthy")
+            .with_description("Whether an endpoint of a subgraph passes its health checks")
+            .with_callback(move |observer| {
+                for (endpoint, healthy) in &endpoints {
+                    observer.observe(
+                        healthy.load(Ordering::Relaxed) as u64,
+                        &[
+                            KeyValue::new("subgraph.name", subgraph_name.clone()),
+                            KeyValue::new("endpoint", endpoint.clone()),
+                        ],
+                    )
+                }
+            })
+            .init()
+    }) else {
+        return;
+    };
+
+    let mut streaks = Vec::new();
+    let mut interval = tokio::time::interval(health_check.interval);
+    loop {
+        interval.tick().await;
+        let Some(load_balancer) = load_balancer.upgrade() else {
+            return;
+        };
+        let results = futures::future::join_all(
+            load_balancer
+                .endpoints
+                .iter()
+                .map(|endpoint| health_check.check(service.clone(), &endpoint.uri)),
+        )
+        .await;
+        streaks.resize(results.len(), Streak::default());
+        for ((endpoint, passed), streak) in load_balancer
+            .endpoints
+            .iter()
+            .zip(results)
+            .zip(&mut streaks)
+        {
+            let healthy = health_check.update(
+                streak,
+                endpoint.passes_health_checks.load(Ordering::Relaxed),
+                passed,
+            );
+            load_balancer.record_health_check(endpoint, healthy);
+        }
+    }
+}
+
+/// A request in flight to an endpoint, counted until its response is received or it fails
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Sends each request to the subgraph to one of its endpoints, instead of the subgraph URL
+#[derive(Clone)]
+pub(crate) struct LoadBalancerLayer {
+    load_balancer: Arc<LoadBalancer>,
+}
+
+impl LoadBalancerLayer {
+    pub(crate) fn new(load_balancer: Arc<LoadBalancer>) -> Self {
+        Self { load_balancer }
+    }
+}
+
+impl<S> Layer<S> for LoadBalancerLayer {
+    type Service = LoadBalancerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LoadBalancerService {
+            inner,
+            load_balancer: self.load_balancer.clone(),
+        }
+    }
+}
+
+pub(crate) struct LoadBalancerService<S> {
+    inner: S,
+    load_balancer: Arc<LoadBalancer>,
+}
+
+impl<S> Service<HttpRequest> for LoadBalancerService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: HttpRequest) -> Self::Future {
+        let (index, probe) = match self.load_balancer.select() {
+            Ok(selected) => selected,
+            Err(error) => {
+                return Box::pin(async move { Err::<HttpResponse, BoxError>(error.into()) })
+            }
+        };
+        let endpoint = &self.load_balancer.endpoints[index];
+        let uri = endpoint_uri(&endpoint.uri, request.http_request.uri());
+        *request.http_request.uri_mut() = uri;
+        let in_flight = InFlight::new(&endpoint.in_flight);
+        let mut outcome = Outcome {
+            load_balancer: self.load_balancer.clone(),
+            index,
+            probe,
+            recorded: false,
+        };
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+            drop(in_flight);
+            // a request rejected before being sent says nothing of the endpoint
+            if matches!(&response, Err(error) if is_rejected(error)) {
+                return response;
+            }
+            outcome.record(matches!(
+                &response,
+                Ok(response) if !response.http_response.status().is_server_error()
+            ));
+            response
+        })
+    }
+}
+
+/// Records the result of a request in the health of its endpoint
+struct Outcome {
+    load_balancer: Arc<LoadBalancer>,
+    index: usize,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Outcome {
+    fn record(&mut self, success: bool) {
+        self.recorded = true;
+        self.load_balancer.record(self.index, self.probe, success);
+    }
+}
+
+impl Drop for Outcome {
+    fn drop(&mut self) {
+        if !self.recorded && self.probe {
+            self.load_balancer.cancel_probe(self.index);
+        }
+    }
+}
+
+/// The endpoint URL replaces the subgraph URL, while the query string of the request is kept
+pub(super) fn endpoint_uri(endpoint: &Uri, uri: &Uri) -> Uri {
+    let Some(query) = uri.query() else {
+        return endpoint.clone();
+    };
+    let mut parts = endpoint.clone().into_parts();
+    parts.path_and_query = PathAndQuery::try_from(format!("{}?{query}", endpoint.path())).ok();
+    Uri::from_parts(parts).unwrap_or_else(|_| endpoint.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_balancer(
+        policy: LoadBalancingPolicy,
+        outlier_detection: Option<OutlierDetection>,
+    ) -> LoadBalancer {
+        LoadBalancer::new(
+            "products",
+            vec![
+                Uri::from_static("http://products-1:4001/graphql"),
+                Uri::from_static("http://products-2:4001/graphql"),
+                Uri::from_static("http://products-3:4001/graphql"),
+            ],
+            policy,
+            outlier_detection,
+            None,
+        )
+    }
+
+    fn select(load_balancer: &LoadBalancer) -> &Endpoint {
+        &load_balancer.endpoints[load_balancer.select().unwrap().0]
+    }
+
+    fn host(load_balancer: &LoadBalancer) -> String {
+        select(load_balancer).uri.host().unwrap().to_string()
+    }
+
+    #[test]
+    fn it_selects_endpoints_in_turn() {
+        let load_balancer = load_balancer(LoadBalancingPolicy::RoundRobin, None);
+        let selected: Vec<_> = (0..4).map(|_| host(&load_balancer)).collect();
+        assert_eq!(
+            selected,
+            ["products-1", "products-2", "products-3", "products-1"]
+        );
+    }
+
+    #[test]
+    fn it_selects_the_endpoint_with_the_fewest_requests_in_flight() {
+        let load_balancer = load_balancer(LoadBalancingPolicy::LeastConnections, None);
+        let first = InFlight::new(&select(&load_balancer).in_flight);
+        let second = InFlight::new(&select(&load_balancer).in_flight);
+        assert_eq!(host(&load_balancer), "products-3");
+
+        drop(second);
+        assert_eq!(host(&load_balancer), "products-2");
+        drop(first);
+    }
+
+    #[test]
+    fn it_ejects_failing_endpoints() {
+        let load_balancer = load_balancer(
+            LoadBalancingPolicy::RoundRobin,
+            Some(OutlierDetection::new(
+                Some(2),
+                Some(Duration::from_millis(50)),
+            )),
+        );
+        load_balancer.record(1, false, false);
+        load_balancer.record(1, false, true);
+        load_balancer.record(1, false, false);
+        assert_eq!(host(&load_balancer), "products-1");
+        assert_eq!(host(&load_balancer), "products-2");
+
+        load_balancer.record(1, false, false);
+        let selected: Vec<_> = (0..3).map(|_| host(&load_balancer)).collect();
+        assert_eq!(selected, ["products-3", "products-1", "products-3"]);
+
+        // after the ejection time, a single probe is sent to the endpoint
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(load_balancer.select().unwrap(), (1, true));
+        assert!(!load_balancer.select().unwrap().1);
+        assert!(!load_balancer.is_healthy(1));
+        load_balancer.record(1, true, true);
+        assert!(load_balancer.is_healthy(1));
+    }
+
+    #[test]
+    fn it_fails_when_all_endpoints_are_ejected() {
+        let load_balancer = load_balancer(
+            LoadBalancingPolicy::LeastConnections,
+            Some(OutlierDetection::new(Some(1), None)),
+        );
+        for index in 0..3 {
+            load_balancer.record(index, false, false);
+        }
+        assert_eq!(
+            load_balancer.select().unwrap_err().to_string(),
+            "HTTP request to 'products' was not sent because all its endpoints are ejected"
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_query_of_the_request() {
+        let endpoint = Uri::from_static("https://products-1:4001/graphql");
+        assert_eq!(
+            endpoint_uri(
+                &endpoint,
+                &Uri::from_static("http://products/?query=%7Bme%7D")
+            ),
+            "https://products-1:4001/graphql?query=%7Bme%7D"
+        );
+        assert_eq!(
+            endpoint_uri(&endpoint, &Uri::from_static("http://products/")),
+            endpoint
+        );
+    }
+}
@@ -6,45 +6,112 @@
 //! * Compression
 //! * Rate limiting
 //!
+mod adaptive_concurrency;
+mod bulkhead;
+mod circuit_breaker;
 mod deduplication;
+mod health_check;
+mod hedging;
+mod http_retry;
+mod load_balancer;
 pub(crate) mod rate;
+mod redirect;
+mod request_batching;
+mod response_cache;
 mod retry;
 pub(crate) mod timeout;
+mod token_bucket;
+mod url_template;
 
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::num::NonZeroU64;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
 use futures::future::BoxFuture;
+use http::header::HeaderName;
 use http::header::CONTENT_ENCODING;
+use http::uri::PathAndQuery;
+use http::HeaderMap;
 use http::HeaderValue;
+use http::Uri;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::retry::Retry;
 use tower::util::Either;
+use tower::util::MapRequestLayer;
 use tower::util::Oneshot;
 use tower::BoxError;
 use tower::Service;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use self::adaptive_concurrency::AdaptiveConcurrency;
+use self::adaptive_concurrency::AdaptiveConcurrencyLayer;
+use self::adaptive_concurrency::DEFAULT_MAX_LIMIT;
+use self::adaptive_concurrency::DEFAULT_MIN_LIMIT;
+use self::bulkhead::Bulkhead;
+use self::bulkhead::BulkheadLayer;
+use self::circuit_breaker::CircuitBreaker;
+use self::circuit_breaker::CircuitBreakerLayer;
 use self::deduplication::QueryDeduplicationLayer;
+use self::health_check::HealthCheck;
+use self::hedging::Hedging;
+use self::hedging::HedgingLayer;
+use self::http_retry::Backoff;
+use self::http_retry::HttpRetryLayer;
+use self::load_balancer::LoadBalancer;
+use self::load_balancer::LoadBalancerLayer;
+use self::load_balancer::OutlierDetection;
 use self::rate::RateLimitLayer;
 pub(crate) use self::rate::RateLimited;
+use self::redirect::FollowRedirectsLayer;
+use self::redirect::RedirectPolicy;
+use self::request_batching::RequestBatcher;
+use self::request_batching::RequestBatchingLayer;
+use self::response_cache::ResponseCache;
+use self::response_cache::ResponseCacheLayer;
 pub(crate) use self::retry::RetryPolicy;
 pub(crate) use self::timeout::Elapsed;
 use self::timeout::TimeoutLayer;
+use self::token_bucket::TokenBucket;
+use self::token_bucket::TokenBucketLayer;
+use self::url_template::UrlTemplate;
+use self::url_template::UrlTemplateLayer;
 use crate::error::ConfigurationError;
-use crate::plugin::Plugin;
+use crate::error::FetchError;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::serde::deserialize_header_name;
+use crate::plugin::serde::deserialize_header_value;
+use crate::plugin::serde::deserialize_option_header_value;
 use crate::plugin::PluginInit;
-use crate::register_plugin;
+use crate::plugin::PluginPrivate;
+use crate::register_private_plugin;
+use crate::services::http::service::user_agent;
 use crate::services::http::service::Compression;
+use crate::services::http::service::CompressionLevel;
+use crate::services::http::service::HttpClientConfig;
+use crate::services::http::service::COMPRESSION_LEVELS;
+use crate::services::http::BoxCloneService;
+use crate::services::http::Drain;
+use crate::services::http::EmptyResponse;
+use crate::services::http::HttpRequest;
+use crate::services::http::ResponseStatuses;
+use crate::services::http::StatusOutcome;
 use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::services::SubgraphRequest;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// flow control window sizes allowed by the HTTP2 specification
+const HTTP2_WINDOW_SIZES: RangeInclusive<u32> = 65_535..=2_147_483_647;
+const LOG_BODIES_MAX_BYTES: usize = 4096;
+// the DSCP field is 6 bits long
+const MAX_DSCP: u8 = 63;
 pub(crate) const APOLLO_TRAFFIC_SHAPING: &str = "apollo.traffic_shaping";
 
 trait Merge {
@@ -57,19 +124,187 @@ trait Merge {
 struct Shaping {
     /// Enable query deduplication
     deduplicate_query: Option<bool>,
-    /// Enable compression for subgraphs (available compressions are deflate, br, gzip)
+    /// Enable compression for subgraphs (available compressions are deflate, br, gzip, zstd).
+    /// `off` disables the compression negotiation: no `Accept-Encoding` is sent and the
+    /// responses are passed through without decoding their `Content-Encoding`
     compression: Option<Compression>,
+    /// Compression level used for subgraph requests: `fastest`, `best`, `default` or a number
+    /// from 0 to 9
+    compression_level: Option<CompressionLevel>,
+    /// Minimum size in bytes of a subgraph request body to compress it. Smaller bodies are sent
+    /// uncompressed (all bodies are compressed by default)
+    compression_min_size: Option<usize>,
+    /// Maximum size in bytes of a compressed subgraph response body once decompressed. Reading
+    /// the response fails with an error when it goes over this limit (no limit by default)
+    max_decompressed_bytes: Option<usize>,
+    /// Maximum size in bytes of a subgraph response body as received, before decompression.
+    /// Reading the response fails with an error as soon as it goes over this limit (no limit by
+    /// default)
+    max_response_bytes: Option<usize>,
+    /// Maximum size in bytes of a subgraph request body, checked before compression. Larger
+    /// requests fail with an error without being sent to the subgraph (no limit by default)
+    max_request_bytes: Option<usize>,
+    /// Maximum total size in bytes of the header names and values of a subgraph response. Larger
+    /// headers fail the request with an error naming the subgraph. Must not be zero, no limit
+    /// by default apart from the HTTP client's, about 400KB for HTTP1
+    max_response_header_size: Option<usize>,
+    /// Maximum number of headers of a subgraph response, counting each value of a repeated
+    /// header. Must not be zero. HTTP1 responses are limited to 100 headers by the HTTP client
+    max_response_headers: Option<usize>,
+    /// Send the `Expect: 100-continue` header with large request bodies, and wait for the
+    /// subgraph to accept the request before sending the body
+    expect_continue: Option<ExpectContinueConfig>,
+    /// Maximum number of idle connections kept open to a subgraph host (no limit by default)
+    pool_max_idle_per_host: Option<usize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Close connections to a subgraph after they have been idle for this duration. Must not be
+    /// zero, default value is 5 seconds
+    pool_idle_timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time a request to the subgraph waits for a connection, idle in the pool or newly
+    /// established, before it fails with a `CONNECTION_POOL_TIMEOUT` error. Must not be zero, no
+    /// timeout by default
+    pool_acquire_timeout: Option<Duration>,
+    /// Open connections to the subgraph before it receives traffic, spread over a ramp to avoid
+    /// connecting all at once. Disabled by default
+    warmup: Option<WarmupConfig>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Idle time of a subgraph connection before TCP keepalive probes are sent. Must not be
+    /// zero, default value is 60 seconds
+    tcp_keepalive: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Interval between TCP keepalive probes on subgraph connections. Must not be zero, the
+    /// operating system default is used if not set
+    tcp_keepalive_interval: Option<Duration>,
+    /// Disable Nagle's algorithm (`TCP_NODELAY`) on the subgraph connections, so that small
+    /// writes are sent without waiting to be coalesced. Default value is true
+    tcp_nodelay: Option<bool>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout of the connection to the subgraph, including the proxy tunnel and the TLS
+    /// handshake. Must not be zero, default value is 5 seconds
+    connect_timeout: Option<Duration>,
+    /// DSCP value (0-63) marking the IP packets of the connections to the subgraph, for quality
+    /// of service. Ignored with a warning on the platforms where it cannot be set
+    dscp: Option<u8>,
+    /// Priority (`SO_PRIORITY`) of the connections to the subgraph, used by Linux to queue
+    /// their packets. Ignored with a warning on other platforms
+    socket_priority: Option<u32>,
+    /// Attempt IPv6 and IPv4 connections to subgraphs concurrently (Happy Eyeballs)
+    happy_eyeballs: Option<HappyEyeballsConfig>,
+    /// Local IP address the connections to the subgraph are bound to. The subgraph is only
+    /// connected to with the addresses of the same family, IPv4 or IPv6. Not bound by default
+    local_address: Option<IpAddr>,
+    /// Send subgraph requests through an HTTP or SOCKS5 proxy
+    proxy: Option<ProxyConfig>,
+    /// Addresses (IP and port) to connect to instead of resolving these subgraph hosts. The
+    /// original host is still used for TLS. Entries set per subgraph take precedence over the
+    /// ones set for all subgraphs
+    host_overrides: Option<HashMap<String, SocketAddr>>,
     /// Enable global rate limiting
     global_rate_limit: Option<RateLimitConf>,
     #[serde(deserialize_with = "humantime_serde::deserialize", default)]
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
     timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout of each HTTP request to the subgraph, covering the connection, the TLS handshake
+    /// and the whole response. Must not be zero, no timeout by default
+    request_timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time given to the requests sent to the subgraph by a router that a configuration reload
+    /// replaced to complete, before they fail. Must not be zero, default value is 30 seconds
+    drain_timeout: Option<Duration>,
     /// Retry configuration
     //  *experimental feature*: Enables request retry
     experimental_retry: Option<RetryConfig>,
+    /// Retry HTTP requests to the subgraph that failed without a response or with a 502, 503 or
+    /// 504 status, with an exponential backoff
+    http_retry: Option<HttpRetryConfig>,
+    /// Send a second request when a query to the subgraph is slower than most recent ones, and
+    /// use the first response received
+    hedging: Option<HedgingConfig>,
+    /// Cache the responses to the queries sent to the subgraph for the `max-age` of their
+    /// `Cache-Control` header
+    response_cache: Option<ResponseCacheConfig>,
+    /// Send the concurrent queries to the subgraph as a single HTTP request with a JSON array of
+    /// operations, for subgraphs supporting batch requests
+    request_batching: Option<RequestBatchingConfig>,
+    /// Follow the redirects returned by the subgraph (disabled by default)
+    follow_redirects: Option<FollowRedirectsConfig>,
+    /// Fail requests to the subgraph without sending them for a while after too many of them
+    /// failed
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Adjust the limit of requests in flight to the subgraph to keep their latency bounded
+    adaptive_concurrency: Option<AdaptiveConcurrencyConfig>,
+    /// Limit the rate of HTTP requests to the subgraph with a token bucket
+    token_bucket: Option<TokenBucketConfig>,
+    /// Limit the HTTP requests in flight to the subgraph and the requests waiting for them
+    bulkhead: Option<BulkheadConfig>,
+    /// Spread the HTTP requests to the subgraph across several endpoints
+    load_balancing: Option<LoadBalancingConfig>,
+    /// URL of the requests to the subgraph, where the `{key}` placeholders are replaced with the
+    /// values of the context entries of the request, like `https://{tenant}.example.com/graphql`.
+    /// The TLS server name is the host of the rendered URL. Can only be set per subgraph
+    url_template: Option<String>,
+    /// Headers added to the HTTP requests to the subgraph
+    headers: Option<HeadersConfig>,
+    /// Value of the `Accept` header of the requests to the subgraph, listing the GraphQL response
+    /// media types it can answer with. Default value is
+    /// `application/json, application/graphql-response+json`
+    #[schemars(with = "Option<String>", default)]
+    #[serde(deserialize_with = "deserialize_option_header_value", default)]
+    accept: Option<HeaderValue>,
+    /// `User-Agent` header of the requests to the subgraph, where `{router_version}` is replaced
+    /// with the version of the router. It replaces the propagated and static headers. Default
+    /// value is `apollo-router/{router_version}`
+    user_agent: Option<String>,
+    /// Handling of the subgraph responses by status code, like `503`, or status class, like
+    /// `5xx`: `graphql` parses the body as a GraphQL response, `error` fails the request so that
+    /// it can be retried. A status code takes precedence over its class. Entries set per subgraph
+    /// take precedence over the ones set for all subgraphs. All the responses are parsed by
+    /// default
+    response_statuses: Option<HashMap<String, StatusOutcome>>,
+    /// Handling of the 2xx subgraph responses with an empty body: `null_data` answers with a
+    /// GraphQL response whose data is null, `error` fails the request with an error naming the
+    /// subgraph, so that it can be retried. The body is parsed as it is by default, which fails
+    /// with a JSON parsing error
+    empty_response: Option<EmptyResponse>,
     /// Enable HTTP2 for subgraphs
     experimental_http2: Option<Http2Config>,
+    /// HTTP2 flow control window of each subgraph connection, in bytes. Must be between 65535 and
+    /// 2147483647, default value is 5MB
+    http2_initial_connection_window_size: Option<u32>,
+    /// HTTP2 flow control window of each subgraph request, in bytes. Must be between 65535 and
+    /// 2147483647, default value is 2MB
+    http2_initial_stream_window_size: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Interval between the HTTP2 PING frames sent on subgraph connections, including idle
+    /// ones, to keep them alive. Must not be zero, no PING is sent by default
+    http2_keepalive_interval: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Close a subgraph connection when a PING frame is not acknowledged within this duration.
+    /// Must not be zero, default value is 20 seconds
+    http2_keepalive_timeout: Option<Duration>,
+    /// Maximum number of requests multiplexed on an HTTP2 connection to a subgraph. Another
+    /// connection is opened when all the connections are at this limit. Must not be zero, no limit
+    /// by default
+    http2_max_concurrent_streams: Option<usize>,
+    /// Enable HTTP3 (QUIC) for subgraphs
+    experimental_http3: Option<Http3Config>,
+    /// Call a unary gRPC method of the subgraph instead of sending GraphQL requests over HTTP.
+    /// The GraphQL request and response are the JSON messages of the call
+    grpc: Option<GrpcConfig>,
+    /// Debugging options of the subgraph requests
+    debug: Option<DebugConfig>,
 }
 
 #[derive(PartialEq, Default, Debug, Clone, Deserialize, JsonSchema)]
@@ -84,6 +319,199 @@ pub(crate) enum Http2Config {
     Http2Only,
 }
 
+#[derive(PartialEq, Default, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Http3Config {
+    /// Enable HTTP3 for HTTPS subgraphs, falling back to HTTP2 or HTTP1 when the HTTP3 connection
+    /// cannot be established
+    Enable,
+    #[default]
+    /// Disable HTTP3 for subgraphs
+    Disable,
+    /// Only HTTP3 is active for HTTPS subgraphs
+    Http3Only,
+}
+
+/// Outbound HTTP or SOCKS5 proxy configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ProxyConfig {
+    /// Proxy used for subgraphs with an `http://` URL. Basic authentication credentials can be
+    /// set in the URL
+    pub(crate) http_proxy: Option<url::Url>,
+    /// Proxy used for subgraphs with an `https://` URL, through a CONNECT tunnel. Basic
+    /// authentication credentials can be set in the URL
+    pub(crate) https_proxy: Option<url::Url>,
+    /// SOCKS5 proxy used for all the subgraph URLs, instead of `http_proxy` and `https_proxy`.
+    /// Username and password credentials can be set in the URL
+    pub(crate) socks5_proxy: Option<url::Url>,
+    /// Resolve the subgraph hosts through the SOCKS5 proxy, instead of locally (default: true)
+    pub(crate) socks5_remote_dns: Option<bool>,
+    /// Hosts reached without going through the proxy. An entry matches the host and its
+    /// subdomains, `*` matches all hosts
+    #[serde(default)]
+    pub(crate) no_proxy: Vec<String>,
+}
+
+/// gRPC transport configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GrpcConfig {
+    /// Fully qualified name of the gRPC service, for example `acme.products.v1.Products`
+    pub(crate) service: String,
+    /// Name of the unary method called with the GraphQL requests, for example `Execute`
+    pub(crate) method: String,
+}
+
+impl GrpcConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let is_valid = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        };
+        let error = if !is_valid(&self.service) {
+            "grpc.service must be a fully qualified gRPC service name"
+        } else if !is_valid(&self.method) || self.method.contains('.') {
+            "grpc.method must be a gRPC method name"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Debugging configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DebugConfig {
+    /// Log the subgraph request and response bodies at the debug level
+    pub(crate) log_bodies: Option<LogBodiesConfig>,
+    /// Log the HTTP/2 settings sent by the subgraph, like its maximum number of concurrent
+    /// streams and its window sizes, on each new connection at the debug level. Default value is
+    /// false
+    pub(crate) log_http2_settings: Option<bool>,
+}
+
+impl Merge for DebugConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => DebugConfig {
+                log_bodies: match (&self.log_bodies, &fallback.log_bodies) {
+                    (Some(log_bodies), fallback) => Some(log_bodies.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
+                log_http2_settings: self.log_http2_settings.or(fallback.log_http2_settings),
+            },
+        }
+    }
+}
+
+/// Body logging configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LogBodiesConfig {
+    /// Maximum number of bytes of each body that are logged, the rest is truncated. The request
+    /// bodies are logged before compression and the response bodies after decompression. Default
+    /// value is 4KB
+    pub(crate) max_bytes: Option<usize>,
+}
+
+impl Merge for LogBodiesConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => LogBodiesConfig {
+                max_bytes: self.max_bytes.or(fallback.max_bytes),
+            },
+        }
+    }
+}
+
+/// Expect/continue configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExpectContinueConfig {
+    /// Minimum size in bytes of a request body, before compression, to send it with the
+    /// `Expect` header. Streamed bodies of unknown size are sent right away. Default value is
+    /// 1MB
+    pub(crate) min_size: Option<usize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time to wait for the 100 (Continue) response before sending the body anyway, for the
+    /// subgraphs which ignore the `Expect` header. Must not be zero, default value is 1 second
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl Merge for ExpectContinueConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => ExpectContinueConfig {
+                min_size: self.min_size.or(fallback.min_size),
+                timeout: self.timeout.or(fallback.timeout),
+            },
+        }
+    }
+}
+
+impl ExpectContinueConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        if self.timeout == Some(Duration::ZERO) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "expect_continue.timeout must not be zero".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Connection warm-up configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WarmupConfig {
+    /// Number of connections opened to the subgraph host. Must not be zero
+    pub(crate) connections: usize,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Duration over which the connections are opened, each one after a random delay. Default
+    /// value is 1 second
+    pub(crate) ramp: Option<Duration>,
+}
+
+impl WarmupConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        if self.connections == 0 {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "warmup.connections must not be zero".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Happy Eyeballs (RFC 8305) configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HappyEyeballsConfig {
+    /// Resolve both the IPv6 and IPv4 addresses of subgraphs, and connect to whichever answers
+    /// first (default: false)
+    #[serde(default)]
+    pub(crate) enabled: bool,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Delay before connecting with IPv4 if the IPv6 connection is not established yet. Default
+    /// value is 250 milliseconds
+    pub(crate) connection_attempt_delay: Option<Duration>,
+}
+
 impl Merge for Shaping {
     fn merge(&self, fallback: Option<&Self>) -> Self {
         match fallback {
@@ -91,7 +519,57 @@ impl Merge for Shaping {
             Some(fallback) => Shaping {
                 deduplicate_query: self.deduplicate_query.or(fallback.deduplicate_query),
                 compression: self.compression.or(fallback.compression),
+                compression_level: self.compression_level.or(fallback.compression_level),
+                compression_min_size: self.compression_min_size.or(fallback.compression_min_size),
+                max_decompressed_bytes: self
+                    .max_decompressed_bytes
+                    .or(fallback.max_decompressed_bytes),
+                max_response_bytes: self.max_response_bytes.or(fallback.max_response_bytes),
+                max_request_bytes: self.max_request_bytes.or(fallback.max_request_bytes),
+                max_response_header_size: self
+                    .max_response_header_size
+                    .or(fallback.max_response_header_size),
+                max_response_headers: self.max_response_headers.or(fallback.max_response_headers),
+                expect_continue: match (&self.expect_continue, &fallback.expect_continue) {
+                    (Some(expect_continue), fallback) => {
+                        Some(expect_continue.merge(fallback.as_ref()))
+                    }
+                    (None, fallback) => fallback.clone(),
+                },
+                pool_max_idle_per_host: self
+                    .pool_max_idle_per_host
+                    .or(fallback.pool_max_idle_per_host),
+                pool_idle_timeout: self.pool_idle_timeout.or(fallback.pool_idle_timeout),
+                pool_acquire_timeout: self.pool_acquire_timeout.or(fallback.pool_acquire_timeout),
+                warmup: self.warmup.as_ref().or(fallback.warmup.as_ref()).cloned(),
+                tcp_keepalive: self.tcp_keepalive.or(fallback.tcp_keepalive),
+                tcp_keepalive_interval: self
+                    .tcp_keepalive_interval
+                    .or(fallback.tcp_keepalive_interval),
+                tcp_nodelay: self.tcp_nodelay.or(fallback.tcp_nodelay),
+                connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+                dscp: self.dscp.or(fallback.dscp),
+                socket_priority: self.socket_priority.or(fallback.socket_priority),
+                happy_eyeballs: self
+                    .happy_eyeballs
+                    .as_ref()
+                    .or(fallback.happy_eyeballs.as_ref())
+                    .cloned(),
+                local_address: self.local_address.or(fallback.local_address),
+                proxy: self.proxy.as_ref().or(fallback.proxy.as_ref()).cloned(),
+                host_overrides: match (&self.host_overrides, &fallback.host_overrides) {
+                    (Some(overrides), Some(fallback)) => Some(
+                        fallback
+                            .iter()
+                            .chain(overrides)
+                            .map(|(host, addr)| (host.clone(), *addr))
+                            .collect(),
+                    ),
+                    (overrides, fallback) => overrides.as_ref().or(fallback.as_ref()).cloned(),
+                },
                 timeout: self.timeout.or(fallback.timeout),
+                request_timeout: self.request_timeout.or(fallback.request_timeout),
+                drain_timeout: self.drain_timeout.or(fallback.drain_timeout),
                 global_rate_limit: self
                     .global_rate_limit
                     .as_ref()
@@ -102,11 +580,118 @@ impl Merge for Shaping {
                     .as_ref()
                     .or(fallback.experimental_retry.as_ref())
                     .cloned(),
+                http_retry: match (&self.http_retry, &fallback.http_retry) {
+                    (Some(http_retry), fallback) => Some(http_retry.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
+                hedging: match (&self.hedging, &fallback.hedging) {
+                    (Some(hedging), fallback) => Some(hedging.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
+                request_batching: match (&self.request_batching, &fallback.request_batching) {
+                    (Some(request_batching), fallback) => {
+                        Some(request_batching.merge(fallback.as_ref()))
+                    }
+                    (None, fallback) => fallback.clone(),
+                },
+                response_cache: match (&self.response_cache, &fallback.response_cache) {
+                    (Some(response_cache), fallback) => {
+                        Some(response_cache.merge(fallback.as_ref()))
+                    }
+                    (None, fallback) => fallback.clone(),
+                },
+                follow_redirects: match (&self.follow_redirects, &fallback.follow_redirects) {
+                    (Some(follow_redirects), fallback) => {
+                        Some(follow_redirects.merge(fallback.as_ref()))
+                    }
+                    (None, fallback) => fallback.clone(),
+                },
+                circuit_breaker: match (&self.circuit_breaker, &fallback.circuit_breaker) {
+                    (Some(circuit_breaker), fallback) => {
+                        Some(circuit_breaker.merge(fallback.as_ref()))
+                    }
+                    (None, fallback) => fallback.clone(),
+                },
+                adaptive_concurrency: match (
+                    &self.adaptive_concurrency,
+                    &fallback.adaptive_concurrency,
+                ) {
+                    (Some(adaptive_concurrency), fallback) => {
+                        Some(adaptive_concurrency.merge(fallback.as_ref()))
+                    }
+                    (None, fallback) => fallback.clone(),
+                },
+                token_bucket: self
+                    .token_bucket
+                    .as_ref()
+                    .or(fallback.token_bucket.as_ref())
+                    .cloned(),
+                bulkhead: self
+                    .bulkhead
+                    .as_ref()
+                    .or(fallback.bulkhead.as_ref())
+                    .cloned(),
+                load_balancing: self
+                    .load_balancing
+                    .as_ref()
+                    .or(fallback.load_balancing.as_ref())
+                    .cloned(),
+                url_template: self
+                    .url_template
+                    .as_ref()
+                    .or(fallback.url_template.as_ref())
+                    .cloned(),
+                accept: self.accept.as_ref().or(fallback.accept.as_ref()).cloned(),
+                user_agent: self
+                    .user_agent
+                    .as_ref()
+                    .or(fallback.user_agent.as_ref())
+                    .cloned(),
+                response_statuses: match (&self.response_statuses, &fallback.response_statuses) {
+                    (Some(statuses), Some(fallback)) => Some(
+                        fallback
+                            .iter()
+                            .chain(statuses)
+                            .map(|(status, outcome)| (status.clone(), *outcome))
+                            .collect(),
+                    ),
+                    (statuses, fallback) => statuses.as_ref().or(fallback.as_ref()).cloned(),
+                },
+                empty_response: self.empty_response.or(fallback.empty_response),
+                headers: match (&self.headers, &fallback.headers) {
+                    (Some(headers), fallback) => Some(headers.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
+                http2_initial_connection_window_size: self
+                    .http2_initial_connection_window_size
+                    .or(fallback.http2_initial_connection_window_size),
+                http2_initial_stream_window_size: self
+                    .http2_initial_stream_window_size
+                    .or(fallback.http2_initial_stream_window_size),
+                http2_keepalive_interval: self
+                    .http2_keepalive_interval
+                    .or(fallback.http2_keepalive_interval),
+                http2_keepalive_timeout: self
+                    .http2_keepalive_timeout
+                    .or(fallback.http2_keepalive_timeout),
+                http2_max_concurrent_streams: self
+                    .http2_max_concurrent_streams
+                    .or(fallback.http2_max_concurrent_streams),
                 experimental_http2: self
                     .experimental_http2
                     .as_ref()
                     .or(fallback.experimental_http2.as_ref())
                     .cloned(),
+                experimental_http3: self
+                    .experimental_http3
+                    .as_ref()
+                    .or(fallback.experimental_http3.as_ref())
+                    .cloned(),
+                grpc: self.grpc.as_ref().or(fallback.grpc.as_ref()).cloned(),
+                debug: match (&self.debug, &fallback.debug) {
+                    (Some(debug), fallback) => Some(debug.merge(fallback.as_ref())),
+                    (None, fallback) => fallback.clone(),
+                },
             },
         }
     }
@@ -148,148 +733,1252 @@ impl Merge for RetryConfig {
     }
 }
 
-// this is a wrapper struct to add subgraph specific options over Shaping
+/// HTTP retry configuration
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
-struct SubgraphShaping {
-    #[serde(flatten)]
-    shaping: Shaping,
+struct HttpRetryConfig {
+    /// maximum number of attempts of a request, including the first one. Must not be zero,
+    /// default value is 3
+    max_attempts: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// delay before the first retry. Default value is 100 milliseconds
+    base_delay: Option<Duration>,
+    /// factor applied to the delay after each retry. Must be at least 1, default value is 2
+    multiplier: Option<f64>,
+    /// fraction of the delay by which it is randomly increased or decreased, to spread the
+    /// retries of concurrent requests. Must be between 0 and 1, default value is 0.2
+    jitter: Option<f64>,
+    /// only retry the requests of queries, mutations are not retried as they may not be
+    /// idempotent. Enabled by default
+    queries_only: Option<bool>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// maximum delay before a retry requested by the `Retry-After` header of a 429 or 503
+    /// response, longer delays are shortened to it. Default value is 10 seconds
+    max_retry_after: Option<Duration>,
 }
 
-impl Merge for SubgraphShaping {
+impl Merge for HttpRetryConfig {
     fn merge(&self, fallback: Option<&Self>) -> Self {
         match fallback {
             None => self.clone(),
-            Some(fallback) => SubgraphShaping {
-                shaping: self.shaping.merge(Some(&fallback.shaping)),
+            Some(fallback) => HttpRetryConfig {
+                max_attempts: self.max_attempts.or(fallback.max_attempts),
+                base_delay: self.base_delay.or(fallback.base_delay),
+                multiplier: self.multiplier.or(fallback.multiplier),
+                jitter: self.jitter.or(fallback.jitter),
+                queries_only: self.queries_only.or(fallback.queries_only),
+                max_retry_after: self.max_retry_after.or(fallback.max_retry_after),
             },
         }
     }
 }
 
+impl HttpRetryConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.max_attempts == Some(0) {
+            "http_retry.max_attempts must not be zero"
+        } else if self
+            .multiplier
+            .is_some_and(|multiplier| !(1.0..).contains(&multiplier))
+        {
+            "http_retry.multiplier must be at least 1"
+        } else if self
+            .jitter
+            .is_some_and(|jitter| !(0.0..=1.0).contains(&jitter))
+        {
+            "http_retry.jitter must be between 0 and 1"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Hedging configuration
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
-struct RouterShaping {
-    /// Enable global rate limiting
-    global_rate_limit: Option<RateLimitConf>,
+struct HedgingConfig {
+    /// percentile of the latencies of the recent queries to the subgraph after which a query
+    /// without response is sent again. Must be between 0 and 100, default value is 95
+    percentile: Option<f64>,
     #[serde(deserialize_with = "humantime_serde::deserialize", default)]
     #[schemars(with = "String", default)]
-    /// Enable timeout for incoming requests
-    timeout: Option<Duration>,
+    /// minimum delay before a query is sent again. Default value is 10 milliseconds
+    min_delay: Option<Duration>,
+    /// maximum number of hedged requests in flight to the subgraph, queries over this limit are
+    /// not sent again. Must not be zero, default value is 10
+    max_concurrent: Option<usize>,
 }
 
-#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
-#[serde(deny_unknown_fields, default)]
-// FIXME: This struct is pub(crate) because we need its configuration in the query planner service.
-// Remove this once the configuration yml changes.
-/// Configuration for the experimental traffic shaping plugin
-pub(crate) struct Config {
-    /// Applied at the router level
-    router: Option<RouterShaping>,
-    /// Applied on all subgraphs
-    all: Option<SubgraphShaping>,
-    /// Applied on specific subgraphs
-    subgraphs: HashMap<String, SubgraphShaping>,
-    /// DEPRECATED, now always enabled: Enable variable deduplication optimization when sending requests to subgraphs (https://github.com/apollographql/router/issues/87)
-    deduplicate_variables: Option<bool>,
+impl Merge for HedgingConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => HedgingConfig {
+                percentile: self.percentile.or(fallback.percentile),
+                min_delay: self.min_delay.or(fallback.min_delay),
+                max_concurrent: self.max_concurrent.or(fallback.max_concurrent),
+            },
+        }
+    }
 }
 
+impl HedgingConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self
+            .percentile
+            .is_some_and(|percentile| !(0.0..=100.0).contains(&percentile))
+        {
+            "hedging.percentile must be between 0 and 100"
+        } else if self.max_concurrent == Some(0) {
+            "hedging.max_concurrent must not be zero"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Request batching configuration
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
-struct RateLimitConf {
-    /// Number of requests allowed
-    capacity: NonZeroU64,
-    #[serde(deserialize_with = "humantime_serde::deserialize")]
-    #[schemars(with = "String")]
-    /// Per interval
-    interval: Duration,
+struct RequestBatchingConfig {
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// time during which the queries are collected into a batch, from the first one. Must not be
+    /// zero, default value is 5 milliseconds
+    window: Option<Duration>,
+    /// number of queries sending a batch before the end of its window. Must be at least 2,
+    /// default value is 10
+    max_size: Option<usize>,
 }
 
-impl Merge for RateLimitConf {
+impl Merge for RequestBatchingConfig {
     fn merge(&self, fallback: Option<&Self>) -> Self {
         match fallback {
             None => self.clone(),
-            Some(fallback) => Self {
-                capacity: fallback.capacity,
-                interval: fallback.interval,
+            Some(fallback) => RequestBatchingConfig {
+                window: self.window.or(fallback.window),
+                max_size: self.max_size.or(fallback.max_size),
             },
         }
     }
 }
 
-// FIXME: This struct is pub(crate) because we need its configuration in the query planner service.
-// Remove this once the configuration yml changes.
-pub(crate) struct TrafficShaping {
-    config: Config,
-    rate_limit_router: Option<RateLimitLayer>,
-    rate_limit_subgraphs: Mutex<HashMap<String, RateLimitLayer>>,
+impl RequestBatchingConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.window == Some(Duration::ZERO) {
+            "request_batching.window must not be zero"
+        } else if self.max_size.is_some_and(|max_size| max_size < 2) {
+            "request_batching.max_size must be at least 2"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
 }
 
-#[async_trait::async_trait]
-impl Plugin for TrafficShaping {
-    type Config = Config;
-
-    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
-        let rate_limit_router = init
-            .config
-            .router
-            .as_ref()
-            .and_then(|r| r.global_rate_limit.as_ref())
-            .map(|router_rate_limit_conf| {
-                if router_rate_limit_conf.interval.as_millis() > u64::MAX as u128 {
-                    Err(ConfigurationError::InvalidConfiguration {
-                        message: "bad configuration for traffic_shaping plugin",
-                        error: format!(
-                            "cannot set an interval for the rate limit greater than {} ms",
-                            u64::MAX
-                        ),
-                    })
-                } else {
-                    Ok(RateLimitLayer::new(
-                        router_rate_limit_conf.capacity,
-                        router_rate_limit_conf.interval,
-                    ))
-                }
-            })
-            .transpose()?;
+/// Response cache configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ResponseCacheConfig {
+    /// maximum number of responses cached for the subgraph, the least recently used ones are
+    /// evicted first. Must not be zero, default value is 1000
+    max_entries: Option<usize>,
+    /// request headers whose values are part of the cache key, in addition to the method, the
+    /// URI and the body of the request. Default value is `authorization` and `cookie`
+    key_headers: Option<Vec<String>>,
+}
 
-        {
-            Ok(Self {
-                config: init.config,
-                rate_limit_router,
-                rate_limit_subgraphs: Mutex::new(HashMap::new()),
-            })
+impl Merge for ResponseCacheConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => ResponseCacheConfig {
+                max_entries: self.max_entries.or(fallback.max_entries),
+                key_headers: self
+                    .key_headers
+                    .as_ref()
+                    .or(fallback.key_headers.as_ref())
+                    .cloned(),
+            },
         }
     }
 }
 
-pub(crate) type TrafficShapingSubgraphFuture<S> = Either<
-    Either<
-        BoxFuture<'static, Result<subgraph::Response, BoxError>>,
-        timeout::future::ResponseFuture<
-            Oneshot<
-                Either<
-                    Retry<RetryPolicy, Either<rate::service::RateLimit<S>, S>>,
-                    Either<rate::service::RateLimit<S>, S>,
-                >,
-                subgraph::Request,
-            >,
-        >,
-    >,
-    <S as Service<subgraph::Request>>::Future,
->;
+impl ResponseCacheConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.max_entries == Some(0) {
+            "response_cache.max_entries must not be zero".to_string()
+        } else if let Some(name) = self
+            .key_headers
+            .iter()
+            .flatten()
+            .find(|name| HeaderName::try_from(name.as_str()).is_err())
+        {
+            format!("response_cache.key_headers '{name}' is not a valid header name")
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error,
+        })
+    }
 
-impl TrafficShaping {
-    fn merge_config<T: Merge + Clone>(
-        all_config: Option<&T>,
-        subgraph_config: Option<&T>,
-    ) -> Option<T> {
-        let merged_subgraph_config = subgraph_config.map(|c| c.merge(all_config));
-        merged_subgraph_config.or_else(|| all_config.cloned())
+    fn key_headers(&self) -> Option<Vec<HeaderName>> {
+        self.key_headers.as_ref().map(|names| {
+            names
+                .iter()
+                .filter_map(|name| HeaderName::try_from(name.as_str()).ok())
+                .collect()
+        })
     }
+}
 
-    pub(crate) fn supergraph_service_internal<S>(
-        &self,
+/// Redirects configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct FollowRedirectsConfig {
+    /// maximum number of redirects followed for a request. Must not be zero, default value is 5
+    max_redirects: Option<u32>,
+    /// keep the authorization and cookie headers when a redirect goes to another host (default:
+    /// false)
+    keep_authorization: Option<bool>,
+}
+
+impl Merge for FollowRedirectsConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => FollowRedirectsConfig {
+                max_redirects: self.max_redirects.or(fallback.max_redirects),
+                keep_authorization: self.keep_authorization.or(fallback.keep_authorization),
+            },
+        }
+    }
+}
+
+impl FollowRedirectsConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        if self.max_redirects == Some(0) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "follow_redirects.max_redirects must not be zero".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Circuit breaker configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConfig {
+    /// number of failed requests within the window that opens the circuit. A request fails when
+    /// no response is received or when the response has a 5xx status. Must not be zero, default
+    /// value is 5
+    failure_threshold: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// duration over which failed requests are counted. Must not be zero, default value is 10
+    /// seconds
+    window: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// duration during which requests fail without being sent once the circuit is open, before
+    /// a probe request decides whether it closes. Must not be zero, default value is 30 seconds
+    cooldown: Option<Duration>,
+}
+
+impl Merge for CircuitBreakerConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => CircuitBreakerConfig {
+                failure_threshold: self.failure_threshold.or(fallback.failure_threshold),
+                window: self.window.or(fallback.window),
+                cooldown: self.cooldown.or(fallback.cooldown),
+            },
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.failure_threshold == Some(0) {
+            "circuit_breaker.failure_threshold must not be zero"
+        } else if self.window == Some(Duration::ZERO) {
+            "circuit_breaker.window must not be zero"
+        } else if self.cooldown == Some(Duration::ZERO) {
+            "circuit_breaker.cooldown must not be zero"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Adaptive concurrency configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AdaptiveConcurrencyConfig {
+    /// limit of requests in flight when the router starts. Default value is 20
+    initial_limit: Option<usize>,
+    /// lowest value of the limit. Must not be zero, default value is 1
+    min_limit: Option<usize>,
+    /// highest value of the limit. Must not be zero, default value is 200
+    max_limit: Option<usize>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// latency of the subgraph response above which the limit decreases. Must not be zero,
+    /// default value is 1 second
+    latency_threshold: Option<Duration>,
+    /// factor applied to the limit when a request fails or goes over the latency threshold.
+    /// Must be between 0 and 1, default value is 0.9
+    backoff_ratio: Option<f64>,
+}
+
+impl Merge for AdaptiveConcurrencyConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => AdaptiveConcurrencyConfig {
+                initial_limit: self.initial_limit.or(fallback.initial_limit),
+                min_limit: self.min_limit.or(fallback.min_limit),
+                max_limit: self.max_limit.or(fallback.max_limit),
+                latency_threshold: self.latency_threshold.or(fallback.latency_threshold),
+                backoff_ratio: self.backoff_ratio.or(fallback.backoff_ratio),
+            },
+        }
+    }
+}
+
+impl AdaptiveConcurrencyConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.min_limit == Some(0) {
+            "adaptive_concurrency.min_limit must not be zero"
+        } else if self.max_limit == Some(0) {
+            "adaptive_concurrency.max_limit must not be zero"
+        } else if self.min_limit.unwrap_or(DEFAULT_MIN_LIMIT)
+            > self.max_limit.unwrap_or(DEFAULT_MAX_LIMIT)
+        {
+            // the defaults apply to the limit that is not set
+            "adaptive_concurrency.min_limit must not be greater than max_limit"
+        } else if self.latency_threshold == Some(Duration::ZERO) {
+            "adaptive_concurrency.latency_threshold must not be zero"
+        } else if self
+            .backoff_ratio
+            .is_some_and(|ratio| ratio <= 0.0 || !(..1.0).contains(&ratio))
+        {
+            "adaptive_concurrency.backoff_ratio must be between 0 and 1"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Token bucket configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct TokenBucketConfig {
+    /// number of tokens added to the bucket per second, each request takes one token. Must be
+    /// greater than zero
+    requests_per_second: f64,
+    /// maximum number of tokens in the bucket, which is the number of requests that can be sent
+    /// at once. Must not be zero, default value is `requests_per_second` rounded up
+    burst: Option<u32>,
+    /// what happens to a request when the bucket is empty: `reject` fails it immediately,
+    /// `queue` delays it until a token is available. Default value is `reject`
+    on_limit: Option<OnRateLimit>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// in `queue` mode, longest delay of a request: requests that would wait longer are
+    /// rejected. Must not be zero, default value is 1 second
+    max_wait: Option<Duration>,
+}
+
+#[derive(PartialEq, Default, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum OnRateLimit {
+    /// Fail the request with a `SUBREQUEST_RATE_LIMITED` error
+    #[default]
+    Reject,
+    /// Wait for a token, up to `max_wait`
+    Queue,
+}
+
+impl TokenBucketConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if !(self.requests_per_second > 0.0 && self.requests_per_second.is_finite()) {
+            "token_bucket.requests_per_second must be greater than zero"
+        } else if self.burst == Some(0) {
+            "token_bucket.burst must not be zero"
+        } else if self.max_wait == Some(Duration::ZERO) {
+            "token_bucket.max_wait must not be zero"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Bulkhead configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct BulkheadConfig {
+    /// maximum number of requests in flight to the subgraph. Must not be zero
+    max_concurrent: usize,
+    /// maximum number of requests waiting for a request in flight to finish. Requests that do
+    /// not fit in the queue fail immediately
+    max_queue: usize,
+}
+
+impl BulkheadConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        if self.max_concurrent == 0 {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "bulkhead.max_concurrent must not be zero".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Load balancing configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct LoadBalancingConfig {
+    /// URLs of the endpoints of the subgraph. Each request is sent to one of them instead of the
+    /// subgraph URL
+    endpoints: Vec<url::Url>,
+    /// policy selecting the endpoint of each request (default: round_robin)
+    policy: Option<LoadBalancingPolicy>,
+    /// ejects the endpoints failing several requests in a row, no endpoint is ejected by default
+    outlier_detection: Option<OutlierDetectionConfig>,
+    /// health checks sent to each endpoint on an interval, no health check is sent by default
+    health_check: Option<HealthCheckConfig>,
+}
+
+/// Health check configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckConfig {
+    /// path of a `GET` request sent to the endpoint as health check, instead of the GraphQL
+    /// query. Must start with `/`
+    path: Option<String>,
+    /// GraphQL query sent to the endpoint as health check (default: `query HealthCheck {
+    /// __typename }`)
+    query: Option<String>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// duration between the health checks of an endpoint. Must not be zero, default value is 10
+    /// seconds
+    interval: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// duration after which a health check without a 2xx response fails. Must not be zero,
+    /// default value is 1 second
+    timeout: Option<Duration>,
+    /// number of health checks in a row that must pass before a failing endpoint receives
+    /// requests again. Must not be zero, default value is 2
+    healthy_threshold: Option<u32>,
+    /// number of health checks in a row that must fail before an endpoint stops receiving
+    /// requests. Must not be zero, default value is 3
+    unhealthy_threshold: Option<u32>,
+}
+
+/// Outlier detection configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct OutlierDetectionConfig {
+    /// number of requests in a row failing without a response or with a 5xx status after which
+    /// an endpoint is ejected. Must not be zero, default value is 5
+    consecutive_failures: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// time during which an ejected endpoint receives no request, before a probe request decides
+    /// whether it receives requests again. Must not be zero, default value is 30 seconds
+    ejection_time: Option<Duration>,
+}
+
+#[derive(PartialEq, Default, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum LoadBalancingPolicy {
+    /// Send the requests to each endpoint in turn
+    #[default]
+    RoundRobin,
+    /// Send each request to a random endpoint
+    Random,
+    /// Send each request to the endpoint with the fewest requests in flight
+    LeastConnections,
+}
+
+impl LoadBalancingConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.endpoints.is_empty() {
+            "load_balancing.endpoints must not be empty".to_string()
+        } else if let Some(endpoint) = self
+            .endpoints
+            .iter()
+            .find(|endpoint| !matches!(endpoint.scheme(), "http" | "https"))
+        {
+            format!("load_balancing.endpoints must be HTTP URLs, got '{endpoint}'")
+        } else {
+            if let Some(outlier_detection) = &self.outlier_detection {
+                outlier_detection.validate()?;
+            }
+            if let Some(health_check) = &self.health_check {
+                health_check.validate()?;
+            }
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error,
+        })
+    }
+
+    fn outlier_detection(&self) -> Option<OutlierDetection> {
+        self.outlier_detection
+            .as_ref()
+            .map(|config| OutlierDetection::new(config.consecutive_failures, config.ejection_time))
+    }
+
+    fn health_check(&self) -> Option<HealthCheck> {
+        self.health_check.as_ref().map(HealthCheck::new)
+    }
+
+    fn endpoints(&self) -> Vec<Uri> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.as_str().parse().expect("a URL is a valid URI"))
+            .collect()
+    }
+}
+
+impl OutlierDetectionConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.consecutive_failures == Some(0) {
+            "load_balancing.outlier_detection.consecutive_failures must not be zero"
+        } else if self.ejection_time == Some(Duration::ZERO) {
+            "load_balancing.outlier_detection.ejection_time must not be zero"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+impl HealthCheckConfig {
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let error = if self.path.is_some() && self.query.is_some() {
+            "load_balancing.health_check.path and query must not be both set"
+        } else if self
+            .path
+            .as_ref()
+            .is_some_and(|path| !path.starts_with('/') || path.parse::<PathAndQuery>().is_err())
+        {
+            "load_balancing.health_check.path must be a path starting with /"
+        } else if self.interval == Some(Duration::ZERO) {
+            "load_balancing.health_check.interval must not be zero"
+        } else if self.timeout == Some(Duration::ZERO) {
+            "load_balancing.health_check.timeout must not be zero"
+        } else if self.healthy_threshold == Some(0) {
+            "load_balancing.health_check.healthy_threshold must not be zero"
+        } else if self.unhealthy_threshold == Some(0) {
+            "load_balancing.health_check.unhealthy_threshold must not be zero"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigurationError::InvalidConfiguration {
+            message: "bad configuration for traffic_shaping plugin",
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Headers configuration
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HeadersConfig {
+    /// static headers inserted in every HTTP request to the subgraph. Entries set per subgraph
+    /// take precedence over the ones with the same name set for all subgraphs
+    #[serde(default)]
+    insert: Vec<StaticHeader>,
+}
+
+/// Static header
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct StaticHeader {
+    /// name of the header
+    #[schemars(with = "String")]
+    #[serde(deserialize_with = "deserialize_header_name")]
+    name: HeaderName,
+    /// value of the header. Secrets can be read from environment variables with the
+    /// `${env.NAME}` syntax
+    #[schemars(with = "String")]
+    #[serde(deserialize_with = "deserialize_header_value")]
+    value: HeaderValue,
+    /// replace the header when the request already has it, for example when it is propagated
+    /// from the client request. Disabled by default
+    #[serde(default)]
+    override_existing: bool,
+}
+
+impl Merge for HeadersConfig {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => HeadersConfig {
+                insert: fallback
+                    .insert
+                    .iter()
+                    .filter(|header| !self.insert.iter().any(|h| h.name == header.name))
+                    .chain(&self.insert)
+                    .cloned()
+                    .collect(),
+            },
+        }
+    }
+}
+
+impl HeadersConfig {
+    fn insert_into(&self, headers: &mut HeaderMap) {
+        for header in &self.insert {
+            if header.override_existing || !headers.contains_key(&header.name) {
+                headers.insert(header.name.clone(), header.value.clone());
+            }
+        }
+    }
+}
+
+// this is a wrapper struct to add subgraph specific options over Shaping
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SubgraphShaping {
+    #[serde(flatten)]
+    shaping: Shaping,
+}
+
+impl Merge for SubgraphShaping {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => SubgraphShaping {
+                shaping: self.shaping.merge(Some(&fallback.shaping)),
+            },
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RouterShaping {
+    /// Enable global rate limiting
+    global_rate_limit: Option<RateLimitConf>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Enable timeout for incoming requests
+    timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+// FIXME: This struct is pub(crate) because we need its configuration in the query planner service.
+// Remove this once the configuration yml changes.
+/// Configuration for the experimental traffic shaping plugin
+pub(crate) struct Config {
+    /// Applied at the router level
+    router: Option<RouterShaping>,
+    /// Applied on all subgraphs
+    all: Option<SubgraphShaping>,
+    /// Applied on specific subgraphs
+    subgraphs: HashMap<String, SubgraphShaping>,
+    /// DEPRECATED, now always enabled: Enable variable deduplication optimization when sending requests to subgraphs (https://github.com/apollographql/router/issues/87)
+    deduplicate_variables: Option<bool>,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConf {
+    /// Number of requests allowed
+    capacity: NonZeroU64,
+    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[schemars(with = "String")]
+    /// Per interval
+    interval: Duration,
+}
+
+impl Merge for RateLimitConf {
+    fn merge(&self, fallback: Option<&Self>) -> Self {
+        match fallback {
+            None => self.clone(),
+            Some(fallback) => Self {
+                capacity: fallback.capacity,
+                interval: fallback.interval,
+            },
+        }
+    }
+}
+
+/// Whether a subgraph request was rejected by the rate limit, the bulkhead or the ejection of all
+/// the endpoints without being sent
+fn is_rejected(error: &BoxError) -> bool {
+    matches!(
+        error.downcast_ref::<FetchError>(),
+        Some(
+            FetchError::SubrequestRateLimited { .. }
+                | FetchError::SubrequestOverloaded { .. }
+                | FetchError::SubrequestEndpointsEjected { .. }
+        )
+    )
+}
+
+// FIXME: This struct is pub(crate) because we need its configuration in the query planner service.
+// Remove this once the configuration yml changes.
+pub(crate) struct TrafficShaping {
+    config: Config,
+    rate_limit_router: Option<RateLimitLayer>,
+    rate_limit_subgraphs: Mutex<HashMap<String, RateLimitLayer>>,
+    circuit_breakers: Mutex<HashMap<String, Arc<CircuitBreaker>>>,
+    concurrency_limiters: Mutex<HashMap<String, Arc<AdaptiveConcurrency>>>,
+    token_buckets: Mutex<HashMap<String, Arc<TokenBucket>>>,
+    bulkheads: Mutex<HashMap<String, Arc<Bulkhead>>>,
+    hedgers: Mutex<HashMap<String, Arc<Hedging>>>,
+    response_caches: Mutex<HashMap<String, Arc<ResponseCache>>>,
+    request_batchers: Mutex<HashMap<String, Arc<RequestBatcher>>>,
+    load_balancers: Mutex<HashMap<String, Arc<LoadBalancer>>>,
+    /// drained once a configuration reload replaced the router using these clients
+    http_clients: Mutex<Vec<Arc<Drain>>>,
+}
+
+#[async_trait::async_trait]
+impl PluginPrivate for TrafficShaping {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let rate_limit_router = init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.global_rate_limit.as_ref())
+            .map(|router_rate_limit_conf| {
+                if router_rate_limit_conf.interval.as_millis() > u64::MAX as u128 {
+                    Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error: format!(
+                            "cannot set an interval for the rate limit greater than {} ms",
+                            u64::MAX
+                        ),
+                    })
+                } else {
+                    Ok(RateLimitLayer::new(
+                        router_rate_limit_conf.capacity,
+                        router_rate_limit_conf.interval,
+                    ))
+                }
+            })
+            .transpose()?;
+
+        for shaping in init.config.all.iter().chain(init.config.subgraphs.values()) {
+            let durations = [
+                ("pool_idle_timeout", shaping.shaping.pool_idle_timeout),
+                ("pool_acquire_timeout", shaping.shaping.pool_acquire_timeout),
+                ("tcp_keepalive", shaping.shaping.tcp_keepalive),
+                (
+                    "tcp_keepalive_interval",
+                    shaping.shaping.tcp_keepalive_interval,
+                ),
+                ("connect_timeout", shaping.shaping.connect_timeout),
+                (
+                    "http2_keepalive_interval",
+                    shaping.shaping.http2_keepalive_interval,
+                ),
+                (
+                    "http2_keepalive_timeout",
+                    shaping.shaping.http2_keepalive_timeout,
+                ),
+                ("request_timeout", shaping.shaping.request_timeout),
+                ("drain_timeout", shaping.shaping.drain_timeout),
+            ];
+            for (option, duration) in durations {
+                if duration == Some(Duration::ZERO) {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error: format!("{option} must not be zero"),
+                    }
+                    .into());
+                }
+            }
+            let window_sizes = [
+                (
+                    "http2_initial_connection_window_size",
+                    shaping.shaping.http2_initial_connection_window_size,
+                ),
+                (
+                    "http2_initial_stream_window_size",
+                    shaping.shaping.http2_initial_stream_window_size,
+                ),
+            ];
+            for (option, window_size) in window_sizes {
+                if window_size.is_some_and(|size| !HTTP2_WINDOW_SIZES.contains(&size)) {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error: format!(
+                            "{option} must be between {} and {}",
+                            HTTP2_WINDOW_SIZES.start(),
+                            HTTP2_WINDOW_SIZES.end()
+                        ),
+                    }
+                    .into());
+                }
+            }
+            if let Some(CompressionLevel::Precise(level)) = shaping.shaping.compression_level {
+                if !COMPRESSION_LEVELS.contains(&level) {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error: format!(
+                            "compression_level must be between {} and {}",
+                            COMPRESSION_LEVELS.start(),
+                            COMPRESSION_LEVELS.end()
+                        ),
+                    }
+                    .into());
+                }
+            }
+            if shaping.shaping.dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+                return Err(ConfigurationError::InvalidConfiguration {
+                    message: "bad configuration for traffic_shaping plugin",
+                    error: format!("dscp must be between 0 and {MAX_DSCP}"),
+                }
+                .into());
+            }
+            let limits = [
+                (
+                    "http2_max_concurrent_streams",
+                    shaping.shaping.http2_max_concurrent_streams,
+                ),
+                (
+                    "max_response_header_size",
+                    shaping.shaping.max_response_header_size,
+                ),
+                ("max_response_headers", shaping.shaping.max_response_headers),
+            ];
+            for (option, limit) in limits {
+                if limit == Some(0) {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error: format!("{option} must not be zero"),
+                    }
+                    .into());
+                }
+            }
+            if let Some(expect_continue) = &shaping.shaping.expect_continue {
+                expect_continue.validate()?;
+            }
+            if let Some(http_retry) = &shaping.shaping.http_retry {
+                http_retry.validate()?;
+            }
+            if let Some(hedging) = &shaping.shaping.hedging {
+                hedging.validate()?;
+            }
+            if let Some(request_batching) = &shaping.shaping.request_batching {
+                request_batching.validate()?;
+            }
+            if let Some(response_cache) = &shaping.shaping.response_cache {
+                response_cache.validate()?;
+            }
+            if let Some(follow_redirects) = &shaping.shaping.follow_redirects {
+                follow_redirects.validate()?;
+            }
+            if let Some(circuit_breaker) = &shaping.shaping.circuit_breaker {
+                circuit_breaker.validate()?;
+            }
+            if let Some(adaptive_concurrency) = &shaping.shaping.adaptive_concurrency {
+                // the limits of a subgraph are checked against the ones it gets from `all`
+                adaptive_concurrency
+                    .merge(
+                        init.config
+                            .all
+                            .as_ref()
+                            .and_then(|all| all.shaping.adaptive_concurrency.as_ref()),
+                    )
+                    .validate()?;
+            }
+            if let Some(token_bucket) = &shaping.shaping.token_bucket {
+                token_bucket.validate()?;
+            }
+            if let Some(bulkhead) = &shaping.shaping.bulkhead {
+                bulkhead.validate()?;
+            }
+            if let Some(load_balancing) = &shaping.shaping.load_balancing {
+                load_balancing.validate()?;
+            }
+            if let Some(grpc) = &shaping.shaping.grpc {
+                grpc.validate()?;
+            }
+            if let Some(warmup) = &shaping.shaping.warmup {
+                warmup.validate()?;
+            }
+            if let Some(template) = &shaping.shaping.user_agent {
+                if user_agent(template).is_err() {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error: format!("user_agent '{template}' is not a valid header value"),
+                    }
+                    .into());
+                }
+            }
+            if let Some(statuses) = &shaping.shaping.response_statuses {
+                if let Err(error) = ResponseStatuses::new(statuses) {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error,
+                    }
+                    .into());
+                }
+            }
+            if let Some(template) = &shaping.shaping.url_template {
+                if let Err(error) = UrlTemplate::new(template) {
+                    return Err(ConfigurationError::InvalidConfiguration {
+                        message: "bad configuration for traffic_shaping plugin",
+                        error,
+                    }
+                    .into());
+                }
+            }
+        }
+        if init
+            .config
+            .all
+            .as_ref()
+            .is_some_and(|all| all.shaping.url_template.is_some())
+        {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "url_template can only be set per subgraph, in traffic_shaping.subgraphs"
+                    .to_string(),
+            }
+            .into());
+        }
+        // the load balancer replaces the URL of the requests with the URL of its endpoints
+        for (name, subgraph) in &init.config.subgraphs {
+            let subgraph = subgraph.merge(init.config.all.as_ref());
+            if subgraph.shaping.url_template.is_some() && subgraph.shaping.load_balancing.is_some()
+            {
+                return Err(ConfigurationError::InvalidConfiguration {
+                    message: "bad configuration for traffic_shaping plugin",
+                    error: format!(
+                        "subgraph '{name}' cannot use url_template together with load_balancing"
+                    ),
+                }
+                .into());
+            }
+        }
+
+        {
+            Ok(Self {
+                config: init.config,
+                rate_limit_router,
+                rate_limit_subgraphs: Mutex::new(HashMap::new()),
+                circuit_breakers: Mutex::new(HashMap::new()),
+                concurrency_limiters: Mutex::new(HashMap::new()),
+                token_buckets: Mutex::new(HashMap::new()),
+                bulkheads: Mutex::new(HashMap::new()),
+                hedgers: Mutex::new(HashMap::new()),
+                response_caches: Mutex::new(HashMap::new()),
+                request_batchers: Mutex::new(HashMap::new()),
+                load_balancers: Mutex::new(HashMap::new()),
+                http_clients: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    fn http_client_service(
+        &self,
+        subgraph_name: &str,
+        service: crate::services::http::BoxService,
+    ) -> crate::services::http::BoxService {
+        let Some(config) = Self::merge_config(
+            self.config.all.as_ref(),
+            self.config.subgraphs.get(subgraph_name),
+        ) else {
+            return service;
+        };
+
+        // the breakers are shared by all the requests to a subgraph
+        let circuit_breaker = config.shaping.circuit_breaker.as_ref().map(|config| {
+            CircuitBreakerLayer::new(
+                self.circuit_breakers
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(CircuitBreaker::new(
+                            subgraph_name,
+                            config.failure_threshold,
+                            config.window,
+                            config.cooldown,
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let http_retry = config.shaping.http_retry.as_ref().map(|config| {
+            HttpRetryLayer::new(
+                Backoff::new(
+                    config.max_attempts,
+                    config.base_delay,
+                    config.multiplier,
+                    config.jitter,
+                    config.queries_only,
+                    config.max_retry_after,
+                ),
+                subgraph_name,
+            )
+        });
+        let hedging = config.shaping.hedging.as_ref().map(|config| {
+            HedgingLayer::new(
+                self.hedgers
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(Hedging::new(
+                            subgraph_name,
+                            config.percentile,
+                            config.min_delay,
+                            config.max_concurrent,
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let response_cache = config.shaping.response_cache.as_ref().map(|config| {
+            ResponseCacheLayer::new(
+                self.response_caches
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(ResponseCache::new(
+                            subgraph_name,
+                            config.max_entries,
+                            config.key_headers(),
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let request_batching = config.shaping.request_batching.as_ref().map(|config| {
+            RequestBatchingLayer::new(
+                self.request_batchers
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(RequestBatcher::new(
+                            subgraph_name,
+                            config.window,
+                            config.max_size,
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let follow_redirects = config.shaping.follow_redirects.as_ref().map(|config| {
+            FollowRedirectsLayer::new(
+                RedirectPolicy::new(config.max_redirects, config.keep_authorization),
+                subgraph_name,
+            )
+        });
+        let adaptive_concurrency = config.shaping.adaptive_concurrency.as_ref().map(|config| {
+            AdaptiveConcurrencyLayer::new(
+                self.concurrency_limiters
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(AdaptiveConcurrency::new(
+                            subgraph_name,
+                            config.initial_limit,
+                            config.min_limit,
+                            config.max_limit,
+                            config.latency_threshold,
+                            config.backoff_ratio,
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let token_bucket = config.shaping.token_bucket.as_ref().map(|config| {
+            TokenBucketLayer::new(
+                self.token_buckets
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(TokenBucket::new(
+                            subgraph_name,
+                            config.requests_per_second,
+                            config.burst,
+                            config.on_limit.unwrap_or_default() == OnRateLimit::Queue,
+                            config.max_wait,
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let bulkhead = config.shaping.bulkhead.as_ref().map(|config| {
+            BulkheadLayer::new(
+                self.bulkheads
+                    .lock()
+                    .unwrap()
+                    .entry(subgraph_name.to_string())
+                    .or_insert_with(|| {
+                        Arc::new(Bulkhead::new(
+                            subgraph_name,
+                            config.max_concurrent,
+                            config.max_queue,
+                        ))
+                    })
+                    .clone(),
+            )
+        });
+        let load_balancer = config.shaping.load_balancing.as_ref().map(|config| {
+            self.load_balancers
+                .lock()
+                .unwrap()
+                .entry(subgraph_name.to_string())
+                .or_insert_with(|| {
+                    Arc::new(LoadBalancer::new(
+                        subgraph_name,
+                        config.endpoints(),
+                        config.policy.unwrap_or_default(),
+                        config.outlier_detection(),
+                        config.health_check(),
+                    ))
+                })
+                .clone()
+        });
+        // the health checks are sent with the client of the first request to the subgraph, which
+        // is shared with that request
+        let service = match &load_balancer {
+            Some(load_balancer) if load_balancer.needs_health_checks() => {
+                let service = ServiceBuilder::new().buffered().service(service);
+                load_balancer.start_health_checks(BoxCloneService::new(service.clone()));
+                service.boxed()
+            }
+            _ => service,
+        };
+        let load_balancer = load_balancer.map(LoadBalancerLayer::new);
+        let url_template = config
+            .shaping
+            .url_template
+            .as_deref()
+            .and_then(|template| UrlTemplate::new(template).ok())
+            .map(|template| UrlTemplateLayer::new(template, subgraph_name));
+        let headers = config.shaping.headers.map(|headers| {
+            MapRequestLayer::new(move |mut request: HttpRequest| {
+                headers.insert_into(request.http_request.headers_mut());
+                request
+            })
+        });
+        if headers.is_none()
+            && url_template.is_none()
+            && response_cache.is_none()
+            && request_batching.is_none()
+            && circuit_breaker.is_none()
+            && http_retry.is_none()
+            && hedging.is_none()
+            && load_balancer.is_none()
+            && follow_redirects.is_none()
+            && token_bucket.is_none()
+            && bulkhead.is_none()
+            && adaptive_concurrency.is_none()
+        {
+            return service;
+        }
+
+        // the URL is rendered once for a request, and the cached responses are used without
+        // sending a request. The queries are then batched, so that a batch is sent like a single
+        // request. A request, its hedged request and their retries are seen as a single request
+        // by the circuit breaker, and each attempt is sent to an endpoint and follows its
+        // redirects, while each request sent takes a token and counts in the concurrency limits.
+        // Requests wait for a token before taking a place in the concurrency limits, so that the
+        // wait is not seen as latency of the subgraph
+        ServiceBuilder::new()
+            .option_layer(headers)
+            .option_layer(url_template)
+            .option_layer(response_cache)
+            .option_layer(request_batching)
+            .option_layer(circuit_breaker)
+            .option_layer(hedging)
+            .option_layer(http_retry)
+            .option_layer(load_balancer)
+            .option_layer(follow_redirects)
+            .option_layer(token_bucket)
+            .option_layer(bulkhead)
+            .option_layer(adaptive_concurrency)
+            .service(service)
+            .boxed()
+    }
+}
+
+pub(crate) type TrafficShapingSubgraphFuture<S> = Either<
+    Either<
+        BoxFuture<'static, Result<subgraph::Response, BoxError>>,
+        timeout::future::ResponseFuture<
+            Oneshot<
+                Either<
+                    Retry<RetryPolicy, Either<rate::service::RateLimit<S>, S>>,
+                    Either<rate::service::RateLimit<S>, S>,
+                >,
+                subgraph::Request,
+            >,
+        >,
+    >,
+    <S as Service<subgraph::Request>>::Future,
+>;
+
+impl TrafficShaping {
+    fn merge_config<T: Merge + Clone>(
+        all_config: Option<&T>,
+        subgraph_config: Option<&T>,
+    ) -> Option<T> {
+        let merged_subgraph_config = subgraph_config.map(|c| c.merge(all_config));
+        merged_subgraph_config.or_else(|| all_config.cloned())
+    }
+
+    pub(crate) fn supergraph_service_internal<S>(
+        &self,
         service: S,
     ) -> impl Service<
         supergraph::Request,
@@ -318,414 +2007,3115 @@ impl TrafficShaping {
                     .and_then(|r| r.timeout)
                     .unwrap_or(DEFAULT_TIMEOUT),
             ))
-            .option_layer(self.rate_limit_router.clone())
-            .service(service)
+            .option_layer(self.rate_limit_router.clone())
+            .service(service)
+    }
+
+    pub(crate) fn subgraph_service_internal<S>(
+        &self,
+        name: &str,
+        service: S,
+    ) -> impl Service<
+        subgraph::Request,
+        Response = subgraph::Response,
+        Error = BoxError,
+        Future = TrafficShapingSubgraphFuture<S>,
+    > + Clone
+           + Send
+           + Sync
+           + 'static
+    where
+        S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        <S as Service<subgraph::Request>>::Future: std::marker::Send,
+    {
+        // Either we have the subgraph config and we merge it with the all config, or we just have the all config or we have nothing.
+        let all_config = self.config.all.as_ref();
+        let subgraph_config = self.config.subgraphs.get(name);
+        let final_config = Self::merge_config(all_config, subgraph_config);
+
+        if let Some(config) = final_config {
+            let rate_limit = config
+                .shaping
+                .global_rate_limit
+                .as_ref()
+                .map(|rate_limit_conf| {
+                    self.rate_limit_subgraphs
+                        .lock()
+                        .unwrap()
+                        .entry(name.to_string())
+                        .or_insert_with(|| {
+                            RateLimitLayer::new(rate_limit_conf.capacity, rate_limit_conf.interval)
+                        })
+                        .clone()
+                });
+
+            let retry = config.shaping.experimental_retry.as_ref().map(|config| {
+                let retry_policy = RetryPolicy::new(
+                    config.ttl,
+                    config.min_per_sec,
+                    config.retry_percent,
+                    config.retry_mutations,
+                    name.to_string(),
+                );
+                tower::retry::RetryLayer::new(retry_policy)
+            });
+
+            Either::A(ServiceBuilder::new()
+
+                .option_layer(config.shaping.deduplicate_query.unwrap_or_default().then(
+                  QueryDeduplicationLayer::default
+                ))
+                    .layer(TimeoutLayer::new(
+                        config.shaping
+                        .timeout
+                        .unwrap_or(DEFAULT_TIMEOUT),
+                    ))
+                    .option_layer(retry)
+                    .option_layer(rate_limit)
+                .service(service)
+                .map_request(move |mut req: SubgraphRequest| {
+                    if let Some(compression) = config.shaping.compression.filter(|compression| *compression != Compression::Off) {
+                        let compression_header_val = HeaderValue::from_str(&compression.to_string()).expect("compression is manually implemented and already have the right values; qed");
+                        req.subgraph_request.headers_mut().insert(CONTENT_ENCODING, compression_header_val);
+                    }
+
+                    req
+                }))
+        } else {
+            Either::B(service)
+        }
+    }
+
+    pub(crate) fn enable_subgraph_http2(&self, service_name: &str) -> Http2Config {
+        Self::merge_config(
+            self.config.all.as_ref(),
+            self.config.subgraphs.get(service_name),
+        )
+        .and_then(|config| config.shaping.experimental_http2)
+        .unwrap_or(Http2Config::Enable)
+    }
+
+    /// Registers the HTTP client of a subgraph, to drain it once a reload replaces this router
+    pub(crate) fn register_http_client(&self, drain: Arc<Drain>) {
+        self.http_clients.lock().unwrap().push(drain);
+    }
+
+    /// Starts the drain timeout of the HTTP clients of the subgraphs, which keep serving the
+    /// operations of the replaced router until it elapses
+    pub(crate) fn drain_http_clients(&self) {
+        for drain in self.http_clients.lock().unwrap().iter() {
+            drain.start();
+        }
+    }
+
+    pub(crate) fn subgraph_client_config(&self, service_name: &str) -> HttpClientConfig {
+        let config = Self::merge_config(
+            self.config.all.as_ref(),
+            self.config.subgraphs.get(service_name),
+        );
+        let grpc = config
+            .as_ref()
+            .and_then(|config| config.shaping.grpc.clone());
+        HttpClientConfig {
+            // gRPC calls are only made over HTTP/2
+            http2: if grpc.is_some() {
+                Http2Config::Http2Only
+            } else {
+                self.enable_subgraph_http2(service_name)
+            },
+            http3: config
+                .as_ref()
+                .and_then(|config| config.shaping.experimental_http3.clone())
+                .filter(|_| grpc.is_none())
+                .unwrap_or_default(),
+            compression_level: config
+                .as_ref()
+                .and_then(|config| config.shaping.compression_level),
+            compression_min_size: config
+                .as_ref()
+                .and_then(|config| config.shaping.compression_min_size),
+            max_decompressed_bytes: config
+                .as_ref()
+                .and_then(|config| config.shaping.max_decompressed_bytes),
+            max_response_bytes: config
+                .as_ref()
+                .and_then(|config| config.shaping.max_response_bytes),
+            max_request_bytes: config
+                .as_ref()
+                .and_then(|config| config.shaping.max_request_bytes),
+            max_response_header_size: config
+                .as_ref()
+                .and_then(|config| config.shaping.max_response_header_size),
+            max_response_headers: config
+                .as_ref()
+                .and_then(|config| config.shaping.max_response_headers),
+            expect_continue: config
+                .as_ref()
+                .and_then(|config| config.shaping.expect_continue.clone()),
+            pool_max_idle_per_host: config
+                .as_ref()
+                .and_then(|config| config.shaping.pool_max_idle_per_host),
+            pool_idle_timeout: config
+                .as_ref()
+                .and_then(|config| config.shaping.pool_idle_timeout),
+            warmup: config
+                .as_ref()
+                .and_then(|config| config.shaping.warmup.clone()),
+            http2_initial_connection_window_size: config
+                .as_ref()
+                .and_then(|config| config.shaping.http2_initial_connection_window_size),
+            http2_initial_stream_window_size: config
+                .as_ref()
+                .and_then(|config| config.shaping.http2_initial_stream_window_size),
+            http2_keepalive_interval: config
+                .as_ref()
+                .and_then(|config| config.shaping.http2_keepalive_interval),
+            http2_keepalive_timeout: config
+                .as_ref()
+                .and_then(|config| config.shaping.http2_keepalive_timeout),
+            http2_max_concurrent_streams: config
+                .as_ref()
+                .and_then(|config| config.shaping.http2_max_concurrent_streams),
+            tcp_keepalive: config
+                .as_ref()
+                .and_then(|config| config.shaping.tcp_keepalive),
+            tcp_keepalive_interval: config
+                .as_ref()
+                .and_then(|config| config.shaping.tcp_keepalive_interval),
+            tcp_nodelay: config
+                .as_ref()
+                .and_then(|config| config.shaping.tcp_nodelay),
+            connect_timeout: config
+                .as_ref()
+                .and_then(|config| config.shaping.connect_timeout),
+            pool_acquire_timeout: config
+                .as_ref()
+                .and_then(|config| config.shaping.pool_acquire_timeout),
+            request_timeout: config
+                .as_ref()
+                .and_then(|config| config.shaping.request_timeout),
+            drain_timeout: config
+                .as_ref()
+                .and_then(|config| config.shaping.drain_timeout),
+            happy_eyeballs: config
+                .as_ref()
+                .and_then(|config| config.shaping.happy_eyeballs.clone())
+                .filter(|happy_eyeballs| happy_eyeballs.enabled),
+            dscp: config.as_ref().and_then(|config| config.shaping.dscp),
+            socket_priority: config
+                .as_ref()
+                .and_then(|config| config.shaping.socket_priority),
+            local_address: config
+                .as_ref()
+                .and_then(|config| config.shaping.local_address),
+            host_overrides: config
+                .as_ref()
+                .and_then(|config| config.shaping.host_overrides.clone())
+                .unwrap_or_default(),
+            accept: config
+                .as_ref()
+                .and_then(|config| config.shaping.accept.clone()),
+            // validated when the plugin is created
+            user_agent: config
+                .as_ref()
+                .and_then(|config| config.shaping.user_agent.as_deref())
+                .and_then(|template| user_agent(template).ok()),
+            // validated when the plugin is created
+            response_statuses: config
+                .as_ref()
+                .and_then(|config| config.shaping.response_statuses.as_ref())
+                .and_then(|statuses| ResponseStatuses::new(statuses).ok())
+                .unwrap_or_default(),
+            empty_response: config
+                .as_ref()
+                .and_then(|config| config.shaping.empty_response),
+            compression_off: config
+                .as_ref()
+                .and_then(|config| config.shaping.compression)
+                == Some(Compression::Off),
+            log_bodies: config
+                .as_ref()
+                .and_then(|config| config.shaping.debug.as_ref())
+                .and_then(|debug| debug.log_bodies.as_ref())
+                .map(|log_bodies| log_bodies.max_bytes.unwrap_or(LOG_BODIES_MAX_BYTES)),
+            log_http2_settings: config
+                .as_ref()
+                .and_then(|config| config.shaping.debug.as_ref())
+                .and_then(|debug| debug.log_http2_settings)
+                .unwrap_or_default(),
+            proxy: config.and_then(|config| config.shaping.proxy),
+            // set from the TLS configuration of the subgraph
+            server_name: None,
+            grpc,
+        }
+    }
+}
+
+register_private_plugin!("apollo", "traffic_shaping", TrafficShaping);
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use http::StatusCode;
+    use once_cell::sync::Lazy;
+    use serde_json_bytes::json;
+    use serde_json_bytes::ByteString;
+    use serde_json_bytes::Value;
+    use tower::Service;
+
+    use super::circuit_breaker::CircuitState;
+    use super::circuit_breaker::CIRCUIT_BREAKER_CONTEXT_KEY;
+    use super::http_retry::parse_retry_after;
+    use super::http_retry::HTTP_RETRIES_CONTEXT_KEY;
+    use super::http_retry::HTTP_RETRY_AFTER_CONTEXT_KEY;
+    use super::*;
+    use crate::json_ext::Object;
+    use crate::plugin::test::MockSubgraph;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+    use crate::query_planner::BridgeQueryPlannerPool;
+    use crate::query_planner::OperationKind;
+    use crate::router_factory::create_plugins;
+    use crate::services::http::service::NamedCompressionLevel;
+    use crate::services::http::HttpRequest;
+    use crate::services::http::HttpResponse;
+    use crate::services::layers::persisted_queries::PersistedQueryLayer;
+    use crate::services::layers::query_analysis::QueryAnalysisLayer;
+    use crate::services::router;
+    use crate::services::router::service::RouterCreator;
+    use crate::services::HasSchema;
+    use crate::services::PluggableSupergraphServiceBuilder;
+    use crate::services::SubgraphResponse;
+    use crate::services::SupergraphRequest;
+    use crate::services::SupergraphResponse;
+    use crate::Configuration;
+    use crate::Context;
+
+    static EXPECTED_RESPONSE: Lazy<Bytes> = Lazy::new(|| {
+        Bytes::from_static(r#"{"data":{"topProducts":[{"upc":"1","name":"Table","reviews":[{"id":"1","product":{"name":"Table"},"author":{"id":"1","name":"Ada Lovelace"}},{"id":"4","product":{"name":"Table"},"author":{"id":"2","name":"Alan Turing"}}]},{"upc":"2","name":"Couch","reviews":[{"id":"2","product":{"name":"Couch"},"author":{"id":"1","name":"Ada Lovelace"}}]}]}}"#.as_bytes())
+    });
+
+    static VALID_QUERY: &str = r#"query TopProducts($first: Int) { topProducts(first: $first) { upc name reviews { id product { name } author { id name } } } }"#;
+
+    async fn execute_router_test(
+        query: &str,
+        body: &Bytes,
+        mut router_service: router::BoxService,
+    ) {
+        let request = SupergraphRequest::fake_builder()
+            .query(query.to_string())
+            .variable("first", 2usize)
+            .build()
+            .expect("expecting valid request")
+            .try_into()
+            .unwrap();
+
+        let response = router_service
+            .ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response, body);
+    }
+
+    async fn build_mock_router_with_variable_dedup_optimization(
+        plugin: Box<dyn DynPlugin>,
+    ) -> router::BoxService {
+        let mut extensions = Object::new();
+        extensions.insert("test", Value::String(ByteString::from("value")));
+
+        let account_mocks = vec![
+            (
+                r#"{"query":"query TopProducts__accounts__3($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}","operationName":"TopProducts__accounts__3","variables":{"representations":[{"__typename":"User","id":"1"},{"__typename":"User","id":"2"}]}}"#,
+                r#"{"data":{"_entities":[{"name":"Ada Lovelace"},{"name":"Alan Turing"}]}}"#
+            )
+        ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
+        let account_service = MockSubgraph::new(account_mocks);
+
+        let review_mocks = vec![
+            (
+                r#"{"query":"query TopProducts__reviews__1($representations:[_Any!]!){_entities(representations:$representations){...on Product{reviews{id product{__typename upc}author{__typename id}}}}}","operationName":"TopProducts__reviews__1","variables":{"representations":[{"__typename":"Product","upc":"1"},{"__typename":"Product","upc":"2"}]}}"#,
+                r#"{"data":{"_entities":[{"reviews":[{"id":"1","product":{"__typename":"Product","upc":"1"},"author":{"__typename":"User","id":"1"}},{"id":"4","product":{"__typename":"Product","upc":"1"},"author":{"__typename":"User","id":"2"}}]},{"reviews":[{"id":"2","product":{"__typename":"Product","upc":"2"},"author":{"__typename":"User","id":"1"}}]}]}}"#
+            )
+            ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
+        let review_service = MockSubgraph::new(review_mocks);
+
+        let product_mocks = vec![
+            (
+                r#"{"query":"query TopProducts__products__0($first:Int){topProducts(first:$first){__typename upc name}}","operationName":"TopProducts__products__0","variables":{"first":2}}"#,
+                r#"{"data":{"topProducts":[{"__typename":"Product","upc":"1","name":"Table"},{"__typename":"Product","upc":"2","name":"Couch"}]}}"#
+            ),
+            (
+                r#"{"query":"query TopProducts__products__2($representations:[_Any!]!){_entities(representations:$representations){...on Product{name}}}","operationName":"TopProducts__products__2","variables":{"representations":[{"__typename":"Product","upc":"1"},{"__typename":"Product","upc":"2"}]}}"#,
+                r#"{"data":{"_entities":[{"name":"Table"},{"name":"Couch"}]}}"#
+            )
+            ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
+
+        let product_service = MockSubgraph::new(product_mocks).with_extensions(extensions);
+
+        let schema = include_str!(
+            "../../../../apollo-router-benchmarks/benches/fixtures/supergraph.graphql"
+        );
+
+        let config: Configuration = serde_yaml::from_str(
+            r#"
+        traffic_shaping:
+            deduplicate_variables: true
+        "#,
+        )
+        .unwrap();
+
+        let config = Arc::new(config);
+        let planner = BridgeQueryPlannerPool::new(
+            schema.to_string(),
+            config.clone(),
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .await
+        .unwrap();
+        let schema = planner.schema();
+        let subgraph_schemas = planner.subgraph_schemas();
+
+        let mut builder =
+            PluggableSupergraphServiceBuilder::new(planner).with_configuration(config.clone());
+
+        let plugins = Arc::new(
+            create_plugins(
+                &config,
+                &schema,
+                subgraph_schemas,
+                None,
+                Some(vec![(APOLLO_TRAFFIC_SHAPING.to_string(), plugin)]),
+            )
+            .await
+            .expect("create plugins should work"),
+        );
+        builder = builder.with_plugins(plugins);
+
+        let builder = builder
+            .with_subgraph_service("accounts", account_service.clone())
+            .with_subgraph_service("reviews", review_service.clone())
+            .with_subgraph_service("products", product_service.clone());
+
+        let supergraph_creator = builder.build().await.expect("should build");
+
+        RouterCreator::new(
+            QueryAnalysisLayer::new(supergraph_creator.schema(), Default::default()).await,
+            Arc::new(PersistedQueryLayer::new(&Default::default()).await.unwrap()),
+            Arc::new(supergraph_creator),
+            Arc::new(Configuration::default()),
+        )
+        .await
+        .unwrap()
+        .make()
+        .boxed()
+    }
+
+    async fn get_traffic_shaping_plugin(config: &serde_json::Value) -> Box<dyn DynPlugin> {
+        // Build a traffic shaping plugin
+        crate::plugin::plugins()
+            .find(|factory| factory.name == APOLLO_TRAFFIC_SHAPING)
+            .expect("Plugin not found")
+            .create_instance_without_schema(config)
+            .await
+            .expect("Plugin not created")
+    }
+
+    #[tokio::test]
+    async fn it_returns_valid_response_for_deduplicated_variables() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        deduplicate_variables: true
+        "#,
+        )
+        .unwrap();
+        // Build a traffic shaping plugin
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let router = build_mock_router_with_variable_dedup_optimization(plugin).await;
+        execute_router_test(VALID_QUERY, &EXPECTED_RESPONSE, router).await;
+    }
+
+    #[tokio::test]
+    async fn it_add_correct_headers_for_compression() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                compression: gzip
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let request = SubgraphRequest::fake_builder().build();
+
+        let test_service = MockSubgraph::new(HashMap::new()).map_request(|req: SubgraphRequest| {
+            assert_eq!(
+                req.subgraph_request
+                    .headers()
+                    .get(&CONTENT_ENCODING)
+                    .unwrap(),
+                HeaderValue::from_static("gzip")
+            );
+
+            req
+        });
+
+        let _response = plugin
+            .as_any()
+            .downcast_ref::<TrafficShaping>()
+            .unwrap()
+            .subgraph_service_internal("test", test_service)
+            .oneshot(request)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_turns_compression_off_per_subgraph() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        all:
+            compression: gzip
+        subgraphs:
+            legacy:
+                compression: off
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let shaping = plugin.as_any().downcast_ref::<TrafficShaping>().unwrap();
+        assert!(shaping.subgraph_client_config("legacy").compression_off);
+        assert!(!shaping.subgraph_client_config("products").compression_off);
+
+        let test_service = MockSubgraph::new(HashMap::new()).map_request(|req: SubgraphRequest| {
+            assert!(req
+                .subgraph_request
+                .headers()
+                .get(&CONTENT_ENCODING)
+                .is_none());
+            req
+        });
+        let _response = shaping
+            .subgraph_service_internal("legacy", test_service)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+    }
+
+    async fn upstream_calls_for_identical_requests(
+        shaping: &TrafficShaping,
+        subgraph_name: &str,
+    ) -> usize {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = {
+            let calls = calls.clone();
+            tower::service_fn(move |request: SubgraphRequest| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    // keeps the first request in flight while the second one arrives
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok::<_, BoxError>(
+                        SubgraphResponse::fake_builder()
+                            .context(request.context)
+                            .build(),
+                    )
+                }
+            })
+        };
+        let service = shaping.subgraph_service_internal(subgraph_name, service);
+
+        let (first, second) = tokio::join!(
+            service
+                .clone()
+                .oneshot(SubgraphRequest::fake_builder().build()),
+            service
+                .clone()
+                .oneshot(SubgraphRequest::fake_builder().build()),
+        );
+        first.unwrap();
+        second.unwrap();
+        calls.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_query_deduplication() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          deduplicate_query: true
+        subgraphs:
+          accounts:
+            deduplicate_query: false
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            upstream_calls_for_identical_requests(&shaping, "products").await,
+            1
+        );
+        assert_eq!(
+            upstream_calls_for_identical_requests(&shaping, "accounts").await,
+            2
+        );
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          deduplicate_query: true
+        subgraphs: 
+          products:
+            deduplicate_query: false
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(TrafficShaping::merge_config::<Shaping>(None, None), None);
+        assert_eq!(
+            TrafficShaping::merge_config(config.all.as_ref(), None),
+            config.all
+        );
+        assert_eq!(
+            TrafficShaping::merge_config(config.all.as_ref(), config.subgraphs.get("products"))
+                .as_ref(),
+            config.subgraphs.get("products")
+        );
+
+        assert_eq!(
+            TrafficShaping::merge_config(None, config.subgraphs.get("products")).as_ref(),
+            config.subgraphs.get("products")
+        );
+    }
+
+    #[test]
+    fn test_merge_http2_all() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          experimental_http2: disable
+        subgraphs: 
+          products:
+            experimental_http2: enable
+          reviews:
+            experimental_http2: disable
+        router:
+          timeout: 65s
+        "#,
+        )
+        .unwrap();
+
+        assert!(
+            TrafficShaping::merge_config(config.all.as_ref(), config.subgraphs.get("products"))
+                .unwrap()
+                .shaping
+                .experimental_http2
+                .unwrap()
+                == Http2Config::Enable
+        );
+        assert!(
+            TrafficShaping::merge_config(config.all.as_ref(), config.subgraphs.get("reviews"))
+                .unwrap()
+                .shaping
+                .experimental_http2
+                .unwrap()
+                == Http2Config::Disable
+        );
+        assert!(
+            TrafficShaping::merge_config(config.all.as_ref(), None)
+                .unwrap()
+                .shaping
+                .experimental_http2
+                .unwrap()
+                == Http2Config::Disable
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enable_subgraph_http2() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          experimental_http2: disable
+        subgraphs: 
+          products:
+            experimental_http2: enable
+          reviews:
+            experimental_http2: disable
+        router:
+          timeout: 65s
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert!(shaping_config.enable_subgraph_http2("products") == Http2Config::Enable);
+        assert!(shaping_config.enable_subgraph_http2("reviews") == Http2Config::Disable);
+        assert!(shaping_config.enable_subgraph_http2("this_doesnt_exist") == Http2Config::Disable);
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_compression_level() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          compression: gzip
+          compression_level: best
+        subgraphs:
+          products:
+            compression_level: 3
+          reviews:
+            compression_level: fastest
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .compression_level,
+            Some(CompressionLevel::Precise(3))
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("reviews")
+                .compression_level,
+            Some(CompressionLevel::Named(NamedCompressionLevel::Fastest))
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("this_doesnt_exist")
+                .compression_level,
+            Some(CompressionLevel::Named(NamedCompressionLevel::Best))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_pool_config() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          pool_max_idle_per_host: 10
+          pool_idle_timeout: 30s
+        subgraphs:
+          products:
+            pool_max_idle_per_host: 2
+          reviews:
+            pool_idle_timeout: 1s
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config.subgraph_client_config("products");
+        assert_eq!(products.pool_max_idle_per_host, Some(2));
+        assert_eq!(products.pool_idle_timeout, Some(Duration::from_secs(30)));
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(reviews.pool_max_idle_per_host, Some(10));
+        assert_eq!(reviews.pool_idle_timeout, Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_connect_timeout() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          connect_timeout: 1s
+        subgraphs:
+          products:
+            connect_timeout: 200ms
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .connect_timeout,
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("reviews")
+                .connect_timeout,
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_socket_marking() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          dscp: 46
+        subgraphs:
+          products:
+            dscp: 10
+            socket_priority: 4
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config.subgraph_client_config("products");
+        assert_eq!(products.dscp, Some(10));
+        assert_eq!(products.socket_priority, Some(4));
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(reviews.dscp, Some(46));
+        assert_eq!(reviews.socket_priority, None);
+
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          dscp: 64
+        "#,
+        )
+        .unwrap();
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .expect("the DSCP value is 6 bits long");
+        assert!(error.to_string().contains("dscp must be between 0 and 63"));
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_connection_warmup() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          warmup:
+            connections: 4
+        subgraphs:
+          products:
+            warmup:
+              connections: 16
+              ramp: 5s
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config.subgraph_client_config("products");
+        assert_eq!(
+            products.warmup,
+            Some(WarmupConfig {
+                connections: 16,
+                ramp: Some(Duration::from_secs(5)),
+            })
+        );
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(
+            reviews.warmup,
+            Some(WarmupConfig {
+                connections: 4,
+                ramp: None,
+            })
+        );
+
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            warmup:
+              connections: 0
+        "#,
+        )
+        .unwrap();
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("warmup.connections must not be zero"));
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_local_address() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          local_address: 10.0.0.5
+        subgraphs:
+          products:
+            local_address: "fd00::5"
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("reviews")
+                .local_address,
+            Some("10.0.0.5".parse().unwrap())
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .local_address,
+            Some("fd00::5".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_happy_eyeballs() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          happy_eyeballs:
+            enabled: true
+            connection_attempt_delay: 100ms
+        subgraphs:
+          products:
+            happy_eyeballs:
+              enabled: false
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("reviews")
+                .happy_eyeballs,
+            Some(HappyEyeballsConfig {
+                enabled: true,
+                connection_attempt_delay: Some(Duration::from_millis(100)),
+            })
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .happy_eyeballs,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_expect_continue() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          expect_continue:
+            min_size: 65536
+            timeout: 2s
+        subgraphs:
+          products:
+            expect_continue:
+              timeout: 500ms
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("reviews")
+                .expect_continue,
+            Some(ExpectContinueConfig {
+                min_size: Some(65536),
+                timeout: Some(Duration::from_secs(2)),
+            })
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .expect_continue,
+            Some(ExpectContinueConfig {
+                min_size: Some(65536),
+                timeout: Some(Duration::from_millis(500)),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_accept() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          accept: "application/json"
+        subgraphs:
+          products:
+            accept: "application/graphql-response+json"
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config.subgraph_client_config("products").accept,
+            Some(HeaderValue::from_static(
+                "application/graphql-response+json"
+            ))
+        );
+        assert_eq!(
+            shaping_config.subgraph_client_config("reviews").accept,
+            Some(HeaderValue::from_static("application/json"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_user_agent() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          user_agent: "router-staging/{router_version}"
+        subgraphs:
+          products:
+            user_agent: "products-client"
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config.subgraph_client_config("products").user_agent,
+            Some(HeaderValue::from_static("products-client"))
+        );
+        assert_eq!(
+            shaping_config.subgraph_client_config("reviews").user_agent,
+            Some(
+                HeaderValue::from_str(&format!(
+                    "router-staging/{}",
+                    std::env!("CARGO_PKG_VERSION")
+                ))
+                .unwrap()
+            )
+        );
+
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            user_agent: "products\nclient"
+        "#,
+        )
+        .unwrap();
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .expect("the user agent is not a valid header value");
+        assert!(error.to_string().contains("user_agent"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_response_statuses() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          response_statuses:
+            5xx: error
+        subgraphs:
+          products:
+            response_statuses:
+              "501": graphql
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config
+            .subgraph_client_config("products")
+            .response_statuses;
+        assert_eq!(
+            products.outcome(StatusCode::SERVICE_UNAVAILABLE),
+            StatusOutcome::Error
+        );
+        assert_eq!(
+            products.outcome(StatusCode::NOT_IMPLEMENTED),
+            StatusOutcome::Graphql
+        );
+        let reviews = shaping_config
+            .subgraph_client_config("reviews")
+            .response_statuses;
+        assert_eq!(
+            reviews.outcome(StatusCode::NOT_IMPLEMENTED),
+            StatusOutcome::Error
+        );
+        assert_eq!(
+            reviews.outcome(StatusCode::NOT_FOUND),
+            StatusOutcome::Graphql
+        );
+
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            response_statuses:
+              server_errors: error
+        "#,
+        )
+        .unwrap();
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .expect("the status is not valid");
+        assert!(
+            error
+                .to_string()
+                .contains("response_statuses key 'server_errors' must be a status code"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_log_bodies() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            debug:
+              log_bodies: {}
+          reviews:
+            debug:
+              log_bodies:
+                max_bytes: 512
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config.subgraph_client_config("products").log_bodies,
+            Some(4096)
+        );
+        assert_eq!(
+            shaping_config.subgraph_client_config("reviews").log_bodies,
+            Some(512)
+        );
+        assert_eq!(
+            shaping_config.subgraph_client_config("accounts").log_bodies,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_log_http2_settings() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          debug:
+            log_http2_settings: true
+        subgraphs:
+          products:
+            debug:
+              log_bodies: {}
+          reviews:
+            debug:
+              log_http2_settings: false
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert!(
+            shaping_config
+                .subgraph_client_config("products")
+                .log_http2_settings
+        );
+        assert!(
+            !shaping_config
+                .subgraph_client_config("reviews")
+                .log_http2_settings
+        );
+        assert!(
+            shaping_config
+                .subgraph_client_config("accounts")
+                .log_http2_settings
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_host_overrides() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          host_overrides:
+            products.example.com: 10.0.0.1:4001
+            reviews.example.com: 10.0.0.2:4002
+        subgraphs:
+          products:
+            host_overrides:
+              products.example.com: 10.0.1.1:4001
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config.subgraph_client_config("products");
+        assert_eq!(
+            products.host_overrides,
+            HashMap::from([
+                (
+                    "products.example.com".to_string(),
+                    "10.0.1.1:4001".parse().unwrap()
+                ),
+                (
+                    "reviews.example.com".to_string(),
+                    "10.0.0.2:4002".parse().unwrap()
+                ),
+            ])
+        );
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(
+            reviews.host_overrides.get("products.example.com"),
+            Some(&"10.0.0.1:4001".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_request_timeout() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          request_timeout: 500ms
+        subgraphs:
+          analytics:
+            request_timeout: 10s
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("analytics")
+                .request_timeout,
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .request_timeout,
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_pool_acquire_timeout() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          pool_acquire_timeout: 2s
+        subgraphs:
+          analytics:
+            pool_acquire_timeout: 100ms
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("analytics")
+                .pool_acquire_timeout,
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .pool_acquire_timeout,
+            Some(Duration::from_secs(2))
+        );
+
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          analytics:
+            pool_acquire_timeout: 0s
+        "#,
+        )
+        .unwrap();
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("pool_acquire_timeout must not be zero"));
+    }
+
+    #[tokio::test]
+    async fn test_drain_http_clients() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          drain_timeout: 10s
+        subgraphs:
+          products:
+            drain_timeout: 1m
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .drain_timeout,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("reviews")
+                .drain_timeout,
+            Some(Duration::from_secs(10))
+        );
+
+        let drains = [
+            Arc::new(Drain::new(Duration::from_secs(10))),
+            Arc::new(Drain::new(Duration::from_secs(60))),
+        ];
+        for drain in &drains {
+            shaping_config.register_http_client(drain.clone());
+        }
+        assert!(!drains.iter().any(|drain| drain.is_draining()));
+        shaping_config.drain_http_clients();
+        assert!(drains.iter().all(|drain| drain.is_draining()));
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_tcp_keepalive() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          tcp_keepalive: 300s
+          tcp_keepalive_interval: 30s
+        subgraphs:
+          products:
+            tcp_keepalive: 120s
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config.subgraph_client_config("products");
+        assert_eq!(products.tcp_keepalive, Some(Duration::from_secs(120)));
+        assert_eq!(
+            products.tcp_keepalive_interval,
+            Some(Duration::from_secs(30))
+        );
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(reviews.tcp_keepalive, Some(Duration::from_secs(300)));
+        assert_eq!(
+            reviews.tcp_keepalive_interval,
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_tcp_nodelay() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          bulk:
+            tcp_nodelay: false
+        "#,
+        )
+        .unwrap();
+
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shaping_config.subgraph_client_config("bulk").tcp_nodelay,
+            Some(false)
+        );
+        assert_eq!(
+            shaping_config
+                .subgraph_client_config("products")
+                .tcp_nodelay,
+            None
+        );
+    }
+
+    async fn call_with_http_retry(
+        config: &str,
+        operation_kind: OperationKind,
+        statuses: Vec<StatusCode>,
+    ) -> (StatusCode, usize, Context) {
+        let config = serde_yaml::from_str::<Config>(config).unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let service = {
+            let attempts = attempts.clone();
+            tower::service_fn(move |request: HttpRequest| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[attempt.min(statuses.len() - 1)];
+                // every attempt is sent with the kind of the operation
+                assert_eq!(
+                    request.http_request.extensions().get::<OperationKind>(),
+                    Some(&operation_kind)
+                );
+                async move {
+                    let body = hyper::body::to_bytes(request.http_request.into_body()).await?;
+                    assert_eq!(body, "{\"query\":\"{ me }\"}");
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed()
+        };
+
+        let mut http_request = http::Request::new(hyper::Body::from("{\"query\":\"{ me }\"}"));
+        http_request.extensions_mut().insert(operation_kind);
+        let context = Context::new();
+        let response = PluginPrivate::http_client_service(&shaping, "products", service)
+            .oneshot(HttpRequest {
+                http_request,
+                context: context.clone(),
+            })
+            .await
+            .unwrap();
+
+        (
+            response.http_response.status(),
+            attempts.load(Ordering::SeqCst),
+            context,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_http_retry() {
+        let config = r#"
+        all:
+          http_retry:
+            max_attempts: 3
+            base_delay: 1ms
+        "#;
+
+        let (status, attempts, context) = call_with_http_retry(
+            config,
+            OperationKind::Query,
+            vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(attempts, 2);
+        assert_eq!(
+            context
+                .get::<_, HashMap<String, u32>>(HTTP_RETRIES_CONTEXT_KEY)
+                .unwrap(),
+            Some(HashMap::from([("products".to_string(), 1)]))
+        );
+
+        // the last response is returned once all the attempts failed
+        let (status, attempts, _) =
+            call_with_http_retry(config, OperationKind::Query, vec![StatusCode::BAD_GATEWAY]).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(attempts, 3);
+
+        // other errors are not retried
+        let (status, attempts, _) = call_with_http_retry(
+            config,
+            OperationKind::Query,
+            vec![StatusCode::INTERNAL_SERVER_ERROR, StatusCode::OK],
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_http_retry_queries_only() {
+        let (status, attempts, context) = call_with_http_retry(
+            r#"
+        all:
+          http_retry:
+            base_delay: 1ms
+        "#,
+            OperationKind::Mutation,
+            vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK],
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(attempts, 1);
+        assert!(!context.contains_key(HTTP_RETRIES_CONTEXT_KEY));
+
+        // retries of mutations can be enabled for subgraphs where they are idempotent
+        let (status, attempts, _) = call_with_http_retry(
+            r#"
+        all:
+          http_retry:
+            base_delay: 1ms
+        subgraphs:
+          products:
+            http_retry:
+              queries_only: false
+        "#,
+            OperationKind::Mutation,
+            vec![StatusCode::GATEWAY_TIMEOUT, StatusCode::OK],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(attempts, 2);
     }
 
-    pub(crate) fn subgraph_service_internal<S>(
-        &self,
-        name: &str,
-        service: S,
-    ) -> impl Service<
-        subgraph::Request,
-        Response = subgraph::Response,
-        Error = BoxError,
-        Future = TrafficShapingSubgraphFuture<S>,
-    > + Clone
-           + Send
-           + Sync
-           + 'static
-    where
-        S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>
-            + Clone
-            + Send
-            + Sync
-            + 'static,
-        <S as Service<subgraph::Request>>::Future: std::marker::Send,
-    {
-        // Either we have the subgraph config and we merge it with the all config, or we just have the all config or we have nothing.
-        let all_config = self.config.all.as_ref();
-        let subgraph_config = self.config.subgraphs.get(name);
-        let final_config = Self::merge_config(all_config, subgraph_config);
+    #[tokio::test(start_paused = true)]
+    async fn test_http_retry_after() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          http_retry:
+            base_delay: 1ms
+            max_retry_after: 5s
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
 
-        if let Some(config) = final_config {
-            let rate_limit = config
-                .shaping
-                .global_rate_limit
-                .as_ref()
-                .map(|rate_limit_conf| {
-                    self.rate_limit_subgraphs
-                        .lock()
-                        .unwrap()
-                        .entry(name.to_string())
-                        .or_insert_with(|| {
-                            RateLimitLayer::new(rate_limit_conf.capacity, rate_limit_conf.interval)
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let service = {
+            let attempts = attempts.clone();
+            tower::service_fn(move |request: HttpRequest| {
+                let response = match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => http::Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header(http::header::RETRY_AFTER, "2"),
+                    1 => http::Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header(http::header::RETRY_AFTER, "120"),
+                    _ => http::Response::builder().status(StatusCode::OK),
+                };
+                async move {
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: response.body(hyper::Body::empty()).unwrap(),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed()
+        };
+
+        let mut http_request = http::Request::new(hyper::Body::from("{\"query\":\"{ me }\"}"));
+        http_request.extensions_mut().insert(OperationKind::Query);
+        let context = Context::new();
+        let start = tokio::time::Instant::now();
+        let response = PluginPrivate::http_client_service(&shaping, "products", service)
+            .oneshot(HttpRequest {
+                http_request,
+                context: context.clone(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.http_response.status(), StatusCode::OK);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        // the second delay is capped by max_retry_after
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(7) && elapsed < Duration::from_secs(8));
+        assert_eq!(
+            context
+                .get::<_, HashMap<String, u64>>(HTTP_RETRY_AFTER_CONTEXT_KEY)
+                .unwrap(),
+            Some(HashMap::from([("products".to_string(), 5000)]))
+        );
+
+        // 429 responses without a Retry-After header are not retried
+        let (status, attempts, _) = call_with_http_retry(
+            r#"
+        all:
+          http_retry:
+            base_delay: 1ms
+        "#,
+            OperationKind::Query,
+            vec![StatusCode::TOO_MANY_REQUESTS, StatusCode::OK],
+        )
+        .await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = std::time::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(
+            parse_retry_after(&http::HeaderValue::from_static("30"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after(
+                &http::HeaderValue::from_static("Wed, 21 Oct 2015 07:28:30 GMT"),
+                now
+            ),
+            Some(Duration::from_secs(30))
+        );
+        // dates in the past allow an immediate retry
+        assert_eq!(
+            parse_retry_after(
+                &http::HeaderValue::from_static("Wed, 21 Oct 2015 07:27:00 GMT"),
+                now
+            ),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_retry_after(&http::HeaderValue::from_static("soon"), now),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            circuit_breaker:
+              failure_threshold: 2
+              window: 10s
+              cooldown: 100ms
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let healthy = Arc::new(AtomicBool::new(false));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let call = |context: Context| {
+            let healthy = healthy.clone();
+            let attempts = attempts.clone();
+            let service = tower::service_fn(move |request: HttpRequest| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                let status = if healthy.load(Ordering::SeqCst) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                async move {
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed();
+            // a new service is created for each request, the breaker is shared between them
+            PluginPrivate::http_client_service(&shaping, "products", service).oneshot(HttpRequest {
+                http_request: http::Request::new(hyper::Body::empty()),
+                context,
+            })
+        };
+        let state = |context: &Context| {
+            context
+                .get::<_, HashMap<String, CircuitState>>(CIRCUIT_BREAKER_CONTEXT_KEY)
+                .unwrap()
+                .unwrap()["products"]
+        };
+
+        for _ in 0..2 {
+            let response = call(Context::new()).await.unwrap();
+            assert_eq!(
+                response.http_response.status(),
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+        }
+
+        // the circuit is open, the request fails without being sent
+        let context = Context::new();
+        let error = call(context.clone()).await.err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<FetchError>(),
+            Some(&FetchError::SubrequestCircuitOpen {
+                service: "products".to_string()
+            })
+        );
+        assert_eq!(state(&context), CircuitState::Open);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // after the cooldown, a failed probe opens the circuit again
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let context = Context::new();
+        call(context.clone()).await.unwrap();
+        assert_eq!(state(&context), CircuitState::HalfOpen);
+        assert!(call(Context::new()).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // a successful probe closes it
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        healthy.store(true, Ordering::SeqCst);
+        call(Context::new()).await.unwrap();
+        let context = Context::new();
+        let response = call(context.clone()).await.unwrap();
+        assert_eq!(response.http_response.status(), StatusCode::OK);
+        assert_eq!(state(&context), CircuitState::Closed);
+        assert_eq!(attempts.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_concurrency() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            adaptive_concurrency:
+              initial_limit: 2
+              min_limit: 1
+              max_limit: 4
+              latency_threshold: 1s
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let status = Arc::new(Mutex::new(StatusCode::OK));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let call = || {
+            let status = status.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            let service = tower::service_fn(move |request: HttpRequest| {
+                let status = *status.lock().unwrap();
+                let in_flight = in_flight.clone();
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed();
+            PluginPrivate::http_client_service(&shaping, "products", service).oneshot(HttpRequest {
+                http_request: http::Request::new(hyper::Body::empty()),
+                context: Context::new(),
+            })
+        };
+        let limit = || shaping.concurrency_limiters.lock().unwrap()["products"].limit();
+
+        // the requests over the limit wait, while the limit grows up to its maximum
+        let responses = futures::future::join_all((0..20).map(|_| call())).await;
+        assert!(responses.into_iter().all(|response| response.is_ok()));
+        assert!(max_in_flight.load(Ordering::SeqCst) <= 4);
+        assert_eq!(limit(), 4);
+
+        // failed requests decrease the limit down to its minimum
+        *status.lock().unwrap() = StatusCode::SERVICE_UNAVAILABLE;
+        for _ in 0..20 {
+            call().await.unwrap();
+        }
+        assert_eq!(limit(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            token_bucket:
+              requests_per_second: 1
+              burst: 2
+          reviews:
+            token_bucket:
+              requests_per_second: 20
+              burst: 1
+              on_limit: queue
+              max_wait: 1s
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let upstream_calls = Arc::new(AtomicUsize::new(0));
+        let call = |subgraph_name: &'static str| {
+            let upstream_calls = upstream_calls.clone();
+            let service = tower::service_fn(move |request: HttpRequest| {
+                upstream_calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::new(hyper::Body::empty()),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed();
+            PluginPrivate::http_client_service(&shaping, subgraph_name, service).oneshot(
+                HttpRequest {
+                    http_request: http::Request::new(hyper::Body::empty()),
+                    context: Context::new(),
+                },
+            )
+        };
+
+        // the burst is sent at once, then requests fail without being sent
+        call("products").await.unwrap();
+        call("products").await.unwrap();
+        let error = call("products").await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<FetchError>(),
+            Some(&FetchError::SubrequestRateLimited {
+                service: "products".to_string()
+            })
+        );
+        assert_eq!(upstream_calls.load(Ordering::SeqCst), 2);
+
+        // queued requests are sent at the rate of the bucket
+        let start = std::time::Instant::now();
+        let responses = futures::future::join_all((0..3).map(|_| call("reviews"))).await;
+        assert!(responses.into_iter().all(|response| response.is_ok()));
+        assert_eq!(upstream_calls.load(Ordering::SeqCst), 5);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn test_bulkhead() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            bulkhead:
+              max_concurrent: 1
+              max_queue: 1
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let call = || {
+            let release = release.clone();
+            let service = tower::service_fn(move |request: HttpRequest| {
+                let release = release.clone();
+                async move {
+                    release.acquire().await.unwrap().forget();
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::new(hyper::Body::empty()),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed();
+            PluginPrivate::http_client_service(&shaping, "products", service).oneshot(HttpRequest {
+                http_request: http::Request::new(hyper::Body::empty()),
+                context: Context::new(),
+            })
+        };
+        let queued = || shaping.bulkheads.lock().unwrap()["products"].queued();
+
+        // one request in flight and one in the queue
+        let first = tokio::spawn(call());
+        let second = tokio::spawn(call());
+        while queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // the queue is full
+        let error = call().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<FetchError>(),
+            Some(&FetchError::SubrequestOverloaded {
+                service: "products".to_string()
+            })
+        );
+
+        release.add_permits(2);
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+        assert_eq!(queued(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hedging() {
+        use crate::metrics::FutureMetricsExt;
+
+        async {
+            let config = serde_yaml::from_str::<Config>(
+                r#"
+        subgraphs:
+          products:
+            hedging:
+              percentile: 90
+              min_delay: 50ms
+        "#,
+            )
+            .unwrap();
+            let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .unwrap();
+
+            // the subgraph never answers the requests sent while `stalled` is set
+            let stalled = Arc::new(AtomicBool::new(false));
+            let calls = Arc::new(AtomicUsize::new(0));
+            let cancelled = Arc::new(AtomicUsize::new(0));
+            let call = |operation_kind: OperationKind| {
+                let stalled = stalled.clone();
+                let calls = calls.clone();
+                let cancelled = cancelled.clone();
+                let service = tower::service_fn(move |request: HttpRequest| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let stall = stalled.swap(false, Ordering::SeqCst);
+                    let cancelled = cancelled.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.http_request.into_body()).await?;
+                        assert_eq!(body, "{\"query\":\"{ me }\"}");
+                        if stall {
+                            struct Cancelled(Arc<AtomicUsize>);
+                            impl Drop for Cancelled {
+                                fn drop(&mut self) {
+                                    self.0.fetch_add(1, Ordering::SeqCst);
+                                }
+                            }
+                            let _cancelled = Cancelled(cancelled);
+                            std::future::pending::<()>().await;
+                        }
+                        Ok::<_, BoxError>(HttpResponse {
+                            http_response: http::Response::new(hyper::Body::empty()),
+                            context: request.context,
                         })
-                        .clone()
-                });
+                    }
+                })
+                .boxed();
+                let mut http_request =
+                    http::Request::new(hyper::Body::from("{\"query\":\"{ me }\"}"));
+                http_request.extensions_mut().insert(operation_kind);
+                PluginPrivate::http_client_service(&shaping, "products", service).oneshot(
+                    HttpRequest {
+                        http_request,
+                        context: Context::new(),
+                    },
+                )
+            };
+            let delay = || shaping.hedgers.lock().unwrap()["products"].delay();
 
-            let retry = config.shaping.experimental_retry.as_ref().map(|config| {
-                let retry_policy = RetryPolicy::new(
-                    config.ttl,
-                    config.min_per_sec,
-                    config.retry_percent,
-                    config.retry_mutations,
-                    name.to_string(),
+            // no request is hedged until enough latencies are known
+            for _ in 0..20 {
+                call(OperationKind::Query).await.unwrap();
+            }
+            assert_eq!(delay(), Some(Duration::from_millis(50)));
+
+            // the hedged request answers, the first one is cancelled
+            calls.store(0, Ordering::SeqCst);
+            stalled.store(true, Ordering::SeqCst);
+            let start = tokio::time::Instant::now();
+            call(OperationKind::Query).await.unwrap();
+            assert_eq!(start.elapsed(), Duration::from_millis(50));
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+            assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+
+            // mutations are never sent twice
+            calls.store(0, Ordering::SeqCst);
+            stalled.store(true, Ordering::SeqCst);
+            let mutation = call(OperationKind::Mutation);
+            assert!(tokio::time::timeout(Duration::from_secs(10), mutation)
+                .await
+                .is_err());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            assert_counter!(
+                "apollo.router.traffic_shaping.hedging.attempts",
+                1,
+                "subgraph.name" = "products"
+            );
+            assert_counter!(
+                "apollo.router.traffic_shaping.hedging.wins",
+                1,
+                "subgraph.name" = "products"
+            );
+        }
+        .with_metrics()
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hedging_with_http_retry() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            hedging:
+              percentile: 90
+              min_delay: 50ms
+            http_retry:
+              max_attempts: 3
+              base_delay: 1ms
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        // the subgraph answers with the status of each call, `None` never answers
+        let statuses = Arc::new(std::sync::Mutex::new(Vec::<Option<StatusCode>>::new()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let call = || {
+            let statuses = statuses.clone();
+            let calls = calls.clone();
+            let service = tower::service_fn(move |request: HttpRequest| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                // the hedged request and its retries are sent as a query too
+                assert_eq!(
+                    request.http_request.extensions().get::<OperationKind>(),
+                    Some(&OperationKind::Query)
                 );
-                tower::retry::RetryLayer::new(retry_policy)
-            });
+                let status = {
+                    let mut statuses = statuses.lock().unwrap();
+                    if statuses.is_empty() {
+                        Some(StatusCode::OK)
+                    } else {
+                        statuses.remove(0)
+                    }
+                };
+                async move {
+                    let Some(status) = status else {
+                        return std::future::pending().await;
+                    };
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed();
+            let mut http_request = http::Request::new(hyper::Body::from("{\"query\":\"{ me }\"}"));
+            http_request.extensions_mut().insert(OperationKind::Query);
+            PluginPrivate::http_client_service(&shaping, "products", service).oneshot(HttpRequest {
+                http_request,
+                context: Context::new(),
+            })
+        };
+
+        // no request is hedged until enough latencies are known
+        for _ in 0..20 {
+            call().await.unwrap();
+        }
+
+        // the first request stalls, the hedged request fails and is retried
+        calls.store(0, Ordering::SeqCst);
+        *statuses.lock().unwrap() = vec![
+            None,
+            Some(StatusCode::SERVICE_UNAVAILABLE),
+            Some(StatusCode::OK),
+        ];
+        let response = call().await.unwrap();
+        assert_eq!(response.http_response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_response_cache() {
+        use crate::metrics::FutureMetricsExt;
+
+        async {
+            let config = serde_yaml::from_str::<Config>(
+                r#"
+        subgraphs:
+          products:
+            response_cache:
+              max_entries: 10
+        "#,
+            )
+            .unwrap();
+            let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .unwrap();
+
+            // the subgraph answers with the number of requests it received
+            let cache_control = Arc::new(Mutex::new("max-age=10, stale-while-revalidate=5"));
+            let calls = Arc::new(AtomicUsize::new(0));
+            let call = |operation_kind: OperationKind, authorization: &'static str| {
+                let cache_control = cache_control.clone();
+                let calls = calls.clone();
+                let service = tower::service_fn(move |request: HttpRequest| {
+                    let count = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                    let cache_control = *cache_control.lock().unwrap();
+                    async move {
+                        Ok::<_, BoxError>(HttpResponse {
+                            http_response: http::Response::builder()
+                                .header(http::header::CACHE_CONTROL, cache_control)
+                                .body(hyper::Body::from(format!("{{\"data\":{count}}}")))
+                                .unwrap(),
+                            context: request.context,
+                        })
+                    }
+                })
+                .boxed();
+                let mut http_request = http::Request::builder()
+                    .header(http::header::AUTHORIZATION, authorization)
+                    .body(hyper::Body::from("{\"query\":\"{ me }\"}"))
+                    .unwrap();
+                http_request.extensions_mut().insert(operation_kind);
+                let response = PluginPrivate::http_client_service(&shaping, "products", service)
+                    .oneshot(HttpRequest {
+                        http_request,
+                        context: Context::new(),
+                    });
+                async move {
+                    let response = response.await.unwrap().http_response;
+                    let age = response.headers().get(http::header::AGE).cloned();
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    (body, age)
+                }
+            };
+
+            // the second request is answered from the cache
+            assert_eq!(call(OperationKind::Query, "a").await.0, "{\"data\":1}");
+            tokio::time::advance(Duration::from_secs(3)).await;
+            let (body, age) = call(OperationKind::Query, "a").await;
+            assert_eq!(body, "{\"data\":1}");
+            assert_eq!(age, Some(HeaderValue::from_static("3")));
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            // the authorization header is part of the key
+            assert_eq!(call(OperationKind::Query, "b").await.0, "{\"data\":2}");
+
+            // the expired response is used while it is refreshed in the background
+            tokio::time::advance(Duration::from_secs(8)).await;
+            assert_eq!(call(OperationKind::Query, "a").await.0, "{\"data\":1}");
+            while calls.load(Ordering::SeqCst) < 3 {
+                tokio::task::yield_now().await;
+            }
+            tokio::task::yield_now().await;
+            assert_eq!(call(OperationKind::Query, "a").await.0, "{\"data\":3}");
+
+            // mutations and the responses with `no-store` or `private` are not cached
+            assert_eq!(call(OperationKind::Mutation, "a").await.0, "{\"data\":4}");
+            assert_eq!(call(OperationKind::Mutation, "a").await.0, "{\"data\":5}");
+            *cache_control.lock().unwrap() = "max-age=10, private";
+            assert_eq!(call(OperationKind::Query, "c").await.0, "{\"data\":6}");
+            assert_eq!(call(OperationKind::Query, "c").await.0, "{\"data\":7}");
+            *cache_control.lock().unwrap() = "no-store";
+            assert_eq!(call(OperationKind::Query, "d").await.0, "{\"data\":8}");
+            assert_eq!(call(OperationKind::Query, "d").await.0, "{\"data\":9}");
+
+            assert_counter!(
+                "apollo.router.traffic_shaping.response_cache.hits",
+                3,
+                "subgraph.name" = "products"
+            );
+            assert_counter!(
+                "apollo.router.traffic_shaping.response_cache.misses",
+                6,
+                "subgraph.name" = "products"
+            );
+        }
+        .with_metrics()
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_load_balancing() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            load_balancing:
+              endpoints:
+                - http://products-1:4001/graphql
+                - http://products-2:4001/graphql
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        // the subgraph answers with the URI of the request
+        let call = |uri: &'static str| {
+            let service = tower::service_fn(|request: HttpRequest| async move {
+                Ok::<_, BoxError>(HttpResponse {
+                    http_response: http::Response::new(hyper::Body::from(
+                        request.http_request.uri().to_string(),
+                    )),
+                    context: request.context,
+                })
+            })
+            .boxed();
+            async move {
+                let response = PluginPrivate::http_client_service(&shaping, "products", service)
+                    .oneshot(HttpRequest {
+                        http_request: http::Request::get(uri).body(hyper::Body::empty()).unwrap(),
+                        context: Context::new(),
+                    })
+                    .await
+                    .unwrap();
+                hyper::body::to_bytes(response.http_response.into_body())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(
+            call("http://products:4001/").await,
+            "http://products-1:4001/graphql"
+        );
+        assert_eq!(
+            call("http://products:4001/?query=%7Bme%7D").await,
+            "http://products-2:4001/graphql?query=%7Bme%7D"
+        );
+        assert_eq!(
+            call("http://products:4001/").await,
+            "http://products-1:4001/graphql"
+        );
+    }
 
-            Either::A(ServiceBuilder::new()
+    #[tokio::test]
+    async fn test_load_balancing_outlier_detection() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            load_balancing:
+              endpoints:
+                - http://products-1:4001/graphql
+                - http://products-2:4001/graphql
+              outlier_detection:
+                consecutive_failures: 1
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
 
-                .option_layer(config.shaping.deduplicate_query.unwrap_or_default().then(
-                  QueryDeduplicationLayer::default
-                ))
-                    .layer(TimeoutLayer::new(
-                        config.shaping
-                        .timeout
-                        .unwrap_or(DEFAULT_TIMEOUT),
-                    ))
-                    .option_layer(retry)
-                    .option_layer(rate_limit)
-                .service(service)
-                .map_request(move |mut req: SubgraphRequest| {
-                    if let Some(compression) = config.shaping.compression {
-                        let compression_header_val = HeaderValue::from_str(&compression.to_string()).expect("compression is manually implemented and already have the right values; qed");
-                        req.subgraph_request.headers_mut().insert(CONTENT_ENCODING, compression_header_val);
-                    }
+        // the first endpoint fails, the subgraph answers with the URI of the request
+        let call = || {
+            let service = tower::service_fn(|request: HttpRequest| async move {
+                let uri = request.http_request.uri().to_string();
+                let mut http_response = http::Response::new(hyper::Body::from(uri.clone()));
+                if uri.contains("products-1") {
+                    *http_response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                Ok::<_, BoxError>(HttpResponse {
+                    http_response,
+                    context: request.context,
+                })
+            })
+            .boxed();
+            async move {
+                let response = PluginPrivate::http_client_service(&shaping, "products", service)
+                    .oneshot(HttpRequest {
+                        http_request: http::Request::get("http://products:4001/")
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: Context::new(),
+                    })
+                    .await
+                    .unwrap();
+                hyper::body::to_bytes(response.http_response.into_body())
+                    .await
+                    .unwrap()
+            }
+        };
 
-                    req
-                }))
-        } else {
-            Either::B(service)
-        }
+        assert_eq!(call().await, "http://products-1:4001/graphql");
+        // the first endpoint is ejected
+        assert_eq!(call().await, "http://products-2:4001/graphql");
+        assert_eq!(call().await, "http://products-2:4001/graphql");
     }
 
-    pub(crate) fn enable_subgraph_http2(&self, service_name: &str) -> Http2Config {
-        Self::merge_config(
-            self.config.all.as_ref(),
-            self.config.subgraphs.get(service_name),
+    #[tokio::test]
+    async fn test_load_balancing_health_check() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            load_balancing:
+              endpoints:
+                - http://products-1:4001/graphql
+                - http://products-2:4001/graphql
+              health_check:
+                path: /health
+                unhealthy_threshold: 1
+        "#,
         )
-        .and_then(|config| config.shaping.experimental_http2)
-        .unwrap_or(Http2Config::Enable)
-    }
-}
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
 
-register_plugin!("apollo", "traffic_shaping", TrafficShaping);
+        // the health check of the first endpoint fails, the subgraph answers with the URI of the
+        // request
+        let call = || {
+            let service = tower::service_fn(|request: HttpRequest| async move {
+                let uri = request.http_request.uri().to_string();
+                let mut http_response = http::Response::new(hyper::Body::from(uri.clone()));
+                if uri == "http://products-1:4001/health" {
+                    *http_response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                }
+                Ok::<_, BoxError>(HttpResponse {
+                    http_response,
+                    context: request.context,
+                })
+            })
+            .boxed();
+            async move {
+                let response = PluginPrivate::http_client_service(&shaping, "products", service)
+                    .oneshot(HttpRequest {
+                        http_request: http::Request::get("http://products:4001/")
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: Context::new(),
+                    })
+                    .await
+                    .unwrap();
+                hyper::body::to_bytes(response.http_response.into_body())
+                    .await
+                    .unwrap()
+            }
+        };
 
-#[cfg(test)]
-mod test {
-    use std::num::NonZeroUsize;
-    use std::sync::Arc;
+        // the endpoints receive requests until their first health check
+        assert_eq!(call().await, "http://products-1:4001/graphql");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(call().await, "http://products-2:4001/graphql");
+        assert_eq!(call().await, "http://products-2:4001/graphql");
+    }
 
-    use bytes::Bytes;
-    use once_cell::sync::Lazy;
-    use serde_json_bytes::json;
-    use serde_json_bytes::ByteString;
-    use serde_json_bytes::Value;
-    use tower::Service;
+    #[tokio::test]
+    async fn test_zero_outlier_detection_failures_are_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            load_balancing:
+              endpoints:
+                - http://products-1:4001/graphql
+              outlier_detection:
+                consecutive_failures: 0
+        "#,
+        )
+        .unwrap();
 
-    use super::*;
-    use crate::json_ext::Object;
-    use crate::plugin::test::MockSubgraph;
-    use crate::plugin::test::MockSupergraphService;
-    use crate::plugin::DynPlugin;
-    use crate::query_planner::BridgeQueryPlannerPool;
-    use crate::router_factory::create_plugins;
-    use crate::services::layers::persisted_queries::PersistedQueryLayer;
-    use crate::services::layers::query_analysis::QueryAnalysisLayer;
-    use crate::services::router;
-    use crate::services::router::service::RouterCreator;
-    use crate::services::HasSchema;
-    use crate::services::PluggableSupergraphServiceBuilder;
-    use crate::services::SupergraphRequest;
-    use crate::services::SupergraphResponse;
-    use crate::Configuration;
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("load_balancing.outlier_detection.consecutive_failures must not be zero"));
+    }
 
-    static EXPECTED_RESPONSE: Lazy<Bytes> = Lazy::new(|| {
-        Bytes::from_static(r#"{"data":{"topProducts":[{"upc":"1","name":"Table","reviews":[{"id":"1","product":{"name":"Table"},"author":{"id":"1","name":"Ada Lovelace"}},{"id":"4","product":{"name":"Table"},"author":{"id":"2","name":"Alan Turing"}}]},{"upc":"2","name":"Couch","reviews":[{"id":"2","product":{"name":"Couch"},"author":{"id":"1","name":"Ada Lovelace"}}]}]}}"#.as_bytes())
-    });
+    #[tokio::test]
+    async fn test_empty_load_balancing_endpoints_are_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            load_balancing:
+              endpoints: []
+        "#,
+        )
+        .unwrap();
 
-    static VALID_QUERY: &str = r#"query TopProducts($first: Int) { topProducts(first: $first) { upc name reviews { id product { name } author { id name } } } }"#;
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("load_balancing.endpoints must not be empty"));
+    }
 
-    async fn execute_router_test(
-        query: &str,
-        body: &Bytes,
-        mut router_service: router::BoxService,
-    ) {
-        let request = SupergraphRequest::fake_builder()
-            .query(query.to_string())
-            .variable("first", 2usize)
-            .build()
-            .expect("expecting valid request")
-            .try_into()
+    #[tokio::test]
+    async fn test_static_headers() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          headers:
+            insert:
+              - name: x-tenant
+                value: tenant
+              - name: x-api-key
+                value: all-key
+        subgraphs:
+          products:
+            headers:
+              insert:
+                - name: x-api-key
+                  value: products-key
+                  override_existing: true
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
             .unwrap();
 
-        let response = router_service
-            .ready()
+        // the subgraph sends back the headers of the request
+        let call = |subgraph_name: &str, http_request: http::Request<hyper::Body>| {
+            let service = tower::service_fn(|request: HttpRequest| async move {
+                let mut http_response = http::Response::new(hyper::Body::empty());
+                *http_response.headers_mut() = request.http_request.headers().clone();
+                Ok::<_, BoxError>(HttpResponse {
+                    http_response,
+                    context: request.context,
+                })
+            })
+            .boxed();
+            PluginPrivate::http_client_service(&shaping, subgraph_name, service).oneshot(
+                HttpRequest {
+                    http_request,
+                    context: Context::new(),
+                },
+            )
+        };
+
+        let response = call("reviews", http::Request::new(hyper::Body::empty()))
             .await
-            .unwrap()
-            .call(request)
+            .unwrap();
+        let headers = response.http_response.headers();
+        assert_eq!(headers["x-tenant"], "tenant");
+        assert_eq!(headers["x-api-key"], "all-key");
+
+        // propagated headers are only replaced when configured
+        let propagated = || {
+            http::Request::builder()
+                .header("x-tenant", "client-tenant")
+                .header("x-api-key", "client-key")
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let response = call("reviews", propagated()).await.unwrap();
+        let headers = response.http_response.headers();
+        assert_eq!(headers["x-tenant"], "client-tenant");
+        assert_eq!(headers["x-api-key"], "client-key");
+
+        let response = call("products", propagated()).await.unwrap();
+        let headers = response.http_response.headers();
+        assert_eq!(headers["x-tenant"], "client-tenant");
+        assert_eq!(headers["x-api-key"], "products-key");
+    }
+
+    #[tokio::test]
+    async fn test_follow_redirects() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            follow_redirects:
+              max_redirects: 3
+        "#,
+        )
+        .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
             .await
-            .unwrap()
-            .next_response()
+            .unwrap();
+
+        // the subgraph records the method, URI, authorization and body of the requests
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let call = |uri: &str| {
+            let requests = requests.clone();
+            let service = tower::service_fn(move |request: HttpRequest| {
+                let requests = requests.clone();
+                async move {
+                    let (parts, body) = request.http_request.into_parts();
+                    let body = hyper::body::to_bytes(body).await?;
+                    requests.lock().unwrap().push((
+                        parts.method.clone(),
+                        parts.uri.to_string(),
+                        parts.headers.get(http::header::AUTHORIZATION).cloned(),
+                        body,
+                    ));
+                    let (status, location) = match parts.uri.path() {
+                        "/moved" => (StatusCode::TEMPORARY_REDIRECT, "/graphql"),
+                        "/graphql" => (StatusCode::FOUND, "http://other:4002/final"),
+                        "/loop" => (StatusCode::PERMANENT_REDIRECT, "/loop2"),
+                        "/loop2" => (StatusCode::PERMANENT_REDIRECT, "/loop"),
+                        _ => (StatusCode::OK, ""),
+                    };
+                    Ok::<_, BoxError>(HttpResponse {
+                        http_response: http::Response::builder()
+                            .status(status)
+                            .header(http::header::LOCATION, location)
+                            .body(hyper::Body::empty())
+                            .unwrap(),
+                        context: request.context,
+                    })
+                }
+            })
+            .boxed();
+            let http_request = http::Request::post(uri)
+                .header(http::header::AUTHORIZATION, "Bearer secret")
+                .body(hyper::Body::from("{\"query\":\"{ me }\"}"))
+                .unwrap();
+            PluginPrivate::http_client_service(&shaping, "products", service).oneshot(HttpRequest {
+                http_request,
+                context: Context::new(),
+            })
+        };
+
+        // 307 keeps the method and body, 302 switches to GET, and the authorization is not sent
+        // to another host
+        let response = call("http://products:4001/moved").await.unwrap();
+        assert_eq!(response.http_response.status(), StatusCode::OK);
+        let requests_sent = std::mem::take(&mut *requests.lock().unwrap());
+        assert_eq!(requests_sent.len(), 3);
+        assert_eq!(requests_sent[1].0, http::Method::POST);
+        assert_eq!(requests_sent[1].1, "http://products:4001/graphql");
+        assert_eq!(requests_sent[1].2.as_ref().unwrap(), "Bearer secret");
+        assert_eq!(requests_sent[1].3, "{\"query\":\"{ me }\"}");
+        assert_eq!(requests_sent[2].0, http::Method::GET);
+        assert_eq!(requests_sent[2].1, "http://other:4002/final");
+        assert_eq!(requests_sent[2].2, None);
+        assert!(requests_sent[2].3.is_empty());
+
+        let error = call("http://products:4001/loop").await.err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("redirect loop detected at http://products:4001/loop"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_http_retry_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          http_retry:
+            jitter: 1.5
+        "#,
+        )
+        .unwrap();
+
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
             .await
-            .unwrap()
+            .err()
             .unwrap();
+        assert!(error
+            .to_string()
+            .contains("http_retry.jitter must be between 0 and 1"));
+    }
 
-        assert_eq!(response, body);
+    #[tokio::test]
+    async fn test_invalid_compression_level_is_rejected() {
+        for level in [-5, 10, 100] {
+            let config = serde_yaml::from_str::<Config>(&format!(
+                r#"
+        subgraphs:
+          products:
+            compression: gzip
+            compression_level: {level}
+        "#
+            ))
+            .unwrap();
+
+            let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .contains("compression_level must be between 0 and 9"));
+        }
     }
 
-    async fn build_mock_router_with_variable_dedup_optimization(
-        plugin: Box<dyn DynPlugin>,
-    ) -> router::BoxService {
-        let mut extensions = Object::new();
-        extensions.insert("test", Value::String(ByteString::from("value")));
+    #[tokio::test]
+    async fn test_invalid_hedging_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            hedging:
+              max_concurrent: 0
+        "#,
+        )
+        .unwrap();
 
-        let account_mocks = vec![
-            (
-                r#"{"query":"query TopProducts__accounts__3($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}","operationName":"TopProducts__accounts__3","variables":{"representations":[{"__typename":"User","id":"1"},{"__typename":"User","id":"2"}]}}"#,
-                r#"{"data":{"_entities":[{"name":"Ada Lovelace"},{"name":"Alan Turing"}]}}"#
-            )
-        ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
-        let account_service = MockSubgraph::new(account_mocks);
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("hedging.max_concurrent must not be zero"));
+    }
 
-        let review_mocks = vec![
-            (
-                r#"{"query":"query TopProducts__reviews__1($representations:[_Any!]!){_entities(representations:$representations){...on Product{reviews{id product{__typename upc}author{__typename id}}}}}","operationName":"TopProducts__reviews__1","variables":{"representations":[{"__typename":"Product","upc":"1"},{"__typename":"Product","upc":"2"}]}}"#,
-                r#"{"data":{"_entities":[{"reviews":[{"id":"1","product":{"__typename":"Product","upc":"1"},"author":{"__typename":"User","id":"1"}},{"id":"4","product":{"__typename":"Product","upc":"1"},"author":{"__typename":"User","id":"2"}}]},{"reviews":[{"id":"2","product":{"__typename":"Product","upc":"2"},"author":{"__typename":"User","id":"1"}}]}]}}"#
-            )
-            ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
-        let review_service = MockSubgraph::new(review_mocks);
+    #[tokio::test]
+    async fn test_invalid_adaptive_concurrency_is_rejected() {
+        let rejected = |config| async move {
+            let config = serde_yaml::from_str::<Config>(config).unwrap();
+            TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .err()
+                .unwrap()
+                .to_string()
+        };
 
-        let product_mocks = vec![
-            (
-                r#"{"query":"query TopProducts__products__0($first:Int){topProducts(first:$first){__typename upc name}}","operationName":"TopProducts__products__0","variables":{"first":2}}"#,
-                r#"{"data":{"topProducts":[{"__typename":"Product","upc":"1","name":"Table"},{"__typename":"Product","upc":"2","name":"Couch"}]}}"#
-            ),
-            (
-                r#"{"query":"query TopProducts__products__2($representations:[_Any!]!){_entities(representations:$representations){...on Product{name}}}","operationName":"TopProducts__products__2","variables":{"representations":[{"__typename":"Product","upc":"1"},{"__typename":"Product","upc":"2"}]}}"#,
-                r#"{"data":{"_entities":[{"name":"Table"},{"name":"Couch"}]}}"#
-            )
-            ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
+        assert!(rejected(
+            r#"
+        subgraphs:
+          products:
+            adaptive_concurrency:
+              max_limit: 0
+        "#
+        )
+        .await
+        .contains("adaptive_concurrency.max_limit must not be zero"));
 
-        let product_service = MockSubgraph::new(product_mocks).with_extensions(extensions);
+        // the default max_limit is 200
+        assert!(rejected(
+            r#"
+        subgraphs:
+          products:
+            adaptive_concurrency:
+              min_limit: 500
+        "#
+        )
+        .await
+        .contains("adaptive_concurrency.min_limit must not be greater than max_limit"));
 
-        let schema = include_str!(
-            "../../../../apollo-router-benchmarks/benches/fixtures/supergraph.graphql"
+        // the default min_limit is 1, and the limits of `all` apply to the subgraphs
+        assert!(rejected(
+            r#"
+        all:
+          adaptive_concurrency:
+            min_limit: 10
+        subgraphs:
+          products:
+            adaptive_concurrency:
+              max_limit: 5
+        "#
+        )
+        .await
+        .contains("adaptive_concurrency.min_limit must not be greater than max_limit"));
+
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          adaptive_concurrency:
+            max_limit: 1000
+        subgraphs:
+          products:
+            adaptive_concurrency:
+              min_limit: 500
+        "#,
+        )
+        .unwrap();
+        assert!(
+            TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .is_ok()
         );
+    }
 
-        let config: Configuration = serde_yaml::from_str(
+    #[tokio::test]
+    async fn test_invalid_response_cache_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
             r#"
-        traffic_shaping:
-            deduplicate_variables: true
+        subgraphs:
+          products:
+            response_cache:
+              key_headers:
+                - "x-user id"
         "#,
         )
         .unwrap();
 
-        let config = Arc::new(config);
-        let planner = BridgeQueryPlannerPool::new(
-            schema.to_string(),
-            config.clone(),
-            NonZeroUsize::new(1).unwrap(),
-        )
-        .await
-        .unwrap();
-        let schema = planner.schema();
-        let subgraph_schemas = planner.subgraph_schemas();
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("response_cache.key_headers 'x-user id' is not a valid header name"));
+    }
 
-        let mut builder =
-            PluggableSupergraphServiceBuilder::new(planner).with_configuration(config.clone());
+    #[tokio::test(start_paused = true)]
+    async fn test_request_batching() {
+        use crate::metrics::FutureMetricsExt;
 
-        let plugins = Arc::new(
-            create_plugins(
-                &config,
-                &schema,
-                subgraph_schemas,
-                None,
-                Some(vec![(APOLLO_TRAFFIC_SHAPING.to_string(), plugin)]),
+        async {
+            let config = serde_yaml::from_str::<Config>(
+                r#"
+        subgraphs:
+          products:
+            request_batching:
+              window: 20ms
+              max_size: 3
+        "#,
             )
-            .await
-            .expect("create plugins should work"),
-        );
-        builder = builder.with_plugins(plugins);
+            .unwrap();
+            let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .unwrap();
 
-        let builder = builder
-            .with_subgraph_service("accounts", account_service.clone())
-            .with_subgraph_service("reviews", review_service.clone())
-            .with_subgraph_service("products", product_service.clone());
+            // the subgraph answers each operation with its query
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let call = |query: &str, operation_kind: OperationKind| {
+                let sent = sent.clone();
+                let service = tower::service_fn(move |request: HttpRequest| {
+                    let sent = sent.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.http_request.into_body()).await?;
+                        let data = |operation: &Value| {
+                            json!({ "data": operation.as_object().unwrap()["query"].clone() })
+                        };
+                        let response = match serde_json::from_slice::<Value>(&body)? {
+                            Value::Array(operations) => {
+                                Value::Array(operations.iter().map(data).collect())
+                            }
+                            operation => data(&operation),
+                        };
+                        sent.lock().unwrap().push(body);
+                        Ok::<_, BoxError>(HttpResponse {
+                            http_response: http::Response::new(hyper::Body::from(
+                                serde_json::to_vec(&response).unwrap(),
+                            )),
+                            context: request.context,
+                        })
+                    }
+                })
+                .boxed();
+                let mut http_request = http::Request::new(hyper::Body::from(
+                    serde_json::to_vec(&json!({ "query": query })).unwrap(),
+                ));
+                *http_request.method_mut() = http::Method::POST;
+                http_request.extensions_mut().insert(operation_kind);
+                let response = PluginPrivate::http_client_service(&shaping, "products", service)
+                    .oneshot(HttpRequest {
+                        http_request,
+                        context: Context::new(),
+                    });
+                async move {
+                    let response = response.await.unwrap();
+                    hyper::body::to_bytes(response.http_response.into_body())
+                        .await
+                        .unwrap()
+                }
+            };
 
-        let supergraph_creator = builder.build().await.expect("should build");
+            // a full batch is sent without waiting for the end of the window
+            let start = tokio::time::Instant::now();
+            let responses = futures::future::join_all([
+                call("{ a }", OperationKind::Query),
+                call("{ b }", OperationKind::Query),
+                call("{ c }", OperationKind::Query),
+            ])
+            .await;
+            assert_eq!(start.elapsed(), Duration::ZERO);
+            assert_eq!(
+                responses,
+                [
+                    r#"{"data":"{ a }"}"#,
+                    r#"{"data":"{ b }"}"#,
+                    r#"{"data":"{ c }"}"#
+                ]
+            );
+            assert_eq!(
+                std::mem::take(&mut *sent.lock().unwrap()),
+                [r#"[{"query":"{ a }"},{"query":"{ b }"},{"query":"{ c }"}]"#]
+            );
 
-        RouterCreator::new(
-            QueryAnalysisLayer::new(supergraph_creator.schema(), Default::default()).await,
-            Arc::new(PersistedQueryLayer::new(&Default::default()).await.unwrap()),
-            Arc::new(supergraph_creator),
-            Arc::new(Configuration::default()),
-        )
-        .await
-        .unwrap()
-        .make()
-        .boxed()
-    }
+            // the other batches are sent at the end of their window, a single query as it is
+            let responses = futures::future::join_all([
+                call("{ a }", OperationKind::Query),
+                call("{ b }", OperationKind::Query),
+            ])
+            .await;
+            assert_eq!(start.elapsed(), Duration::from_millis(20));
+            assert_eq!(responses, [r#"{"data":"{ a }"}"#, r#"{"data":"{ b }"}"#]);
+            assert_eq!(
+                call("{ c }", OperationKind::Query).await,
+                r#"{"data":"{ c }"}"#
+            );
+            assert_eq!(start.elapsed(), Duration::from_millis(40));
+            assert_eq!(
+                std::mem::take(&mut *sent.lock().unwrap()),
+                [
+                    r#"[{"query":"{ a }"},{"query":"{ b }"}]"#,
+                    r#"{"query":"{ c }"}"#
+                ]
+            );
 
-    async fn get_traffic_shaping_plugin(config: &serde_json::Value) -> Box<dyn DynPlugin> {
-        // Build a traffic shaping plugin
-        crate::plugin::plugins()
-            .find(|factory| factory.name == APOLLO_TRAFFIC_SHAPING)
-            .expect("Plugin not found")
-            .create_instance_without_schema(config)
-            .await
-            .expect("Plugin not created")
+            // mutations are never batched
+            let responses = futures::future::join_all([
+                call("mutation { a }", OperationKind::Mutation),
+                call("mutation { b }", OperationKind::Mutation),
+            ])
+            .await;
+            assert_eq!(start.elapsed(), Duration::from_millis(40));
+            assert_eq!(
+                responses,
+                [
+                    r#"{"data":"mutation { a }"}"#,
+                    r#"{"data":"mutation { b }"}"#
+                ]
+            );
+            assert_eq!(sent.lock().unwrap().len(), 2);
+
+            assert_counter!(
+                "apollo.router.traffic_shaping.request_batching.batches",
+                2,
+                "subgraph.name" = "products"
+            );
+            assert_counter!(
+                "apollo.router.traffic_shaping.request_batching.queries",
+                5,
+                "subgraph.name" = "products"
+            );
+        }
+        .with_metrics()
+        .await;
     }
 
     #[tokio::test]
-    async fn it_returns_valid_response_for_deduplicated_variables() {
-        let config = serde_yaml::from_str::<serde_json::Value>(
+    async fn test_invalid_request_batching_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
             r#"
-        deduplicate_variables: true
+        subgraphs:
+          products:
+            request_batching:
+              max_size: 1
         "#,
         )
         .unwrap();
-        // Build a traffic shaping plugin
-        let plugin = get_traffic_shaping_plugin(&config).await;
-        let router = build_mock_router_with_variable_dedup_optimization(plugin).await;
-        execute_router_test(VALID_QUERY, &EXPECTED_RESPONSE, router).await;
+
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("request_batching.max_size must be at least 2"));
     }
 
     #[tokio::test]
-    async fn it_add_correct_headers_for_compression() {
-        let config = serde_yaml::from_str::<serde_json::Value>(
+    async fn test_url_template() {
+        let config = serde_yaml::from_str::<Config>(
             r#"
         subgraphs:
-            test:
-                compression: gzip
+          products:
+            url_template: "https://{tenant}.products.example.com/graphql"
         "#,
         )
         .unwrap();
+        let shaping = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
 
-        let plugin = get_traffic_shaping_plugin(&config).await;
-        let request = SubgraphRequest::fake_builder().build();
-
-        let test_service = MockSubgraph::new(HashMap::new()).map_request(|req: SubgraphRequest| {
-            assert_eq!(
-                req.subgraph_request
-                    .headers()
-                    .get(&CONTENT_ENCODING)
+        let call = |context: Context| {
+            let service = tower::service_fn(|request: HttpRequest| async move {
+                Ok::<_, BoxError>(HttpResponse {
+                    http_response: http::Response::new(hyper::Body::from(
+                        request.http_request.uri().to_string(),
+                    )),
+                    context: request.context,
+                })
+            })
+            .boxed();
+            PluginPrivate::http_client_service(&shaping, "products", service).oneshot(HttpRequest {
+                http_request: http::Request::builder()
+                    .uri("http://products/graphql?query=%7Bme%7D")
+                    .body(hyper::Body::empty())
                     .unwrap(),
-                HeaderValue::from_static("gzip")
-            );
+                context,
+            })
+        };
 
-            req
-        });
+        let context = Context::new();
+        context.insert("tenant", "acme".to_string()).unwrap();
+        let response = call(context).await.unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(response.http_response.into_body())
+                .await
+                .unwrap(),
+            "https://acme.products.example.com/graphql?query=%7Bme%7D"
+        );
 
-        let _response = plugin
-            .as_any()
-            .downcast_ref::<TrafficShaping>()
-            .unwrap()
-            .subgraph_service_internal("test", test_service)
-            .oneshot(request)
+        let error = call(Context::new()).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "HTTP fetch failed from 'products': the context entry 'tenant' of the url_template is missing"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_url_template_with_load_balancing_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          load_balancing:
+            endpoints:
+              - http://products-1:4001/graphql
+              - http://products-2:4001/graphql
+        subgraphs:
+          products:
+            url_template: "https://{tenant}.products.example.com/graphql"
+        "#,
+        )
+        .unwrap();
+
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
             .await
+            .err()
             .unwrap();
+        assert!(error
+            .to_string()
+            .contains("subgraph 'products' cannot use url_template together with load_balancing"));
     }
 
-    #[test]
-    fn test_merge_config() {
+    #[tokio::test]
+    async fn test_subgraph_http2_window_sizes() {
         let config = serde_yaml::from_str::<Config>(
             r#"
         all:
-          deduplicate_query: true
-        subgraphs: 
+          http2_initial_connection_window_size: 16777216
+        subgraphs:
           products:
-            deduplicate_query: false
+            http2_initial_stream_window_size: 8388608
         "#,
         )
         .unwrap();
 
-        assert_eq!(TrafficShaping::merge_config::<Shaping>(None, None), None);
-        assert_eq!(
-            TrafficShaping::merge_config(config.all.as_ref(), None),
-            config.all
-        );
-        assert_eq!(
-            TrafficShaping::merge_config(config.all.as_ref(), config.subgraphs.get("products"))
-                .as_ref(),
-            config.subgraphs.get("products")
-        );
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
 
+        let products = shaping_config.subgraph_client_config("products");
         assert_eq!(
-            TrafficShaping::merge_config(None, config.subgraphs.get("products")).as_ref(),
-            config.subgraphs.get("products")
+            products.http2_initial_connection_window_size,
+            Some(16777216)
         );
+        assert_eq!(products.http2_initial_stream_window_size, Some(8388608));
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(reviews.http2_initial_stream_window_size, None);
     }
 
-    #[test]
-    fn test_merge_http2_all() {
+    #[tokio::test]
+    async fn test_subgraph_http2_keepalive() {
         let config = serde_yaml::from_str::<Config>(
             r#"
         all:
-          experimental_http2: disable
-        subgraphs: 
+          http2_keepalive_interval: 30s
+        subgraphs:
           products:
-            experimental_http2: enable
-          reviews:
-            experimental_http2: disable
-        router:
-          timeout: 65s
+            http2_keepalive_timeout: 5s
         "#,
         )
         .unwrap();
 
-        assert!(
-            TrafficShaping::merge_config(config.all.as_ref(), config.subgraphs.get("products"))
-                .unwrap()
-                .shaping
-                .experimental_http2
-                .unwrap()
-                == Http2Config::Enable
-        );
-        assert!(
-            TrafficShaping::merge_config(config.all.as_ref(), config.subgraphs.get("reviews"))
-                .unwrap()
-                .shaping
-                .experimental_http2
-                .unwrap()
-                == Http2Config::Disable
+        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .unwrap();
+
+        let products = shaping_config.subgraph_client_config("products");
+        assert_eq!(
+            products.http2_keepalive_interval,
+            Some(Duration::from_secs(30))
         );
-        assert!(
-            TrafficShaping::merge_config(config.all.as_ref(), None)
-                .unwrap()
-                .shaping
-                .experimental_http2
-                .unwrap()
-                == Http2Config::Disable
+        assert_eq!(
+            products.http2_keepalive_timeout,
+            Some(Duration::from_secs(5))
         );
+        let reviews = shaping_config.subgraph_client_config("reviews");
+        assert_eq!(reviews.http2_keepalive_timeout, None);
     }
 
     #[tokio::test]
-    async fn test_enable_subgraph_http2() {
+    async fn test_zero_http2_max_concurrent_streams_is_rejected() {
         let config = serde_yaml::from_str::<Config>(
             r#"
         all:
-          experimental_http2: disable
-        subgraphs: 
+          http2_max_concurrent_streams: 0
+        "#,
+        )
+        .unwrap();
+
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("http2_max_concurrent_streams must not be zero"));
+    }
+
+    #[tokio::test]
+    async fn test_zero_response_header_limits_are_rejected() {
+        for option in ["max_response_header_size", "max_response_headers"] {
+            let config = serde_yaml::from_str::<Config>(&format!(
+                r#"
+        subgraphs:
           products:
-            experimental_http2: enable
-          reviews:
-            experimental_http2: disable
-        router:
-          timeout: 65s
+            {option}: 0
+        "#
+            ))
+            .unwrap();
+
+            let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+                .await
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .contains(&format!("{option} must not be zero")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_expect_continue_timeout_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        all:
+          expect_continue:
+            timeout: 0s
         "#,
         )
         .unwrap();
 
-        let shaping_config = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
             .await
+            .err()
             .unwrap();
+        assert!(error
+            .to_string()
+            .contains("expect_continue.timeout must not be zero"));
+    }
 
-        assert!(shaping_config.enable_subgraph_http2("products") == Http2Config::Enable);
-        assert!(shaping_config.enable_subgraph_http2("reviews") == Http2Config::Disable);
-        assert!(shaping_config.enable_subgraph_http2("this_doesnt_exist") == Http2Config::Disable);
+    #[tokio::test]
+    async fn test_small_http2_window_size_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            http2_initial_stream_window_size: 1024
+        "#,
+        )
+        .unwrap();
+
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("http2_initial_stream_window_size must be between 65535 and 2147483647"));
+    }
+
+    #[tokio::test]
+    async fn test_zero_pool_idle_timeout_is_rejected() {
+        let config = serde_yaml::from_str::<Config>(
+            r#"
+        subgraphs:
+          products:
+            pool_idle_timeout: 0s
+        "#,
+        )
+        .unwrap();
+
+        let error = TrafficShaping::new(PluginInit::fake_builder().config(config).build())
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("pool_idle_timeout must not be zero"));
     }
 
     #[tokio::test(flavor = "multi_thread")]
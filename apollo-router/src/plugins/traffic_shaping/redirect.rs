@@ -0,0 +1,196 @@
+//! Following of the HTTP redirects returned by subgraphs
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::header::AUTHORIZATION;
+use http::header::CONTENT_ENCODING;
+use http::header::CONTENT_LENGTH;
+use http::header::CONTENT_TYPE;
+use http::header::COOKIE;
+use http::header::HOST;
+use http::header::LOCATION;
+use http::header::PROXY_AUTHORIZATION;
+use http::Method;
+use http::StatusCode;
+use http::Uri;
+use tokio::sync::Mutex;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use super::http_retry::replay;
+use super::http_retry::take_extensions;
+use crate::error::FetchError;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_MAX_REDIRECTS: u32 = 5;
+
+/// Redirects followed for the requests to a subgraph
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RedirectPolicy {
+    max_redirects: u32,
+    keep_authorization: bool,
+}
+
+impl RedirectPolicy {
+    pub(crate) fn new(max_redirects: Option<u32>, keep_authorization: Option<bool>) -> Self {
+        Self {
+            max_redirects: max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+            keep_authorization: keep_authorization.unwrap_or_default(),
+        }
+    }
+}
+
+/// Follows the redirects returned by a subgraph
+///
+/// 307 and 308 redirects are sent again with the same method and body, while 301, 302 and 303
+/// redirects are sent as `GET` requests without a body. The authorization and cookie headers are
+/// removed when the redirect goes to another host, unless `keep_authorization` is set.
+#[derive(Clone)]
+pub(crate) struct FollowRedirectsLayer {
+    policy: Arc<RedirectPolicy>,
+    subgraph_name: Arc<String>,
+}
+
+impl FollowRedirectsLayer {
+    pub(crate) fn new(policy: RedirectPolicy, subgraph_name: &str) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            subgraph_name: Arc::new(subgraph_name.to_string()),
+        }
+    }
+}
+
+impl<S> Layer<S> for FollowRedirectsLayer {
+    type Service = FollowRedirects<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FollowRedirects {
+            // the inner service is called once per redirect from the response future
+            inner: Arc::new(Mutex::new(inner)),
+            policy: self.policy.clone(),
+            subgraph_name: self.subgraph_name.clone(),
+        }
+    }
+}
+
+pub(crate) struct FollowRedirects<S> {
+    inner: Arc<Mutex<S>>,
+    policy: Arc<RedirectPolicy>,
+    subgraph_name: Arc<String>,
+}
+
+impl<S> Service<HttpRequest> for FollowRedirects<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is polled before each request
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let subgraph_name = self.subgraph_name.clone();
+
+        Box::pin(async move {
+            let HttpRequest {
+                http_request,
+                context,
+            } = request;
+            // the body is buffered to be sent again to the redirect location
+            let (mut parts, body) = http_request.into_parts();
+            let mut body = hyper::body::to_bytes(body).await?;
+            let mut extensions = Some(take_extensions(&mut parts));
+            let mut visited = HashSet::from([parts.uri.clone()]);
+
+            loop {
+                let response = call_inner(
+                    &inner,
+                    HttpRequest {
+                        http_request: replay(&parts, extensions.take(), &body),
+                        context: context.clone(),
+                    },
+                )
+                .await?;
+
+                let status = response.http_response.status();
+                let keeps_method = match status {
+                    StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => true,
+                    StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => {
+                        false
+                    }
+                    _ => return Ok(response),
+                };
+                let Some(location) = response.http_response.headers().get(LOCATION) else {
+                    return Ok(response);
+                };
+                let error = |reason: String| FetchError::SubrequestHttpError {
+                    status_code: Some(status.as_u16()),
+                    service: subgraph_name.to_string(),
+                    reason,
+                };
+                let uri = resolve(&parts.uri, location.as_bytes()).ok_or_else(|| {
+                    error(format!(
+                        "invalid redirect location: {}",
+                        String::from_utf8_lossy(location.as_bytes())
+                    ))
+                })?;
+
+                if visited.contains(&uri) {
+                    return Err(error(format!("redirect loop detected at {uri}")).into());
+                }
+                // the URI of the first request is also in the visited URIs
+                if visited.len() > policy.max_redirects as usize {
+                    return Err(error(format!(
+                        "too many redirects, the limit is {}",
+                        policy.max_redirects
+                    ))
+                    .into());
+                }
+                visited.insert(uri.clone());
+                // releases the connection of the redirect response
+                drop(response);
+                tracing::debug!("following the redirect of subgraph '{subgraph_name}' to {uri}");
+
+                if !keeps_method {
+                    parts.method = Method::GET;
+                    body.clear();
+                    parts.headers.remove(CONTENT_TYPE);
+                    parts.headers.remove(CONTENT_LENGTH);
+                    parts.headers.remove(CONTENT_ENCODING);
+                }
+                if uri.authority() != parts.uri.authority() {
+                    parts.headers.remove(HOST);
+                    if !policy.keep_authorization {
+                        parts.headers.remove(AUTHORIZATION);
+                        parts.headers.remove(PROXY_AUTHORIZATION);
+                        parts.headers.remove(COOKIE);
+                    }
+                }
+                parts.uri = uri;
+            }
+        })
+    }
+}
+
+/// Resolves the location of a redirect, which can be relative to the URI of the request
+fn resolve(uri: &Uri, location: &[u8]) -> Option<Uri> {
+    let location = std::str::from_utf8(location).ok()?;
+    let url = url::Url::parse(&uri.to_string())
+        .ok()?
+        .join(location)
+        .ok()?;
+    url.as_str().parse().ok()
+}
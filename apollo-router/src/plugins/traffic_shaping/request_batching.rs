@@ -0,0 +1,355 @@
+//! Batching of the concurrent queries to a subgraph into a single HTTP request
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use http::header::CONTENT_LENGTH;
+use http::request::Parts;
+use http::Method;
+use hyper::Body;
+use serde_json_bytes::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use super::http_retry::replay;
+use crate::error::FetchError;
+use crate::query_planner::OperationKind;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_WINDOW: Duration = Duration::from_millis(5);
+const DEFAULT_MAX_SIZE: usize = 10;
+
+type Key = [u8; 32];
+
+struct Member {
+    body: Bytes,
+    reply: oneshot::Sender<Result<http::Response<Body>, FetchError>>,
+}
+
+/// Queries collected for a batch, until its window elapses or it is full
+#[derive(Default)]
+struct PendingBatch {
+    members: Mutex<Vec<Member>>,
+    full: Notify,
+}
+
+/// Sends the queries to a subgraph that are made within a short window of each other as a single
+/// HTTP request, whose body is the JSON array of their bodies, and answers each query with its
+/// item of the JSON array of the response
+///
+/// Only the queries with the same URL and headers are batched together. A response that is not
+/// an array, like an error for the whole batch, is returned to each query of the batch.
+/// Mutations are never batched, as the subgraph may not execute the operations of a batch in
+/// order.
+pub(crate) struct RequestBatcher {
+    subgraph_name: String,
+    window: Duration,
+    max_size: usize,
+    batches: Mutex<HashMap<Key, Arc<PendingBatch>>>,
+}
+
+impl RequestBatcher {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        window: Option<Duration>,
+        max_size: Option<usize>,
+    ) -> Self {
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            window: window.unwrap_or(DEFAULT_WINDOW),
+            max_size: max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            batches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(parts: &Parts) -> Key {
+        let mut hasher = Sha256::new();
+        hasher.update(parts.uri.to_string().as_bytes());
+        hasher.update([0]);
+        for (name, value) in &parts.headers {
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+            hasher.update(name.as_str().as_bytes());
+            hasher.update([0]);
+            hasher.update(value.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Adds a query to the pending batch of its key, returning the new batch if there was none
+    fn join(&self, key: Key, member: Member) -> Option<Arc<PendingBatch>> {
+        let mut batches = self.batches.lock().expect("lock poisoned");
+        match batches.get(&key) {
+            Some(batch) => {
+                let mut members = batch.members.lock().expect("lock poisoned");
+                members.push(member);
+                if members.len() >= self.max_size {
+                    drop(members);
+                    // no query joins a batch once it is removed
+                    if let Some(batch) = batches.remove(&key) {
+                        batch.full.notify_one();
+                    }
+                }
+                None
+            }
+            None => {
+                let batch = Arc::new(PendingBatch::default());
+                batch.members.lock().expect("lock poisoned").push(member);
+                batches.insert(key, batch.clone());
+                Some(batch)
+            }
+        }
+    }
+
+    /// Waits for the end of the window of a batch, or for it to be full, and takes its queries
+    async fn close(&self, key: Key, batch: &Arc<PendingBatch>) -> Vec<Member> {
+        tokio::select! {
+            _ = tokio::time::sleep(self.window) => {}
+            _ = batch.full.notified() => {}
+        }
+        let mut batches = self.batches.lock().expect("lock poisoned");
+        if batches
+            .get(&key)
+            .is_some_and(|pending| Arc::ptr_eq(pending, batch))
+        {
+            batches.remove(&key);
+        }
+        drop(batches);
+        std::mem::take(&mut *batch.members.lock().expect("lock poisoned"))
+    }
+
+    /// Answers each query of a batch with its item of the response
+    async fn dispatch(&self, members: Vec<Member>, result: Result<HttpResponse, BoxError>) {
+        let response = match result {
+            Ok(response) => response.http_response,
+            Err(error) => return self.fail(members, self.fetch_error(&error)),
+        };
+        let (mut parts, body) = response.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(error) => return self.fail(members, self.fetch_error(&error.into())),
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        let response = |body: Body| {
+            let mut response = http::Response::new(body);
+            *response.status_mut() = parts.status;
+            *response.headers_mut() = parts.headers.clone();
+            response
+        };
+        let items = match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Array(items)) if items.len() == members.len() => items,
+            Ok(Value::Array(items)) => {
+                return self.fail(
+                    members,
+                    FetchError::SubrequestMalformedResponse {
+                        service: self.subgraph_name.clone(),
+                        reason: format!(
+                            "the response to a batch of {} queries has {} items",
+                            members.len(),
+                            items.len()
+                        ),
+                    },
+                )
+            }
+            // the response applies to the whole batch
+            _ => {
+                for member in members {
+                    let _ = member.reply.send(Ok(response(Body::from(body.clone()))));
+                }
+                return;
+            }
+        };
+        for (member, item) in members.into_iter().zip(items) {
+            let body = serde_json::to_vec(&item).expect("a JSON value can be serialized");
+            let _ = member.reply.send(Ok(response(Body::from(body))));
+        }
+    }
+
+    fn fail(&self, members: Vec<Member>, error: FetchError) {
+        for member in members {
+            let _ = member.reply.send(Err(error.clone()));
+        }
+    }
+
+    fn fetch_error(&self, error: &BoxError) -> FetchError {
+        error
+            .downcast_ref::<FetchError>()
+            .cloned()
+            .unwrap_or_else(|| FetchError::SubrequestHttpError {
+                status_code: None,
+                service: self.subgraph_name.clone(),
+                reason: error.to_string(),
+            })
+    }
+
+    fn record_batch(&self, size: usize) {
+        u64_counter!(
+            "apollo.router.traffic_shaping.request_batching.batches",
+            "Number of batches of queries sent to a subgraph",
+            1,
+            "subgraph.name" = self.subgraph_name.clone()
+        );
+        u64_counter!(
+            "apollo.router.traffic_shaping.request_batching.queries",
+            "Number of queries sent to a subgraph in batches",
+            size as u64,
+            "subgraph.name" = self.subgraph_name.clone()
+        );
+    }
+}
+
+/// JSON array of the bodies of the queries of a batch
+fn batch_body(members: &[Member]) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u8(b'[');
+    for (index, member) in members.iter().enumerate() {
+        if index > 0 {
+            body.put_u8(b',');
+        }
+        body.put_slice(&member.body);
+    }
+    body.put_u8(b']');
+    body.freeze()
+}
+
+/// Applies the request batching of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct RequestBatchingLayer {
+    batcher: Arc<RequestBatcher>,
+}
+
+impl RequestBatchingLayer {
+    pub(crate) fn new(batcher: Arc<RequestBatcher>) -> Self {
+        Self { batcher }
+    }
+}
+
+impl<S> Layer<S> for RequestBatchingLayer {
+    type Service = RequestBatching<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestBatching {
+            // the inner service sends the batch from a spawned task
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            batcher: self.batcher.clone(),
+        }
+    }
+}
+
+pub(crate) struct RequestBatching<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    batcher: Arc<RequestBatcher>,
+}
+
+impl<S> Service<HttpRequest> for RequestBatching<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is polled before each request sent
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let batcher = self.batcher.clone();
+
+        Box::pin(async move {
+            if request.http_request.method() != Method::POST
+                || request.http_request.extensions().get::<OperationKind>()
+                    != Some(&OperationKind::Query)
+            {
+                return call_inner(&inner, request).await;
+            }
+
+            let HttpRequest {
+                http_request,
+                context,
+            } = request;
+            let (mut parts, body) = http_request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            // the bodies that are already batches are sent as they are
+            if body.iter().find(|byte| !byte.is_ascii_whitespace()) != Some(&b'{') {
+                return call_inner(
+                    &inner,
+                    HttpRequest {
+                        http_request: http::Request::from_parts(parts, Body::from(body)),
+                        context,
+                    },
+                )
+                .await;
+            }
+
+            let key = RequestBatcher::key(&parts);
+            let (reply, response) = oneshot::channel();
+            // the first query of a batch sends it, with its URL, headers and context
+            if let Some(batch) = batcher.join(key, Member { body, reply }) {
+                let batcher = batcher.clone();
+                let extensions = std::mem::take(&mut parts.extensions);
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let members = batcher.close(key, &batch).await;
+                    let body = match members.as_slice() {
+                        [member] => member.body.clone(),
+                        members => {
+                            batcher.record_batch(members.len());
+                            batch_body(members)
+                        }
+                    };
+                    let result = call_inner(
+                        &inner,
+                        HttpRequest {
+                            http_request: replay(&parts, Some(extensions), &body),
+                            context,
+                        },
+                    )
+                    .await;
+                    match members.len() {
+                        // a single query is answered with the response as it is
+                        1 => {
+                            let member = members.into_iter().next().expect("one member");
+                            let result = result
+                                .map(|response| response.http_response)
+                                .map_err(|error| batcher.fetch_error(&error));
+                            let _ = member.reply.send(result);
+                        }
+                        _ => batcher.dispatch(members, result).await,
+                    }
+                });
+            }
+
+            let http_response = response
+                .await
+                .map_err(|_| FetchError::SubrequestHttpError {
+                    status_code: None,
+                    service: batcher.subgraph_name.clone(),
+                    reason: "the batch of the request was cancelled".to_string(),
+                })??;
+            Ok(HttpResponse {
+                http_response,
+                context,
+            })
+        })
+    }
+}
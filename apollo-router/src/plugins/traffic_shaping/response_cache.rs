@@ -0,0 +1,345 @@
+//! Caching of the subgraph HTTP responses according to their `Cache-Control` header
+
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::header::AGE;
+use http::header::AUTHORIZATION;
+use http::header::COOKIE;
+use http::HeaderMap;
+use http::HeaderName;
+use http::StatusCode;
+use http::Version;
+use hyper::Body;
+use lru::LruCache;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::time::Instant;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use super::http_retry::replay;
+use crate::plugins::cache::cache_control::CacheControl;
+use crate::query_planner::OperationKind;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+type Key = [u8; 32];
+
+struct Entry {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    // value of the `Age` header of the response when it was stored
+    age: u64,
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    revalidating: bool,
+}
+
+impl Entry {
+    fn to_response(&self, now: Instant) -> http::Response<Body> {
+        let mut response = http::Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers.clone();
+        let age = self.age + now.duration_since(self.stored).as_secs();
+        response.headers_mut().insert(AGE, age.into());
+        response
+    }
+}
+
+enum Lookup {
+    Fresh(http::Response<Body>),
+    /// Expired response still usable while it is refreshed in the background, by the request
+    /// that found it first
+    Stale {
+        response: http::Response<Body>,
+        revalidate: bool,
+    },
+    Miss,
+}
+
+/// Stores the successful responses of the queries to a subgraph for the `max-age` (or
+/// `s-maxage`) of their `Cache-Control` header, and answers the same requests with them
+///
+/// Requests are identified by their method, URI, body and the values of the `key_headers`.
+/// Responses with `no-store`, `no-cache` or `private` are never stored. Once expired, a response
+/// with `stale-while-revalidate` is still used during that delay while a single request
+/// refreshes it in the background. It is removed if the refresh fails.
+pub(crate) struct ResponseCache {
+    subgraph_name: String,
+    key_headers: Vec<HeaderName>,
+    entries: Mutex<LruCache<Key, Entry>>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        max_entries: Option<usize>,
+        key_headers: Option<Vec<HeaderName>>,
+    ) -> Self {
+        let max_entries = NonZeroUsize::new(max_entries.unwrap_or(DEFAULT_MAX_ENTRIES))
+            .expect("max_entries was validated");
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            key_headers: key_headers.unwrap_or_else(|| vec![AUTHORIZATION, COOKIE]),
+            entries: Mutex::new(LruCache::new(max_entries)),
+        }
+    }
+
+    fn key(&self, parts: &http::request::Parts, body: &Bytes) -> Key {
+        let mut hasher = Sha256::new();
+        hasher.update(parts.method.as_str().as_bytes());
+        hasher.update([0]);
+        hasher.update(parts.uri.to_string().as_bytes());
+        hasher.update([0]);
+        for name in &self.key_headers {
+            for value in parts.headers.get_all(name) {
+                hasher.update(name.as_str().as_bytes());
+                hasher.update([0]);
+                hasher.update(value.as_bytes());
+                hasher.update([0]);
+            }
+        }
+        hasher.update(body);
+        hasher.finalize().into()
+    }
+
+    fn lookup(&self, key: &Key) -> Lookup {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        let Some(entry) = entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        let elapsed = now.duration_since(entry.stored);
+        if elapsed < entry.ttl {
+            self.record_hit();
+            return Lookup::Fresh(entry.to_response(now));
+        }
+        if elapsed < entry.ttl + entry.stale_while_revalidate {
+            // a single request refreshes a stale response
+            let revalidate = !entry.revalidating;
+            entry.revalidating = true;
+            self.record_hit();
+            return Lookup::Stale {
+                response: entry.to_response(now),
+                revalidate,
+            };
+        }
+        entries.pop(key);
+        Lookup::Miss
+    }
+
+    /// Stores the response if its `Cache-Control` header allows it, buffering its body
+    async fn store(&self, key: Key, response: HttpResponse) -> Result<HttpResponse, BoxError> {
+        let cacheable = CacheControl::new(response.http_response.headers(), None)
+            .ok()
+            .filter(|cache_control| {
+                response.http_response.status() == StatusCode::OK
+                    && cache_control.should_store()
+                    && !cache_control.private()
+                    && !cache_control.no_cache()
+            })
+            .and_then(|cache_control| {
+                let ttl = cache_control.ttl().filter(|ttl| *ttl > 0)?;
+                Some((ttl, cache_control.stale_while_revalidate().unwrap_or(0)))
+            });
+        let Some((ttl, stale_while_revalidate)) = cacheable else {
+            self.forget(&key);
+            return Ok(response);
+        };
+
+        let HttpResponse {
+            http_response,
+            context,
+        } = response;
+        let (parts, body) = http_response.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(error) => {
+                self.forget(&key);
+                return Err(error.into());
+            }
+        };
+        let age = parts
+            .headers
+            .get(AGE)
+            .and_then(|age| age.to_str().ok())
+            .and_then(|age| age.trim().parse().ok())
+            .unwrap_or(0);
+        self.entries.lock().expect("lock poisoned").put(
+            key,
+            Entry {
+                status: parts.status,
+                version: parts.version,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                stored: Instant::now(),
+                age,
+                ttl: Duration::from_secs(ttl.into()),
+                stale_while_revalidate: Duration::from_secs(stale_while_revalidate.into()),
+                revalidating: false,
+            },
+        );
+        Ok(HttpResponse {
+            http_response: http::Response::from_parts(parts, Body::from(body)),
+            context,
+        })
+    }
+
+    /// Removes a stale response that could not be refreshed, or that must not be stored anymore
+    fn forget(&self, key: &Key) {
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        if entries.peek(key).is_some_and(|entry| entry.revalidating) {
+            entries.pop(key);
+        }
+    }
+
+    fn record_hit(&self) {
+        u64_counter!(
+            "apollo.router.traffic_shaping.response_cache.hits",
+            "Number of subgraph requests answered from the response cache",
+            1,
+            "subgraph.name" = self.subgraph_name.clone()
+        );
+    }
+
+    fn record_miss(&self) {
+        u64_counter!(
+            "apollo.router.traffic_shaping.response_cache.misses",
+            "Number of subgraph requests not found in the response cache",
+            1,
+            "subgraph.name" = self.subgraph_name.clone()
+        );
+    }
+}
+
+/// Applies the response cache of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct ResponseCacheLayer {
+    cache: Arc<ResponseCache>,
+}
+
+impl ResponseCacheLayer {
+    pub(crate) fn new(cache: Arc<ResponseCache>) -> Self {
+        Self { cache }
+    }
+}
+
+impl<S> Layer<S> for ResponseCacheLayer {
+    type Service = ResponseCacheService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseCacheService {
+            // the inner service is also called from the background revalidations
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+pub(crate) struct ResponseCacheService<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    cache: Arc<ResponseCache>,
+}
+
+impl<S> Service<HttpRequest> for ResponseCacheService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the inner service is polled before each request sent
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let cache = self.cache.clone();
+
+        Box::pin(async move {
+            // the responses of mutations must not be reused
+            if request.http_request.extensions().get::<OperationKind>()
+                != Some(&OperationKind::Query)
+            {
+                return call_inner(&inner, request).await;
+            }
+
+            let HttpRequest {
+                http_request,
+                context,
+            } = request;
+            // the body is buffered to be part of the key
+            let (mut parts, body) = http_request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            let key = cache.key(&parts, &body);
+
+            match cache.lookup(&key) {
+                Lookup::Fresh(http_response)
+                | Lookup::Stale {
+                    response: http_response,
+                    revalidate: false,
+                } => Ok(HttpResponse {
+                    http_response,
+                    context,
+                }),
+                Lookup::Stale {
+                    response: http_response,
+                    revalidate: true,
+                } => {
+                    let extensions = std::mem::take(&mut parts.extensions);
+                    let revalidation = HttpRequest {
+                        http_request: replay(&parts, Some(extensions), &body),
+                        context: context.clone(),
+                    };
+                    tokio::spawn(async move {
+                        let result = match call_inner(&inner, revalidation).await {
+                            Ok(response) => cache.store(key, response).await,
+                            Err(error) => Err(error),
+                        };
+                        if let Err(error) = result {
+                            cache.forget(&key);
+                            tracing::debug!(
+                                "cannot refresh the cached response of subgraph '{}': {error}",
+                                cache.subgraph_name
+                            );
+                        }
+                    });
+                    Ok(HttpResponse {
+                        http_response,
+                        context,
+                    })
+                }
+                Lookup::Miss => {
+                    cache.record_miss();
+                    let extensions = std::mem::take(&mut parts.extensions);
+                    let response = call_inner(
+                        &inner,
+                        HttpRequest {
+                            http_request: replay(&parts, Some(extensions), &body),
+                            context,
+                        },
+                    )
+                    .await?;
+                    cache.store(key, response).await
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,176 @@
+//! Token bucket rate limit of the requests to a subgraph
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use opentelemetry_api::metrics::MeterProvider as _;
+use opentelemetry_api::metrics::ObservableGauge;
+use opentelemetry_api::KeyValue;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::http_retry::call_inner;
+use crate::error::FetchError;
+use crate::metrics::meter_provider;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    // negative when requests are queued for tokens that are not refilled yet
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: Instant) -> f64 {
+        (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.rate)
+            .min(self.burst)
+    }
+}
+
+/// Sends requests to a subgraph while the bucket has tokens. The bucket holds up to `burst`
+/// tokens and is refilled at `rate` tokens per second, each request takes one token.
+///
+/// When the bucket is empty, a request either fails immediately, or is queued if its token is
+/// refilled within `max_wait`.
+pub(crate) struct TokenBucket {
+    subgraph_name: String,
+    // requests fail immediately when not set
+    max_wait: Option<Duration>,
+    bucket: Arc<Mutex<Bucket>>,
+    _tokens_gauge: ObservableGauge<f64>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(
+        subgraph_name: &str,
+        rate: f64,
+        burst: Option<u32>,
+        queue: bool,
+        max_wait: Option<Duration>,
+    ) -> Self {
+        // by default, the bucket holds one second of requests
+        let burst = burst.map(f64::from).unwrap_or_else(|| rate.ceil());
+        let bucket = Arc::new(Mutex::new(Bucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        }));
+
+        let bucket_for_gauge = bucket.clone();
+        let subgraph_name_for_gauge = subgraph_name.to_string();
+        let tokens_gauge = meter_provider()
+            .meter("apollo/router")
+            .f64_observable_gauge("apollo.router.traffic_shaping.token_bucket.tokens")
+            .with_description("Tokens remaining in the rate limit bucket of a subgraph")
+            .with_callback(move |observer| {
+                let tokens = bucket_for_gauge
+                    .lock()
+                    .expect("lock poisoned")
+                    .tokens_at(Instant::now());
+                observer.observe(
+                    tokens.max(0.0),
+                    &[KeyValue::new(
+                        "subgraph.name",
+                        subgraph_name_for_gauge.clone(),
+                    )],
+                )
+            })
+            .init();
+
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            max_wait: queue.then(|| max_wait.unwrap_or(DEFAULT_MAX_WAIT)),
+            bucket,
+            _tokens_gauge: tokens_gauge,
+        }
+    }
+
+    /// Takes a token, returns how long the request must wait for it
+    fn take(&self) -> Result<Duration, FetchError> {
+        let mut bucket = self.bucket.lock().expect("lock poisoned");
+        let now = Instant::now();
+        let tokens = bucket.tokens_at(now);
+        let wait = if tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            let wait = Duration::from_secs_f64((1.0 - tokens) / bucket.rate);
+            if !self.max_wait.is_some_and(|max_wait| wait <= max_wait) {
+                return Err(FetchError::SubrequestRateLimited {
+                    service: self.subgraph_name.clone(),
+                });
+            }
+            wait
+        };
+        bucket.tokens = tokens - 1.0;
+        bucket.refilled_at = now;
+        Ok(wait)
+    }
+}
+
+/// Applies the token bucket of a subgraph to its HTTP requests
+#[derive(Clone)]
+pub(crate) struct TokenBucketLayer {
+    bucket: Arc<TokenBucket>,
+}
+
+impl TokenBucketLayer {
+    pub(crate) fn new(bucket: Arc<TokenBucket>) -> Self {
+        Self { bucket }
+    }
+}
+
+impl<S> Layer<S> for TokenBucketLayer {
+    type Service = TokenBucketService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TokenBucketService {
+            // the inner service is called from the response future, once a token is available
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            bucket: self.bucket.clone(),
+        }
+    }
+}
+
+pub(crate) struct TokenBucketService<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    bucket: Arc<TokenBucket>,
+}
+
+impl<S> Service<HttpRequest> for TokenBucketService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the rate limit is applied in the response future
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let taken = self.bucket.take();
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            let wait = taken?;
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            call_inner(&inner, request).await
+        })
+    }
+}
@@ -0,0 +1,242 @@
+//! Rewriting of the subgraph URL from the values of the request context
+
+use std::sync::Arc;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::load_balancer::endpoint_uri;
+use crate::error::FetchError;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+use crate::Context;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// key of the context entry whose value replaces the placeholder
+    Context(String),
+}
+
+/// URL of the requests to a subgraph, where the `{key}` placeholders are replaced with the
+/// values of the context entries of the request
+///
+/// The values are inserted as they are, so only strings, numbers and booleans made of letters,
+/// digits and `-`, `.`, `_`, `~` are accepted: they cannot change the structure of the URL. The
+/// query string of the request is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct UrlTemplate {
+    segments: Vec<Segment>,
+}
+
+impl UrlTemplate {
+    pub(crate) fn new(template: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("url_template '{template}' {reason}");
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| invalid("has an unclosed placeholder"))?;
+            let key = &rest[start + 1..end];
+            if key.is_empty() || key.contains('{') {
+                return Err(invalid("has an invalid placeholder"));
+            }
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            segments.push(Segment::Context(key.to_string()));
+            rest = &rest[end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(invalid("has an invalid placeholder"));
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        // the placeholders are checked with a value they accept
+        let example = segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.as_str(),
+                Segment::Context(_) => "x",
+            })
+            .collect::<String>();
+        match example.parse::<Uri>() {
+            Ok(uri)
+                if matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some() =>
+            {
+                Ok(Self { segments })
+            }
+            _ => Err(invalid("must render an absolute http or https URL")),
+        }
+    }
+
+    /// Renders the URL of a request, failing when a context entry is missing or has a value that
+    /// cannot be part of a URL
+    fn render(&self, context: &Context) -> Result<Uri, String> {
+        let mut url = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => url.push_str(literal),
+                Segment::Context(key) => {
+                    let value = match context.get_json_value(key.as_str()) {
+                        Some(Value::String(value)) => value.as_str().to_string(),
+                        Some(Value::Number(value)) => value.to_string(),
+                        Some(Value::Bool(value)) => value.to_string(),
+                        Some(_) => {
+                            return Err(format!(
+                                "the context entry '{key}' of the url_template is not a string, a number or a boolean"
+                            ))
+                        }
+                        None => {
+                            return Err(format!(
+                                "the context entry '{key}' of the url_template is missing"
+                            ))
+                        }
+                    };
+                    if value.is_empty()
+                        || !value.chars().all(|c| {
+                            c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~')
+                        })
+                    {
+                        return Err(format!(
+                            "the context entry '{key}' of the url_template has a value that cannot be part of a URL"
+                        ));
+                    }
+                    url.push_str(&value);
+                }
+            }
+        }
+        url.parse()
+            .map_err(|e| format!("the url_template rendered an invalid URL: {e}"))
+    }
+}
+
+/// Sends the requests to a subgraph to the URL rendered from their context
+///
+/// The connection, the server name sent in the TLS handshake and the verification of the subgraph
+/// certificate all use the host of the rendered URL.
+#[derive(Clone)]
+pub(crate) struct UrlTemplateLayer {
+    template: Arc<UrlTemplate>,
+    subgraph_name: Arc<String>,
+}
+
+impl UrlTemplateLayer {
+    pub(crate) fn new(template: UrlTemplate, subgraph_name: &str) -> Self {
+        Self {
+            template: Arc::new(template),
+            subgraph_name: Arc::new(subgraph_name.to_string()),
+        }
+    }
+}
+
+impl<S> Layer<S> for UrlTemplateLayer {
+    type Service = UrlTemplateService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        UrlTemplateService {
+            inner,
+            template: self.template.clone(),
+            subgraph_name: self.subgraph_name.clone(),
+        }
+    }
+}
+
+pub(crate) struct UrlTemplateService<S> {
+    inner: S,
+    template: Arc<UrlTemplate>,
+    subgraph_name: Arc<String>,
+}
+
+impl<S> Service<HttpRequest> for UrlTemplateService<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: HttpRequest) -> Self::Future {
+        match self.template.render(&request.context) {
+            Ok(url) => {
+                let uri = endpoint_uri(&url, request.http_request.uri());
+                *request.http_request.uri_mut() = uri;
+                Box::pin(self.inner.call(request))
+            }
+            Err(reason) => {
+                let error = FetchError::SubrequestHttpError {
+                    status_code: None,
+                    service: self.subgraph_name.to_string(),
+                    reason,
+                };
+                Box::pin(std::future::ready(Err(error.into())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_the_url_from_the_context() {
+        let template =
+            UrlTemplate::new("https://{tenant}.products.example.com/{version}/graphql").unwrap();
+        let context = Context::new();
+        context.insert("tenant", "acme".to_string()).unwrap();
+        context.insert("version", 2).unwrap();
+        assert_eq!(
+            template.render(&context).unwrap(),
+            Uri::from_static("https://acme.products.example.com/2/graphql")
+        );
+
+        context
+            .insert("tenant", "acme.com/evil".to_string())
+            .unwrap();
+        assert_eq!(
+            template.render(&context).unwrap_err(),
+            "the context entry 'tenant' of the url_template has a value that cannot be part of a URL"
+        );
+        assert_eq!(
+            template.render(&Context::new()).unwrap_err(),
+            "the context entry 'tenant' of the url_template is missing"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_templates() {
+        for (template, reason) in [
+            ("https://{tenant.example.com", "has an unclosed placeholder"),
+            ("https://{}.example.com", "has an invalid placeholder"),
+            ("https://tenant}.example.com", "has an invalid placeholder"),
+            (
+                "/{tenant}/graphql",
+                "must render an absolute http or https URL",
+            ),
+            (
+                "ftp://{tenant}.example.com",
+                "must render an absolute http or https URL",
+            ),
+        ] {
+            assert_eq!(
+                UrlTemplate::new(template).unwrap_err(),
+                format!("url_template '{template}' {reason}")
+            );
+        }
+    }
+}
@@ -4,6 +4,7 @@ use std::sync::Arc;
 
 use apollo_compiler::validation::Valid;
 use axum::response::IntoResponse;
+use futures::future::join_all;
 use http::StatusCode;
 use indexmap::IndexMap;
 use multimap::MultiMap;
@@ -17,8 +18,10 @@ use tower::ServiceExt;
 use tower_service::Service;
 use tracing::Instrument;
 
+use crate::configuration::CertificateAuthorities;
 use crate::configuration::Configuration;
 use crate::configuration::ConfigurationError;
+use crate::configuration::StartupHealthCheck;
 use crate::configuration::TlsClient;
 use crate::configuration::APOLLO_PLUGIN_PREFIX;
 use crate::plugin::DynPlugin;
@@ -33,6 +36,8 @@ use crate::plugins::traffic_shaping::APOLLO_TRAFFIC_SHAPING;
 use crate::query_planner::BridgeQueryPlannerPool;
 use crate::services::apollo_graph_reference;
 use crate::services::apollo_key;
+use crate::services::http::startup_check::check_subgraphs;
+use crate::services::http::startup_check::Probe;
 use crate::services::http::HttpClientServiceFactory;
 use crate::services::layers::persisted_queries::PersistedQueryLayer;
 use crate::services::layers::query_analysis::QueryAnalysisLayer;
@@ -123,6 +128,10 @@ pub(crate) trait RouterFactory:
     type Future: Send;
 
     fn web_endpoints(&self) -> MultiMap<ListenAddr, Endpoint>;
+
+    /// Called once a configuration reload replaced this router, the HTTP clients of the
+    /// subgraphs keep serving its operations and their requests get a timeout to complete
+    fn drain(&self) {}
 }
 
 /// Factory for creating a RouterFactory
@@ -408,14 +417,27 @@ pub(crate) async fn create_subgraph_services(
         .and_then(|plugin| (*plugin.1).as_any().downcast_ref::<TrafficShaping>())
         .expect("traffic shaping should always be part of the plugin list");
 
+    let health_check = configuration.subgraph.health_check_on_startup;
+    let mut probes = Vec::new();
+    let mut warmups = Vec::new();
     let mut subgraph_services = IndexMap::new();
-    for (name, _) in schema.subgraphs() {
+    for (name, url) in schema.subgraphs() {
+        check_subgraph_tls(name, url, configuration)?;
         let http_service = crate::services::http::HttpClientService::from_config(
             name,
             configuration,
             &tls_root_store,
-            shaping.enable_subgraph_http2(name),
+            shaping.subgraph_client_config(name),
         )?;
+        shaping.register_http_client(http_service.drain());
+        if health_check != StartupHealthCheck::Disabled {
+            probes.push(Probe {
+                name: name.clone(),
+                url: url.clone(),
+                client: http_service.clone(),
+            });
+        }
+        warmups.push((http_service.clone(), url.clone()));
 
         let http_service_factory =
             HttpClientServiceFactory::new(Arc::new(http_service), plugins.clone());
@@ -432,29 +454,110 @@ pub(crate) async fn create_subgraph_services(
         subgraph_services.insert(name.clone(), subgraph_service);
     }
 
+    check_subgraphs(health_check, probes).await?;
+    // the connections are opened before the new router serves the requests
+    join_all(
+        warmups
+            .iter()
+            .map(|(http_service, url)| http_service.warm_up(url)),
+    )
+    .await;
+
     Ok(subgraph_services)
 }
 
+// TLS does not apply to unix sockets, a TLS configuration for such a subgraph is a mistake
+fn check_subgraph_tls(
+    name: &str,
+    url: &http::Uri,
+    configuration: &Configuration,
+) -> Result<(), ConfigurationError> {
+    if url.scheme_str() == Some("unix") && configuration.tls.subgraph.subgraphs.contains_key(name) {
+        return Err(ConfigurationError::InvalidConfiguration {
+            message: "bad TLS configuration for subgraph",
+            error: format!(
+                "subgraph '{name}' is reached over a unix socket, it cannot have a TLS configuration in tls.subgraph.subgraphs"
+            ),
+        });
+    }
+    Ok(())
+}
+
 impl TlsClient {
+    /// Store of the root certificates set in this configuration, `None` if it does not set any
     pub(crate) fn create_certificate_store(
         &self,
     ) -> Option<Result<RootCertStore, ConfigurationError>> {
-        self.certificate_authorities
-            .as_deref()
-            .map(create_certificate_store)
+        if !self.sets_roots() {
+            return None;
+        }
+        Some(
+            self.read_certificate_authorities()
+                .and_then(|certificate_authorities| {
+                    create_certificate_store(
+                        certificate_authorities.as_ref(),
+                        self.uses_native_roots(),
+                    )
+                }),
+        )
+    }
+
+    /// Whether this configuration sets the root certificates, instead of using the ones of
+    /// `all` or the platform ones
+    pub(crate) fn sets_roots(&self) -> bool {
+        self.has_certificate_authorities() || self.use_native_roots.is_some()
+    }
+
+    /// The native roots are only trusted by default when no certificate authority is set
+    pub(crate) fn uses_native_roots(&self) -> bool {
+        self.use_native_roots
+            .unwrap_or(!self.has_certificate_authorities())
+    }
+
+    /// The certificate authorities set inline, or read from `certificate_authorities_file`
+    pub(crate) fn read_certificate_authorities(
+        &self,
+    ) -> Result<Option<CertificateAuthorities>, ConfigurationError> {
+        match (
+            &self.certificate_authorities,
+            &self.certificate_authorities_file,
+        ) {
+            (Some(_), Some(_)) => Err(ConfigurationError::CertificateAuthorities {
+                error: "certificate_authorities and certificate_authorities_file cannot be used together".to_string(),
+            }),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map(|pem| Some(CertificateAuthorities::Single(pem)))
+                .map_err(|e| ConfigurationError::CertificateAuthorities {
+                    error: format!("could not read {}: {e}", path.display()),
+                }),
+            (certificate_authorities, None) => Ok(certificate_authorities.clone()),
+        }
+    }
+
+    fn has_certificate_authorities(&self) -> bool {
+        self.certificate_authorities.is_some() || self.certificate_authorities_file.is_some()
     }
 }
 
 pub(crate) fn create_certificate_store(
-    certificate_authorities: &str,
+    certificate_authorities: Option<&CertificateAuthorities>,
+    use_native_roots: bool,
 ) -> Result<RootCertStore, ConfigurationError> {
     let mut store = RootCertStore::empty();
-    let certificates = load_certs(certificate_authorities).map_err(|e| {
-        ConfigurationError::CertificateAuthorities {
-            error: format!("could not parse the certificate list: {e}"),
-        }
-    })?;
-    for certificate in certificates {
+    if use_native_roots {
+        // some of the platform certificates can be invalid, they are skipped
+        let native_roots: Vec<_> = load_native_certificates()?
+            .into_iter()
+            .map(|certificate| certificate.0)
+            .collect();
+        let (valid_count, invalid_count) = store.add_parsable_certificates(&native_roots);
+        tracing::debug!(
+            "with_native_roots processed {} valid and {} invalid certs",
+            valid_count,
+            invalid_count
+        );
+    }
+    for certificate in load_certificate_authorities(certificate_authorities)? {
         store
             .add(&certificate)
             .map_err(|e| ConfigurationError::CertificateAuthorities {
@@ -470,6 +573,34 @@ pub(crate) fn create_certificate_store(
     }
 }
 
+/// Certificates of all the lists of certificate authorities
+pub(crate) fn load_certificate_authorities(
+    certificate_authorities: Option<&CertificateAuthorities>,
+) -> Result<Vec<rustls::Certificate>, ConfigurationError> {
+    let mut certificates = Vec::new();
+    for pem in certificate_authorities.map_or(&[][..], CertificateAuthorities::pems) {
+        certificates.extend(load_certs(pem).map_err(|e| {
+            ConfigurationError::CertificateAuthorities {
+                error: format!("could not parse the certificate list: {e}"),
+            }
+        })?);
+    }
+    Ok(certificates)
+}
+
+/// Root certificates of the operating system
+pub(crate) fn load_native_certificates() -> Result<Vec<rustls::Certificate>, ConfigurationError> {
+    let certificates = rustls_native_certs::load_native_certs().map_err(|e| {
+        ConfigurationError::CertificateAuthorities {
+            error: format!("could not load the platform certificates: {e}"),
+        }
+    })?;
+    Ok(certificates
+        .into_iter()
+        .map(|certificate| rustls::Certificate(certificate.0))
+        .collect())
+}
+
 fn load_certs(certificates: &str) -> io::Result<Vec<rustls::Certificate>> {
     tracing::debug!("loading root certificates");
 
@@ -638,6 +769,7 @@ pub(crate) async fn create_plugins(
 
     add_mandatory_apollo_plugin!("include_subgraph_errors");
     add_mandatory_apollo_plugin!("csrf");
+    add_mandatory_apollo_plugin!("request_id");
     add_mandatory_apollo_plugin!("headers");
     if apollo_telemetry_plugin_mandatory {
         match initial_telemetry_plugin {
@@ -735,10 +867,13 @@ mod test {
     use serde_json::json;
     use tower_http::BoxError;
 
+    use crate::configuration::CertificateAuthorities;
     use crate::configuration::Configuration;
+    use crate::configuration::TlsClient;
     use crate::plugin::Plugin;
     use crate::plugin::PluginInit;
     use crate::register_plugin;
+    use crate::router_factory::check_subgraph_tls;
     use crate::router_factory::inject_schema_id;
     use crate::router_factory::RouterSuperServiceFactory;
     use crate::router_factory::YamlRouterFactory;
@@ -880,4 +1015,49 @@ mod test {
             "8e2021d131b23684671c3b85f82dfca836908c6a541bbd5c3772c66e7f8429d8"
         );
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_subgraph_rejects_tls() {
+        let mut config = Configuration::default();
+        config
+            .tls
+            .subgraph
+            .subgraphs
+            .insert("accounts".to_string(), TlsClient::default());
+        let unix_url: http::Uri = hyperlocal::Uri::new("/tmp/accounts.sock", "/").into();
+        let https_url = http::Uri::from_static("https://accounts.example.com");
+
+        assert!(check_subgraph_tls("accounts", &https_url, &config).is_ok());
+        assert!(check_subgraph_tls("products", &unix_url, &config).is_ok());
+        let error = check_subgraph_tls("accounts", &unix_url, &config).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("subgraph 'accounts' is reached over a unix socket"));
+    }
+
+    #[test]
+    fn test_certificate_store_from_several_sources() {
+        let server_pem = include_str!("./services/http/testdata/server_self_signed.crt");
+        let ca_pem = include_str!("./services/http/testdata/CA/ca.crt");
+
+        assert!(TlsClient::default().create_certificate_store().is_none());
+
+        let tls = TlsClient::builder()
+            .certificate_authorities(CertificateAuthorities::Multiple(vec![
+                server_pem.to_string(),
+                ca_pem.to_string(),
+            ]))
+            .build();
+        assert!(!tls.uses_native_roots());
+        let store = tls.create_certificate_store().unwrap().unwrap();
+        assert_eq!(store.len(), 2);
+
+        let tls = TlsClient::builder()
+            .certificate_authorities(CertificateAuthorities::from(ca_pem))
+            .use_native_roots(true)
+            .build();
+        let store = tls.create_certificate_store().unwrap().unwrap();
+        assert!(store.len() > 1);
+    }
 }
@@ -9,10 +9,50 @@ use tower_service::Service;
 use super::Plugins;
 use crate::Context;
 
+mod body_limit;
+mod body_log;
+mod certificate_authorities;
+mod certificate_expiry;
+mod client_cert;
+mod connect_timeout;
+mod connection_metrics;
+mod drain;
+mod empty_response;
+mod expect_continue;
+mod grpc;
+mod header_limits;
+mod host_override;
+mod http2_settings;
+mod http3;
+mod insecure;
+pub(crate) mod ipv6_zone;
+mod keepalive;
+mod local_address;
+mod ocsp;
+mod peer_certificate;
+mod pinning;
+mod pool_acquire;
+mod priority;
+mod proxy;
+mod request_size;
+mod response_status;
+mod revocation;
 pub(crate) mod service;
+mod socket_marking;
+mod socks5;
+pub(crate) mod startup_check;
+mod stream_limit;
 #[cfg(test)]
 mod tests;
+mod tls_error;
+mod tls_handshake;
+pub(crate) mod trace_context;
+mod warmup;
 
+pub(crate) use drain::Drain;
+pub(crate) use empty_response::EmptyResponse;
+pub(crate) use response_status::ResponseStatuses;
+pub(crate) use response_status::StatusOutcome;
 pub(crate) use service::HttpClientService;
 
 pub(crate) type BoxService = tower::util::BoxService<HttpRequest, HttpResponse, BoxError>;
@@ -25,6 +65,10 @@ pub(crate) struct HttpRequest {
     pub(crate) context: Context,
 }
 
+/// Response of a subgraph
+///
+/// The body is streamed from the connection as it is read, and decompressed on the fly, so
+/// large responses can be consumed incrementally instead of being buffered first.
 #[non_exhaustive]
 pub(crate) struct HttpResponse {
     pub(crate) http_response: http::Response<Body>,
@@ -54,7 +98,10 @@ impl HttpClientServiceFactory {
             service,
             configuration,
             &rustls::RootCertStore::empty(),
-            http2,
+            crate::services::http::service::HttpClientConfig {
+                http2,
+                ..Default::default()
+            },
         )
         .unwrap();
 
@@ -0,0 +1,266 @@
+//! Limit of the size of subgraph response bodies, as received from the connection
+
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::header::CONTENT_LENGTH;
+use http::header::TRANSFER_ENCODING;
+use http::HeaderMap;
+use http::Request;
+use http::Response;
+use http::StatusCode;
+use hyper::body::HttpBody;
+use hyper::body::SizeHint;
+use hyper::Body;
+use pin_project_lite::pin_project;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::stream_limit::OpenStream;
+
+/// Error returned while reading a subgraph response body once its size goes over
+/// `max_response_bytes`
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "response from subgraph '{service}' exceeds the `max_response_bytes` limit of {limit} bytes"
+)]
+pub(crate) struct ResponseBodyLimitError {
+    service: Arc<String>,
+    limit: usize,
+}
+
+/// Error returned when a subgraph response body ends before the size declared in its
+/// `Content-Length` header, instead of the partial body being parsed
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "response from subgraph '{service}' was truncated: received {received} of the {declared} bytes of its Content-Length"
+)]
+pub(crate) struct TruncatedBodyError {
+    service: Arc<String>,
+    received: usize,
+    declared: usize,
+    #[source]
+    cause: Option<hyper::Error>,
+}
+
+/// Number of bytes of a response body received so far, before decompression, added to the
+/// extensions of the responses
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReceivedBytes(Arc<AtomicUsize>);
+
+impl ReceivedBytes {
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits the size of the response bodies returned by the inner client
+///
+/// The bytes are counted as they are received, before decompression, so a large body fails
+/// without being buffered first. The count is also kept in the `ReceivedBytes` extension of the
+/// response, and checked against the `Content-Length` of the response once the body ends, so
+/// that a truncated body fails. The HTTP/2 stream of the response stays counted in the stream
+/// limit of its connection until the body is dropped.
+#[derive(Clone)]
+pub(crate) struct ResponseBodyLimitLayer {
+    service: Arc<String>,
+    limit: Option<usize>,
+}
+
+impl ResponseBodyLimitLayer {
+    pub(crate) fn new(service: &str, limit: Option<usize>) -> Self {
+        Self {
+            service: Arc::new(service.to_string()),
+            limit,
+        }
+    }
+}
+
+impl<S> Layer<S> for ResponseBodyLimitLayer {
+    type Service = ResponseBodyLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseBodyLimit {
+            inner,
+            service: self.service.clone(),
+            limit: self.limit,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ResponseBodyLimit<S> {
+    inner: S,
+    service: Arc<String>,
+    limit: Option<usize>,
+}
+
+impl<S> Service<Request<Body>> for ResponseBodyLimit<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<LimitedBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let response = self.inner.call(request);
+        let service = self.service.clone();
+        let limit = self.limit.map(|limit| Limit {
+            service: self.service.clone(),
+            limit,
+            remaining: limit,
+        });
+        Box::pin(async move {
+            let mut response = response.await?;
+            let stream = response.extensions_mut().remove::<OpenStream>();
+            let received = ReceivedBytes::default();
+            response.extensions_mut().insert(received.clone());
+            let declared = declared_length(&response).map(|declared| Declared {
+                service: service.clone(),
+                declared,
+            });
+            Ok(response.map(|inner| LimitedBody {
+                inner,
+                limit,
+                declared,
+                received,
+                _stream: stream,
+            }))
+        })
+    }
+}
+
+struct Limit {
+    service: Arc<String>,
+    limit: usize,
+    remaining: usize,
+}
+
+/// Size of a response body declared by its `Content-Length` header
+struct Declared {
+    service: Arc<String>,
+    declared: usize,
+}
+
+impl Declared {
+    fn truncated(self, received: usize) -> Option<TruncatedBodyError> {
+        (received < self.declared).then_some(TruncatedBodyError {
+            service: self.service,
+            received,
+            declared: self.declared,
+            cause: None,
+        })
+    }
+}
+
+/// Size declared by the `Content-Length` header of a response, when it has a body of that size
+fn declared_length<B>(response: &Response<B>) -> Option<usize> {
+    // the length of a chunked body is ignored, and 204 and 304 responses have no body
+    if response.headers().contains_key(TRANSFER_ENCODING)
+        || response.status() == StatusCode::NO_CONTENT
+        || response.status() == StatusCode::NOT_MODIFIED
+    {
+        return None;
+    }
+    response
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pin_project! {
+    /// Response body failing once it goes over the limit, or when it ends before its declared
+    /// size, the trailers are kept
+    pub(crate) struct LimitedBody {
+        #[pin]
+        inner: Body,
+        limit: Option<Limit>,
+        declared: Option<Declared>,
+        received: ReceivedBytes,
+        _stream: Option<OpenStream>,
+    }
+}
+
+impl HttpBody for LimitedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let data = match this.inner.poll_data(cx) {
+            Poll::Ready(Some(Ok(data))) => data,
+            // a connection closed before the end of the body is reported as a truncation
+            Poll::Ready(Some(Err(err))) => {
+                let truncated = this
+                    .declared
+                    .take()
+                    .and_then(|declared| declared.truncated(this.received.get()));
+                return Poll::Ready(Some(Err(match truncated {
+                    Some(truncated) => TruncatedBodyError {
+                        cause: Some(err),
+                        ..truncated
+                    }
+                    .into(),
+                    None => err.into(),
+                })));
+            }
+            Poll::Ready(None) => {
+                let truncated = this
+                    .declared
+                    .take()
+                    .and_then(|declared| declared.truncated(this.received.get()));
+                return Poll::Ready(truncated.map(|truncated| Err(truncated.into())));
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        if let Some(limit) = this.limit {
+            match limit.remaining.checked_sub(data.len()) {
+                Some(remaining) => limit.remaining = remaining,
+                None => {
+                    return Poll::Ready(Some(Err(ResponseBodyLimitError {
+                        service: limit.service.clone(),
+                        limit: limit.limit,
+                    }
+                    .into())))
+                }
+            }
+        }
+        this.received.0.fetch_add(data.len(), Ordering::Relaxed);
+        Poll::Ready(Some(Ok(data)))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx).map_err(Into::into)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,152 @@
+//! Debug logging of the subgraph request and response bodies
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Bytes;
+use futures::Stream;
+use pin_project_lite::pin_project;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BodyKind {
+    Request,
+    Response,
+}
+
+/// Copy of the start of a body, logged once the body is read or dropped
+///
+/// Only the first `max_bytes` are kept, the size of the whole body is still counted. The body
+/// itself is passed through unchanged, so logging it does not change what is sent to the
+/// subgraph or returned from it.
+pub(crate) struct BodyLog {
+    service: Arc<String>,
+    kind: BodyKind,
+    max_bytes: usize,
+    logged: Vec<u8>,
+    size: usize,
+    complete: bool,
+}
+
+impl BodyLog {
+    pub(crate) fn new(service: &Arc<String>, kind: BodyKind, max_bytes: usize) -> Self {
+        Self {
+            service: service.clone(),
+            kind,
+            max_bytes,
+            logged: Vec::new(),
+            size: 0,
+            complete: false,
+        }
+    }
+
+    pub(crate) fn record(&mut self, data: &[u8]) {
+        let missing = self.max_bytes.saturating_sub(self.logged.len());
+        self.logged
+            .extend_from_slice(&data[..missing.min(data.len())]);
+        self.size += data.len();
+    }
+
+    /// Marks the body as entirely read, otherwise it is logged as interrupted
+    pub(crate) fn complete(&mut self) {
+        self.complete = true;
+    }
+}
+
+impl Drop for BodyLog {
+    fn drop(&mut self) {
+        let service_name = &self.service;
+        let body = String::from_utf8_lossy(&self.logged);
+        let truncated = self.size > self.logged.len();
+        let complete = self.complete;
+        match self.kind {
+            BodyKind::Request => tracing::debug!(
+                http.request.body = %body,
+                http.request.body.size = self.size,
+                truncated,
+                complete,
+                apollo.subgraph.name = %service_name,
+                "Request body to subgraph {service_name:?}"
+            ),
+            BodyKind::Response => tracing::debug!(
+                http.response.body = %body,
+                http.response.body.size = self.size,
+                truncated,
+                complete,
+                apollo.subgraph.name = %service_name,
+                "Response body from subgraph {service_name:?}"
+            ),
+        }
+    }
+}
+
+pin_project! {
+    /// Body stream recording its chunks in a `BodyLog` as they are read
+    pub(crate) struct LoggedBody<S> {
+        #[pin]
+        inner: S,
+        log: BodyLog,
+    }
+}
+
+impl<S> LoggedBody<S> {
+    pub(crate) fn new(inner: S, log: BodyLog) -> Self {
+        Self { inner, log }
+    }
+}
+
+impl<S, E> Stream for LoggedBody<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let res = this.inner.poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(data))) => this.log.record(data),
+            Poll::Ready(None) => this.log.complete(),
+            _ => {}
+        }
+        res
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_the_start_of_the_body() {
+        let service = Arc::new("products".to_string());
+        let mut log = BodyLog::new(&service, BodyKind::Response, 8);
+        log.record(b"{\"data\":");
+        log.record(b"{\"me\":null}}");
+        log.complete();
+        assert_eq!(log.logged, b"{\"data\":");
+        assert_eq!(log.size, 20);
+    }
+
+    #[tokio::test]
+    async fn it_passes_the_body_through() {
+        use futures::StreamExt;
+
+        let service = Arc::new("products".to_string());
+        let chunks: Vec<Result<Bytes, ()>> = vec![
+            Ok(Bytes::from_static(b"hello ")),
+            Ok(Bytes::from_static(b"world")),
+        ];
+        let body = LoggedBody::new(
+            futures::stream::iter(chunks),
+            BodyLog::new(&service, BodyKind::Request, 4),
+        );
+        let read: Vec<_> = body.map(Result::unwrap).collect().await;
+        assert_eq!(read.concat(), b"hello world");
+    }
+}
@@ -0,0 +1,101 @@
+//! Subgraph certificate authorities read from a file, reloaded when it changes
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use arc_swap::ArcSwap;
+use futures::StreamExt;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::Certificate;
+use rustls::ServerName;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+
+use crate::files::watch;
+
+type BuildVerifier = dyn Fn() -> Result<Arc<dyn ServerCertVerifier>, BoxError> + Send;
+
+/// Verifies the subgraph certificates against the certificate authorities of a file, and
+/// rebuilds the verifier when the file changes on disk
+///
+/// Only the TLS handshakes use the verifier: the established connections and the requests in
+/// flight are not affected by a reload, the new connections are verified with the reloaded
+/// certificate authorities.
+pub(crate) struct ReloadingVerifier {
+    verifier: Arc<ArcSwap<Arc<dyn ServerCertVerifier>>>,
+    watcher: JoinHandle<()>,
+}
+
+impl ReloadingVerifier {
+    /// Must be called from a tokio runtime, the file is watched by a spawned task
+    pub(crate) fn new(
+        subgraph: &str,
+        path: &Path,
+        verifier: Arc<dyn ServerCertVerifier>,
+        build: impl Fn() -> Result<Arc<dyn ServerCertVerifier>, BoxError> + Send + 'static,
+    ) -> Self {
+        let verifier = Arc::new(ArcSwap::from_pointee(verifier));
+        let watcher = tokio::spawn(reload(
+            subgraph.to_string(),
+            path.to_path_buf(),
+            verifier.clone(),
+            Box::new(build),
+        ));
+
+        Self { verifier, watcher }
+    }
+}
+
+impl Drop for ReloadingVerifier {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+impl ServerCertVerifier for ReloadingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier.load().verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+async fn reload(
+    subgraph: String,
+    path: PathBuf,
+    verifier: Arc<ArcSwap<Arc<dyn ServerCertVerifier>>>,
+    build: Box<BuildVerifier>,
+) {
+    // the watch stream starts with an event for the initial read, but the file was already
+    // loaded with the configuration
+    let mut changes = watch(&path).skip(1);
+    while changes.next().await.is_some() {
+        match build() {
+            Ok(reloaded) => {
+                verifier.store(Arc::new(reloaded));
+                tracing::info!("reloaded the certificate authorities of subgraph '{subgraph}'");
+            }
+            // the previous certificate authorities are kept, a later change of the file can
+            // fix it
+            Err(e) => tracing::error!(
+                "could not reload the certificate authorities of subgraph '{subgraph}': {e}"
+            ),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Warnings and refusals for the subgraph certificates nearing their expiry
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::Certificate;
+use rustls::CertificateError;
+use rustls::ServerName;
+
+pub(crate) const DEFAULT_WARN_BEFORE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Verifies subgraph certificates with the inner verifier, then checks how long the subgraph
+/// certificate remains valid
+///
+/// A certificate expiring within `warn_before` is logged and counted, one expiring within
+/// `reject_before` fails the handshake. Expired certificates are already refused by the inner
+/// verifier.
+pub(crate) struct ExpiryVerifier {
+    subgraph: String,
+    inner: Arc<dyn ServerCertVerifier>,
+    warn_before: Duration,
+    reject_before: Option<Duration>,
+}
+
+impl ExpiryVerifier {
+    pub(crate) fn new(
+        subgraph: &str,
+        inner: Arc<dyn ServerCertVerifier>,
+        warn_before: Duration,
+        reject_before: Option<Duration>,
+    ) -> Self {
+        Self {
+            subgraph: subgraph.to_string(),
+            inner,
+            warn_before,
+            reject_before,
+        }
+    }
+}
+
+impl ServerCertVerifier for ExpiryVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let (_, certificate) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let remaining = Duration::from_secs(
+            certificate
+                .validity()
+                .not_after
+                .timestamp()
+                .saturating_sub(now)
+                .max(0) as u64,
+        );
+
+        if self
+            .reject_before
+            .is_some_and(|reject_before| remaining < reject_before)
+        {
+            return Err(rustls::Error::General(format!(
+                "the certificate of subgraph '{}' expires in {}, within its reject_before threshold",
+                self.subgraph,
+                humantime::format_duration(remaining)
+            )));
+        }
+        if remaining < self.warn_before {
+            tracing::warn!(
+                subgraph = %self.subgraph,
+                "the certificate of subgraph '{}' expires in {}",
+                self.subgraph,
+                humantime::format_duration(remaining)
+            );
+            u64_counter!(
+                "apollo.router.subgraph.tls.certificate_expiring",
+                "Number of TLS handshakes where the subgraph certificate expires within the warn_before threshold",
+                1,
+                "subgraph.name" = self.subgraph.clone()
+            );
+        }
+
+        Ok(verified)
+    }
+}
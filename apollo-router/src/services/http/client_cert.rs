@@ -0,0 +1,270 @@
+//! Subgraph client certificates, reloaded when their files change and selected by host
+
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use futures::stream;
+use futures::StreamExt;
+use rustls::client::ResolvesClientCert;
+use rustls::sign::CertifiedKey;
+use rustls::Certificate;
+use rustls::PrivateKey;
+use rustls::SignatureScheme;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+
+use crate::configuration::TlsClientAuth;
+use crate::configuration::TlsClientAuthFiles;
+use crate::configuration::TlsClientIdentity;
+use crate::files::watch;
+
+tokio::task_local! {
+    /// Connection being established, visible to the client certificate resolvers during the TLS
+    /// handshake
+    static CONNECTING: Connecting;
+}
+
+/// Resolves the client certificate of a subgraph from its certificate chain and key files, and
+/// reloads them when they change on disk. Established connections keep the certificate they
+/// were authenticated with, new connections use the reloaded one
+pub(crate) struct ReloadingClientCert {
+    certified_key: Arc<ArcSwap<CertifiedKey>>,
+    watcher: JoinHandle<()>,
+}
+
+impl ReloadingClientCert {
+    /// Must be called from a tokio runtime, the files are watched by a spawned task
+    ///
+    /// The files are read again, they may have changed since the configuration was loaded.
+    pub(crate) fn new(subgraph: &str, files: &TlsClientAuthFiles) -> Result<Self, BoxError> {
+        let (certificate_chain, key) = files.load()?;
+        let certified_key = Arc::new(ArcSwap::from_pointee(certified_key(
+            certificate_chain,
+            &key,
+        )?));
+        let watcher = tokio::spawn(reload(
+            subgraph.to_string(),
+            files.clone(),
+            certified_key.clone(),
+        ));
+
+        Ok(Self {
+            certified_key,
+            watcher,
+        })
+    }
+}
+
+impl Drop for ReloadingClientCert {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+impl ResolvesClientCert for ReloadingClientCert {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.load_full())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Host of a connection being established, whether a client certificate was configured for it,
+/// and whether the subgraph certificate was verified
+#[derive(Clone)]
+pub(crate) struct Connecting {
+    host: Arc<String>,
+    unmatched: Arc<AtomicBool>,
+    certificate_verified: Arc<AtomicBool>,
+}
+
+impl Connecting {
+    pub(crate) fn new(host: &str) -> Self {
+        Self {
+            host: Arc::new(host.to_string()),
+            unmatched: Default::default(),
+            certificate_verified: Default::default(),
+        }
+    }
+
+    /// Runs the TLS handshake of the connection
+    pub(crate) fn scope<F: Future>(&self, handshake: F) -> impl Future<Output = F::Output> {
+        CONNECTING.scope(self.clone(), handshake)
+    }
+
+    /// Records that the subgraph certificate of the connection being established was verified
+    pub(crate) fn certificate_verified() {
+        let _ = CONNECTING.try_with(|connecting| {
+            connecting
+                .certificate_verified
+                .store(true, Ordering::Relaxed)
+        });
+    }
+
+    /// Whether the TLS handshake resumed a previous session: the subgraph certificate is only
+    /// verified in full handshakes
+    pub(crate) fn resumed(&self) -> bool {
+        !self.certificate_verified.load(Ordering::Relaxed)
+    }
+
+    /// Fails when the subgraph requested a client certificate, but none is configured for the
+    /// host
+    pub(crate) fn check(&self, subgraph: &str) -> Result<(), BoxError> {
+        if self.unmatched.load(Ordering::Relaxed) {
+            return Err(format!(
+                "no client certificate is configured for host '{}' of subgraph '{subgraph}'",
+                self.host
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the client certificate of a subgraph from the host of the connection, with the
+/// certificate of `client_authentication` as the default
+///
+/// rustls does not give the server name to the resolvers, so the host is set by the connector
+/// while the TLS handshake runs. The handshakes of HTTP/3 connections run in the QUIC endpoint,
+/// they use the default certificate.
+pub(crate) struct HostClientCert {
+    identities: Vec<(TlsClientIdentity, Arc<dyn ResolvesClientCert>)>,
+    default: Option<Arc<dyn ResolvesClientCert>>,
+}
+
+impl HostClientCert {
+    /// Must be called from a tokio runtime, the files are watched by spawned tasks
+    pub(crate) fn new(
+        subgraph: &str,
+        identities: &[TlsClientIdentity],
+        default: Option<&TlsClientAuth>,
+    ) -> Result<Self, BoxError> {
+        Ok(Self {
+            identities: identities
+                .iter()
+                .map(|identity| {
+                    Ok((
+                        identity.clone(),
+                        resolver(subgraph, &identity.client_authentication)?,
+                    ))
+                })
+                .collect::<Result<_, BoxError>>()?,
+            default: default
+                .map(|client_auth| resolver(subgraph, client_auth))
+                .transpose()?,
+        })
+    }
+}
+
+impl ResolvesClientCert for HostClientCert {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        let connecting = CONNECTING.try_with(Connecting::clone).ok();
+        let resolver = connecting
+            .as_ref()
+            .and_then(|connecting| {
+                self.identities
+                    .iter()
+                    .find(|(identity, _)| identity.matches(&connecting.host))
+            })
+            .map(|(_, resolver)| resolver)
+            .or(self.default.as_ref());
+        match resolver {
+            Some(resolver) => resolver.resolve(acceptable_issuers, sigschemes),
+            None => {
+                if let Some(connecting) = connecting {
+                    connecting.unmatched.store(true, Ordering::Relaxed);
+                }
+                None
+            }
+        }
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Client certificate configured inline
+struct StaticClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for StaticClientCert {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Resolver of a client certificate, failing with the name of the subgraph when the certificate
+/// or the key cannot be used
+pub(crate) fn resolver(
+    subgraph: &str,
+    client_auth: &TlsClientAuth,
+) -> Result<Arc<dyn ResolvesClientCert>, BoxError> {
+    let resolver: Result<Arc<dyn ResolvesClientCert>, BoxError> = match &client_auth.files {
+        Some(files) => {
+            ReloadingClientCert::new(subgraph, files).map(|resolver| Arc::new(resolver) as _)
+        }
+        None => certified_key(client_auth.certificate_chain.clone(), &client_auth.key)
+            .map(|certified_key| Arc::new(StaticClientCert(Arc::new(certified_key))) as _),
+    };
+    resolver
+        .map_err(|e| format!("invalid client certificate for subgraph '{subgraph}': {e}").into())
+}
+
+fn certified_key(
+    certificate_chain: Vec<Certificate>,
+    key: &PrivateKey,
+) -> Result<CertifiedKey, BoxError> {
+    let key = rustls::sign::any_supported_type(key)
+        .map_err(|_| "the client key uses an unsupported algorithm")?;
+    Ok(CertifiedKey::new(certificate_chain, key))
+}
+
+async fn reload(
+    subgraph: String,
+    files: TlsClientAuthFiles,
+    certified_key: Arc<ArcSwap<CertifiedKey>>,
+) {
+    // the watch streams start with an event for the initial read, but the files were already
+    // loaded when the resolver was created
+    let mut changes = stream::select(
+        watch(&files.certificate_chain).skip(1),
+        watch(&files.key).skip(1),
+    );
+    while changes.next().await.is_some() {
+        let reloaded = files
+            .load()
+            .map_err(BoxError::from)
+            .and_then(|(certificate_chain, key)| certified_key(certificate_chain, &key));
+        match reloaded {
+            Ok(reloaded) => {
+                certified_key.store(Arc::new(reloaded));
+                tracing::info!("reloaded the client certificate of subgraph '{subgraph}'");
+            }
+            // the previous certificate is kept, a later change of the files can fix it
+            Err(e) => tracing::error!(
+                "could not reload the client certificate of subgraph '{subgraph}': {e}"
+            ),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Connect timeout for subgraph connections
+
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use tower::BoxError;
+use tower::Service;
+
+/// Error returned when a subgraph connection is not established within the connect timeout
+#[derive(Debug, thiserror::Error)]
+#[error("connection to {uri} timed out after {timeout:?}")]
+pub(crate) struct ConnectTimeoutError {
+    uri: Uri,
+    timeout: Duration,
+}
+
+/// Wraps the HTTPS connector to limit the time spent establishing a connection
+///
+/// Unlike the connect timeout of hyper's connector, this covers the proxy tunnel and the TLS
+/// handshake as well as the TCP connection, so that an unreachable subgraph fails fast.
+#[derive(Clone)]
+pub(crate) struct ConnectTimeoutConnector<C> {
+    inner: C,
+    timeout: Duration,
+}
+
+impl<C> ConnectTimeoutConnector<C> {
+    pub(crate) fn new(inner: C, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<C> Service<Uri> for ConnectTimeoutConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let timeout = self.timeout;
+        let connecting = self.inner.call(uri.clone());
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, connecting).await {
+                Ok(connection) => connection.map_err(Into::into),
+                Err(_) => Err(ConnectTimeoutError { uri, timeout }.into()),
+            }
+        })
+    }
+}
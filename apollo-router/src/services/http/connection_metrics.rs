@@ -0,0 +1,216 @@
+//! Metrics on the reuse of subgraph connections
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper::client::connect::Connected;
+use hyper::client::connect::Connection;
+use opentelemetry_api::metrics::MeterProvider as _;
+use opentelemetry_api::metrics::ObservableGauge;
+use opentelemetry_api::KeyValue;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tower::BoxError;
+use tower::Service;
+
+use crate::metrics::meter_provider;
+
+#[derive(Default)]
+struct Counts {
+    open: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+/// Connections of the pool of a subgraph
+///
+/// hyper does not expose the state of its pool, so the connections are counted by the connector
+/// and the requests by the client service. A connection is idle when it is open without a
+/// request in flight, which is estimated as the difference of both counts.
+pub(crate) struct ConnectionMetrics {
+    subgraph_name: String,
+    counts: Arc<Counts>,
+    _idle_gauge: ObservableGauge<u64>,
+}
+
+impl ConnectionMetrics {
+    pub(crate) fn new(subgraph_name: &str) -> Self {
+        let counts = Arc::new(Counts::default());
+        let counts_for_gauge = counts.clone();
+        let subgraph_name_for_gauge = subgraph_name.to_string();
+        let idle_gauge = meter_provider()
+            .meter("apollo/router")
+            .u64_observable_gauge("apollo.router.subgraph.connections.idle")
+            .with_description("Number of idle connections in the pool of a subgraph")
+            .with_callback(move |observer| {
+                let open = counts_for_gauge.open.load(Ordering::Relaxed);
+                let in_flight = counts_for_gauge.in_flight.load(Ordering::Relaxed);
+                observer.observe(
+                    open.saturating_sub(in_flight),
+                    &[KeyValue::new(
+                        "subgraph.name",
+                        subgraph_name_for_gauge.clone(),
+                    )],
+                )
+            })
+            .init();
+
+        Self {
+            subgraph_name: subgraph_name.to_string(),
+            counts,
+            _idle_gauge: idle_gauge,
+        }
+    }
+
+    /// Counts a request in flight until the guard is dropped
+    pub(crate) fn request(&self) -> InFlight {
+        self.counts.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            counts: self.counts.clone(),
+        }
+    }
+
+    /// Records whether the response was received on a connection used by a previous request
+    pub(crate) fn record_response(&self, extensions: &http::Extensions) {
+        if let Some(ConnectionUses(uses)) = extensions.get::<ConnectionUses>() {
+            if uses.fetch_add(1, Ordering::Relaxed) > 0 {
+                u64_counter!(
+                    "apollo.router.subgraph.connections.reused",
+                    "Number of subgraph requests sent on a connection from the pool",
+                    1,
+                    "subgraph.name" = self.subgraph_name.clone()
+                );
+            }
+        }
+    }
+}
+
+/// A request in flight to a subgraph
+pub(crate) struct InFlight {
+    counts: Arc<Counts>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.counts.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Number of responses received on a connection, added by hyper to the extensions of each
+/// response
+#[derive(Clone)]
+struct ConnectionUses(Arc<AtomicU64>);
+
+/// Wraps the connector of a subgraph to count the connections it opens
+#[derive(Clone)]
+pub(crate) struct ConnectionMetricsConnector<C> {
+    inner: C,
+    metrics: Arc<ConnectionMetrics>,
+}
+
+impl<C> ConnectionMetricsConnector<C> {
+    pub(crate) fn new(inner: C, metrics: Arc<ConnectionMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<C> Service<Uri> for ConnectionMetricsConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = MeteredStream<C::Response>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let stream = connecting.await.map_err(Into::into)?;
+            u64_counter!(
+                "apollo.router.subgraph.connections.created",
+                "Number of connections opened to a subgraph",
+                1,
+                "subgraph.name" = metrics.subgraph_name.clone()
+            );
+            metrics.counts.open.fetch_add(1, Ordering::Relaxed);
+            Ok(MeteredStream {
+                stream,
+                counts: metrics.counts.clone(),
+                uses: ConnectionUses(Arc::new(AtomicU64::new(0))),
+            })
+        })
+    }
+}
+
+/// Connection to a subgraph, counted as open until it is dropped
+pub(crate) struct MeteredStream<S> {
+    stream: S,
+    counts: Arc<Counts>,
+    uses: ConnectionUses,
+}
+
+impl<S> Drop for MeteredStream<S> {
+    fn drop(&mut self) {
+        self.counts.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: Connection> Connection for MeteredStream<S> {
+    fn connected(&self) -> Connected {
+        self.stream.connected().extra(self.uses.clone())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
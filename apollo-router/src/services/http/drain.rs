@@ -0,0 +1,88 @@
+//! Draining of the HTTP client of a subgraph once a configuration reload replaced it
+
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Drain state shared by the clones of the HTTP client of a subgraph
+///
+/// Once draining, the client keeps serving the replaced router: the operations it admitted
+/// before the reload still send their next fetches, like the second step of a query plan or the
+/// deferred fetches. The requests of the client, in flight or sent later, get `timeout` from the
+/// start of the drain to receive their response. The connection pool is dropped with the last
+/// clone of the client, once the replaced router is dropped.
+#[derive(Debug)]
+pub(crate) struct Drain {
+    timeout: Duration,
+    /// deadline of the requests in flight, set once draining
+    deadline: watch::Sender<Option<Instant>>,
+}
+
+impl Drain {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            deadline: watch::channel(None).0,
+        }
+    }
+
+    /// Starts the drain timeout, the requests fail once it elapses
+    pub(crate) fn start(&self) {
+        let timeout = self.timeout;
+        self.deadline.send_if_modified(|deadline| {
+            if deadline.is_some() {
+                return false;
+            }
+            *deadline = Some(Instant::now() + timeout);
+            true
+        });
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_draining(&self) -> bool {
+        self.deadline.borrow().is_some()
+    }
+
+    /// Resolves once draining and the drain timeout elapsed
+    pub(crate) fn expired(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut deadline = self.deadline.subscribe();
+        async move {
+            if deadline.wait_for(Option::is_some).await.is_err() {
+                // the client is dropped, it cannot drain anymore
+                return std::future::pending().await;
+            }
+            let deadline = deadline.borrow().expect("the deadline is set");
+            tokio::time::sleep_until(deadline).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn it_expires_after_the_timeout_once_draining() {
+        let drain = Drain::new(Duration::from_secs(5));
+        let expired = drain.expired();
+        tokio::pin!(expired);
+        assert!(!drain.is_draining());
+        assert!(tokio::time::timeout(Duration::from_secs(60), &mut expired)
+            .await
+            .is_err());
+
+        drain.start();
+        assert!(drain.is_draining());
+        let start = Instant::now();
+        expired.await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        // starting again does not push the deadline back
+        drain.start();
+        let start = Instant::now();
+        drain.expired().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
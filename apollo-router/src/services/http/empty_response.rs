@@ -0,0 +1,70 @@
+//! Handling of the successful subgraph responses with an empty body
+
+use bytes::Bytes;
+use futures::stream;
+use futures::StreamExt;
+use http::header::CONTENT_LENGTH;
+use http::header::CONTENT_TYPE;
+use http::HeaderValue;
+use hyper::body::HttpBody;
+use hyper::Body;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::error::FetchError;
+
+const NULL_DATA: &str = r#"{"data":null}"#;
+
+/// Handling of the 2xx subgraph responses with an empty body
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EmptyResponse {
+    /// Answer with a GraphQL response whose data is `null`
+    NullData,
+    /// Fail the request with a `SUBREQUEST_MALFORMED_RESPONSE` error naming the subgraph
+    Error,
+}
+
+impl EmptyResponse {
+    /// Applies the handling to a 2xx response with an empty body, other responses are returned
+    /// as they are
+    ///
+    /// The body is streamed, so its first non-empty chunk is read to know whether it is empty,
+    /// and put back in front of the rest of the body.
+    pub(crate) async fn apply(
+        self,
+        service_name: &str,
+        response: http::Response<Body>,
+    ) -> Result<http::Response<Body>, FetchError> {
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        let (mut parts, mut body) = response.into_parts();
+        let first = loop {
+            match body.data().await {
+                Some(Ok(chunk)) if chunk.is_empty() => continue,
+                first => break first,
+            }
+        };
+        let body = match first {
+            // errors reading the body are left to the parsing of the response
+            Some(first) => Body::wrap_stream(stream::once(async move { first }).chain(body)),
+            None => match self {
+                EmptyResponse::NullData => {
+                    parts
+                        .headers
+                        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    parts.headers.insert(CONTENT_LENGTH, NULL_DATA.len().into());
+                    Body::from(Bytes::from_static(NULL_DATA.as_bytes()))
+                }
+                EmptyResponse::Error => {
+                    return Err(FetchError::SubrequestMalformedResponse {
+                        service: service_name.to_string(),
+                        reason: format!("{} response with an empty body", parts.status.as_str()),
+                    })
+                }
+            },
+        };
+        Ok(http::Response::from_parts(parts, body))
+    }
+}
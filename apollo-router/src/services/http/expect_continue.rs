@@ -0,0 +1,262 @@
+//! `Expect: 100-continue` flow for the large request bodies sent to subgraphs
+
+use std::io;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use http::header::CONTENT_LENGTH;
+use http::header::HOST;
+use http::HeaderValue;
+use http::Request;
+use http::Response;
+use http::Uri;
+use http::Version;
+use hyper::body::HttpBody;
+use hyper::client::connect::Connection;
+use hyper::Body;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::sync::oneshot;
+use tower::BoxError;
+use tower::Service;
+
+/// Start of the status line of a response, up to the status code
+const STATUS_LINE_LENGTH: usize = "HTTP/1.1 100".len();
+
+/// Added to the extensions of the requests sent with `Expect: 100-continue`, with the time to
+/// wait for the 100 (Continue) response before sending the body anyway
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ExpectContinue {
+    pub(crate) timeout: Duration,
+}
+
+/// First response received on the connection
+#[derive(Debug, PartialEq)]
+enum Interim {
+    Continue,
+    Final,
+}
+
+/// Sends a request on a new connection, holding back its body until the subgraph answers with
+/// 100 (Continue)
+///
+/// hyper skips the informational responses, so they are detected in the bytes read from the
+/// connection. The body is sent once the 100 response is received or the timeout elapses, for
+/// the servers which ignore the `Expect` header. If the subgraph sends its final response first,
+/// like a 401 or a 413, the body is not sent and the connection is closed. HTTP/2 connections
+/// send the request right away, as the body is flow controlled by the subgraph.
+pub(crate) async fn send<C>(
+    mut connector: C,
+    request: Request<Body>,
+) -> Result<Response<Body>, BoxError>
+where
+    C: Service<Uri>,
+    C::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+    C::Future: Send,
+    C::Error: Into<BoxError>,
+{
+    let timeout = request
+        .extensions()
+        .get::<ExpectContinue>()
+        .map(|expect| expect.timeout)
+        .unwrap_or_default();
+    futures::future::poll_fn(|cx| connector.poll_ready(cx))
+        .await
+        .map_err(Into::into)?;
+    let io = connector
+        .call(request.uri().clone())
+        .await
+        .map_err(Into::into)?;
+
+    if io.connected().is_negotiated_h2() {
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(io)
+            .await?;
+        tokio::spawn(drive(connection));
+        return Ok(sender.send_request(request).await?);
+    }
+
+    let (interim_sender, interim) = oneshot::channel();
+    let io = StatusSniffer {
+        io,
+        status_line: Vec::with_capacity(STATUS_LINE_LENGTH),
+        interim: Some(interim_sender),
+    };
+    let (mut sender, connection) = hyper::client::conn::Builder::new().handshake(io).await?;
+    tokio::spawn(drive(connection));
+
+    let (mut parts, body) = request.into_parts();
+    // the request is sent on its own connection, in origin form
+    if !parts.headers.contains_key(HOST) {
+        if let Some(host) = host(&parts.uri) {
+            parts.headers.insert(HOST, host);
+        }
+    }
+    if let Some(path_and_query) = parts.uri.path_and_query() {
+        parts.uri = Uri::from(path_and_query.clone());
+    }
+    parts.version = Version::HTTP_11;
+    // the size of the held back body is lost, it would be sent chunked otherwise
+    if let Some(size) = body.size_hint().exact() {
+        parts.headers.entry(CONTENT_LENGTH).or_insert(size.into());
+    }
+
+    let (body_sender, held_back) = Body::channel();
+    tokio::spawn(async move {
+        match tokio::time::timeout(timeout, interim).await {
+            Ok(Ok(Interim::Final)) | Ok(Err(_)) => body_sender.abort(),
+            Ok(Ok(Interim::Continue)) | Err(_) => forward(body, body_sender).await,
+        }
+    });
+    Ok(sender
+        .send_request(Request::from_parts(parts, held_back))
+        .await?)
+}
+
+async fn drive<F>(connection: F)
+where
+    F: std::future::Future<Output = Result<(), hyper::Error>>,
+{
+    if let Err(err) = connection.await {
+        tracing::debug!("expect/continue connection to subgraph closed: {err}");
+    }
+}
+
+fn host(uri: &Uri) -> Option<HeaderValue> {
+    let authority = uri.authority()?;
+    let host = match (uri.scheme_str(), authority.port_u16()) {
+        (Some("http"), Some(80)) | (Some("https"), Some(443)) => authority.host(),
+        _ => authority.as_str(),
+    };
+    HeaderValue::from_str(host).ok()
+}
+
+async fn forward(mut body: Body, mut sender: hyper::body::Sender) {
+    while let Some(data) = body.data().await {
+        match data {
+            Ok(data) => {
+                if sender.send_data(data).await.is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                sender.abort();
+                return;
+            }
+        }
+    }
+    if let Ok(Some(trailers)) = body.trailers().await {
+        let _ = sender.send_trailers(trailers).await;
+    }
+}
+
+/// Connection reporting whether its first response is 100 (Continue) or a final response
+struct StatusSniffer<S> {
+    io: S,
+    status_line: Vec<u8>,
+    interim: Option<oneshot::Sender<Interim>>,
+}
+
+impl<S> StatusSniffer<S> {
+    fn inspect(&mut self, read: &[u8]) {
+        if self.interim.is_none() {
+            return;
+        }
+        let missing = STATUS_LINE_LENGTH - self.status_line.len();
+        self.status_line
+            .extend_from_slice(&read[..missing.min(read.len())]);
+        if self.status_line.len() < STATUS_LINE_LENGTH {
+            return;
+        }
+        let interim = match &self.status_line[9..] {
+            b"100" => Some(Interim::Continue),
+            // other informational responses are skipped by hyper, the timeout applies
+            [b'1', ..] => None,
+            _ => Some(Interim::Final),
+        };
+        if let (Some(sender), Some(interim)) = (self.interim.take(), interim) {
+            let _ = sender.send(interim);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for StatusSniffer<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.io).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.inspect(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StatusSniffer<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sniff(reads: &[&[u8]]) -> Option<Interim> {
+        let (sender, mut interim) = oneshot::channel();
+        let mut sniffer = StatusSniffer {
+            io: (),
+            status_line: Vec::new(),
+            interim: Some(sender),
+        };
+        for read in reads {
+            sniffer.inspect(read);
+        }
+        interim.try_recv().ok()
+    }
+
+    #[test]
+    fn it_detects_the_first_response() {
+        assert_eq!(
+            sniff(&[b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"]),
+            Some(Interim::Continue)
+        );
+        assert_eq!(
+            sniff(&[b"HTTP/1", b".1 4", b"13 Payload Too Large\r\n"]),
+            Some(Interim::Final)
+        );
+        assert_eq!(sniff(&[b"HTTP/1.1 103 Early Hints\r\n"]), None);
+        assert_eq!(sniff(&[b"HTTP/1.1 10"]), None);
+    }
+}
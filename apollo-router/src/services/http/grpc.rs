@@ -0,0 +1,192 @@
+//! Unary gRPC calls to subgraphs
+//!
+//! The GraphQL request and response are sent as the JSON messages of a unary call, framed as
+//! described in <https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md>.
+
+use bytes::Buf;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use http::header::CONTENT_ENCODING;
+use http::header::CONTENT_LENGTH;
+use http::header::CONTENT_TYPE;
+use http::header::TE;
+use http::response::Parts;
+use http::uri::PathAndQuery;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::Request;
+use http::Response;
+use hyper::body::HttpBody;
+use hyper::Body;
+use tower::BoxError;
+
+use crate::error::FetchError;
+use crate::services::subgraph_service::APPLICATION_JSON_HEADER_VALUE;
+
+const GRPC_CONTENT_TYPE: &str = "application/grpc+json";
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+// compressed flag and message length
+const PREFIX_LENGTH: usize = 5;
+
+/// Method of a subgraph called with the GraphQL requests
+pub(crate) struct GrpcTransport {
+    path: PathAndQuery,
+}
+
+impl GrpcTransport {
+    pub(crate) fn new(service: &str, method: &str) -> Result<Self, BoxError> {
+        Ok(Self {
+            path: PathAndQuery::try_from(format!("/{service}/{method}"))?,
+        })
+    }
+
+    /// Turns the GraphQL request into the request of a unary call
+    pub(crate) async fn encode_request(
+        &self,
+        request: Request<Body>,
+        service_name: &str,
+    ) -> Result<Request<Body>, FetchError> {
+        let (mut parts, body) = request.into_parts();
+        let message =
+            hyper::body::to_bytes(body)
+                .await
+                .map_err(|err| FetchError::SubrequestHttpError {
+                    status_code: None,
+                    service: service_name.to_string(),
+                    reason: format!("cannot read the gRPC request message: {err}"),
+                })?;
+
+        let mut uri = parts.uri.into_parts();
+        uri.path_and_query = Some(self.path.clone());
+        parts.uri = http::Uri::from_parts(uri).map_err(|err| FetchError::SubrequestHttpError {
+            status_code: None,
+            service: service_name.to_string(),
+            reason: format!("invalid gRPC request URI: {err}"),
+        })?;
+        parts.method = http::Method::POST;
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.remove(CONTENT_ENCODING);
+        parts
+            .headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static(GRPC_CONTENT_TYPE));
+        parts
+            .headers
+            .insert(TE, HeaderValue::from_static("trailers"));
+
+        let mut frame = BytesMut::with_capacity(PREFIX_LENGTH + message.len());
+        frame.put_u8(0);
+        frame.put_u32(message.len() as u32);
+        frame.put(message);
+        Ok(Request::from_parts(parts, Body::from(frame.freeze())))
+    }
+}
+
+/// Reads the response of a unary call, and returns its message as a GraphQL response, or an
+/// error if the call did not succeed
+pub(crate) async fn decode_response<B>(
+    service_name: &str,
+    mut parts: Parts,
+    body: B,
+) -> Result<Response<Body>, FetchError>
+where
+    B: HttpBody,
+    B::Error: Into<BoxError>,
+{
+    let error = |reason: String| FetchError::SubrequestHttpError {
+        status_code: Some(parts.status.as_u16()),
+        service: service_name.to_string(),
+        reason,
+    };
+    if !parts.status.is_success() {
+        return Err(error(format!(
+            "gRPC call failed with HTTP status {}",
+            parts.status
+        )));
+    }
+
+    let mut body = Box::pin(body);
+    let mut frames = BytesMut::new();
+    while let Some(data) = body.data().await {
+        let data = data
+            .map_err(Into::into)
+            .map_err(|err: BoxError| error(format!("cannot read the gRPC response: {err}")))?;
+        frames.put(data);
+    }
+    let trailers = body
+        .trailers()
+        .await
+        .map_err(Into::into)
+        .map_err(|err: BoxError| error(format!("cannot read the gRPC trailers: {err}")))?;
+
+    // responses without a message can carry the status in their headers
+    let status = trailers
+        .as_ref()
+        .and_then(|trailers| trailers.get(GRPC_STATUS).map(|status| (status, trailers)))
+        .or_else(|| {
+            parts
+                .headers
+                .get(GRPC_STATUS)
+                .map(|status| (status, &parts.headers))
+        });
+    let Some((status, metadata)) = status else {
+        return Err(error("gRPC response without a grpc-status".to_string()));
+    };
+    if status != "0" {
+        return Err(error(format!(
+            "gRPC call failed with status {}: {}",
+            String::from_utf8_lossy(status.as_bytes()),
+            grpc_message(metadata)
+        )));
+    }
+
+    let message = single_message(frames.freeze()).map_err(error)?;
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE.clone());
+    Ok(Response::from_parts(parts, Body::from(message)))
+}
+
+fn single_message(mut frames: Bytes) -> Result<Bytes, String> {
+    if frames.len() < PREFIX_LENGTH {
+        return Err("gRPC response without a message".to_string());
+    }
+    let compressed = frames.get_u8();
+    let length = frames.get_u32() as usize;
+    if compressed != 0 {
+        return Err("compressed gRPC messages are not supported".to_string());
+    }
+    if frames.len() != length {
+        return Err("a unary gRPC response must contain a single message".to_string());
+    }
+    Ok(frames)
+}
+
+/// Percent-decoded `grpc-message`
+fn grpc_message(metadata: &HeaderMap) -> String {
+    let Some(message) = metadata.get(GRPC_MESSAGE) else {
+        return String::new();
+    };
+    let mut bytes = message.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    while let Some((&byte, rest)) = bytes.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| rest.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(escaped) => {
+                decoded.push(escaped);
+                bytes = &rest[2..];
+            }
+            None => {
+                decoded.push(byte);
+                bytes = rest;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,130 @@
+//! Limits of the size and number of the headers of subgraph responses
+
+use std::error::Error;
+
+use http::HeaderMap;
+use http::StatusCode;
+
+use crate::error::FetchError;
+
+/// Read buffer size of hyper HTTP1 connections, which also bounds the size of the response head
+const HYPER_DEFAULT_MAX_BUF_SIZE: usize = 8192 + 4096 * 100;
+
+/// Limits checked on the headers of each subgraph response, once they are received
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct HeaderLimits {
+    /// total size of the header names and values, in bytes
+    pub(crate) max_size: Option<usize>,
+    pub(crate) max_count: Option<usize>,
+}
+
+impl HeaderLimits {
+    /// Read buffer size of HTTP1 connections large enough for the maximum header size, `None`
+    /// when the hyper default fits
+    pub(crate) fn http1_max_buf_size(&self) -> Option<usize> {
+        // room for the status line, and for the start of the body read with the headers
+        self.max_size
+            .map(|max_size| max_size.saturating_add(8192))
+            .filter(|size| *size > HYPER_DEFAULT_MAX_BUF_SIZE)
+    }
+
+    pub(crate) fn check(
+        &self,
+        service: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Result<(), FetchError> {
+        let error = |reason: String| FetchError::SubrequestHttpError {
+            status_code: Some(status.as_u16()),
+            service: service.to_string(),
+            reason,
+        };
+        if let Some(max_count) = self.max_count {
+            let count = headers.len();
+            if count > max_count {
+                return Err(error(format!(
+                    "the response has {count} headers, more than the `max_response_headers` limit of {max_count}"
+                )));
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            let size: usize = headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum();
+            if size > max_size {
+                return Err(error(format!(
+                    "the response headers are {size} bytes, more than the `max_response_header_size` limit of {max_size} bytes"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the HTTP client refused a response because its head was too large, or had
+/// too many headers
+pub(crate) fn is_head_too_large(error: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(|error| error.is_parse_too_large())
+        {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Reason of the failure of a request whose response head was refused by the HTTP client
+pub(crate) const HEAD_TOO_LARGE: &str = "the response headers are larger than the HTTP client accepts, or there are more than 100 of them: set a larger `max_response_header_size` if they are expected";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checks_the_response_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        headers.insert("x-version", "12".parse().unwrap());
+
+        let limits = |max_size, max_count| HeaderLimits {
+            max_size,
+            max_count,
+        };
+        let check = |limits: HeaderLimits| {
+            limits
+                .check("products", StatusCode::OK, &headers)
+                .map_err(|error| error.to_string())
+        };
+        // 13 + 13 + 11 bytes
+        assert!(check(limits(Some(37), Some(3))).is_ok());
+        assert!(check(HeaderLimits::default()).is_ok());
+        assert_eq!(
+            check(limits(Some(36), None)).unwrap_err(),
+            "HTTP fetch failed from 'products': the response headers are 37 bytes, more than the `max_response_header_size` limit of 36 bytes"
+        );
+        assert_eq!(
+            check(limits(None, Some(2))).unwrap_err(),
+            "HTTP fetch failed from 'products': the response has 3 headers, more than the `max_response_headers` limit of 2"
+        );
+    }
+
+    #[test]
+    fn it_raises_the_http1_buffer_size_for_large_headers() {
+        let limits = |max_size| HeaderLimits {
+            max_size: Some(max_size),
+            max_count: None,
+        };
+        assert_eq!(limits(16 * 1024).http1_max_buf_size(), None);
+        assert_eq!(
+            limits(1024 * 1024).http1_max_buf_size(),
+            Some(1024 * 1024 + 8192)
+        );
+        assert_eq!(HeaderLimits::default().http1_max_buf_size(), None);
+    }
+}
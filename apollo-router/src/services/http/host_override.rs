@@ -0,0 +1,55 @@
+//! Static host overrides for subgraph connections
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use http::Uri;
+use tower::BoxError;
+
+/// Addresses connected to instead of resolving the host of subgraph URLs
+///
+/// Only the address of the connection changes: the original host is still used in the `Host`
+/// header, for SNI and to verify the subgraph certificate.
+#[derive(Debug, Default)]
+pub(crate) struct HostOverrides(HashMap<String, SocketAddr>);
+
+impl HostOverrides {
+    pub(crate) fn new(overrides: &HashMap<String, SocketAddr>) -> Self {
+        Self(
+            overrides
+                .iter()
+                .map(|(host, addr)| (normalize(host), *addr))
+                .collect(),
+        )
+    }
+
+    /// Address overriding this host, if any
+    pub(crate) fn get(&self, host: &str) -> Option<SocketAddr> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.0.get(&normalize(host)).copied()
+    }
+
+    /// URI of the address overriding the host of this URI, to connect to it directly
+    pub(crate) fn rewrite(&self, uri: &Uri) -> Result<Option<Uri>, BoxError> {
+        let Some(addr) = uri.host().and_then(|host| self.get(host)) else {
+            return Ok(None);
+        };
+        Ok(Some(
+            Uri::builder()
+                .scheme("http")
+                .authority(addr.to_string())
+                .path_and_query("/")
+                .build()?,
+        ))
+    }
+}
+
+// hosts are case insensitive, and IPv6 hosts are bracketed in URIs
+fn normalize(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
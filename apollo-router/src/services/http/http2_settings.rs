@@ -0,0 +1,381 @@
+//! Debug logging of the HTTP/2 settings sent by the subgraphs
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper::client::connect::Connected;
+use hyper::client::connect::Connection;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tower::BoxError;
+use tower::Service;
+
+/// Connection preface sent by HTTP/2 clients, before their first frame
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_SIZE: usize = 9;
+const FRAME_TYPE_SETTINGS: u8 = 0x4;
+const FLAG_ACK: u8 = 0x1;
+/// Larger SETTINGS frames are not logged, there are 7 known settings of 6 bytes
+const MAX_SETTINGS_SIZE: usize = 16 * 6;
+
+/// Settings of the first SETTINGS frame sent by a subgraph on a connection, the ones it does not
+/// send keep their default value
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Http2Settings {
+    header_table_size: Option<u32>,
+    enable_push: Option<u32>,
+    max_concurrent_streams: Option<u32>,
+    initial_window_size: Option<u32>,
+    max_frame_size: Option<u32>,
+    max_header_list_size: Option<u32>,
+    enable_connect_protocol: Option<u32>,
+}
+
+impl Http2Settings {
+    fn parse(payload: &[u8]) -> Self {
+        let mut settings = Self::default();
+        for setting in payload.chunks_exact(6) {
+            let value = Some(u32::from_be_bytes([
+                setting[2], setting[3], setting[4], setting[5],
+            ]));
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                0x1 => settings.header_table_size = value,
+                0x2 => settings.enable_push = value,
+                0x3 => settings.max_concurrent_streams = value,
+                0x4 => settings.initial_window_size = value,
+                0x5 => settings.max_frame_size = value,
+                0x6 => settings.max_header_list_size = value,
+                0x8 => settings.enable_connect_protocol = value,
+                // unknown settings must be ignored
+                _ => {}
+            }
+        }
+        settings
+    }
+}
+
+impl fmt::Display for Http2Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let settings = [
+            ("header_table_size", self.header_table_size),
+            ("enable_push", self.enable_push),
+            ("max_concurrent_streams", self.max_concurrent_streams),
+            ("initial_window_size", self.initial_window_size),
+            ("max_frame_size", self.max_frame_size),
+            ("max_header_list_size", self.max_header_list_size),
+            ("enable_connect_protocol", self.enable_connect_protocol),
+        ];
+        let mut sent = settings
+            .iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value)))
+            .peekable();
+        if sent.peek().is_none() {
+            return write!(f, "defaults");
+        }
+        for (index, (name, value)) in sent.enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the settings of the subgraph in the bytes exchanged on a connection
+///
+/// hyper does not expose the HTTP/2 connections, so the settings are read from the stream: the
+/// connection uses HTTP/2 if the router starts it with the HTTP/2 preface, with ALPN or with
+/// prior knowledge, and the first frame sent by the subgraph must then be its SETTINGS frame.
+struct SettingsSniffer {
+    /// bytes of the preface written so far, `None` once the connection is known to use HTTP/2
+    preface: Option<usize>,
+    received: Vec<u8>,
+    done: bool,
+}
+
+impl SettingsSniffer {
+    fn new() -> Self {
+        Self {
+            preface: Some(0),
+            received: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn written(&mut self, data: &[u8]) -> Option<Http2Settings> {
+        let matched = self.preface.filter(|_| !self.done)?;
+        let expected = &PREFACE[matched..];
+        let compared = expected.len().min(data.len());
+        if data[..compared] != expected[..compared] {
+            self.finish();
+            return None;
+        }
+        if compared < expected.len() {
+            self.preface = Some(matched + compared);
+            return None;
+        }
+        self.preface = None;
+        self.settings()
+    }
+
+    fn read(&mut self, data: &[u8]) -> Option<Http2Settings> {
+        if self.done {
+            return None;
+        }
+        // the subgraph may send its settings before the whole preface is written
+        let missing = (FRAME_HEADER_SIZE + MAX_SETTINGS_SIZE).saturating_sub(self.received.len());
+        self.received
+            .extend_from_slice(&data[..missing.min(data.len())]);
+        if self.preface.is_some() {
+            return None;
+        }
+        self.settings()
+    }
+
+    fn settings(&mut self) -> Option<Http2Settings> {
+        let header = self.received.get(..FRAME_HEADER_SIZE)?;
+        let size = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream_id =
+            u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & !(1 << 31);
+        if header[3] != FRAME_TYPE_SETTINGS
+            || header[4] & FLAG_ACK != 0
+            || stream_id != 0
+            || size > MAX_SETTINGS_SIZE
+        {
+            self.finish();
+            return None;
+        }
+        let payload = self
+            .received
+            .get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + size)?;
+        let settings = Http2Settings::parse(payload);
+        self.finish();
+        Some(settings)
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.received = Vec::new();
+    }
+}
+
+/// Wraps the connector of a subgraph to log the HTTP/2 settings of the subgraph on each new
+/// connection, at the debug level
+#[derive(Clone)]
+pub(crate) struct Http2SettingsConnector<C> {
+    inner: C,
+    /// name of the subgraph, only set when the logging is enabled
+    service: Option<Arc<String>>,
+}
+
+impl<C> Http2SettingsConnector<C> {
+    pub(crate) fn new(inner: C, service: &str, enabled: bool) -> Self {
+        Self {
+            inner,
+            service: enabled.then(|| Arc::new(service.to_string())),
+        }
+    }
+}
+
+impl<C> Service<Uri> for Http2SettingsConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = Http2SettingsStream<C::Response>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let service = self.service.clone();
+        let host = uri.authority().map(|authority| authority.to_string());
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            let stream = connecting.await.map_err(Into::into)?;
+            Ok(Http2SettingsStream {
+                stream,
+                log: service.map(|service| SettingsLog {
+                    service,
+                    host: host.unwrap_or_default(),
+                    sniffer: SettingsSniffer::new(),
+                }),
+            })
+        })
+    }
+}
+
+struct SettingsLog {
+    service: Arc<String>,
+    host: String,
+    sniffer: SettingsSniffer,
+}
+
+impl SettingsLog {
+    fn log(&self, settings: Option<Http2Settings>) {
+        if let Some(settings) = settings {
+            let service_name = &self.service;
+            tracing::debug!(
+                http2.settings = %settings,
+                server.address = %self.host,
+                apollo.subgraph.name = %service_name,
+                "HTTP/2 settings of subgraph {service_name:?}: {settings}"
+            );
+        }
+    }
+}
+
+/// Connection to a subgraph, read and written unchanged
+pub(crate) struct Http2SettingsStream<S> {
+    stream: S,
+    log: Option<SettingsLog>,
+}
+
+impl<S: Connection> Connection for Http2SettingsStream<S> {
+    fn connected(&self) -> Connected {
+        self.stream.connected()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Http2SettingsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(log)) = (&res, &mut this.log) {
+            let settings = log.sniffer.read(&buf.filled()[filled..]);
+            log.log(settings);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Http2SettingsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(log)) = (&res, &mut this.log) {
+            let settings = log.sniffer.written(&buf[..*written]);
+            log.log(settings);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.stream).poll_write_vectored(cx, bufs);
+        if let (Poll::Ready(Ok(written)), Some(log)) = (&res, &mut this.log) {
+            let mut remaining = *written;
+            for buf in bufs {
+                if remaining == 0 {
+                    break;
+                }
+                let data = &buf[..remaining.min(buf.len())];
+                remaining -= data.len();
+                let settings = log.sniffer.written(data);
+                log.log(settings);
+            }
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings_frame(flags: u8, settings: &[(u16, u32)]) -> Vec<u8> {
+        let size = (settings.len() * 6) as u32;
+        let mut frame = size.to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[FRAME_TYPE_SETTINGS, flags, 0, 0, 0, 0]);
+        for (id, value) in settings {
+            frame.extend_from_slice(&id.to_be_bytes());
+            frame.extend_from_slice(&value.to_be_bytes());
+        }
+        frame
+    }
+
+    #[test]
+    fn it_reads_the_settings_of_http2_connections() {
+        let frame = settings_frame(0, &[(0x3, 100), (0x4, 1 << 20), (0x9, 1)]);
+        let mut sniffer = SettingsSniffer::new();
+        assert_eq!(sniffer.written(&PREFACE[..10]), None);
+        // the subgraph sends its settings before the end of the preface
+        assert_eq!(sniffer.read(&frame[..12]), None);
+        assert_eq!(sniffer.written(&PREFACE[10..]), None);
+        let settings = sniffer.read(&frame[12..]).unwrap();
+        assert_eq!(
+            settings,
+            Http2Settings {
+                max_concurrent_streams: Some(100),
+                initial_window_size: Some(1 << 20),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            settings.to_string(),
+            "max_concurrent_streams=100, initial_window_size=1048576"
+        );
+        // only the first frame is read
+        assert_eq!(sniffer.read(&frame), None);
+        assert!(sniffer.received.is_empty());
+
+        let mut sniffer = SettingsSniffer::new();
+        sniffer.written(PREFACE);
+        assert_eq!(
+            sniffer.read(&settings_frame(0, &[])).unwrap().to_string(),
+            "defaults"
+        );
+    }
+
+    #[test]
+    fn it_ignores_the_other_connections() {
+        let frame = settings_frame(0, &[(0x3, 100)]);
+        let mut sniffer = SettingsSniffer::new();
+        assert_eq!(sniffer.written(b"POST / HTTP/1.1\r\n"), None);
+        assert_eq!(sniffer.read(&frame), None);
+        assert!(sniffer.received.is_empty());
+
+        // the first frame of the subgraph is not its settings
+        let mut sniffer = SettingsSniffer::new();
+        sniffer.written(PREFACE);
+        assert_eq!(sniffer.read(&settings_frame(FLAG_ACK, &[])), None);
+        assert_eq!(sniffer.read(&frame), None);
+    }
+}
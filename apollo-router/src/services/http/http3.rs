@@ -0,0 +1,231 @@
+//! HTTP/3 client for subgraph requests
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Buf;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::uri::Authority;
+use http::Request;
+use http::Response;
+use hyper::Body;
+use parking_lot::Mutex;
+use rustls::ClientConfig;
+use tower::BoxError;
+use tower::Service;
+
+use super::connect_timeout::ConnectTimeoutConnector;
+use super::connection_metrics::ConnectionMetricsConnector;
+use super::host_override::HostOverrides;
+use super::stream_limit::StreamLimitedClient;
+use super::tls_handshake::TlsHandshakeConnector;
+
+const ALPN_H3: &[u8] = b"h3";
+// how long we stop trying HTTP/3 for a subgraph after a failed connection, when we can fall back
+const BROKEN_DURATION: Duration = Duration::from_secs(300);
+
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+pub(crate) type FallbackClient =
+    StreamLimitedClient<ConnectionMetricsConnector<ConnectTimeoutConnector<TlsHandshakeConnector>>>;
+
+enum Entry {
+    Connected {
+        connection: quinn::Connection,
+        sender: SendRequest,
+    },
+    Broken(Instant),
+}
+
+/// Sends requests over QUIC, reusing one connection per subgraph authority.
+///
+/// If a fallback client is set, requests go through it when the HTTP/3 connection cannot be
+/// established.
+#[derive(Clone)]
+pub(crate) struct Http3Client {
+    endpoint: quinn::Endpoint,
+    connections: Arc<Mutex<HashMap<Authority, Entry>>>,
+    fallback: Option<FallbackClient>,
+    server_name: Option<Arc<String>>,
+    connect_timeout: Duration,
+    host_overrides: Arc<HostOverrides>,
+}
+
+impl Http3Client {
+    pub(crate) fn new(
+        mut tls_config: ClientConfig,
+        fallback: Option<FallbackClient>,
+        server_name: Option<String>,
+        connect_timeout: Duration,
+        host_overrides: Arc<HostOverrides>,
+    ) -> Result<Self, BoxError> {
+        tls_config.alpn_protocols = vec![ALPN_H3.to_vec()];
+
+        // prefer a dual stack socket, to reach both IPv4 and IPv6 subgraphs
+        let mut endpoint = quinn::Endpoint::client(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+            .or_else(|_| quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls_config)));
+
+        Ok(Self {
+            endpoint,
+            connections: Default::default(),
+            fallback,
+            server_name: server_name.map(Arc::new),
+            connect_timeout,
+            host_overrides,
+        })
+    }
+
+    async fn connection(&self, authority: &Authority) -> Result<SendRequest, BoxError> {
+        match self.connections.lock().get(authority) {
+            Some(Entry::Connected { connection, sender })
+                if connection.close_reason().is_none() =>
+            {
+                return Ok(sender.clone())
+            }
+            Some(Entry::Broken(until)) if Instant::now() < *until => {
+                return Err(format!("HTTP/3 is unavailable for {authority}").into())
+            }
+            _ => {}
+        }
+
+        match self.connect(authority).await {
+            Ok((connection, sender)) => {
+                self.connections.lock().insert(
+                    authority.clone(),
+                    Entry::Connected {
+                        connection,
+                        sender: sender.clone(),
+                    },
+                );
+                Ok(sender)
+            }
+            Err(err) => {
+                if self.fallback.is_some() {
+                    self.connections.lock().insert(
+                        authority.clone(),
+                        Entry::Broken(Instant::now() + BROKEN_DURATION),
+                    );
+                } else {
+                    self.connections.lock().remove(authority);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn connect(
+        &self,
+        authority: &Authority,
+    ) -> Result<(quinn::Connection, SendRequest), BoxError> {
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = authority.port_u16().unwrap_or(443);
+        let addr = match self.host_overrides.get(host) {
+            Some(addr) => addr,
+            None => {
+                let ipv6 = self.endpoint.local_addr()?.is_ipv6();
+                tokio::net::lookup_host((host, port))
+                    .await?
+                    .find(|addr| ipv6 || addr.is_ipv4())
+                    .ok_or_else(|| format!("cannot resolve {authority}"))?
+            }
+        };
+
+        let server_name = self.server_name.as_deref().map_or(host, String::as_str);
+        let connecting = self.endpoint.connect(addr, server_name)?;
+        let connection = tokio::time::timeout(self.connect_timeout, connecting)
+            .await
+            .map_err(|_| format!("HTTP/3 connection to {authority} timed out"))??;
+        let (mut driver, sender) =
+            h3::client::new(h3_quinn::Connection::new(connection.clone())).await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = futures::future::poll_fn(|cx| driver.poll_close(cx)).await {
+                tracing::debug!("HTTP/3 connection closed: {err}");
+            }
+        });
+
+        Ok((connection, sender))
+    }
+
+    async fn send(
+        mut sender: SendRequest,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, BoxError> {
+        let (parts, body) = request.into_parts();
+        let mut head = Request::builder().method(parts.method).uri(parts.uri);
+        if let Some(headers) = head.headers_mut() {
+            *headers = parts.headers;
+        }
+        let body = hyper::body::to_bytes(body).await?;
+
+        let mut stream = sender.send_request(head.body(())?).await?;
+        if !body.is_empty() {
+            stream.send_data(body).await?;
+        }
+        stream.finish().await?;
+
+        let (parts, ()) = stream.recv_response().await?.into_parts();
+        let (mut body_sender, body) = Body::channel();
+        tokio::task::spawn(async move {
+            loop {
+                match stream.recv_data().await {
+                    Ok(Some(mut data)) => {
+                        let data = data.copy_to_bytes(data.remaining());
+                        if body_sender.send_data(data).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(err) => {
+                        tracing::debug!("cannot read HTTP/3 response body: {err}");
+                        body_sender.abort();
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+impl Service<Request<Body>> for Http3Client {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let client = self.clone();
+        Box::pin(async move {
+            let authority = request
+                .uri()
+                .authority()
+                .cloned()
+                .ok_or("missing authority in subgraph URL")?;
+
+            match client.connection(&authority).await {
+                Ok(sender) => Self::send(sender, request).await,
+                Err(err) => match client.fallback {
+                    Some(fallback) => {
+                        tracing::debug!("falling back to TCP for {authority}: {err}");
+                        Ok(fallback.request(request).await?)
+                    }
+                    None => Err(err),
+                },
+            }
+        })
+    }
+}
@@ -0,0 +1,29 @@
+//! Disabled verification of the subgraph certificates, for development only
+
+use std::time::SystemTime;
+
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::Certificate;
+use rustls::ServerName;
+
+/// Accepts any certificate presented by a subgraph: expired, self-signed, issued by an unknown
+/// certificate authority or for another name
+///
+/// The signatures of the TLS handshake are still checked against the public key of the
+/// certificate, so the subgraph must own its key.
+pub(crate) struct AcceptInvalidCerts;
+
+impl ServerCertVerifier for AcceptInvalidCerts {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,154 @@
+//! Zone identifiers of the IPv6 literal hosts of subgraph URLs
+
+use std::future::Future;
+use std::io;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV6;
+
+use http::uri::Authority;
+use http::Uri;
+
+tokio::task_local! {
+    /// Zone identifier removed from the host of the connection being established, so that the
+    /// HTTPS connector gets a host it can use as the server name
+    static ZONE: String;
+}
+
+/// Splits an IPv6 literal host with a zone identifier, bracketed or not, into its address and
+/// zone, like `fe80::1` and `eth0` for `[fe80::1%25eth0]`
+///
+/// URIs encode the `%` separator as `%25` (RFC 6874), the unencoded form is accepted too.
+pub(crate) fn split_zone(host: &str) -> Option<(Ipv6Addr, &str)> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let (address, zone) = host.split_once('%')?;
+    let zone = zone
+        .strip_prefix("25")
+        .filter(|zone| !zone.is_empty())
+        .unwrap_or(zone);
+    if zone.is_empty() {
+        return None;
+    }
+    Some((address.parse().ok()?, zone))
+}
+
+/// Socket address of an IPv6 literal host with a zone identifier, `None` for the other hosts
+///
+/// The zone is an interface name, or its index.
+pub(crate) fn socket_addr(host: &str) -> Option<io::Result<SocketAddr>> {
+    let (address, zone) = split_zone(host)?;
+    Some(scope_id(zone).map(|scope_id| SocketAddr::V6(SocketAddrV6::new(address, 0, 0, scope_id))))
+}
+
+fn scope_id(zone: &str) -> io::Result<u32> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+    interface_index(zone).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown network interface '{zone}' in the zone of an IPv6 address"),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: the name is a valid NUL terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Replaces the host of a URI, keeping its port
+fn with_host(uri: &Uri, host: &str) -> Option<Uri> {
+    let authority = match uri.port_u16() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Some(Authority::try_from(authority).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Removes the zone identifier from the host of a URI, to establish the connection within its
+/// [`scope`]
+pub(crate) fn take_zone(uri: Uri) -> (Uri, Option<String>) {
+    let zoned = uri.host().and_then(split_zone).and_then(|(address, zone)| {
+        Some((with_host(&uri, &format!("[{address}]"))?, zone.to_string()))
+    });
+    match zoned {
+        Some((uri, zone)) => (uri, Some(zone)),
+        None => (uri, None),
+    }
+}
+
+/// Runs the connection to a URI whose zone identifier was removed, the connector opening the
+/// TCP connection gets it back with [`restore`]
+pub(crate) async fn scope<F: Future>(zone: Option<String>, connecting: F) -> F::Output {
+    match zone {
+        Some(zone) => ZONE.scope(zone, connecting).await,
+        None => connecting.await,
+    }
+}
+
+/// Adds back the zone identifier removed from the host of the URI of the connection being
+/// established, if any
+pub(crate) fn restore(uri: Uri) -> Uri {
+    ZONE.try_with(|zone| {
+        let address = uri.host()?.trim_end_matches(']');
+        with_host(&uri, &format!("{address}%25{zone}]"))
+    })
+    .ok()
+    .flatten()
+    .unwrap_or(uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_splits_the_zone_of_ipv6_hosts() {
+        let link_local: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(split_zone("[fe80::1%25eth0]"), Some((link_local, "eth0")));
+        assert_eq!(split_zone("[fe80::1%eth0]"), Some((link_local, "eth0")));
+        assert_eq!(split_zone("fe80::1%252"), Some((link_local, "2")));
+        assert_eq!(split_zone("[fe80::1]"), None);
+        assert_eq!(split_zone("[fe80::1%]"), None);
+        assert_eq!(split_zone("products.example.com"), None);
+    }
+
+    #[test]
+    fn it_resolves_the_zone_to_a_scope_id() {
+        assert_eq!(
+            socket_addr("[fe80::1%252]").unwrap().unwrap(),
+            "[fe80::1%2]:0".parse::<SocketAddr>().unwrap()
+        );
+        assert!(socket_addr("[fe80::1]").is_none());
+        assert_eq!(
+            socket_addr("[fe80::1%25nosuchif0]")
+                .unwrap()
+                .unwrap_err()
+                .to_string(),
+            "unknown network interface 'nosuchif0' in the zone of an IPv6 address"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_restores_the_zone_in_the_connector() {
+        let (uri, zone) = take_zone(Uri::from_static("https://[fe80::1%25eth0]:4001"));
+        assert_eq!(uri, Uri::from_static("https://[fe80::1]:4001"));
+        let restored = scope(zone, async move { restore(uri) }).await;
+        assert_eq!(restored, Uri::from_static("https://[fe80::1%25eth0]:4001"));
+        assert_eq!(
+            restore(Uri::from_static("https://[fe80::1]:4001")),
+            Uri::from_static("https://[fe80::1]:4001")
+        );
+    }
+}
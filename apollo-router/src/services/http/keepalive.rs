@@ -0,0 +1,71 @@
+//! TCP keepalive configuration for subgraph connections
+
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper::client::HttpConnector;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
+use tokio::net::TcpStream;
+use tower::BoxError;
+use tower::Service;
+
+use super::socket_marking::SocketMarking;
+use crate::services::trust_dns_connector::AsyncHyperResolver;
+
+/// Wraps the HTTP connector to set the TCP keepalive probe interval on new connections
+///
+/// hyper's connector can only configure the time before the first keepalive probe, so the
+/// interval is applied on the socket once it is connected, like the packet marking.
+#[derive(Clone)]
+pub(crate) struct KeepaliveConnector {
+    inner: HttpConnector<AsyncHyperResolver>,
+    keepalive: Option<TcpKeepalive>,
+    marking: SocketMarking,
+}
+
+impl KeepaliveConnector {
+    pub(crate) fn new(
+        mut inner: HttpConnector<AsyncHyperResolver>,
+        time: Duration,
+        interval: Option<Duration>,
+        marking: SocketMarking,
+    ) -> Self {
+        inner.set_keepalive(Some(time));
+        Self {
+            inner,
+            keepalive: interval
+                .map(|interval| TcpKeepalive::new().with_time(time).with_interval(interval)),
+            marking,
+        }
+    }
+}
+
+impl Service<Uri> for KeepaliveConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let keepalive = self.keepalive.clone();
+        let marking = self.marking.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            if let Some(keepalive) = keepalive {
+                SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+            }
+            if !marking.is_empty() {
+                marking.apply(&stream);
+            }
+            Ok(stream)
+        })
+    }
+}
@@ -0,0 +1,102 @@
+//! Local address the subgraph connections are bound to
+
+use std::net::IpAddr;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use tokio::net::TcpStream;
+use tower::BoxError;
+use tower::Service;
+
+use super::ipv6_zone;
+use super::keepalive::KeepaliveConnector;
+
+/// Wraps the TCP connector binding the connections to `local_address`
+///
+/// The socket is bound by hyper's connector, which skips the binding when the address families of
+/// the local and remote addresses differ. The resolver only returns the addresses of the family
+/// of the local address, and the hosts given as IP addresses, like the host overrides, are checked
+/// here so that such a connection fails instead of going out from another address.
+#[derive(Clone)]
+pub(crate) struct LocalAddressConnector {
+    inner: KeepaliveConnector,
+    local_address: Option<IpAddr>,
+}
+
+impl LocalAddressConnector {
+    pub(crate) fn new(inner: KeepaliveConnector, local_address: Option<IpAddr>) -> Self {
+        Self {
+            inner,
+            local_address,
+        }
+    }
+}
+
+impl Service<Uri> for LocalAddressConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let Some(local_address) = self.local_address else {
+            return self.inner.call(uri);
+        };
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        if let Err(err) = check_family(local_address, &host) {
+            return Box::pin(async move { Err(err) });
+        }
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            connecting.await.map_err(|err| {
+                BoxError::from(format!(
+                    "cannot connect to {host} from local_address {local_address}: {err}"
+                ))
+            })
+        })
+    }
+}
+
+fn check_family(local_address: IpAddr, host: &str) -> Result<(), BoxError> {
+    let remote = host
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| ipv6_zone::split_zone(host).map(|(address, _)| IpAddr::V6(address)));
+    match remote {
+        Some(remote) if remote.is_ipv4() != local_address.is_ipv4() => Err(format!(
+            "cannot connect to {remote} from local_address {local_address}: the address families differ"
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checks_the_family_of_ip_hosts() {
+        let ipv4: IpAddr = "10.0.0.1".parse().unwrap();
+        let ipv6: IpAddr = "fd00::1".parse().unwrap();
+        assert!(check_family(ipv4, "10.0.0.2").is_ok());
+        assert!(check_family(ipv4, "products.example.com").is_ok());
+        assert!(check_family(ipv6, "fd00::2").is_ok());
+        let error = check_family(ipv4, "fd00::2").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot connect to fd00::2 from local_address 10.0.0.1: the address families differ"
+        );
+        assert!(check_family(ipv4, "fe80::1%25eth0").is_err());
+    }
+}
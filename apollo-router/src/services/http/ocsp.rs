@@ -0,0 +1,247 @@
+//! Verification of the OCSP responses stapled by subgraphs
+//!
+//! Only the parts of RFC 6960 needed to check a stapled response are implemented: the response
+//! must be signed by the issuer of the subgraph certificate, or by a responder the issuer
+//! delegated OCSP signing to.
+
+use sha1::Digest;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::der_parser::asn1_rs::Any;
+use x509_parser::der_parser::asn1_rs::BitString;
+use x509_parser::der_parser::asn1_rs::Class;
+use x509_parser::der_parser::asn1_rs::GeneralizedTime;
+use x509_parser::der_parser::asn1_rs::Tag;
+use x509_parser::oid_registry::OID_HASH_SHA1;
+use x509_parser::oid_registry::OID_NIST_HASH_SHA256;
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::AlgorithmIdentifier;
+
+const OCSP_BASIC_RESPONSE: &str = "1.3.6.1.5.5.7.48.1.1";
+// tolerated difference between the clocks of the router and of the OCSP responder
+const CLOCK_SKEW: i64 = 300;
+
+/// Status of the subgraph certificate in a valid OCSP response
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CertStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+/// Verifies an OCSP response for `certificate`, issued by `issuer`, at the `now` timestamp
+pub(crate) fn verify(
+    response: &[u8],
+    certificate: &X509Certificate,
+    issuer: &X509Certificate,
+    now: i64,
+) -> Result<CertStatus, String> {
+    // OCSPResponse ::= SEQUENCE { responseStatus ENUMERATED, responseBytes [0] EXPLICIT ResponseBytes OPTIONAL }
+    let mut response = sequence(response)?;
+    let status = next(&mut response, Tag::Enumerated)?;
+    if status.data != [0] {
+        return Err(format!(
+            "the OCSP responder returned the error status {}",
+            status.data.first().copied().unwrap_or_default()
+        ));
+    }
+    let mut response_bytes = sequence(explicit(&mut response, 0)?.data)?;
+    let response_type = next(&mut response_bytes, Tag::Oid)?;
+    let response_type = response_type.as_oid().map_err(|e| e.to_string())?;
+    if response_type.to_id_string() != OCSP_BASIC_RESPONSE {
+        return Err(format!("unsupported OCSP response type {response_type}"));
+    }
+
+    // BasicOCSPResponse ::= SEQUENCE { tbsResponseData ResponseData, signatureAlgorithm
+    //   AlgorithmIdentifier, signature BIT STRING, certs [0] EXPLICIT SEQUENCE OF Certificate OPTIONAL }
+    let mut basic = sequence(next(&mut response_bytes, Tag::OctetString)?.data)?;
+    let tbs_response_data_raw = basic;
+    let tbs_response_data = next(&mut basic, Tag::Sequence)?;
+    let tbs_response_data_raw = &tbs_response_data_raw[..tbs_response_data_raw.len() - basic.len()];
+    let (basic, signature_algorithm) =
+        AlgorithmIdentifier::from_der(basic).map_err(|e| e.to_string())?;
+    let (basic, signature) = BitString::from_der(basic).map_err(|e| e.to_string())?;
+    let mut basic = basic;
+    let mut certs = Vec::new();
+    if !basic.is_empty() {
+        let mut sequence_of_certs = sequence(explicit(&mut basic, 0)?.data)?;
+        while !sequence_of_certs.is_empty() {
+            let (rest, cert) =
+                X509Certificate::from_der(sequence_of_certs).map_err(|e| e.to_string())?;
+            certs.push(cert);
+            sequence_of_certs = rest;
+        }
+    }
+
+    // ResponseData ::= SEQUENCE { version [0] EXPLICIT Version DEFAULT v1, responderID ResponderID,
+    //   producedAt GeneralizedTime, responses SEQUENCE OF SingleResponse, ... }
+    let mut data = tbs_response_data.data;
+    let mut element = any(&mut data)?;
+    if element.class() == Class::ContextSpecific && element.tag() == Tag(0) {
+        element = any(&mut data)?;
+    }
+    let signer = responder(&element, issuer, &certs)?;
+    x509_parser::verify::verify_signature(
+        signer.public_key(),
+        &signature_algorithm,
+        &signature,
+        tbs_response_data_raw,
+    )
+    .map_err(|e| format!("invalid OCSP response signature: {e}"))?;
+    next(&mut data, Tag::GeneralizedTime)?;
+
+    let mut responses = next(&mut data, Tag::Sequence)?.data;
+    while !responses.is_empty() {
+        let single_response = next(&mut responses, Tag::Sequence)?;
+        if let Some(status) =
+            single_response_status(single_response.data, certificate, issuer, now)?
+        {
+            return Ok(status);
+        }
+    }
+    Err("the OCSP response does not cover the subgraph certificate".to_string())
+}
+
+// SingleResponse ::= SEQUENCE { certID CertID, certStatus CertStatus, thisUpdate GeneralizedTime,
+//   nextUpdate [0] EXPLICIT GeneralizedTime OPTIONAL, ... }
+fn single_response_status(
+    mut single_response: &[u8],
+    certificate: &X509Certificate,
+    issuer: &X509Certificate,
+    now: i64,
+) -> Result<Option<CertStatus>, String> {
+    let cert_id = next(&mut single_response, Tag::Sequence)?;
+    if !matches_cert_id(cert_id.data, certificate, issuer)? {
+        return Ok(None);
+    }
+
+    let status = any(&mut single_response)?;
+    let status = match (status.class(), status.tag()) {
+        (Class::ContextSpecific, Tag(0)) => CertStatus::Good,
+        (Class::ContextSpecific, Tag(1)) => CertStatus::Revoked,
+        (Class::ContextSpecific, Tag(2)) => CertStatus::Unknown,
+        _ => return Err("invalid certificate status in the OCSP response".to_string()),
+    };
+
+    let this_update = timestamp(next(&mut single_response, Tag::GeneralizedTime)?)?;
+    if this_update > now + CLOCK_SKEW {
+        return Err("the OCSP response is not valid yet".to_string());
+    }
+    if !single_response.is_empty() {
+        let element = any(&mut single_response)?;
+        if element.class() == Class::ContextSpecific && element.tag() == Tag(0) {
+            let mut next_update = element.data;
+            let next_update = timestamp(next(&mut next_update, Tag::GeneralizedTime)?)?;
+            if next_update + CLOCK_SKEW < now {
+                return Err("the OCSP response has expired".to_string());
+            }
+        }
+    }
+
+    Ok(Some(status))
+}
+
+// CertID ::= SEQUENCE { hashAlgorithm AlgorithmIdentifier, issuerNameHash OCTET STRING,
+//   issuerKeyHash OCTET STRING, serialNumber CertificateSerialNumber }
+fn matches_cert_id(
+    cert_id: &[u8],
+    certificate: &X509Certificate,
+    issuer: &X509Certificate,
+) -> Result<bool, String> {
+    let (mut cert_id, hash_algorithm) =
+        AlgorithmIdentifier::from_der(cert_id).map_err(|e| e.to_string())?;
+    let issuer_name_hash = next(&mut cert_id, Tag::OctetString)?.data;
+    let issuer_key_hash = next(&mut cert_id, Tag::OctetString)?.data;
+    let serial_number = next(&mut cert_id, Tag::Integer)?.data;
+
+    let hash = |data: &[u8]| -> Result<Vec<u8>, String> {
+        if hash_algorithm.algorithm == OID_HASH_SHA1 {
+            Ok(sha1::Sha1::digest(data).to_vec())
+        } else if hash_algorithm.algorithm == OID_NIST_HASH_SHA256 {
+            Ok(sha2::Sha256::digest(data).to_vec())
+        } else {
+            Err(format!(
+                "unsupported hash algorithm {} in the OCSP response",
+                hash_algorithm.algorithm
+            ))
+        }
+    };
+
+    Ok(serial_number == certificate.raw_serial()
+        && issuer_name_hash == hash(issuer.subject().as_raw())?
+        && issuer_key_hash == hash(&issuer.public_key().subject_public_key.data)?)
+}
+
+// ResponderID ::= CHOICE { byName [1] Name, byKey [2] KeyHash }
+fn responder<'a>(
+    responder_id: &Any,
+    issuer: &'a X509Certificate<'a>,
+    certs: &'a [X509Certificate<'a>],
+) -> Result<&'a X509Certificate<'a>, String> {
+    let is_responder = |cert: &X509Certificate| match (responder_id.class(), responder_id.tag()) {
+        (Class::ContextSpecific, Tag(1)) => responder_id.data == cert.subject().as_raw(),
+        (Class::ContextSpecific, Tag(2)) => {
+            let mut key_hash = responder_id.data;
+            next(&mut key_hash, Tag::OctetString).map_or(false, |key_hash| {
+                *key_hash.data == sha1::Sha1::digest(&cert.public_key().subject_public_key.data)[..]
+            })
+        }
+        _ => false,
+    };
+
+    if is_responder(issuer) {
+        return Ok(issuer);
+    }
+
+    // the issuer can delegate OCSP signing to a certificate it issued
+    certs
+        .iter()
+        .find(|cert| {
+            is_responder(cert)
+                && cert.issuer().as_raw() == issuer.subject().as_raw()
+                && cert.verify_signature(Some(issuer.public_key())).is_ok()
+                && matches!(
+                    cert.extended_key_usage(),
+                    Ok(Some(extension)) if extension.value.ocsp_signing
+                )
+        })
+        .ok_or_else(|| "the OCSP response is not signed by an authorized responder".to_string())
+}
+
+fn any<'a>(input: &mut &'a [u8]) -> Result<Any<'a>, String> {
+    let (rest, element) = Any::from_der(input).map_err(|e| e.to_string())?;
+    *input = rest;
+    Ok(element)
+}
+
+fn next<'a>(input: &mut &'a [u8], tag: Tag) -> Result<Any<'a>, String> {
+    let element = any(input)?;
+    if element.tag() != tag || element.class() != Class::Universal {
+        return Err(format!(
+            "invalid OCSP response: expected {tag}, got {}",
+            element.tag()
+        ));
+    }
+    Ok(element)
+}
+
+fn explicit<'a>(input: &mut &'a [u8], tag: u32) -> Result<Any<'a>, String> {
+    let element = any(input)?;
+    if element.tag() != Tag(tag) || element.class() != Class::ContextSpecific {
+        return Err(format!("invalid OCSP response: expected [{tag}]"));
+    }
+    Ok(element)
+}
+
+fn sequence(input: &[u8]) -> Result<&[u8], String> {
+    let mut input = input;
+    Ok(next(&mut input, Tag::Sequence)?.data)
+}
+
+fn timestamp(time: Any) -> Result<i64, String> {
+    let time = GeneralizedTime::try_from(time).map_err(|e| e.to_string())?;
+    Ok(time
+        .0
+        .to_datetime()
+        .map_err(|e| e.to_string())?
+        .unix_timestamp())
+}
@@ -0,0 +1,59 @@
+//! Summary of the certificates presented by the subgraphs during the TLS handshakes
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::FromDer;
+
+/// Leaf certificate presented by a subgraph on a connection, added to the extensions of the
+/// responses received on it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PeerCertificate {
+    /// distinguished name of the subject, like `C=US, CN=products.example.com`
+    pub(crate) subject: String,
+    /// distinguished name of the issuer
+    pub(crate) issuer: String,
+    /// SHA-256 hash of the DER encoded certificate, in lowercase hexadecimal
+    pub(crate) fingerprint: String,
+    /// end of the validity of the certificate, in seconds since the Unix epoch
+    pub(crate) not_after: i64,
+}
+
+impl PeerCertificate {
+    /// Summarizes a DER encoded certificate, `None` if it cannot be parsed
+    pub(crate) fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, certificate) = X509Certificate::from_der(der).ok()?;
+        Some(Self {
+            subject: certificate.subject().to_string(),
+            issuer: certificate.issuer().to_string(),
+            fingerprint: hex::encode(Sha256::digest(der)),
+            not_after: certificate.validity().not_after.timestamp(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::load_certs;
+
+    #[test]
+    fn it_summarizes_a_certificate() {
+        let certificate = &load_certs(include_str!("./testdata/server.crt")).unwrap()[0];
+        let summary = PeerCertificate::from_der(&certificate.0).unwrap();
+
+        assert_eq!(
+            summary,
+            PeerCertificate {
+                subject: "C=FR, O=Apollo GraphQL".to_string(),
+                issuer: "C=FR, O=Apollo GraphQL, CN=Apollo Test CA".to_string(),
+                fingerprint: "16f0d88296bdb5fa2da7cdd5e1644af6bf6133f0eca2af4ee923178a8a358e1b"
+                    .to_string(),
+                not_after: 2558186652,
+            }
+        );
+        assert!(PeerCertificate::from_der(b"not a certificate").is_none());
+    }
+}
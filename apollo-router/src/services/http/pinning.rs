@@ -0,0 +1,94 @@
+//! Public key pinning for subgraph TLS connections
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use base64::Engine as _;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::Certificate;
+use rustls::CertificateError;
+use rustls::ServerName;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::configuration::ConfigurationError;
+
+/// Verifies subgraph certificates with the inner verifier, then checks that the public key of
+/// the subgraph certificate matches one of the pinned keys
+pub(crate) struct PinningVerifier {
+    subgraph: String,
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinningVerifier {
+    pub(crate) fn new(
+        subgraph: &str,
+        inner: Arc<dyn ServerCertVerifier>,
+        pinned_public_keys: &[String],
+    ) -> Result<Self, ConfigurationError> {
+        let error = |error| ConfigurationError::InvalidConfiguration {
+            message: "bad TLS configuration for subgraph",
+            error,
+        };
+        if pinned_public_keys.is_empty() {
+            return Err(error(format!(
+                "subgraph '{subgraph}' has an empty pinned_public_keys list"
+            )));
+        }
+        let pins = pinned_public_keys
+            .iter()
+            .map(|pin| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(pin.trim())
+                    .ok()
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .ok_or_else(|| {
+                        error(format!(
+                            "subgraph '{subgraph}' has an invalid pinned public key '{pin}', expected the base64 encoded SHA-256 hash of a public key"
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            subgraph: subgraph.to_string(),
+            inner,
+            pins,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let (_, certificate) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let hash: [u8; 32] = Sha256::digest(certificate.public_key().raw).into();
+        if !self.pins.contains(&hash) {
+            return Err(rustls::Error::General(format!(
+                "the public key of subgraph '{}' does not match any pinned public key",
+                self.subgraph
+            )));
+        }
+
+        Ok(verified)
+    }
+}
@@ -0,0 +1,83 @@
+//! Timeout of the wait of subgraph requests for a connection
+
+use std::future::Future;
+use std::time::Duration;
+use std::time::Instant;
+
+use http::Request;
+use hyper::client::connect::capture_connection;
+use hyper::client::connect::CaptureConnection;
+
+use crate::error::FetchError;
+
+/// Wait of a request for a connection of the pool of the HTTP client, either an idle one or a
+/// new one
+///
+/// hyper gives the connection to the request once it is acquired, without queueing the request
+/// anywhere the router could see, so the acquisition is detected when the connection is captured
+/// in the request. Requests that are not sent on the pool, like the ones with
+/// `Expect: 100-continue`, are not captured and must not be bounded.
+pub(crate) struct PoolAcquire {
+    connection: CaptureConnection,
+    timeout: Duration,
+    start: Instant,
+}
+
+impl PoolAcquire {
+    pub(crate) fn start<B>(request: &mut Request<B>, timeout: Duration) -> Self {
+        Self {
+            connection: capture_connection(request),
+            timeout,
+            start: Instant::now(),
+        }
+    }
+
+    /// Fails the response with a `CONNECTION_POOL_TIMEOUT` error if the request gets no
+    /// connection within the timeout
+    ///
+    /// The wait ends when the connection is acquired, or when hyper drops the request because it
+    /// could not be sent.
+    pub(crate) async fn bound<T>(
+        self,
+        service: &str,
+        response: impl Future<Output = Result<T, FetchError>>,
+    ) -> Result<T, FetchError> {
+        let Self {
+            mut connection,
+            timeout,
+            start,
+        } = self;
+        tokio::pin!(response);
+        let acquired = tokio::time::timeout(timeout, async {
+            connection.wait_for_connection_metadata().await.is_some()
+        });
+        tokio::select! {
+            biased;
+            response = &mut response => return response,
+            acquired = acquired => match acquired {
+                Ok(true) => {
+                    f64_histogram!(
+                        "apollo.router.subgraph.connections.acquire.duration",
+                        "Time subgraph requests waited for a connection, idle in the pool or new, in seconds",
+                        start.elapsed().as_secs_f64(),
+                        "subgraph.name" = service.to_string()
+                    );
+                }
+                Ok(false) => {}
+                Err(_) => {
+                    u64_counter!(
+                        "apollo.router.subgraph.connections.acquire_timeouts",
+                        "Number of subgraph requests that got no connection within their pool_acquire_timeout",
+                        1,
+                        "subgraph.name" = service.to_string()
+                    );
+                    return Err(FetchError::SubrequestPoolTimeout {
+                        service: service.to_string(),
+                        timeout_ms: timeout.as_millis() as u64,
+                    });
+                }
+            }
+        }
+        response.await
+    }
+}
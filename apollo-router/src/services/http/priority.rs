@@ -0,0 +1,64 @@
+//! Priority of the subgraph requests, sent as the `priority` header of RFC 9218
+
+use http::HeaderName;
+use http::HeaderValue;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub(crate) static PRIORITY: HeaderName = HeaderName::from_static("priority");
+
+/// Highest urgency value, the least urgent
+const MAX_URGENCY: u8 = 7;
+
+/// Priority of a request, set in the context by the hooks that run before the subgraph request
+///
+/// hyper does not send the PRIORITY frames of HTTP/2, which RFC 9113 deprecated, so the priority
+/// is sent with the extensible priorities of RFC 9218 instead: a request header that the servers
+/// and proxies supporting it use to schedule the responses on a connection, over HTTP/2 and
+/// HTTP/3. It is a hint, the servers that do not support it ignore it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Priority {
+    /// from 0, the most urgent, to 7. The servers use 3 when it is not sent
+    pub(crate) urgency: u8,
+    /// the response can be processed as it is received, interleaved with the other responses
+    /// of the same urgency
+    #[serde(default)]
+    pub(crate) incremental: bool,
+}
+
+impl Priority {
+    pub(crate) fn header_value(&self) -> Result<HeaderValue, String> {
+        if self.urgency > MAX_URGENCY {
+            return Err(format!(
+                "the urgency of the priority in the context must be between 0 and {MAX_URGENCY}, not {}",
+                self.urgency
+            ));
+        }
+        let value = if self.incremental {
+            format!("u={}, i", self.urgency)
+        } else {
+            format!("u={}", self.urgency)
+        };
+        Ok(HeaderValue::from_str(&value).expect("the priority is a valid header value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_the_priority_header() {
+        let priority = |urgency, incremental| Priority {
+            urgency,
+            incremental,
+        };
+        assert_eq!(priority(0, false).header_value().unwrap(), "u=0");
+        assert_eq!(priority(5, true).header_value().unwrap(), "u=5, i");
+        assert_eq!(
+            priority(8, false).header_value().unwrap_err(),
+            "the urgency of the priority in the context must be between 0 and 7, not 8"
+        );
+    }
+}
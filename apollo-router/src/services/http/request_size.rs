@@ -0,0 +1,171 @@
+//! Size of the request bodies sent to the subgraphs, recorded in the context
+
+use std::pin::Pin;
+use std::task::Context as TaskContext;
+use std::task::Poll;
+
+use bytes::Bytes;
+use futures::Stream;
+use pin_project_lite::pin_project;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Context;
+
+/// Size of the body of the last request sent to a subgraph, in bytes
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RequestSize {
+    /// size of the serialized body, before compression
+    pub(crate) bytes: u64,
+    /// size of the body sent to the subgraph, only set when it is compressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compressed_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SizeKind {
+    Uncompressed,
+    Compressed,
+}
+
+/// Records the size of a request body in the context
+///
+/// The size of a body is known before it is sent when it is not streamed, the size of the
+/// compressed body is only known once the compressor produced all of it, so it is counted as it
+/// is sent.
+#[derive(Clone)]
+pub(crate) struct RequestSizeRecorder {
+    context: Context,
+    key: String,
+}
+
+impl RequestSizeRecorder {
+    pub(crate) fn new(context: &Context, key: String) -> Self {
+        Self {
+            context: context.clone(),
+            key,
+        }
+    }
+
+    /// Replaces the size of the previous request to the subgraph
+    pub(crate) fn start(&self, bytes: Option<u64>) {
+        self.record(|_| RequestSize {
+            bytes: bytes.unwrap_or_default(),
+            compressed_bytes: None,
+        });
+    }
+
+    pub(crate) fn counted<S>(&self, inner: S, kind: SizeKind) -> CountedBody<S> {
+        CountedBody {
+            inner,
+            recorder: self.clone(),
+            kind,
+            size: 0,
+        }
+    }
+
+    fn record(&self, update: impl FnOnce(RequestSize) -> RequestSize) {
+        if let Err(e) = self.context.upsert(self.key.as_str(), update) {
+            tracing::error!("could not record the size of the request to a subgraph: {e}");
+        }
+    }
+}
+
+pin_project! {
+    /// Body stream counting the size of its chunks as they are read, recorded once it ends
+    pub(crate) struct CountedBody<S> {
+        #[pin]
+        inner: S,
+        recorder: RequestSizeRecorder,
+        kind: SizeKind,
+        size: u64,
+    }
+}
+
+impl<S, E> Stream for CountedBody<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let res = this.inner.poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(data))) => *this.size += data.len() as u64,
+            Poll::Ready(None) => {
+                let size = *this.size;
+                this.recorder.record(|recorded| match this.kind {
+                    SizeKind::Uncompressed => RequestSize {
+                        bytes: size,
+                        ..recorded
+                    },
+                    SizeKind::Compressed => RequestSize {
+                        compressed_bytes: Some(size),
+                        ..recorded
+                    },
+                });
+            }
+            _ => {}
+        }
+        res
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_records_the_size_of_the_body() {
+        let context = Context::new();
+        let recorder = RequestSizeRecorder::new(&context, "size".to_string());
+        let size = || context.get::<_, RequestSize>("size").unwrap().unwrap();
+
+        recorder.start(Some(12));
+        assert_eq!(
+            size(),
+            RequestSize {
+                bytes: 12,
+                compressed_bytes: None
+            }
+        );
+
+        let chunks = vec![
+            Ok::<_, std::io::Error>(Bytes::from_static(b"abc")),
+            Ok(Bytes::from_static(b"de")),
+        ];
+        let body = recorder.counted(futures::stream::iter(chunks), SizeKind::Compressed);
+        let sent: Vec<_> = body.collect().await;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            size(),
+            RequestSize {
+                bytes: 12,
+                compressed_bytes: Some(5)
+            }
+        );
+        assert_eq!(
+            context.get_json_value("size"),
+            Some(serde_json_bytes::json!({ "bytes": 12, "compressed_bytes": 5 }))
+        );
+
+        // the size of the next request replaces it
+        recorder.start(None);
+        let body = recorder.counted(
+            futures::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(b"abcd"))]),
+            SizeKind::Uncompressed,
+        );
+        body.collect::<Vec<_>>().await;
+        assert_eq!(
+            context.get_json_value("size"),
+            Some(serde_json_bytes::json!({ "bytes": 4 }))
+        );
+    }
+}
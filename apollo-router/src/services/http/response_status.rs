@@ -0,0 +1,123 @@
+//! Handling of the subgraph responses according to their status
+
+use std::collections::HashMap;
+
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Handling of the subgraph responses with a status
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StatusOutcome {
+    /// Parse the body as a GraphQL response, an error is added to it for non-2xx statuses
+    Graphql,
+    /// Fail the request with a `SUBREQUEST_HTTP_ERROR` error without reading the body, so that
+    /// it can be retried
+    Error,
+}
+
+/// Outcomes of the subgraph responses by status code and status class
+///
+/// A status code takes precedence over its class, and the responses with a status that is not
+/// configured are parsed as GraphQL responses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ResponseStatuses {
+    codes: HashMap<u16, StatusOutcome>,
+    // indexed by the first digit of the status, 1xx to 5xx
+    classes: [Option<StatusOutcome>; 5],
+}
+
+impl ResponseStatuses {
+    /// Parses the configured statuses, either status codes like `503` or classes like `5xx`
+    pub(crate) fn new(statuses: &HashMap<String, StatusOutcome>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        for (status, outcome) in statuses {
+            match status.strip_suffix("xx") {
+                Some(class) => {
+                    let class = class
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|class| (1..=5).contains(class))
+                        .ok_or_else(|| invalid_status(status))?;
+                    parsed.classes[class - 1] = Some(*outcome);
+                }
+                None => {
+                    let code = status
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|code| (100..=599).contains(code))
+                        .ok_or_else(|| invalid_status(status))?;
+                    parsed.codes.insert(code, *outcome);
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub(crate) fn outcome(&self, status: StatusCode) -> StatusOutcome {
+        let code = status.as_u16();
+        self.codes
+            .get(&code)
+            .copied()
+            .or_else(|| {
+                self.classes
+                    .get(usize::from(code / 100).wrapping_sub(1))
+                    .copied()
+                    .flatten()
+            })
+            .unwrap_or(StatusOutcome::Graphql)
+    }
+}
+
+fn invalid_status(status: &str) -> String {
+    format!("response_statuses key '{status}' must be a status code or a status class like 5xx")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_the_statuses_to_their_outcome() {
+        let statuses = ResponseStatuses::new(&HashMap::from([
+            ("4xx".to_string(), StatusOutcome::Graphql),
+            ("5xx".to_string(), StatusOutcome::Error),
+            ("501".to_string(), StatusOutcome::Graphql),
+            ("429".to_string(), StatusOutcome::Error),
+        ]))
+        .unwrap();
+        assert_eq!(statuses.outcome(StatusCode::OK), StatusOutcome::Graphql);
+        assert_eq!(
+            statuses.outcome(StatusCode::BAD_REQUEST),
+            StatusOutcome::Graphql
+        );
+        assert_eq!(
+            statuses.outcome(StatusCode::TOO_MANY_REQUESTS),
+            StatusOutcome::Error
+        );
+        assert_eq!(
+            statuses.outcome(StatusCode::SERVICE_UNAVAILABLE),
+            StatusOutcome::Error
+        );
+        assert_eq!(
+            statuses.outcome(StatusCode::NOT_IMPLEMENTED),
+            StatusOutcome::Graphql
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_statuses() {
+        for status in ["6xx", "0xx", "xx", "99", "600", "5XX", "server_error"] {
+            let error =
+                ResponseStatuses::new(&HashMap::from([(status.to_string(), StatusOutcome::Error)]))
+                    .unwrap_err();
+            assert_eq!(
+                error,
+                format!(
+                    "response_statuses key '{status}' must be a status code or a status class like 5xx"
+                )
+            );
+        }
+    }
+}
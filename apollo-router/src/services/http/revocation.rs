@@ -0,0 +1,305 @@
+//! Certificate revocation checks for subgraph TLS connections
+
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::client::WebPkiVerifier;
+use rustls::Certificate;
+use rustls::CertificateError;
+use rustls::RootCertStore;
+use rustls::ServerName;
+use webpki::CertRevocationList;
+use webpki::OwnedCertRevocationList;
+
+use super::ocsp;
+use super::ocsp::CertStatus;
+use crate::configuration::ConfigurationError;
+use crate::configuration::ExpiredCrl;
+use crate::configuration::Ocsp;
+
+// same list as the one used by rustls to verify server certificates
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+struct TrustAnchor {
+    subject: Vec<u8>,
+    spki: Vec<u8>,
+    name_constraints: Option<Vec<u8>>,
+}
+
+struct Crl {
+    list: OwnedCertRevocationList,
+    issuer: Vec<u8>,
+    issuer_name: String,
+    next_update: Option<i64>,
+}
+
+/// Verifies subgraph certificates, then checks that they were not revoked, using the configured
+/// certificate revocation lists and the OCSP response stapled by the subgraph
+pub(crate) struct RevocationVerifier {
+    subgraph: String,
+    inner: WebPkiVerifier,
+    roots: Vec<Certificate>,
+    trust_anchors: Vec<TrustAnchor>,
+    crls: Vec<Crl>,
+    expired_crl: ExpiredCrl,
+    ocsp: Ocsp,
+}
+
+impl RevocationVerifier {
+    pub(crate) fn new(
+        subgraph: &str,
+        roots: Vec<Certificate>,
+        certificate_revocation_lists: Option<&str>,
+        expired_crl: ExpiredCrl,
+        ocsp: Ocsp,
+    ) -> Result<Self, ConfigurationError> {
+        let mut store = RootCertStore::empty();
+        store.add_parsable_certificates(&roots);
+        let trust_anchors = roots
+            .iter()
+            .filter_map(|root| webpki::TrustAnchor::try_from_cert_der(&root.0).ok())
+            .map(|anchor| TrustAnchor {
+                subject: anchor.subject.to_vec(),
+                spki: anchor.spki.to_vec(),
+                name_constraints: anchor.name_constraints.map(<[u8]>::to_vec),
+            })
+            .collect();
+
+        Ok(Self {
+            subgraph: subgraph.to_string(),
+            inner: WebPkiVerifier::new(store, None),
+            roots,
+            trust_anchors,
+            crls: certificate_revocation_lists
+                .map(load_crls)
+                .transpose()?
+                .unwrap_or_default(),
+            expired_crl,
+            ocsp,
+        })
+    }
+
+    fn check_expiration(
+        &self,
+        chain: &[&Certificate],
+        now: SystemTime,
+    ) -> Result<(), rustls::Error> {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| rustls::Error::FailedToGetCurrentTime)?
+            .as_secs() as i64;
+        let issuers: Vec<Vec<u8>> = chain
+            .iter()
+            .filter_map(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+            .map(|(_, cert)| cert.issuer().as_raw().to_vec())
+            .collect();
+
+        for crl in &self.crls {
+            let expired = crl
+                .next_update
+                .map_or(false, |next_update| next_update < now);
+            if !expired || !issuers.contains(&crl.issuer) {
+                continue;
+            }
+            match self.expired_crl {
+                ExpiredCrl::FailClosed => {
+                    return Err(rustls::Error::General(format!(
+                        "the certificate revocation list of '{}' has expired",
+                        crl.issuer_name
+                    )))
+                }
+                ExpiredCrl::FailOpen => tracing::warn!(
+                    "the certificate revocation list of '{}' has expired, it is still used to check subgraph certificates",
+                    crl.issuer_name
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_crls(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<(), rustls::Error> {
+        let chain: Vec<&Certificate> = std::iter::once(end_entity)
+            .chain(intermediates.iter())
+            .collect();
+        self.check_expiration(&chain, now)?;
+
+        let trust_anchors: Vec<webpki::TrustAnchor> = self
+            .trust_anchors
+            .iter()
+            .map(|anchor| webpki::TrustAnchor {
+                subject: &anchor.subject,
+                spki: &anchor.spki,
+                name_constraints: anchor.name_constraints.as_deref(),
+            })
+            .collect();
+        let intermediates: Vec<&[u8]> =
+            intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+        let crls: Vec<&dyn CertRevocationList> = self
+            .crls
+            .iter()
+            .map(|crl| &crl.list as &dyn CertRevocationList)
+            .collect();
+        let time =
+            webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+
+        webpki::EndEntityCert::try_from(end_entity.0.as_slice())
+            .and_then(|cert| {
+                cert.verify_for_usage(
+                    SUPPORTED_SIG_ALGS,
+                    &trust_anchors,
+                    &intermediates,
+                    time,
+                    webpki::KeyUsage::server_auth(),
+                    &crls,
+                )
+            })
+            .map_err(|err| match err {
+                webpki::Error::CertRevoked => {
+                    rustls::Error::InvalidCertificate(CertificateError::Revoked)
+                }
+                err => rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(err))),
+            })
+    }
+
+    fn check_ocsp(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<(), rustls::Error> {
+        let subgraph = &self.subgraph;
+        match self.ocsp {
+            Ocsp::Off => return Ok(()),
+            Ocsp::Prefer if ocsp_response.is_empty() => return Ok(()),
+            Ocsp::Require if ocsp_response.is_empty() => {
+                return Err(rustls::Error::General(format!(
+                    "subgraph '{subgraph}' did not staple an OCSP response"
+                )))
+            }
+            _ => {}
+        }
+
+        let (_, certificate) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        // the chain was verified, the issuer is either an intermediate or a root
+        let issuer = intermediates
+            .iter()
+            .chain(self.roots.iter())
+            .filter_map(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+            .map(|(_, cert)| cert)
+            .find(|issuer| {
+                issuer.subject().as_raw() == certificate.issuer().as_raw()
+                    && certificate
+                        .verify_signature(Some(issuer.public_key()))
+                        .is_ok()
+            })
+            .ok_or_else(|| {
+                rustls::Error::General(format!(
+                    "cannot find the issuer of the certificate of subgraph '{subgraph}' to verify its OCSP response"
+                ))
+            })?;
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| rustls::Error::FailedToGetCurrentTime)?
+            .as_secs() as i64;
+
+        match ocsp::verify(ocsp_response, &certificate, &issuer, now) {
+            Ok(CertStatus::Good) => Ok(()),
+            Ok(CertStatus::Revoked) => {
+                Err(rustls::Error::InvalidCertificate(CertificateError::Revoked))
+            }
+            Ok(CertStatus::Unknown) if self.ocsp == Ocsp::Prefer => Ok(()),
+            Ok(CertStatus::Unknown) => Err(rustls::Error::General(format!(
+                "the OCSP response stapled by subgraph '{subgraph}' does not know its certificate"
+            ))),
+            Err(error) => Err(rustls::Error::General(format!(
+                "invalid OCSP response stapled by subgraph '{subgraph}': {error}"
+            ))),
+        }
+    }
+}
+
+impl ServerCertVerifier for RevocationVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        if !self.crls.is_empty() {
+            self.check_crls(end_entity, intermediates, now)?;
+        }
+        self.check_ocsp(end_entity, intermediates, ocsp_response, now)?;
+
+        Ok(verified)
+    }
+}
+
+fn load_crls(data: &str) -> Result<Vec<Crl>, ConfigurationError> {
+    let ders = rustls_pemfile::crls(&mut BufReader::new(data.as_bytes())).map_err(|e| {
+        ConfigurationError::CertificateRevocationLists {
+            error: format!("could not parse the list: {e}"),
+        }
+    })?;
+    if ders.is_empty() {
+        return Err(ConfigurationError::CertificateRevocationLists {
+            error: "the list is empty".to_string(),
+        });
+    }
+
+    ders.iter()
+        .map(|der| {
+            let list = webpki::BorrowedCertRevocationList::from_der(der)
+                .and_then(|crl| crl.to_owned())
+                .map_err(|e| ConfigurationError::CertificateRevocationLists {
+                    error: format!("invalid certificate revocation list: {e:?}"),
+                })?;
+            let (_, parsed) = x509_parser::parse_x509_crl(der).map_err(|e| {
+                ConfigurationError::CertificateRevocationLists {
+                    error: format!("invalid certificate revocation list: {e}"),
+                }
+            })?;
+            Ok(Crl {
+                list,
+                issuer: parsed.issuer().as_raw().to_vec(),
+                issuer_name: parsed.issuer().to_string(),
+                next_update: parsed.next_update().map(|time| time.timestamp()),
+            })
+        })
+        .collect()
+}
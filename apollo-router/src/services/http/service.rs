@@ -1,29 +1,48 @@
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::future::Future;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
+use std::time::Instant;
 
 use ::serde::Deserialize;
+use async_compression::Level;
 use bytes::Bytes;
 use futures::future::BoxFuture;
 use futures::Stream;
 use futures::TryFutureExt;
-use global::get_text_map_propagator;
+use http::header::InvalidHeaderValue;
+use http::header::ACCEPT;
 use http::header::ACCEPT_ENCODING;
+use http::header::AUTHORIZATION;
 use http::header::CONTENT_ENCODING;
+use http::header::CONTENT_LENGTH;
+use http::header::EXPECT;
+use http::header::PROXY_AUTHORIZATION;
+use http::header::USER_AGENT;
 use http::HeaderValue;
 use http::Request;
-use hyper::client::HttpConnector;
+use http::Uri;
 use hyper::Body;
-use hyper_rustls::HttpsConnector;
 #[cfg(unix)]
 use hyperlocal::UnixConnector;
-use opentelemetry::global;
 use pin_project_lite::pin_project;
+use rustls::client::Resumption;
+use rustls::client::ServerCertVerifier;
+use rustls::client::WebPkiVerifier;
 use rustls::ClientConfig;
 use rustls::RootCertStore;
+use rustls::SupportedCipherSuite;
+use rustls::SupportedProtocolVersion;
 use schemars::JsonSchema;
+use tokio::time::Sleep;
 use tower::util::Either;
+use tower::util::MapResponse;
 use tower::BoxError;
 use tower::Service;
 use tower::ServiceBuilder;
@@ -32,35 +51,169 @@ use tower_http::decompression::DecompressionBody;
 use tower_http::decompression::DecompressionLayer;
 use tracing::Instrument;
 
+use super::body_limit::ReceivedBytes;
+use super::body_limit::ResponseBodyLimit;
+use super::body_limit::ResponseBodyLimitLayer;
+use super::body_log::BodyKind;
+use super::body_log::BodyLog;
+use super::body_log::LoggedBody;
+use super::certificate_authorities::ReloadingVerifier;
+use super::certificate_expiry::ExpiryVerifier;
+use super::certificate_expiry::DEFAULT_WARN_BEFORE;
+use super::client_cert;
+use super::client_cert::HostClientCert;
+use super::connect_timeout::ConnectTimeoutConnector;
+use super::connection_metrics::ConnectionMetrics;
+use super::connection_metrics::ConnectionMetricsConnector;
+use super::connection_metrics::InFlight;
+use super::drain::Drain;
+use super::empty_response::EmptyResponse;
+use super::expect_continue::ExpectContinue;
+use super::grpc;
+use super::grpc::GrpcTransport;
+use super::header_limits;
+use super::header_limits::HeaderLimits;
+use super::host_override::HostOverrides;
+use super::http2_settings::Http2SettingsConnector;
+use super::http3::Http3Client;
+use super::insecure::AcceptInvalidCerts;
+use super::keepalive::KeepaliveConnector;
+use super::local_address::LocalAddressConnector;
+use super::peer_certificate::PeerCertificate;
+use super::pinning::PinningVerifier;
+use super::pool_acquire::PoolAcquire;
+use super::priority::Priority;
+use super::priority::PRIORITY;
+use super::proxy::Proxy;
+use super::proxy::ProxyConnector;
+use super::request_size::RequestSizeRecorder;
+use super::request_size::SizeKind;
+use super::response_status::ResponseStatuses;
+use super::response_status::StatusOutcome;
+use super::revocation::RevocationVerifier;
+use super::socket_marking::SocketMarking;
+use super::stream_limit::StreamLimitedClient;
+use super::tls_error::TlsHandshakeError;
+use super::tls_handshake::HandshakeVerifier;
+use super::tls_handshake::TlsHandshakeConnector;
+use super::trace_context::inject_trace_context;
+use super::trace_context::TraceResponse;
+use super::warmup::WarmupConnector;
+use super::warmup::DEFAULT_RAMP;
 use super::HttpRequest;
 use super::HttpResponse;
 use crate::axum_factory::compression::Compressor;
+use crate::configuration::subgraph::SubgraphConfiguration;
+use crate::configuration::ConfigurationError;
+use crate::configuration::Ocsp;
+use crate::configuration::TlsClient;
 use crate::configuration::TlsClientAuth;
+use crate::configuration::TlsClientIdentity;
+use crate::configuration::TlsVersion;
 use crate::error::FetchError;
+use crate::executable::APOLLO_ROUTER_DEV_ENV;
+use crate::plugins::authentication::subgraph::AuthorizationHeader;
 use crate::plugins::authentication::subgraph::SigningParamsConfig;
-use crate::plugins::telemetry::otel::OpenTelemetrySpanExt;
-use crate::plugins::telemetry::reload::prepare_context;
+use crate::plugins::telemetry::config_new::logging::RedactedHeaders;
 use crate::plugins::telemetry::LOGGING_DISPLAY_BODY;
 use crate::plugins::telemetry::LOGGING_DISPLAY_HEADERS;
+use crate::plugins::traffic_shaping::ExpectContinueConfig;
+use crate::plugins::traffic_shaping::GrpcConfig;
+use crate::plugins::traffic_shaping::HappyEyeballsConfig;
 use crate::plugins::traffic_shaping::Http2Config;
+use crate::plugins::traffic_shaping::Http3Config;
+use crate::plugins::traffic_shaping::ProxyConfig;
+use crate::plugins::traffic_shaping::WarmupConfig;
+use crate::router_factory::load_certificate_authorities;
+use crate::router_factory::load_native_certificates;
+use crate::services::subgraph_service::ACCEPT_GRAPHQL_JSON;
 use crate::services::trust_dns_connector::new_async_http_connector;
-use crate::services::trust_dns_connector::AsyncHyperResolver;
+use crate::services::trust_dns_connector::new_bound_async_http_connector;
+use crate::services::trust_dns_connector::new_dual_stack_async_http_connector;
 use crate::Configuration;
 use crate::Context;
 
+type EncodedResponseClient<S> = MapResponse<S, fn(http::Response<Body>) -> http::Response<Body>>;
+type HTTPConnector = WarmupConnector<
+    ConnectionMetricsConnector<
+        Http2SettingsConnector<ConnectTimeoutConnector<TlsHandshakeConnector>>,
+    >,
+>;
 type HTTPClient =
-    Decompression<hyper::Client<HttpsConnector<HttpConnector<AsyncHyperResolver>>, Body>>;
+    Decompression<ResponseBodyLimit<EncodedResponseClient<StreamLimitedClient<HTTPConnector>>>>;
 #[cfg(unix)]
-type UnixHTTPClient = Decompression<hyper::Client<UnixConnector, Body>>;
+type UnixHTTPClient =
+    Decompression<ResponseBodyLimit<EncodedResponseClient<hyper::Client<UnixConnector, Body>>>>;
+type HTTP3Client = Decompression<
+    ResponseBodyLimit<MapResponse<Http3Client, fn(http::Response<Body>) -> http::Response<Body>>>,
+>;
 #[cfg(unix)]
-type MixedClient = Either<HTTPClient, UnixHTTPClient>;
+type StreamClient = Either<HTTPClient, UnixHTTPClient>;
 #[cfg(not(unix))]
-type MixedClient = HTTPClient;
+type StreamClient = HTTPClient;
+type MixedClient = Either<StreamClient, HTTP3Client>;
 
 // interior mutability is not a concern here, the value is never modified
 #[allow(clippy::declare_interior_mutable_const)]
-static ACCEPTED_ENCODINGS: HeaderValue = HeaderValue::from_static("gzip, br, deflate");
+static ACCEPTED_ENCODINGS: HeaderValue = HeaderValue::from_static("gzip, br, deflate, zstd");
+/// `{router_version}` is replaced with the version of the router
+pub(crate) const DEFAULT_USER_AGENT: &str = "apollo-router/{router_version}";
 const POOL_IDLE_TIMEOUT_DURATION: Option<Duration> = Some(Duration::from_secs(5));
+const TCP_KEEPALIVE_DURATION: Duration = Duration::from_secs(60);
+// the requests are small and latency sensitive, they are not delayed to be coalesced
+const TCP_NODELAY: bool = true;
+const CONNECT_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
+// the requests are bounded by the traffic shaping timeout, 30 seconds by default
+const DRAIN_TIMEOUT_DURATION: Duration = Duration::from_secs(30);
+const EXPECT_CONTINUE_MIN_SIZE: usize = 1024 * 1024;
+const EXPECT_CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+// recommended value of the Happy Eyeballs RFC
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// same size as the default session cache of rustls
+const TLS_SESSION_CACHE_SIZE: usize = 256;
+const ALPN_H2: &str = "h2";
+const ALPN_HTTP1: &str = "http/1.1";
+
+/// Context key of the HTTP status code of the last response received from a subgraph
+pub(crate) fn http_status_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::http_status")
+}
+
+/// Context key of the URI the last request to a subgraph was sent to
+pub(crate) fn http_uri_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::http_uri")
+}
+
+/// Context key of the certificate presented by a subgraph on the TLS connection of the last
+/// response received from it, with its `subject`, `issuer`, SHA-256 `fingerprint` and
+/// `not_after` expiry in seconds since the Unix epoch
+pub(crate) fn peer_certificate_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::peer_certificate")
+}
+
+/// Context key of the span returned by a subgraph in the `traceresponse` header of the last
+/// response received from it, with its `trace_id`, `span_id` and `sampled` flag
+pub(crate) fn trace_response_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::trace_response")
+}
+
+/// Context key of the priority of the requests to a subgraph, with its `urgency` from 0 to 7 and
+/// whether its response is `incremental`, sent in the `priority` header of RFC 9218
+pub(crate) fn priority_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::priority")
+}
+
+/// Context key of the size of the body of the last request sent to a subgraph, in `bytes`
+/// before compression, with the `compressed_bytes` sent when it is compressed
+pub(crate) fn http_request_size_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::http_request_size")
+}
+
+/// Context key of the HTTP version forced for the requests to a subgraph, overriding the HTTP/2
+/// configuration of its client. Only "HTTP/1.1" can be forced
+pub(crate) fn http_version_context_key(subgraph_name: &str) -> String {
+    format!("apollo_subgraph::{subgraph_name}::http_version")
+}
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema, Copy)]
 #[serde(rename_all = "lowercase")]
@@ -71,8 +224,13 @@ pub(crate) enum Compression {
     Deflate,
     /// brotli
     Br,
+    /// zstd
+    Zstd,
     /// identity
     Identity,
+    /// no compression negotiation: the requests are not compressed, no `Accept-Encoding` is
+    /// sent and the `Content-Encoding` of the responses is not decoded
+    Off,
 }
 
 impl Display for Compression {
@@ -81,20 +239,158 @@ impl Display for Compression {
             Compression::Gzip => write!(f, "gzip"),
             Compression::Deflate => write!(f, "deflate"),
             Compression::Br => write!(f, "br"),
+            Compression::Zstd => write!(f, "zstd"),
             Compression::Identity => write!(f, "identity"),
+            Compression::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// Compression level for subgraph requests
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema, Copy)]
+#[serde(untagged)]
+pub(crate) enum CompressionLevel {
+    Named(NamedCompressionLevel),
+    // checked against `COMPRESSION_LEVELS` by the traffic shaping plugin
+    Precise(#[schemars(range(min = 0, max = 9))] i32),
+}
+
+/// Range of the numeric compression levels
+pub(crate) const COMPRESSION_LEVELS: RangeInclusive<i32> = 0..=9;
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NamedCompressionLevel {
+    /// Fastest compression, usually produces bigger payloads
+    Fastest,
+    /// Best compression, usually produces the smallest payloads
+    Best,
+    /// Default level of the compression algorithm
+    Default,
+}
+
+impl From<CompressionLevel> for Level {
+    fn from(level: CompressionLevel) -> Self {
+        match level {
+            CompressionLevel::Named(NamedCompressionLevel::Fastest) => Level::Fastest,
+            CompressionLevel::Named(NamedCompressionLevel::Best) => Level::Best,
+            CompressionLevel::Named(NamedCompressionLevel::Default) => Level::Default,
+            CompressionLevel::Precise(level) => Level::Precise(level),
         }
     }
 }
 
+/// Per subgraph options of the HTTP client
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HttpClientConfig {
+    pub(crate) http2: Http2Config,
+    pub(crate) http3: Http3Config,
+    pub(crate) compression_level: Option<CompressionLevel>,
+    /// request bodies smaller than this size are sent uncompressed
+    pub(crate) compression_min_size: Option<usize>,
+    pub(crate) max_decompressed_bytes: Option<usize>,
+    /// maximum size of response bodies as received, before decompression
+    pub(crate) max_response_bytes: Option<usize>,
+    /// maximum size of request bodies, before compression
+    pub(crate) max_request_bytes: Option<usize>,
+    /// maximum total size of the header names and values of responses
+    pub(crate) max_response_header_size: Option<usize>,
+    /// maximum number of headers of responses
+    pub(crate) max_response_headers: Option<usize>,
+    /// large request bodies wait for the 100 (Continue) response, only set when enabled
+    pub(crate) expect_continue: Option<ExpectContinueConfig>,
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    /// connections opened before the subgraph receives traffic, only set when enabled
+    pub(crate) warmup: Option<WarmupConfig>,
+    /// HTTP2 flow control windows, the hyper defaults are used if not set
+    pub(crate) http2_initial_connection_window_size: Option<u32>,
+    pub(crate) http2_initial_stream_window_size: Option<u32>,
+    /// interval of the HTTP2 PING frames, none are sent if not set
+    pub(crate) http2_keepalive_interval: Option<Duration>,
+    pub(crate) http2_keepalive_timeout: Option<Duration>,
+    /// requests multiplexed on each HTTP2 connection before another connection is opened
+    pub(crate) http2_max_concurrent_streams: Option<usize>,
+    pub(crate) tcp_keepalive: Option<Duration>,
+    pub(crate) tcp_keepalive_interval: Option<Duration>,
+    /// `TCP_NODELAY` of the connections, enabled if not set
+    pub(crate) tcp_nodelay: Option<bool>,
+    /// DSCP value of the IP packets of the connections
+    pub(crate) dscp: Option<u8>,
+    /// `SO_PRIORITY` of the connections, on Linux
+    pub(crate) socket_priority: Option<u32>,
+    /// timeout of the TCP connection, the proxy tunnel and the TLS handshake
+    pub(crate) connect_timeout: Option<Duration>,
+    /// time a request waits for a pooled or new connection
+    pub(crate) pool_acquire_timeout: Option<Duration>,
+    /// concurrent IPv6 and IPv4 connection attempts, only set when enabled
+    pub(crate) happy_eyeballs: Option<HappyEyeballsConfig>,
+    /// local address the connections are bound to, for the subgraph hosts of its family only
+    pub(crate) local_address: Option<IpAddr>,
+    pub(crate) proxy: Option<ProxyConfig>,
+    /// addresses connected to instead of resolving subgraph hosts
+    pub(crate) host_overrides: HashMap<String, SocketAddr>,
+    /// timeout of each request, from the connection to the end of the response body
+    pub(crate) request_timeout: Option<Duration>,
+    /// time given to the requests in flight once a configuration reload replaced the client
+    pub(crate) drain_timeout: Option<Duration>,
+    /// server name used for TLS instead of the host of the subgraph URL
+    pub(crate) server_name: Option<String>,
+    /// protocols offered with ALPN, in order, instead of the ones enabled by the HTTP/2
+    /// configuration
+    pub(crate) alpn: Option<Vec<Vec<u8>>>,
+    /// unary gRPC method called instead of sending GraphQL requests over HTTP
+    pub(crate) grpc: Option<GrpcConfig>,
+    /// maximum size of the logged request and response bodies, only set when enabled
+    pub(crate) log_bodies: Option<usize>,
+    /// log the HTTP/2 settings of the subgraph on each new connection
+    pub(crate) log_http2_settings: bool,
+    /// GraphQL response media types accepted from the subgraph, instead of the default ones
+    pub(crate) accept: Option<HeaderValue>,
+    /// `User-Agent` of the requests, rendered from `DEFAULT_USER_AGENT` if not set
+    pub(crate) user_agent: Option<HeaderValue>,
+    /// responses failing the request instead of being parsed as GraphQL responses
+    pub(crate) response_statuses: ResponseStatuses,
+    /// handling of the 2xx responses with an empty body, parsed as they are if not set
+    pub(crate) empty_response: Option<EmptyResponse>,
+    /// no `Accept-Encoding` is sent, and the response bodies are passed through without
+    /// decoding their `Content-Encoding`
+    pub(crate) compression_off: bool,
+}
+
 #[derive(Clone)]
 pub(crate) struct HttpClientService {
     // Note: We use hyper::Client here in preference to reqwest to avoid expensive URL translation
     // in the hot path. We use reqwest elsewhere because it's convenient and some of the
     // opentelemetry crate require reqwest clients to work correctly (at time of writing).
     http_client: HTTPClient,
+    /// client of the requests forced to HTTP/1.1, when HTTP/2 is enabled
+    http1_client: Option<HTTPClient>,
     #[cfg(unix)]
     unix_client: UnixHTTPClient,
+    http3_client: Option<HTTP3Client>,
+    /// connector of `http_client`, keeping the warm-up connections until hyper uses them
+    connector: HTTPConnector,
+    warmup: Option<WarmupConfig>,
+    proxy: Option<Arc<Proxy>>,
+    connection_metrics: Arc<ConnectionMetrics>,
     service: Arc<String>,
+    compression_level: Option<CompressionLevel>,
+    compression_min_size: Option<usize>,
+    max_decompressed_bytes: Option<usize>,
+    max_request_bytes: Option<usize>,
+    header_limits: HeaderLimits,
+    expect_continue: Option<ExpectContinueConfig>,
+    pool_acquire_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    drain: Arc<Drain>,
+    grpc: Option<Arc<GrpcTransport>>,
+    log_bodies: Option<usize>,
+    accept: Option<HeaderValue>,
+    user_agent: HeaderValue,
+    response_statuses: Arc<ResponseStatuses>,
+    empty_response: Option<EmptyResponse>,
+    compression_off: bool,
 }
 
 impl HttpClientService {
@@ -102,15 +398,14 @@ impl HttpClientService {
         service: impl Into<String>,
         configuration: &Configuration,
         tls_root_store: &RootCertStore,
-        http2: Http2Config,
+        mut client_config: HttpClientConfig,
     ) -> Result<Self, BoxError> {
         let name: String = service.into();
-        let tls_cert_store = configuration
-            .tls
-            .subgraph
+        client_config.server_name = Self::server_name(&name, configuration)?;
+        let tls = &configuration.tls.subgraph;
+        let tls_cert_store = tls
             .subgraphs
             .get(&name)
-            .as_ref()
             .and_then(|subgraph| subgraph.create_certificate_store())
             .transpose()?
             .unwrap_or_else(|| tls_root_store.clone());
@@ -127,49 +422,659 @@ impl HttpClientService {
                 .all
                 .client_authentication
                 .as_ref());
+        let client_identities = configuration
+            .tls
+            .subgraph
+            .subgraphs
+            .get(&name)
+            .and_then(|tls| tls.client_identities.as_deref())
+            .or(configuration.tls.subgraph.all.client_identities.as_deref());
+
+        let mut verifier = Self::verifier(&name, tls, tls_cert_store)?;
+        // the subgraph uses the roots of `all` unless it sets its own
+        let roots_config = tls
+            .subgraphs
+            .get(&name)
+            .filter(|tls| tls.sets_roots())
+            .unwrap_or(&tls.all);
+        if let Some(path) = &roots_config.certificate_authorities_file {
+            let build = {
+                let (name, tls, roots_config) = (name.clone(), tls.clone(), roots_config.clone());
+                move || {
+                    let tls_cert_store = roots_config
+                        .create_certificate_store()
+                        .expect("the certificate authorities file is set")?;
+                    Self::verifier(&name, &tls, tls_cert_store)
+                }
+            };
+            verifier = Arc::new(ReloadingVerifier::new(&name, path, verifier, build));
+        }
+        if Self::accepts_invalid_certs(&name, configuration, is_dev_mode())? {
+            verifier = Arc::new(AcceptInvalidCerts);
+        }
+        client_config.alpn = Self::alpn(&name, configuration, &client_config)?;
+        let protocol_versions = Self::protocol_versions(&name, configuration, &client_config)?;
+        let cipher_suites =
+            Self::cipher_suites(&name, configuration, &client_config, &protocol_versions)?;
+
+        let mut tls_client_config = generate_tls_client_config(
+            &name,
+            client_cert_config,
+            client_identities,
+            verifier,
+            &protocol_versions,
+            &cipher_suites,
+        )?;
+        tls_client_config.enable_sni = configuration
+            .tls
+            .subgraph
+            .subgraphs
+            .get(&name)
+            .and_then(|tls| tls.send_sni)
+            .or(configuration.tls.subgraph.all.send_sni)
+            .unwrap_or(true);
+        tls_client_config.resumption = Self::resumption(&name, configuration)?;
+
+        HttpClientService::new(name, client_config, tls_client_config)
+    }
+
+    fn server_name(
+        name: &str,
+        configuration: &Configuration,
+    ) -> Result<Option<String>, ConfigurationError> {
+        if configuration.tls.subgraph.all.server_name.is_some() {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad TLS configuration for subgraph",
+                error: "server_name can only be set per subgraph, in tls.subgraph.subgraphs"
+                    .to_string(),
+            });
+        }
+        let Some(server_name) = configuration
+            .tls
+            .subgraph
+            .subgraphs
+            .get(name)
+            .and_then(|tls| tls.server_name.as_ref())
+        else {
+            return Ok(None);
+        };
+        if rustls::ServerName::try_from(server_name.as_str()).is_err() {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad TLS configuration for subgraph",
+                error: format!("subgraph '{name}' has an invalid server_name '{server_name}'"),
+            });
+        }
+        Ok(Some(server_name.clone()))
+    }
+
+    fn accepts_invalid_certs(
+        name: &str,
+        configuration: &Configuration,
+        dev_mode: bool,
+    ) -> Result<bool, ConfigurationError> {
+        let error = |error: String| ConfigurationError::InvalidConfiguration {
+            message: "bad TLS configuration for subgraph",
+            error,
+        };
+        if configuration
+            .tls
+            .subgraph
+            .all
+            .dangerous_accept_invalid_certs
+            .is_some()
+        {
+            return Err(error(
+                "dangerous_accept_invalid_certs can only be set per subgraph, in tls.subgraph.subgraphs"
+                    .to_string(),
+            ));
+        }
+        let accepts_invalid_certs = configuration
+            .tls
+            .subgraph
+            .subgraphs
+            .get(name)
+            .and_then(|tls| tls.dangerous_accept_invalid_certs)
+            .unwrap_or(false);
+        if !accepts_invalid_certs {
+            return Ok(false);
+        }
+        if !dev_mode {
+            return Err(error(format!(
+                "subgraph '{name}' sets dangerous_accept_invalid_certs, which is only allowed in development mode (--dev)"
+            )));
+        }
+        tracing::warn!(
+            "the certificates of subgraph '{name}' are NOT verified, because dangerous_accept_invalid_certs is set: any server can impersonate this subgraph. Never use this setting in production"
+        );
+        Ok(true)
+    }
+
+    fn alpn(
+        name: &str,
+        configuration: &Configuration,
+        client_config: &HttpClientConfig,
+    ) -> Result<Option<Vec<Vec<u8>>>, ConfigurationError> {
+        let Some(alpn) = configuration
+            .tls
+            .subgraph
+            .subgraphs
+            .get(name)
+            .and_then(|tls| tls.alpn.as_ref())
+            .or(configuration.tls.subgraph.all.alpn.as_ref())
+        else {
+            return Ok(None);
+        };
+        let error = |error: String| ConfigurationError::InvalidConfiguration {
+            message: "bad TLS configuration for subgraph",
+            error,
+        };
+        // the client only speaks these protocols on TCP connections
+        if let Some(protocol) = alpn
+            .iter()
+            .find(|protocol| *protocol != ALPN_H2 && *protocol != ALPN_HTTP1)
+        {
+            return Err(error(format!(
+                "subgraph '{name}' has an unsupported ALPN protocol '{protocol}', only h2 and http/1.1 can be offered"
+            )));
+        }
+        let offers_h2 = alpn.iter().any(|protocol| protocol == ALPN_H2);
+        if offers_h2 && client_config.http2 == Http2Config::Disable {
+            return Err(error(format!(
+                "subgraph '{name}' offers h2 with ALPN, but HTTP/2 is disabled for it"
+            )));
+        }
+        if !offers_h2 && client_config.http2 == Http2Config::Http2Only {
+            return Err(error(format!(
+                "subgraph '{name}' only uses HTTP/2, but h2 is not among its ALPN protocols"
+            )));
+        }
+        Ok(Some(
+            alpn.iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect(),
+        ))
+    }
+
+    fn resumption(
+        name: &str,
+        configuration: &Configuration,
+    ) -> Result<Resumption, ConfigurationError> {
+        let subgraph = configuration.tls.subgraph.subgraphs.get(name);
+        let all = &configuration.tls.subgraph.all;
+        if !subgraph
+            .and_then(|tls| tls.session_resumption)
+            .or(all.session_resumption)
+            .unwrap_or(true)
+        {
+            return Ok(Resumption::disabled());
+        }
+        match subgraph
+            .and_then(|tls| tls.session_cache_size)
+            .or(all.session_cache_size)
+            .unwrap_or(TLS_SESSION_CACHE_SIZE)
+        {
+            0 => Err(ConfigurationError::InvalidConfiguration {
+                message: "bad TLS configuration for subgraph",
+                error: format!(
+                    "subgraph '{name}' has a session_cache_size of 0, set session_resumption to false to disable the resumption instead"
+                ),
+            }),
+            size => Ok(Resumption::in_memory_sessions(size)),
+        }
+    }
 
-        let tls_client_config = generate_tls_client_config(tls_cert_store, client_cert_config)?;
+    fn protocol_versions(
+        name: &str,
+        configuration: &Configuration,
+        client_config: &HttpClientConfig,
+    ) -> Result<Vec<&'static SupportedProtocolVersion>, ConfigurationError> {
+        let subgraph = configuration.tls.subgraph.subgraphs.get(name);
+        let all = &configuration.tls.subgraph.all;
+        let min_version = subgraph
+            .and_then(|tls| tls.min_version)
+            .or(all.min_version)
+            .unwrap_or(TlsVersion::Tls12);
+        let max_version = subgraph
+            .and_then(|tls| tls.max_version)
+            .or(all.max_version)
+            .unwrap_or(TlsVersion::Tls13);
 
-        HttpClientService::new(name, http2, tls_client_config)
+        if min_version > max_version {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad TLS configuration for subgraph",
+                error: format!(
+                    "subgraph '{name}' has a min_version ({min_version}) greater than its max_version ({max_version})"
+                ),
+            });
+        }
+        if max_version < TlsVersion::Tls13 && client_config.http3 != Http3Config::Disable {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad TLS configuration for subgraph",
+                error: format!(
+                    "subgraph '{name}' uses HTTP/3, which requires tls1.3, but its max_version is {max_version}"
+                ),
+            });
+        }
+
+        Ok([
+            (TlsVersion::Tls12, &rustls::version::TLS12),
+            (TlsVersion::Tls13, &rustls::version::TLS13),
+        ]
+        .into_iter()
+        .filter(|(version, _)| (min_version..=max_version).contains(version))
+        .map(|(_, supported)| supported)
+        .collect())
+    }
+
+    fn cipher_suites(
+        name: &str,
+        configuration: &Configuration,
+        client_config: &HttpClientConfig,
+        protocol_versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<Vec<SupportedCipherSuite>, ConfigurationError> {
+        let Some(names) = configuration
+            .tls
+            .subgraph
+            .subgraphs
+            .get(name)
+            .and_then(|tls| tls.cipher_suites.as_ref())
+            .or(configuration.tls.subgraph.all.cipher_suites.as_ref())
+        else {
+            return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec());
+        };
+
+        let error = |error| ConfigurationError::InvalidConfiguration {
+            message: "bad TLS configuration for subgraph",
+            error,
+        };
+        if names.is_empty() {
+            return Err(error(format!(
+                "subgraph '{name}' has an empty cipher_suites list"
+            )));
+        }
+        let cipher_suites = names
+            .iter()
+            .map(|suite_name| {
+                rustls::ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| cipher_suite_matches(suite, suite_name))
+                    .copied()
+                    .ok_or_else(|| {
+                        let supported = rustls::ALL_CIPHER_SUITES
+                            .iter()
+                            .map(|suite| format!("{:?}", suite.suite()))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        error(format!(
+                            "subgraph '{name}' uses the unsupported cipher suite '{suite_name}', the supported cipher suites are: {supported}"
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !cipher_suites.iter().any(|suite| {
+            protocol_versions
+                .iter()
+                .any(|version| version.version == suite.version().version)
+        }) {
+            return Err(error(format!(
+                "subgraph '{name}' has no cipher suite for the TLS versions it uses"
+            )));
+        }
+        if client_config.http3 != Http3Config::Disable
+            && !cipher_suites
+                .iter()
+                .any(|suite| suite.version().version == rustls::ProtocolVersion::TLSv1_3)
+        {
+            return Err(error(format!(
+                "subgraph '{name}' uses HTTP/3, which requires a tls1.3 cipher suite"
+            )));
+        }
+
+        Ok(cipher_suites)
+    }
+
+    /// Verifier of the subgraph certificates, with the revocation checks and the pinned keys of
+    /// the configuration
+    fn verifier(
+        name: &str,
+        tls: &SubgraphConfiguration<TlsClient>,
+        tls_cert_store: RootCertStore,
+    ) -> Result<Arc<dyn ServerCertVerifier>, BoxError> {
+        // same verifier as the one set up by `with_root_certificates`, unless revocation checks
+        // are enabled
+        let verifier = Self::revocation_verifier(name, tls)?
+            .unwrap_or_else(|| Arc::new(WebPkiVerifier::new(tls_cert_store, None)));
+        let subgraph = tls.subgraphs.get(name);
+        let all = &tls.all;
+        let verifier: Arc<dyn ServerCertVerifier> = match subgraph
+            .and_then(|tls| tls.pinned_public_keys.as_ref())
+            .or(all.pinned_public_keys.as_ref())
+        {
+            Some(pinned_public_keys) => {
+                Arc::new(PinningVerifier::new(name, verifier, pinned_public_keys)?)
+            }
+            None => verifier,
+        };
+
+        let certificate_expiry =
+            |tls: &TlsClient| tls.certificate_expiry.clone().unwrap_or_default();
+        let subgraph_expiry = subgraph.map(certificate_expiry).unwrap_or_default();
+        let all_expiry = certificate_expiry(all);
+        let warn_before = subgraph_expiry
+            .warn_before
+            .or(all_expiry.warn_before)
+            .unwrap_or(DEFAULT_WARN_BEFORE);
+        let reject_before = subgraph_expiry.reject_before.or(all_expiry.reject_before);
+        if warn_before.is_zero() && reject_before.is_none() {
+            return Ok(verifier);
+        }
+        Ok(Arc::new(ExpiryVerifier::new(
+            name,
+            verifier,
+            warn_before,
+            reject_before,
+        )))
+    }
+
+    fn revocation_verifier(
+        name: &str,
+        tls: &SubgraphConfiguration<TlsClient>,
+    ) -> Result<Option<Arc<dyn ServerCertVerifier>>, BoxError> {
+        let subgraph = tls.subgraphs.get(name);
+        let all = &tls.all;
+        let certificate_revocation_lists = subgraph
+            .and_then(|tls| tls.certificate_revocation_lists.as_deref())
+            .or(all.certificate_revocation_lists.as_deref());
+        let ocsp = subgraph
+            .and_then(|tls| tls.ocsp)
+            .or(all.ocsp)
+            .unwrap_or_default();
+        if certificate_revocation_lists.is_none() && ocsp == Ocsp::Off {
+            return Ok(None);
+        }
+        let expired_crl = subgraph
+            .and_then(|tls| tls.expired_crl)
+            .or(all.expired_crl)
+            .unwrap_or_default();
+
+        // the revocation checks need the root certificates themselves, not only the store, so
+        // they are loaded from the configuration the store of the subgraph comes from
+        let roots_config = subgraph.filter(|tls| tls.sets_roots()).unwrap_or(all);
+        let mut roots =
+            load_certificate_authorities(roots_config.read_certificate_authorities()?.as_ref())?;
+        if roots_config.uses_native_roots() {
+            roots.extend(load_native_certificates()?);
+        }
+
+        Ok(Some(Arc::new(RevocationVerifier::new(
+            name,
+            roots,
+            certificate_revocation_lists,
+            expired_crl,
+            ocsp,
+        )?)))
     }
 
     pub(crate) fn new(
         service: impl Into<String>,
-        http2: Http2Config,
+        client_config: HttpClientConfig,
         tls_config: ClientConfig,
     ) -> Result<Self, BoxError> {
-        let mut http_connector = new_async_http_connector()?;
-        http_connector.set_nodelay(true);
-        http_connector.set_keepalive(Some(std::time::Duration::from_secs(60)));
+        let service = service.into();
+        let http2 = client_config.http2;
+        // the connections bound to a local address use a single address family
+        let mut http_connector = match (&client_config.local_address, &client_config.happy_eyeballs)
+        {
+            (Some(local_address), _) => new_bound_async_http_connector(*local_address)?,
+            (None, Some(happy_eyeballs)) => new_dual_stack_async_http_connector(
+                happy_eyeballs
+                    .connection_attempt_delay
+                    .unwrap_or(CONNECTION_ATTEMPT_DELAY),
+            )?,
+            (None, None) => new_async_http_connector()?,
+        };
+        http_connector.set_nodelay(client_config.tcp_nodelay.unwrap_or(TCP_NODELAY));
         http_connector.enforce_http(false);
+        let http_connector = KeepaliveConnector::new(
+            http_connector,
+            client_config
+                .tcp_keepalive
+                .unwrap_or(TCP_KEEPALIVE_DURATION),
+            client_config.tcp_keepalive_interval,
+            SocketMarking::new(&service, client_config.dscp, client_config.socket_priority),
+        );
+        let proxy = client_config
+            .proxy
+            .as_ref()
+            .map(Proxy::new)
+            .transpose()?
+            .map(Arc::new);
+        let host_overrides = Arc::new(HostOverrides::new(&client_config.host_overrides));
+        let http_connector =
+            LocalAddressConnector::new(http_connector, client_config.local_address);
+        let http_connector =
+            ProxyConnector::new(http_connector, proxy.clone(), host_overrides.clone());
 
-        let builder = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_tls_config(tls_config)
-            .https_or_http()
-            .enable_http1();
+        let http3_tls_config =
+            (client_config.http3 != Http3Config::Disable).then(|| tls_config.clone());
+        let connect_timeout = client_config
+            .connect_timeout
+            .unwrap_or(CONNECT_TIMEOUT_DURATION);
+        let connection_metrics = Arc::new(ConnectionMetrics::new(&service));
+        let pool_idle_timeout = client_config
+            .pool_idle_timeout
+            .or(POOL_IDLE_TIMEOUT_DURATION);
+        let https_connector = |mut tls_config: ClientConfig, enable_http2: bool| -> HTTPConnector {
+            // the connector only sets the protocols of the TLS configuration when it enables
+            // HTTP/2, the configured ones are kept otherwise
+            let alpn = client_config.alpn.as_ref().map(|alpn| {
+                alpn.iter()
+                    .filter(|protocol| enable_http2 || protocol.as_slice() != ALPN_H2.as_bytes())
+                    .cloned()
+                    .collect()
+            });
+            let custom_alpn = alpn.is_some();
+            if let Some(alpn) = alpn {
+                tls_config.alpn_protocols = alpn;
+            }
+            let mut builder = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls_config)
+                .https_or_http();
+            if let Some(server_name) = client_config.server_name.clone() {
+                builder = builder.with_server_name(server_name);
+            }
+            let builder = builder.enable_http1();
 
-        let connector = if http2 != Http2Config::Disable {
-            builder.enable_http2().wrap_connector(http_connector)
-        } else {
-            builder.wrap_connector(http_connector)
+            let connector = if enable_http2 && !custom_alpn {
+                builder
+                    .enable_http2()
+                    .wrap_connector(http_connector.clone())
+            } else {
+                builder.wrap_connector(http_connector.clone())
+            };
+            let connector = TlsHandshakeConnector::new(connector, &service);
+            let connector = ConnectTimeoutConnector::new(connector, connect_timeout);
+            let connector =
+                Http2SettingsConnector::new(connector, &service, client_config.log_http2_settings);
+            let connector = ConnectionMetricsConnector::new(connector, connection_metrics.clone());
+            WarmupConnector::new(connector, pool_idle_timeout)
         };
+        // the connections of the requests forced to HTTP/1.1 do not negotiate HTTP/2
+        let http1_connector =
+            (http2 != Http2Config::Disable).then(|| https_connector(tls_config.clone(), false));
+        let connector = https_connector(tls_config, http2 != Http2Config::Disable);
 
-        let http_client = hyper::Client::builder()
-            .pool_idle_timeout(POOL_IDLE_TIMEOUT_DURATION)
+        // hyper does not limit the number of idle connections by default
+        let pool_max_idle_per_host = client_config.pool_max_idle_per_host.unwrap_or(usize::MAX);
+        let mut client_builder = hyper::Client::builder();
+        client_builder
+            .pool_idle_timeout(pool_idle_timeout)
+            .pool_max_idle_per_host(pool_max_idle_per_host)
             .http2_only(http2 == Http2Config::Http2Only)
-            .build(connector);
+            .http2_initial_connection_window_size(
+                client_config.http2_initial_connection_window_size,
+            )
+            .http2_initial_stream_window_size(client_config.http2_initial_stream_window_size);
+        let header_limits = HeaderLimits {
+            max_size: client_config.max_response_header_size,
+            max_count: client_config.max_response_headers,
+        };
+        if let Some(max_buf_size) = header_limits.http1_max_buf_size() {
+            client_builder.http1_max_buf_size(max_buf_size);
+        }
+        if let Some(interval) = client_config.http2_keepalive_interval {
+            // the PINGs are sent on idle connections too, which are the ones dropped by
+            // intermediaries
+            client_builder
+                .http2_keep_alive_interval(interval)
+                .http2_keep_alive_while_idle(true);
+            if let Some(timeout) = client_config.http2_keepalive_timeout {
+                client_builder.http2_keep_alive_timeout(timeout);
+            }
+        }
+        let http_client = StreamLimitedClient::new(
+            client_builder.clone(),
+            connector.clone(),
+            client_config.http2_max_concurrent_streams,
+        );
+        let body_limit = ResponseBodyLimitLayer::new(&service, client_config.max_response_bytes);
+        // the decompression layer sends the `Accept-Encoding` of the algorithms it decodes
+        let (decompression, prepare_response): (
+            _,
+            fn(http::Response<Body>) -> http::Response<Body>,
+        ) = if client_config.compression_off {
+            (
+                DecompressionLayer::new()
+                    .no_gzip()
+                    .no_deflate()
+                    .no_br()
+                    .no_zstd(),
+                pass_through_encoded_response,
+            )
+        } else {
+            (DecompressionLayer::new(), prepare_encoded_response)
+        };
+        let http1_client = http1_connector.map(|connector| {
+            let mut client_builder = client_builder.clone();
+            client_builder.http2_only(false);
+            ServiceBuilder::new()
+                .layer(decompression.clone())
+                .layer(body_limit.clone())
+                .map_response(prepare_response)
+                .service(StreamLimitedClient::new(client_builder, connector, None))
+        });
+        let http3_client = match http3_tls_config {
+            Some(tls_config) => {
+                let fallback =
+                    (client_config.http3 == Http3Config::Enable).then(|| http_client.clone());
+                Some(
+                    ServiceBuilder::new()
+                        .layer(decompression.clone())
+                        .layer(body_limit.clone())
+                        .map_response(prepare_response)
+                        .service(Http3Client::new(
+                            tls_config,
+                            fallback,
+                            client_config.server_name.clone(),
+                            connect_timeout,
+                            host_overrides,
+                        )?),
+                )
+            }
+            None => None,
+        };
+        let grpc = client_config
+            .grpc
+            .as_ref()
+            .map(|grpc| GrpcTransport::new(&grpc.service, &grpc.method))
+            .transpose()?
+            .map(Arc::new);
         Ok(Self {
             http_client: ServiceBuilder::new()
-                .layer(DecompressionLayer::new())
+                .layer(decompression.clone())
+                .layer(body_limit.clone())
+                .map_response(prepare_response)
                 .service(http_client),
+            http1_client,
             #[cfg(unix)]
             unix_client: ServiceBuilder::new()
-                .layer(DecompressionLayer::new())
-                .service(hyper::Client::builder().build(UnixConnector)),
-            service: Arc::new(service.into()),
+                .layer(decompression.clone())
+                .layer(body_limit)
+                .map_response(prepare_response)
+                .service(client_builder.build(UnixConnector)),
+            http3_client,
+            connector,
+            // the HTTP3 connections are not opened by the connector
+            warmup: client_config
+                .warmup
+                .clone()
+                .filter(|_| client_config.http3 != Http3Config::Http3Only),
+            proxy,
+            connection_metrics,
+            service: Arc::new(service),
+            compression_level: client_config.compression_level,
+            compression_min_size: client_config.compression_min_size,
+            max_decompressed_bytes: client_config.max_decompressed_bytes,
+            max_request_bytes: client_config.max_request_bytes,
+            header_limits,
+            // HTTP2 connections do not need it, the body is flow controlled by the subgraph
+            expect_continue: client_config
+                .expect_continue
+                .filter(|_| http2 != Http2Config::Http2Only),
+            pool_acquire_timeout: client_config.pool_acquire_timeout,
+            request_timeout: client_config.request_timeout,
+            drain: Arc::new(Drain::new(
+                client_config
+                    .drain_timeout
+                    .unwrap_or(DRAIN_TIMEOUT_DURATION),
+            )),
+            grpc,
+            log_bodies: client_config.log_bodies,
+            accept: client_config.accept.clone(),
+            user_agent: match &client_config.user_agent {
+                Some(user_agent) => user_agent.clone(),
+                None => user_agent(DEFAULT_USER_AGENT).expect("the default user agent is valid"),
+            },
+            response_statuses: Arc::new(client_config.response_statuses.clone()),
+            empty_response: client_config.empty_response,
+            compression_off: client_config.compression_off,
         })
     }
 
+    /// Opens the warm-up connections to the subgraph, if enabled, before it receives traffic
+    pub(crate) async fn warm_up(&self, uri: &Uri) {
+        // the Unix socket connections are not opened by the connector
+        let Some(warmup) = self
+            .warmup
+            .as_ref()
+            .filter(|_| uri.scheme_str() != Some("unix"))
+        else {
+            return;
+        };
+        let errors = self
+            .connector
+            .warm_up(uri, warmup.connections, warmup.ramp.unwrap_or(DEFAULT_RAMP))
+            .await;
+        let opened = warmup.connections - errors.len();
+        match errors.first() {
+            None => tracing::debug!(
+                "opened {opened} warm-up connections to subgraph '{}'",
+                self.service
+            ),
+            Some(error) => tracing::warn!(
+                "opened {opened} of the {} warm-up connections to subgraph '{}': {error}",
+                warmup.connections,
+                self.service
+            ),
+        }
+    }
+
+    /// Drain state of the client, started once a configuration reload replaced its router
+    pub(crate) fn drain(&self) -> Arc<Drain> {
+        self.drain.clone()
+    }
+
     pub(crate) fn native_roots_store() -> RootCertStore {
         let mut roots = rustls::RootCertStore::empty();
         let mut valid_count = 0;
@@ -197,21 +1102,93 @@ impl HttpClientService {
     }
 }
 
+/// Renders a `User-Agent` template, replacing `{router_version}` with the version of the router
+pub(crate) fn user_agent(template: &str) -> Result<HeaderValue, InvalidHeaderValue> {
+    HeaderValue::from_str(&template.replace("{router_version}", std::env!("CARGO_PKG_VERSION")))
+}
+
+// Some subgraphs send a content-encoding with a body that is not encoded. With compression off,
+// the header is dropped so that the body reaches the router as it was received.
+fn pass_through_encoded_response(mut response: http::Response<Body>) -> http::Response<Body> {
+    response.headers_mut().remove(CONTENT_ENCODING);
+    response
+}
+
+/// Marks responses whose body goes through the decompression layer, with their content-encoding
+#[derive(Clone, Debug)]
+struct EncodedBody(String);
+
+// The decoders expect at least a header for the compressed stream, so an empty body with a
+// content-encoding would fail to decode. There is nothing to decompress in that case.
+fn prepare_encoded_response(mut response: http::Response<Body>) -> http::Response<Body> {
+    use hyper::body::HttpBody;
+
+    let body = response.body();
+    if body.is_end_stream() || HttpBody::size_hint(body).exact() == Some(0) {
+        response.headers_mut().remove(CONTENT_ENCODING);
+    } else if let Some(encoding) = response.headers().get(CONTENT_ENCODING) {
+        let encoding = String::from_utf8_lossy(encoding.as_bytes())
+            .trim()
+            .to_ascii_lowercase();
+        response.extensions_mut().insert(EncodedBody(encoding));
+    }
+    response
+}
+
+/// Error returned while reading a subgraph response body once its decompressed size goes over
+/// `max_decompressed_bytes`
+#[derive(Debug, thiserror::Error)]
+#[error("decompressed response from subgraph '{service}' exceeds the `max_decompressed_bytes` limit of {limit} bytes")]
+pub(crate) struct DecompressedBodyLimitError {
+    service: Arc<String>,
+    limit: usize,
+}
+
+// the `--dev` option is copied to this environment variable, like the other options
+fn is_dev_mode() -> bool {
+    std::env::var(APOLLO_ROUTER_DEV_ENV).ok().as_deref() == Some("true")
+}
+
+// accepts both the IANA name and the rustls name of TLS 1.3 cipher suites, like
+// TLS_AES_256_GCM_SHA384 and TLS13_AES_256_GCM_SHA384
+fn cipher_suite_matches(suite: &SupportedCipherSuite, name: &str) -> bool {
+    let suite_name = format!("{:?}", suite.suite());
+    let name = name.trim();
+    name.eq_ignore_ascii_case(&suite_name)
+        || suite_name.strip_prefix("TLS13_").map_or(false, |rest| {
+            name.eq_ignore_ascii_case(&format!("TLS_{rest}"))
+        })
+}
+
 pub(crate) fn generate_tls_client_config(
-    tls_cert_store: RootCertStore,
+    subgraph: &str,
     client_cert_config: Option<&TlsClientAuth>,
+    client_identities: Option<&[TlsClientIdentity]>,
+    verifier: Arc<dyn ServerCertVerifier>,
+    protocol_versions: &[&'static SupportedProtocolVersion],
+    cipher_suites: &[SupportedCipherSuite],
 ) -> Result<rustls::ClientConfig, BoxError> {
-    let tls_builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let tls_builder = rustls::ClientConfig::builder()
+        .with_cipher_suites(cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(protocol_versions)?;
+    let verifier = Arc::new(HandshakeVerifier::new(verifier));
+    let tls_builder = tls_builder.with_custom_certificate_verifier(verifier);
+    if let Some(client_identities) = client_identities {
+        return Ok(
+            tls_builder.with_client_cert_resolver(Arc::new(HostClientCert::new(
+                subgraph,
+                client_identities,
+                client_cert_config,
+            )?)),
+        );
+    }
     Ok(match client_cert_config {
-        Some(client_auth_config) => tls_builder
-            .with_root_certificates(tls_cert_store)
-            .with_client_auth_cert(
-                client_auth_config.certificate_chain.clone(),
-                client_auth_config.key.clone(),
-            )?,
-        None => tls_builder
-            .with_root_certificates(tls_cert_store)
-            .with_no_client_auth(),
+        // reloaded when its files change, if it was loaded from files
+        Some(client_auth) => {
+            tls_builder.with_client_cert_resolver(client_cert::resolver(subgraph, client_auth)?)
+        }
+        None => tls_builder.with_no_client_auth(),
     })
 }
 
@@ -221,9 +1198,7 @@ impl tower::Service<HttpRequest> for HttpClientService {
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.http_client
-            .poll_ready(cx)
-            .map(|res| res.map_err(|e| Box::new(e) as BoxError))
+        self.http_client.poll_ready(cx).map_err(Into::into)
     }
 
     fn call(&mut self, request: HttpRequest) -> Self::Future {
@@ -232,6 +1207,53 @@ impl tower::Service<HttpRequest> for HttpClientService {
             context,
         } = request;
 
+        // checked before the compression, the size of the compressed body cannot be predicted.
+        // The size of streamed bodies is not known, the bodies of subgraph requests are not
+        // streamed
+        if let (Some(max_request_bytes), Some(size)) = (
+            self.max_request_bytes,
+            hyper::body::HttpBody::size_hint(http_request.body()).exact(),
+        ) {
+            if size > max_request_bytes as u64 {
+                let error = FetchError::SubrequestHttpError {
+                    status_code: None,
+                    service: self.service.to_string(),
+                    reason: format!(
+                        "request body of {size} bytes exceeds the `max_request_bytes` limit of {max_request_bytes} bytes"
+                    ),
+                };
+                return Box::pin(std::future::ready(Err(error.into())));
+            }
+        }
+
+        // escape hatch to reproduce the issues specific to a protocol version
+        let http1_forced = match context.get::<_, String>(http_version_context_key(&self.service)) {
+            Ok(None) => false,
+            Ok(Some(version)) if version == "HTTP/1.1" => true,
+            _ => {
+                let error = FetchError::SubrequestHttpError {
+                    status_code: None,
+                    service: self.service.to_string(),
+                    reason: "only \"HTTP/1.1\" can be forced as the HTTP version in the context"
+                        .to_string(),
+                };
+                return Box::pin(std::future::ready(Err(error.into())));
+            }
+        };
+
+        // the priority is a hint, the request is sent without it when it is invalid
+        let priority = context
+            .get::<_, Priority>(priority_context_key(&self.service))
+            .map_err(|e| format!("invalid priority in the context: {e}"))
+            .and_then(|priority| priority.map(|priority| priority.header_value()).transpose())
+            .unwrap_or_else(|e| {
+                tracing::warn!(
+                    "the priority of the request to subgraph '{}' is not sent: {e}",
+                    self.service
+                );
+                None
+            });
+
         let schema_uri = http_request.uri();
         let host = schema_uri.host().unwrap_or_default();
         let port = schema_uri.port_u16().unwrap_or_else(|| {
@@ -245,15 +1267,51 @@ impl tower::Service<HttpRequest> for HttpClientService {
             }
         });
 
+        let http_client = match &self.http1_client {
+            Some(http1_client) if http1_forced => http1_client.clone(),
+            _ => self.http_client.clone(),
+        };
         #[cfg(unix)]
         let client = match schema_uri.scheme().map(|s| s.as_str()) {
             Some("unix") => Either::B(self.unix_client.clone()),
-            _ => Either::A(self.http_client.clone()),
+            _ => Either::A(http_client),
+        };
+        #[cfg(not(unix))]
+        let client = http_client;
+        // QUIC connections cannot go through the proxy
+        let proxied = self
+            .proxy
+            .as_ref()
+            .map_or(false, |proxy| proxy.is_proxied(schema_uri));
+        let client = match &self.http3_client {
+            Some(http3_client)
+                if schema_uri.scheme_str() == Some("https") && !proxied && !http1_forced =>
+            {
+                Either::B(http3_client.clone())
+            }
+            _ => Either::A(client),
         };
+        // only the requests sent with the HTTP client use the connections of its pool
+        #[cfg(unix)]
+        let pooled = matches!(client, Either::A(Either::A(_)));
         #[cfg(not(unix))]
-        let client = self.http_client.clone();
+        let pooled = matches!(client, Either::A(_));
+        let in_flight = pooled.then(|| self.connection_metrics.request());
+        let connection_metrics = self.connection_metrics.clone();
+        let proxy_authorization = self
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.forward_authorization(schema_uri));
 
         let service_name = self.service.clone();
+        let max_decompressed_bytes = self.max_decompressed_bytes;
+        let log_bodies = self.log_bodies;
+        // started before signing the request, which can also take time
+        let deadline = self.request_timeout.map(|timeout| Deadline {
+            service: service_name.clone(),
+            start: Instant::now(),
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        });
 
         let path = schema_uri.path();
 
@@ -267,30 +1325,131 @@ impl tower::Service<HttpRequest> for HttpClientService {
             //"apollo.subgraph.name" = %service_name,
             //"graphql.operation.name" = %operation_name,
         );
-        get_text_map_propagator(|propagator| {
-            propagator.inject_context(
-                &prepare_context(http_req_span.context()),
-                &mut opentelemetry_http::HeaderInjector(http_request.headers_mut()),
-            );
-        });
+        inject_trace_context(http_request.headers_mut(), &context, &http_req_span);
+        if let Some(authorization) = proxy_authorization {
+            http_request
+                .headers_mut()
+                .insert(PROXY_AUTHORIZATION, authorization);
+        }
+
+        let (mut parts, body) = http_request.into_parts();
 
-        let (parts, body) = http_request.into_parts();
+        // only sent on the connections of the pool, which can be dedicated to the request
+        let expect_continue = self
+            .expect_continue
+            .as_ref()
+            .filter(|_| pooled && !proxied && self.grpc.is_none())
+            .filter(|expect_continue| {
+                let min_size = expect_continue.min_size.unwrap_or(EXPECT_CONTINUE_MIN_SIZE);
+                hyper::body::HttpBody::size_hint(&body)
+                    .exact()
+                    .is_some_and(|size| size >= min_size as u64)
+            })
+            .map(|expect_continue| ExpectContinue {
+                timeout: expect_continue.timeout.unwrap_or(EXPECT_CONTINUE_TIMEOUT),
+            });
 
+        // gRPC messages are not compressed with the HTTP content-encoding
+        if self.grpc.is_some() || self.compression_off {
+            parts.headers.remove(CONTENT_ENCODING);
+        }
+        // small bodies are sent uncompressed, the size of streamed bodies is not known
+        if let (Some(min_size), Some(size)) = (
+            self.compression_min_size,
+            hyper::body::HttpBody::size_hint(&body).exact(),
+        ) {
+            if size < min_size as u64 {
+                parts.headers.remove(CONTENT_ENCODING);
+            }
+        }
         let content_encoding = parts.headers.get(&CONTENT_ENCODING);
         let opt_compressor = content_encoding
             .as_ref()
             .and_then(|value| value.to_str().ok())
-            .and_then(|v| Compressor::new(v.split(',').map(|s| s.trim())));
+            .and_then(|v| {
+                let encodings = v.split(',').map(|s| s.trim());
+                match self.compression_level {
+                    Some(level) => Compressor::with_level(encodings, level.into()),
+                    None => Compressor::new(encodings),
+                }
+            });
+
+        // the size of the bodies that are not streamed is recorded before the request is sent,
+        // the other sizes are counted as the body is sent
+        let request_size =
+            RequestSizeRecorder::new(&context, http_request_size_context_key(&self.service));
+        let body_size = hyper::body::HttpBody::size_hint(&body).exact();
+        request_size.start(body_size);
 
+        // logged before compression, the wrapped body loses its size so it is sent as the
+        // content-length
+        let body = match self.log_bodies {
+            None => body,
+            Some(max_bytes) => {
+                if opt_compressor.is_none() {
+                    if let Some(size) = hyper::body::HttpBody::size_hint(&body).exact() {
+                        parts.headers.entry(CONTENT_LENGTH).or_insert(size.into());
+                    }
+                }
+                let log = BodyLog::new(&self.service, BodyKind::Request, max_bytes);
+                Body::wrap_stream(LoggedBody::new(body, log))
+            }
+        };
+        let body = match body_size {
+            Some(_) => body,
+            None => Body::wrap_stream(request_size.counted(body, SizeKind::Uncompressed)),
+        };
         let body = match opt_compressor {
             None => body,
-            Some(compressor) => Body::wrap_stream(compressor.process(body)),
+            Some(compressor) => Body::wrap_stream(
+                request_size.counted(compressor.process(body), SizeKind::Compressed),
+            ),
         };
         let mut http_request = http::Request::from_parts(parts, body);
 
+        if self.compression_off {
+            http_request.headers_mut().remove(ACCEPT_ENCODING);
+        } else {
+            http_request
+                .headers_mut()
+                .insert(ACCEPT_ENCODING, ACCEPTED_ENCODINGS.clone());
+        }
+        // set last so that it is not replaced by the propagated headers
         http_request
             .headers_mut()
-            .insert(ACCEPT_ENCODING, ACCEPTED_ENCODINGS.clone());
+            .insert(USER_AGENT, self.user_agent.clone());
+        if let Some(priority) = priority {
+            http_request
+                .headers_mut()
+                .insert(PRIORITY.clone(), priority);
+        }
+
+        // replaces the GraphQL media types, the other values like the callback protocol are kept
+        if let Some(accept) = &self.accept {
+            let headers = http_request.headers_mut();
+            let others: Vec<HeaderValue> = headers
+                .get_all(ACCEPT)
+                .iter()
+                .filter(|value| *value != ACCEPT_GRAPHQL_JSON)
+                .cloned()
+                .collect();
+            headers.insert(ACCEPT, accept.clone());
+            for value in others {
+                headers.append(ACCEPT, value);
+            }
+        }
+
+        // the value is sensitive, so it is redacted from the logged headers
+        let authorization = context
+            .extensions()
+            .lock()
+            .get::<AuthorizationHeader>()
+            .cloned();
+        if let Some(AuthorizationHeader(authorization)) = authorization {
+            http_request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization);
+        }
 
         let signing_params = context
             .extensions()
@@ -298,33 +1457,111 @@ impl tower::Service<HttpRequest> for HttpClientService {
             .get::<Arc<SigningParamsConfig>>()
             .cloned();
 
+        let grpc = self.grpc.clone();
+        let drained = self.drain.expired();
+        let response_statuses = self.response_statuses.clone();
+        let empty_response = self.empty_response;
+        let header_limits = self.header_limits;
+        // the requests sent on a connection of their own do not wait for the pool
+        let pool_acquire_timeout = self
+            .pool_acquire_timeout
+            .filter(|_| pooled && expect_continue.is_none());
+
         Box::pin(async move {
-            let http_request = if let Some(signing_params) = signing_params {
+            let http_request = match &grpc {
+                Some(grpc) => grpc.encode_request(http_request, &service_name).await?,
+                None => http_request,
+            };
+            // signed once the body is final, compressed and framed for gRPC, so that the
+            // signature covers the bytes sent to the subgraph
+            let mut http_request = if let Some(signing_params) = signing_params {
                 signing_params.sign(http_request, &service_name).await?
             } else {
                 http_request
             };
+            // added once signed, intermediaries can remove it
+            if let Some(expect_continue) = expect_continue {
+                http_request
+                    .headers_mut()
+                    .insert(EXPECT, HeaderValue::from_static("100-continue"));
+                http_request.extensions_mut().insert(expect_continue);
+            }
+            let pool_acquire =
+                pool_acquire_timeout.map(|timeout| PoolAcquire::start(&mut http_request, timeout));
 
+            // the URI as sent, once the gRPC path is set
+            let uri = http_request.uri().to_string();
             let display_headers = context.contains_key(LOGGING_DISPLAY_HEADERS);
             let display_body = context.contains_key(LOGGING_DISPLAY_BODY);
+            let redacted_headers = RedactedHeaders::from_context(&context);
 
             // Print out the debug for the request
             if display_headers {
-                tracing::info!(http.request.headers = ?http_request.headers(), apollo.subgraph.name = %service_name, "Request headers to subgraph {service_name:?}");
+                tracing::info!(http.request.headers = ?redacted_headers.redacted(http_request.headers()), apollo.subgraph.name = %service_name, "Request headers to subgraph {service_name:?}");
             }
             if display_body {
                 tracing::info!(http.request.body = ?http_request.body(), apollo.subgraph.name = %service_name, "Request body to subgraph {service_name:?}");
             }
 
-            let http_response = do_fetch(client, &context, &service_name, http_request)
-                .instrument(http_req_span)
-                .await?;
+            let response = do_fetch(
+                client,
+                &context,
+                &service_name,
+                max_decompressed_bytes,
+                log_bodies,
+                deadline,
+                pool_acquire,
+                in_flight,
+                grpc.is_some(),
+                http_request,
+            )
+            .instrument(http_req_span.clone());
+            let http_response = tokio::select! {
+                response = response => response?,
+                _ = drained => {
+                    return Err(FetchError::SubrequestHttpError {
+                        status_code: None,
+                        service: service_name.to_string(),
+                        reason: "the drain timeout elapsed after a configuration reload"
+                            .to_string(),
+                    }
+                    .into())
+                }
+            };
+            connection_metrics.record_response(http_response.extensions());
+            record_response(&context, &service_name, &http_response, uri, &http_req_span);
+            header_limits.check(
+                &service_name,
+                http_response.status(),
+                http_response.headers(),
+            )?;
 
             // Print out the debug for the response
             if display_headers {
-                tracing::info!(response.headers = ?http_response.headers(), apollo.subgraph.name = %service_name, "Response headers from subgraph {service_name:?}");
+                tracing::info!(response.headers = ?redacted_headers.redacted(http_response.headers()), apollo.subgraph.name = %service_name, "Response headers from subgraph {service_name:?}");
             }
 
+            // the body is dropped unread, failing the request lets the retries send it again
+            let status = http_response.status();
+            if response_statuses.outcome(status) == StatusOutcome::Error {
+                return Err(FetchError::SubrequestHttpError {
+                    status_code: Some(status.as_u16()),
+                    service: service_name.to_string(),
+                    reason: format!(
+                        "{}: {}",
+                        status.as_str(),
+                        status.canonical_reason().unwrap_or("Unknown")
+                    ),
+                }
+                .into());
+            }
+            // like the statuses, an empty body fails the request before it is parsed, so that it
+            // can be retried
+            let http_response = match empty_response {
+                Some(empty_response) => empty_response.apply(&service_name, http_response).await?,
+                None => http_response,
+            };
+
             Ok(HttpResponse {
                 http_response,
                 context,
@@ -333,42 +1570,227 @@ impl tower::Service<HttpRequest> for HttpClientService {
     }
 }
 
+fn record_response(
+    context: &Context,
+    subgraph_name: &str,
+    response: &http::Response<Body>,
+    uri: String,
+    span: &tracing::Span,
+) {
+    let mut result = context
+        .insert(
+            http_status_context_key(subgraph_name),
+            response.status().as_u16(),
+        )
+        .and_then(|_| context.insert(http_uri_context_key(subgraph_name), uri));
+    if let Some(certificate) = response.extensions().get::<PeerCertificate>() {
+        result = result.and_then(|_| {
+            context.insert(
+                peer_certificate_context_key(subgraph_name),
+                certificate.clone(),
+            )
+        });
+    }
+    if let Some(trace_response) = TraceResponse::from_headers(response.headers()) {
+        trace_response.link_to(span);
+        result = result.and_then(|_| {
+            context.insert(trace_response_context_key(subgraph_name), trace_response)
+        });
+    }
+    if let Err(e) = result {
+        tracing::error!("could not record the HTTP response of subgraph '{subgraph_name}': {e}");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn do_fetch(
     mut client: MixedClient,
     context: &Context,
-    service_name: &str,
+    service_name: &Arc<String>,
+    max_decompressed_bytes: Option<usize>,
+    log_bodies: Option<usize>,
+    deadline: Option<Deadline>,
+    pool_acquire: Option<PoolAcquire>,
+    in_flight: Option<InFlight>,
+    grpc: bool,
     request: Request<Body>,
 ) -> Result<http::Response<Body>, FetchError> {
     let _active_request_guard = context.enter_active_request();
-    let (parts, body) = client
-        .call(request)
-        .map_err(|err| {
-            tracing::error!(fetch_error = ?err);
-            FetchError::SubrequestHttpError {
-                status_code: None,
+    let host = request.uri().host().unwrap_or_default().to_string();
+    let response = client.call(request).map_err(|err| {
+        tracing::error!(fetch_error = ?err);
+        let reason = if header_limits::is_head_too_large(err.as_ref()) {
+            header_limits::HEAD_TOO_LARGE.to_string()
+        } else if let Some(tls_error) = TlsHandshakeError::find(err.as_ref(), service_name, &host) {
+            tls_error.to_string()
+        } else {
+            err.to_string()
+        };
+        FetchError::SubrequestHttpError {
+            status_code: None,
+            service: service_name.to_string(),
+            reason,
+        }
+    });
+    let response = async {
+        match pool_acquire {
+            Some(pool_acquire) => pool_acquire.bound(service_name, response).await,
+            None => response.await,
+        }
+    };
+    // the connection and the TLS handshake happen while waiting for the response headers
+    let response = match &deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.sleep.deadline(), response)
+            .await
+            .unwrap_or_else(|_| Err(deadline.error())),
+        None => response.await,
+    };
+    let (parts, body) = response?.into_parts();
+
+    // the decompression layer removes the content-encoding header once it knows how to decode
+    // the body, so if it is still there, the subgraph used an encoding we cannot handle
+    if let Some(encoding) = parts.headers.get(&CONTENT_ENCODING) {
+        let encoding = String::from_utf8_lossy(encoding.as_bytes());
+        if !encoding.trim().eq_ignore_ascii_case("identity") {
+            return Err(FetchError::SubrequestHttpError {
+                status_code: Some(parts.status.as_u16()),
                 service: service_name.to_string(),
-                reason: err.to_string(),
-            }
-        })
-        .await?
-        .into_parts();
+                reason: format!("unsupported content-encoding in subgraph response: {encoding}"),
+            });
+        }
+    }
+
+    // the trailers of gRPC responses are read with the whole body, before they are lost in the
+    // body stream
+    if grpc {
+        let response = grpc::decode_response(service_name, parts, body);
+        return match &deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.sleep.deadline(), response)
+                .await
+                .unwrap_or_else(|_| Err(deadline.error())),
+            None => response.await,
+        };
+    }
+
+    let limit = max_decompressed_bytes
+        .filter(|_| parts.extensions.get::<EncodedBody>().is_some())
+        .map(|limit| Limit {
+            service: service_name.clone(),
+            limit,
+            remaining: limit,
+        });
+    let log = log_bodies.map(|max_bytes| BodyLog::new(service_name, BodyKind::Response, max_bytes));
+    let compression = match (
+        parts.extensions.get::<EncodedBody>(),
+        parts.extensions.get::<ReceivedBytes>(),
+    ) {
+        (Some(EncodedBody(algorithm)), Some(received)) => Some(CompressionMetrics {
+            service: service_name.clone(),
+            algorithm: algorithm.clone(),
+            received: received.clone(),
+            decompressed: 0,
+        }),
+        _ => None,
+    };
+
     Ok(http::Response::from_parts(
         parts,
-        Body::wrap_stream(BodyStream { inner: body }),
+        Body::wrap_stream(BodyStream {
+            inner: body,
+            limit,
+            deadline,
+            in_flight,
+            log,
+            compression,
+        }),
     ))
 }
 
 pin_project! {
     pub(crate) struct BodyStream<B: hyper::body::HttpBody> {
         #[pin]
-        inner: DecompressionBody<B>
+        inner: DecompressionBody<B>,
+        limit: Option<Limit>,
+        deadline: Option<Deadline>,
+        // the connection is in use until the body is read
+        in_flight: Option<InFlight>,
+        // the decompressed body, logged once read or dropped
+        log: Option<BodyLog>,
+        compression: Option<CompressionMetrics>,
+    }
+}
+
+/// End of the `request_timeout` of a subgraph request
+struct Deadline {
+    service: Arc<String>,
+    start: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Deadline {
+    fn error(&self) -> FetchError {
+        FetchError::SubrequestTimeout {
+            service: self.service.to_string(),
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+struct Limit {
+    service: Arc<String>,
+    limit: usize,
+    remaining: usize,
+}
+
+/// Sizes of a compressed response body, recorded once it is entirely read
+struct CompressionMetrics {
+    service: Arc<String>,
+    algorithm: String,
+    /// bytes received from the connection, before decompression
+    received: ReceivedBytes,
+    decompressed: usize,
+}
+
+impl CompressionMetrics {
+    fn record(self) {
+        let compressed = self.received.get();
+        u64_counter!(
+            "apollo.router.subgraph.response.compressed_bytes",
+            "Size of the compressed subgraph response bodies, as received",
+            compressed as u64,
+            "subgraph.name" = self.service.to_string(),
+            "compression.algorithm" = self.algorithm.clone()
+        );
+        u64_counter!(
+            "apollo.router.subgraph.response.decompressed_bytes",
+            "Size of the compressed subgraph response bodies, once decompressed",
+            self.decompressed as u64,
+            "subgraph.name" = self.service.to_string(),
+            "compression.algorithm" = self.algorithm.clone()
+        );
+        if compressed > 0 {
+            f64_histogram!(
+                "apollo.router.subgraph.response.compression_ratio",
+                "Decompressed size of the compressed subgraph response bodies divided by their compressed size",
+                self.decompressed as f64 / compressed as f64,
+                "subgraph.name" = self.service.to_string(),
+                "compression.algorithm" = self.algorithm
+            );
+        }
     }
 }
 
 impl<B: hyper::body::HttpBody> BodyStream<B> {
     /// Create a new `BodyStream`.
     pub(crate) fn new(body: DecompressionBody<B>) -> Self {
-        Self { inner: body }
+        Self {
+            inner: body,
+            limit: None,
+            deadline: None,
+            in_flight: None,
+            log: None,
+            compression: None,
+        }
     }
 }
 
@@ -385,6 +1807,50 @@ where
     ) -> Poll<Option<Self::Item>> {
         use hyper::body::HttpBody;
 
-        self.project().inner.poll_data(cx)
+        let this = self.project();
+        let res = this.inner.poll_data(cx);
+        if res.is_pending() {
+            if let Some(deadline) = this.deadline {
+                if deadline.sleep.as_mut().poll(cx).is_ready() {
+                    let error = deadline.error();
+                    *this.deadline = None;
+                    return Poll::Ready(Some(Err(error.into())));
+                }
+            }
+        }
+        if let (Poll::Ready(Some(Ok(data))), Some(limit)) = (&res, this.limit) {
+            match limit.remaining.checked_sub(data.len()) {
+                Some(remaining) => limit.remaining = remaining,
+                None => {
+                    return Poll::Ready(Some(Err(DecompressedBodyLimitError {
+                        service: limit.service.clone(),
+                        limit: limit.limit,
+                    }
+                    .into())))
+                }
+            }
+        }
+        match &res {
+            Poll::Ready(Some(Ok(data))) => {
+                if let Some(compression) = this.compression {
+                    compression.decompressed += data.len();
+                }
+            }
+            // only the bodies entirely read are recorded
+            Poll::Ready(None) => {
+                if let Some(compression) = this.compression.take() {
+                    compression.record();
+                }
+            }
+            _ => {}
+        }
+        if let Some(log) = this.log {
+            match &res {
+                Poll::Ready(Some(Ok(data))) => log.record(data),
+                Poll::Ready(None) => log.complete(),
+                _ => {}
+            }
+        }
+        res
     }
 }
@@ -0,0 +1,175 @@
+//! Marking of the IP packets of subgraph connections, for quality of service
+
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+
+/// DSCP value and socket priority set on new subgraph connections
+///
+/// The options are set once the connection is established, so the packets of the TCP handshake
+/// are not marked. They are not supported on every platform: the unsupported ones are ignored,
+/// with a warning when the HTTP client is created. The kernel can still refuse them, like a
+/// socket priority above 6 without the `CAP_NET_ADMIN` capability: the connection is then used
+/// unmarked, with a warning logged once.
+#[derive(Clone, Debug)]
+pub(crate) struct SocketMarking {
+    service: Arc<str>,
+    dscp: Option<u8>,
+    priority: Option<u32>,
+    warned: Arc<AtomicBool>,
+}
+
+impl SocketMarking {
+    pub(crate) fn new(service: &str, dscp: Option<u8>, priority: Option<u32>) -> Self {
+        if dscp.is_some() && !cfg!(unix) {
+            tracing::warn!(
+                "the DSCP value of the connections to subgraph '{service}' cannot be set on this platform, it is ignored"
+            );
+        }
+        if priority.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+            tracing::warn!(
+                "the socket priority of the connections to subgraph '{service}' can only be set on Linux, it is ignored"
+            );
+        }
+        Self {
+            service: service.into(),
+            dscp: dscp.filter(|_| cfg!(unix)),
+            priority: priority.filter(|_| cfg!(any(target_os = "linux", target_os = "android"))),
+            warned: Default::default(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.dscp.is_none() && self.priority.is_none()
+    }
+
+    /// Marks the connection, a failure is logged without failing the connection
+    pub(crate) fn apply(&self, stream: &TcpStream) {
+        if let Err(e) = self.mark(stream) {
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "could not mark the connections to subgraph '{}', they are sent unmarked: {e}",
+                    self.service
+                );
+            }
+        }
+    }
+
+    fn mark(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(dscp) = self.dscp {
+            // the DSCP is the upper 6 bits of the IPv4 TOS and of the IPv6 traffic class
+            set_dscp(stream, u32::from(dscp) << 2)?;
+        }
+        if let Some(priority) = self.priority {
+            set_priority(stream, priority)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_dscp(stream: &TcpStream, tos: u32) -> io::Result<()> {
+    if stream.local_addr()?.is_ipv4() {
+        return socket2::SockRef::from(stream).set_tos(tos);
+    }
+    setsockopt(
+        stream,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_TCLASS,
+        tos as libc::c_int,
+    )
+}
+
+#[cfg(not(unix))]
+fn set_dscp(_stream: &TcpStream, _tos: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_priority(stream: &TcpStream, priority: u32) -> io::Result<()> {
+    setsockopt(
+        stream,
+        libc::SOL_SOCKET,
+        libc::SO_PRIORITY,
+        priority as libc::c_int,
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_priority(_stream: &TcpStream, _priority: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn setsockopt(
+    stream: &TcpStream,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the file descriptor is open for the lifetime of the stream, and the value is
+    // an integer as expected by these options
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_marks_the_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let marking = SocketMarking::new("test", Some(46), Some(4));
+        marking.apply(&stream);
+        assert!(!marking.warned.load(Ordering::Relaxed));
+        let socket = socket2::SockRef::from(&stream);
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_connections_it_cannot_mark() {
+        use std::os::fd::AsRawFd;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // replaces the socket with a file, so that setting the socket options fails
+        let file = std::fs::File::open("/dev/null").unwrap();
+        // SAFETY: both file descriptors are open
+        assert_ne!(
+            unsafe { libc::dup2(file.as_raw_fd(), stream.as_raw_fd()) },
+            -1
+        );
+
+        let marking = SocketMarking::new("test", Some(46), Some(4));
+        assert!(marking.mark(&stream).is_err());
+        marking.apply(&stream);
+        assert!(marking.warned.load(Ordering::Relaxed));
+        // the warning is shared by the clones used for each connection
+        let clone = marking.clone();
+        clone.apply(&stream);
+        assert!(clone.warned.load(Ordering::Relaxed));
+    }
+}
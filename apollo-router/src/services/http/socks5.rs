@@ -0,0 +1,213 @@
+//! SOCKS5 proxy for subgraph connections (RFC 1928), with the username and password
+//! authentication of RFC 1929
+
+use std::net::IpAddr;
+
+use http::Uri;
+use hyper::client::connect::dns::Name;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tower::BoxError;
+use tower::Service;
+
+use crate::services::trust_dns_connector::AsyncHyperResolver;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Destination of a connection through the proxy
+enum Destination<'a> {
+    Ip(IpAddr),
+    Name(&'a str),
+}
+
+/// SOCKS5 proxy server the subgraph connections are opened through
+///
+/// The proxy only relays the TCP connection: the TLS handshake happens with the subgraph itself,
+/// and the subgraph certificate is verified against the host of the subgraph URL.
+pub(crate) struct Socks5Proxy {
+    /// address of the proxy server, to connect to it with the TCP connector
+    pub(crate) uri: Uri,
+    credentials: Option<(String, String)>,
+    /// resolver of the subgraph hosts, unless the proxy resolves them
+    resolver: Option<AsyncHyperResolver>,
+}
+
+impl Socks5Proxy {
+    pub(crate) fn new(url: &url::Url, remote_dns: bool) -> Result<Self, BoxError> {
+        if url.scheme() != "socks5" {
+            return Err(
+                format!("unsupported SOCKS5 proxy URL '{url}': the scheme must be socks5").into(),
+            );
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("invalid SOCKS5 proxy URL '{url}': missing host"))?;
+        let port = url.port().unwrap_or(1080);
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority(format!("{host}:{port}"))
+            .path_and_query("/")
+            .build()?;
+
+        let credentials = if url.username().is_empty() {
+            None
+        } else {
+            let username = urlencoding::decode(url.username())?.into_owned();
+            let password = urlencoding::decode(url.password().unwrap_or_default())?.into_owned();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(format!(
+                    "invalid SOCKS5 proxy URL '{url}': the username and the password are limited to 255 bytes"
+                )
+                .into());
+            }
+            Some((username, password))
+        };
+        let resolver = (!remote_dns)
+            .then(AsyncHyperResolver::new_from_system_conf)
+            .transpose()?;
+
+        Ok(Self {
+            uri,
+            credentials,
+            resolver,
+        })
+    }
+
+    /// Opens a connection to the host of the subgraph URL through the proxy, on a stream
+    /// connected to the proxy
+    pub(crate) async fn connect(&self, stream: &mut TcpStream, uri: &Uri) -> Result<(), BoxError> {
+        let host = uri
+            .host()
+            .ok_or("missing host in subgraph URL")?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") {
+                443
+            } else {
+                80
+            });
+        let destination = self.destination(host).await?;
+
+        self.authenticate(stream).await?;
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match destination {
+            Destination::Ip(IpAddr::V4(address)) => {
+                request.push(IPV4);
+                request.extend_from_slice(&address.octets());
+            }
+            Destination::Ip(IpAddr::V6(address)) => {
+                request.push(IPV6);
+                request.extend_from_slice(&address.octets());
+            }
+            Destination::Name(name) => {
+                let length = u8::try_from(name.len())
+                    .map_err(|_| format!("the host '{name}' is too long for the SOCKS5 proxy"))?;
+                request.push(DOMAIN_NAME);
+                request.push(length);
+                request.extend_from_slice(name.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err("the SOCKS5 proxy answered with another protocol version".into());
+        }
+        if reply[1] != 0 {
+            return Err(format!(
+                "the SOCKS5 proxy could not connect to {host}:{port}: {}",
+                reply_reason(reply[1])
+            )
+            .into());
+        }
+        // the address the proxy connected from is not used
+        let address_length = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => stream.read_u8().await? as usize,
+            _ => return Err("the SOCKS5 proxy answered with an unknown address type".into()),
+        };
+        let mut bound_address = vec![0; address_length + 2];
+        stream.read_exact(&mut bound_address).await?;
+        Ok(())
+    }
+
+    async fn destination<'a>(&self, host: &'a str) -> Result<Destination<'a>, BoxError> {
+        if let Ok(address) = host.parse() {
+            return Ok(Destination::Ip(address));
+        }
+        let Some(resolver) = &self.resolver else {
+            return Ok(Destination::Name(host));
+        };
+        let name = host.parse::<Name>()?;
+        resolver
+            .clone()
+            .call(name)
+            .await?
+            .next()
+            .map(|address| Destination::Ip(address.ip()))
+            .ok_or_else(|| format!("{host} has no address to connect to").into())
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> Result<(), BoxError> {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => &[VERSION, 1, NO_AUTHENTICATION],
+        };
+        stream.write_all(greeting).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err("the SOCKS5 proxy answered with another protocol version".into());
+        }
+        match (reply[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => Ok(()),
+            (USERNAME_PASSWORD, Some((username, password))) => {
+                let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+                let mut reply = [0; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err("the SOCKS5 proxy refused the username and password".into());
+                }
+                Ok(())
+            }
+            (NO_ACCEPTABLE_METHOD, None) => Err(
+                "the SOCKS5 proxy requires authentication, set a username and a password in its URL"
+                    .into(),
+            ),
+            _ => Err("the SOCKS5 proxy requires an unsupported authentication method".into()),
+        }
+    }
+}
+
+fn reply_reason(reply: u8) -> &'static str {
+    match reply {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,97 @@
+//! Probes of the subgraphs before serving a new schema or configuration
+
+use std::time::Duration;
+
+use futures::future::join_all;
+use http::header::CONTENT_TYPE;
+use http::Method;
+use http::Uri;
+use hyper::Body;
+use mime::APPLICATION_JSON;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use super::HttpClientService;
+use super::HttpRequest;
+use crate::configuration::StartupHealthCheck;
+use crate::Context;
+
+/// Longest wait for the response to a probe, in addition to the connection timeout
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PROBE_QUERY: &str = r#"{"query":"query SubgraphHealthCheck { __typename }"}"#;
+
+/// Subgraph to probe with its HTTP client
+pub(crate) struct Probe {
+    pub(crate) name: String,
+    pub(crate) url: Uri,
+    pub(crate) client: HttpClientService,
+}
+
+/// Sends a `__typename` query to each subgraph, concurrently, and reports the subgraphs that
+/// could not be reached in a single message
+///
+/// Any HTTP response counts as reachable, even an error status: the probe checks the connection
+/// and the TLS handshake, not the authorization of the router or the health of the subgraph.
+pub(crate) async fn check_subgraphs(
+    mode: StartupHealthCheck,
+    probes: Vec<Probe>,
+) -> Result<(), BoxError> {
+    if mode == StartupHealthCheck::Disabled || probes.is_empty() {
+        return Ok(());
+    }
+    let count = probes.len();
+    let failures: Vec<String> = join_all(probes.into_iter().map(probe))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if failures.is_empty() {
+        tracing::info!("the {count} subgraphs are reachable");
+        return Ok(());
+    }
+    let message = format!(
+        "{} of the {count} subgraphs could not be reached: {}",
+        failures.len(),
+        failures.join("; ")
+    );
+    match mode {
+        StartupHealthCheck::Error => Err(message.into()),
+        _ => {
+            tracing::warn!("{message}");
+            Ok(())
+        }
+    }
+}
+
+/// Description of the failure, if the subgraph could not be reached
+async fn probe(probe: Probe) -> Option<String> {
+    let Probe { name, url, client } = probe;
+    let request = http::Request::builder()
+        .method(Method::POST)
+        .uri(url.clone())
+        .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+        .body(Body::from(PROBE_QUERY))
+        .expect("the probe request is valid");
+    let response = client.oneshot(HttpRequest {
+        http_request: request,
+        context: Context::new(),
+    });
+
+    let reason = match tokio::time::timeout(PROBE_TIMEOUT, response).await {
+        Ok(Ok(response)) => {
+            tracing::debug!(
+                "subgraph '{name}' at {url} answered the probe with status {}",
+                response.http_response.status()
+            );
+            return None;
+        }
+        Ok(Err(error)) => error.to_string(),
+        Err(_) => format!(
+            "no response after {}",
+            humantime::format_duration(PROBE_TIMEOUT)
+        ),
+    };
+    Some(format!("subgraph '{name}' at {url}: {reason}"))
+}
@@ -0,0 +1,175 @@
+//! Limit of the concurrent requests multiplexed on each HTTP/2 connection to a subgraph
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use http::Request;
+use http::Response;
+use http::Uri;
+use hyper::client::connect::Connect;
+use hyper::client::connect::Connection;
+use hyper::Body;
+use hyper::Client;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tower::BoxError;
+use tower::Service;
+
+use super::expect_continue;
+use super::expect_continue::ExpectContinue;
+
+/// HTTP client opening more connections instead of multiplexing too many requests on one
+///
+/// hyper sends all the HTTP/2 requests to a host on a single connection. With a limit, the
+/// requests are spread across several clients, each with its own connection pool, and a new
+/// client is created when all of them have `max_streams` requests in flight. A request is in
+/// flight until its response body is dropped.
+///
+/// Requests sent with `Expect: 100-continue` go on their own connection instead.
+#[derive(Clone)]
+pub(crate) struct StreamLimitedClient<C> {
+    builder: hyper::client::Builder,
+    connector: C,
+    max_streams: Option<usize>,
+    clients: Arc<Mutex<Vec<PooledClient<C>>>>,
+}
+
+#[derive(Clone)]
+struct PooledClient<C> {
+    client: Client<C, Body>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<C> PooledClient<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    fn new(builder: &hyper::client::Builder, connector: C) -> Self {
+        Self {
+            client: builder.build(connector),
+            in_flight: Default::default(),
+        }
+    }
+}
+
+impl<C> StreamLimitedClient<C>
+where
+    C: Connect + Service<Uri> + Clone + Send + Sync + 'static,
+    <C as Service<Uri>>::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+    <C as Service<Uri>>::Future: Send,
+    <C as Service<Uri>>::Error: Into<BoxError>,
+{
+    pub(crate) fn new(
+        builder: hyper::client::Builder,
+        connector: C,
+        max_streams: Option<usize>,
+    ) -> Self {
+        let client = PooledClient::new(&builder, connector.clone());
+        Self {
+            builder,
+            connector,
+            max_streams,
+            clients: Arc::new(Mutex::new(vec![client])),
+        }
+    }
+
+    /// Sends the request with the first client below the limit
+    pub(crate) fn request(
+        &self,
+        request: Request<Body>,
+    ) -> BoxFuture<'static, Result<Response<Body>, BoxError>> {
+        if request.extensions().get::<ExpectContinue>().is_some() {
+            return Box::pin(expect_continue::send(self.connector.clone(), request));
+        }
+        let client = self.select();
+        let stream = OpenStream::new(&client.in_flight);
+        let response = client.client.request(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            response.extensions_mut().insert(stream);
+            Ok(response)
+        })
+    }
+
+    fn select(&self) -> PooledClient<C> {
+        let mut clients = self.clients.lock().expect("lock poisoned");
+        let Some(max_streams) = self.max_streams else {
+            return clients[0].clone();
+        };
+        if let Some(client) = clients
+            .iter()
+            .find(|client| client.in_flight.load(Ordering::Relaxed) < max_streams)
+        {
+            return client.clone();
+        }
+        // the clients are kept once created, the connections of their pools are closed once idle
+        let client = PooledClient::new(&self.builder, self.connector.clone());
+        clients.push(client.clone());
+        client
+    }
+}
+
+impl<C> Service<Request<Body>> for StreamLimitedClient<C>
+where
+    C: Connect + Service<Uri> + Clone + Send + Sync + 'static,
+    <C as Service<Uri>>::Response: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+    <C as Service<Uri>>::Future: Send,
+    <C as Service<Uri>>::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        self.request(request)
+    }
+}
+
+/// A request in flight, added to the extensions of its response and kept with its body
+pub(crate) struct OpenStream(Arc<AtomicUsize>);
+
+impl OpenStream {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight.clone())
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::client::HttpConnector;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_creates_a_client_when_all_clients_are_at_the_limit() {
+        let client = StreamLimitedClient::new(Client::builder(), HttpConnector::new(), Some(2));
+        let first = client.select();
+        let streams = [
+            OpenStream::new(&first.in_flight),
+            OpenStream::new(&first.in_flight),
+        ];
+
+        let second = client.select();
+        assert!(!Arc::ptr_eq(&first.in_flight, &second.in_flight));
+        assert_eq!(client.clients.lock().unwrap().len(), 2);
+
+        drop(streams);
+        assert!(Arc::ptr_eq(&client.select().in_flight, &first.in_flight));
+    }
+}
@@ -0,0 +1,190 @@
+//! Errors of the TLS handshakes with subgraphs, telling the common misconfigurations apart
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use rustls::AlertDescription;
+use rustls::CertificateError;
+
+/// Reason of the failure of a TLS handshake with a subgraph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TlsFailure {
+    /// the subgraph certificate is not issued by a trusted certificate authority
+    UnknownCa,
+    /// the subgraph certificate is not valid for the name it is checked against
+    HostnameMismatch,
+    /// the subgraph certificate is expired, or not valid yet
+    CertificateExpired,
+    /// the subgraph refused the client certificate, or requires one
+    ClientCertificateRejected,
+    Other,
+}
+
+impl TlsFailure {
+    fn of(error: &rustls::Error) -> Self {
+        match error {
+            rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer) => Self::UnknownCa,
+            rustls::Error::InvalidCertificate(CertificateError::NotValidForName) => {
+                Self::HostnameMismatch
+            }
+            rustls::Error::InvalidCertificate(
+                CertificateError::Expired | CertificateError::NotValidYet,
+            ) => Self::CertificateExpired,
+            // the alerts received are sent by the subgraph about the client certificate
+            rustls::Error::AlertReceived(
+                AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA
+                | AlertDescription::AccessDenied
+                | AlertDescription::CertificateRequired,
+            ) => Self::ClientCertificateRejected,
+            _ => Self::Other,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::UnknownCa => Some(
+                "the subgraph certificate is not issued by a trusted certificate authority, check the `certificate_authorities` of the subgraph",
+            ),
+            Self::HostnameMismatch => Some(
+                "the subgraph certificate is not valid for the host of the subgraph URL, or for the `server_name` of the subgraph",
+            ),
+            Self::CertificateExpired => Some(
+                "the subgraph certificate is expired or not valid yet, check its validity dates and the clock of the router",
+            ),
+            Self::ClientCertificateRejected => Some(
+                "the subgraph rejected the client certificate, check the `client_authentication` of the subgraph",
+            ),
+            Self::Other => None,
+        }
+    }
+}
+
+/// TLS handshake with a subgraph that failed
+///
+/// The TLS 1.3 handshake completes on the side of the router before the subgraph checks the
+/// client certificate, so a rejected client certificate fails the first request sent on the
+/// connection instead: the error is found in the sources of the request error too.
+#[derive(Clone, Debug)]
+pub(crate) struct TlsHandshakeError {
+    subgraph: String,
+    host: String,
+    pub(crate) failure: TlsFailure,
+    source: rustls::Error,
+}
+
+impl TlsHandshakeError {
+    pub(crate) fn new(subgraph: &str, host: &str, source: rustls::Error) -> Self {
+        Self {
+            subgraph: subgraph.to_string(),
+            host: host.to_string(),
+            failure: TlsFailure::of(&source),
+            source,
+        }
+    }
+
+    /// Finds the TLS error in the sources of the error of a connection or a request
+    pub(crate) fn find(error: &(dyn Error + 'static), subgraph: &str, host: &str) -> Option<Self> {
+        let mut source = Some(error);
+        while let Some(error) = source {
+            // io errors do not list the error they wrap in their sources
+            let wrapped = error
+                .downcast_ref::<io::Error>()
+                .and_then(io::Error::get_ref)
+                .map(|wrapped| wrapped as &(dyn Error + 'static));
+            for error in std::iter::once(error).chain(wrapped) {
+                if let Some(error) = error.downcast_ref::<TlsHandshakeError>() {
+                    return Some(error.clone());
+                }
+                if let Some(error) = error.downcast_ref::<rustls::Error>() {
+                    return Some(Self::new(subgraph, host, error.clone()));
+                }
+            }
+            source = error.source();
+        }
+        None
+    }
+}
+
+impl fmt::Display for TlsHandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TLS handshake with subgraph '{}' at {} failed: ",
+            self.subgraph, self.host
+        )?;
+        match self.failure.hint() {
+            Some(hint) => write!(f, "{hint} ({})", self.source),
+            None => write!(f, "{}", self.source),
+        }
+    }
+}
+
+impl Error for TlsHandshakeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tells_the_tls_failures_apart() {
+        let failure = |error| TlsHandshakeError::new("products", "products.example.com", error);
+        let unknown_ca = failure(rustls::Error::InvalidCertificate(
+            CertificateError::UnknownIssuer,
+        ));
+        assert_eq!(unknown_ca.failure, TlsFailure::UnknownCa);
+        assert_eq!(
+            unknown_ca.to_string(),
+            "TLS handshake with subgraph 'products' at products.example.com failed: the subgraph certificate is not issued by a trusted certificate authority, check the `certificate_authorities` of the subgraph (invalid peer certificate: UnknownIssuer)"
+        );
+        assert_eq!(
+            failure(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName
+            ))
+            .failure,
+            TlsFailure::HostnameMismatch
+        );
+        assert_eq!(
+            failure(rustls::Error::InvalidCertificate(CertificateError::Expired)).failure,
+            TlsFailure::CertificateExpired
+        );
+        assert_eq!(
+            failure(rustls::Error::AlertReceived(
+                AlertDescription::CertificateRequired
+            ))
+            .failure,
+            TlsFailure::ClientCertificateRejected
+        );
+        let other = failure(rustls::Error::AlertReceived(
+            AlertDescription::HandshakeFailure,
+        ));
+        assert_eq!(other.failure, TlsFailure::Other);
+        assert_eq!(
+            other.to_string(),
+            "TLS handshake with subgraph 'products' at products.example.com failed: received fatal alert: HandshakeFailure"
+        );
+    }
+
+    #[test]
+    fn it_finds_the_tls_error_wrapped_in_io_errors() {
+        let error = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer),
+        );
+        let error: Box<dyn Error + Send + Sync> = Box::new(error);
+        let found = TlsHandshakeError::find(error.as_ref(), "products", "products.example.com");
+        assert_eq!(found.unwrap().failure, TlsFailure::UnknownCa);
+
+        let error = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert!(TlsHandshakeError::find(&error, "products", "products.example.com").is_none());
+    }
+}
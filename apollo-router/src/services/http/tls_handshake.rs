@@ -0,0 +1,180 @@
+//! Duration of the TLS handshakes of subgraph connections
+
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper_rustls::HttpsConnector;
+use hyper_rustls::MaybeHttpsStream;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::Certificate;
+use rustls::ServerName;
+use tower::BoxError;
+use tower::Service;
+use tracing::Span;
+
+use super::client_cert::Connecting;
+use super::ipv6_zone;
+use super::peer_certificate::PeerCertificate;
+use super::proxy::ProxyConnector;
+use super::proxy::ProxyStream;
+use super::tls_error::TlsHandshakeError;
+
+/// TLS handshake of a subgraph connection, started once the TCP connection (and the proxy
+/// tunnel) is established
+pub(crate) struct TlsHandshake {
+    span: Span,
+    start: Instant,
+}
+
+impl TlsHandshake {
+    pub(crate) fn start(uri: &Uri) -> Self {
+        Self {
+            span: tracing::info_span!(
+                "tls_handshake",
+                "otel.kind" = "INTERNAL",
+                "net.peer.name" = uri.host().unwrap_or_default(),
+                "apollo.subgraph.name" = ::tracing::field::Empty,
+                "tls.next_protocol" = ::tracing::field::Empty,
+                "tls.resumed" = ::tracing::field::Empty,
+            ),
+            start: Instant::now(),
+        }
+    }
+
+    // the span is closed when the handshake is dropped
+    fn finish(self, subgraph_name: &str, alpn_protocol: Option<&[u8]>, resumed: bool) {
+        // a subgraph without ALPN support gets HTTP/1.1 requests
+        let next_protocol = alpn_protocol
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned())
+            .unwrap_or_else(|| "none".to_string());
+        self.span.record("apollo.subgraph.name", subgraph_name);
+        self.span
+            .record("tls.next_protocol", next_protocol.as_str());
+        self.span.record("tls.resumed", resumed);
+        u64_counter!(
+            "apollo.router.subgraph.tls_handshakes",
+            "Number of TLS handshakes completed with a subgraph, full or resuming a previous session",
+            1,
+            "subgraph.name" = subgraph_name.to_string(),
+            "tls.handshake.kind" = if resumed { "resumed" } else { "full" }
+        );
+        f64_histogram!(
+            "apollo.router.subgraph.tls_handshake.duration",
+            "Duration of the TLS handshakes with a subgraph, in seconds",
+            self.start.elapsed().as_secs_f64(),
+            "subgraph.name" = subgraph_name.to_string(),
+            "tls.next_protocol" = next_protocol
+        );
+    }
+}
+
+/// Wraps the HTTPS connector to record the duration of the TLS handshakes it completes, with the
+/// protocol negotiated with ALPN
+///
+/// The handshake starts when the inner connector returns the TCP connection, and ends when the
+/// HTTPS connector returns the TLS stream. Handshakes that fail are only visible as spans, and
+/// their errors tell why the TLS configuration was refused. The client certificate is selected
+/// from the host of the URI during the handshake, and the certificate presented by the subgraph
+/// is kept with the connection.
+#[derive(Clone)]
+pub(crate) struct TlsHandshakeConnector {
+    inner: HttpsConnector<ProxyConnector>,
+    subgraph_name: Arc<String>,
+}
+
+impl TlsHandshakeConnector {
+    pub(crate) fn new(inner: HttpsConnector<ProxyConnector>, subgraph_name: &str) -> Self {
+        Self {
+            inner,
+            subgraph_name: Arc::new(subgraph_name.to_string()),
+        }
+    }
+}
+
+impl Service<Uri> for TlsHandshakeConnector {
+    type Response = MaybeHttpsStream<ProxyStream>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default().to_string();
+        let client_cert = Connecting::new(&host);
+        // the zone identifier of an IPv6 host cannot be part of the server name
+        let (uri, zone) = ipv6_zone::take_zone(uri);
+        let connecting = ipv6_zone::scope(zone, client_cert.scope(self.inner.call(uri)));
+        let subgraph_name = self.subgraph_name.clone();
+        Box::pin(async move {
+            let connected = connecting.await;
+            // the subgraph can abort the handshake without a client certificate
+            client_cert.check(&subgraph_name)?;
+            let mut stream = connected.map_err(|error| {
+                match TlsHandshakeError::find(error.as_ref(), &subgraph_name, &host) {
+                    Some(tls_error) => tls_error.into(),
+                    None => error,
+                }
+            })?;
+            if let MaybeHttpsStream::Https(tls_stream) = &mut stream {
+                let (proxy_stream, connection) = tls_stream.get_mut();
+                if let Some(handshake) = proxy_stream.take_tls_handshake() {
+                    handshake.finish(
+                        &subgraph_name,
+                        connection.alpn_protocol(),
+                        client_cert.resumed(),
+                    );
+                }
+                // a resumed session keeps the certificates of the handshake that created it
+                proxy_stream.set_peer_certificate(
+                    connection
+                        .peer_certificates()
+                        .and_then(|certificates| certificates.first())
+                        .and_then(|certificate| PeerCertificate::from_der(&certificate.0)),
+                );
+            }
+            Ok(stream)
+        })
+    }
+}
+
+/// Wraps the verifier of the subgraph certificates to tell the full TLS handshakes from the ones
+/// resuming a previous session, where the certificate is not sent again
+pub(crate) struct HandshakeVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl HandshakeVerifier {
+    pub(crate) fn new(inner: Arc<dyn ServerCertVerifier>) -> Self {
+        Self { inner }
+    }
+}
+
+impl ServerCertVerifier for HandshakeVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Connecting::certificate_verified();
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
@@ -0,0 +1,162 @@
+//! W3C trace context headers of subgraph requests
+
+use http::header::HeaderName;
+use http::HeaderMap;
+use http::HeaderValue;
+use opentelemetry::global::get_text_map_propagator;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::trace::SpanContext;
+use opentelemetry::trace::SpanId;
+use opentelemetry::trace::TraceFlags;
+use opentelemetry::trace::TraceId;
+use opentelemetry::trace::TraceState;
+use opentelemetry::KeyValue;
+use serde::Serialize;
+use tracing::Span;
+
+use crate::plugins::telemetry::config::ExistingTraceParent;
+use crate::plugins::telemetry::config::SubgraphTraceContext;
+use crate::plugins::telemetry::otel::OpenTelemetrySpanExt;
+use crate::plugins::telemetry::reload::prepare_context;
+use crate::Context;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+const TRACERESPONSE: &str = "traceresponse";
+
+/// Trace context headers of the client request, kept in the context extensions when they are
+/// forwarded to subgraphs
+#[derive(Clone, Debug)]
+pub(crate) struct IncomingTraceContext {
+    traceparent: HeaderValue,
+    tracestate: Option<HeaderValue>,
+}
+
+impl IncomingTraceContext {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        Some(Self {
+            traceparent: headers.get(TRACEPARENT)?.clone(),
+            tracestate: headers.get(TRACESTATE).cloned(),
+        })
+    }
+
+    fn insert_into(&self, headers: &mut HeaderMap) {
+        headers.insert(
+            HeaderName::from_static(TRACEPARENT),
+            self.traceparent.clone(),
+        );
+        match &self.tracestate {
+            Some(tracestate) => {
+                headers.insert(HeaderName::from_static(TRACESTATE), tracestate.clone());
+            }
+            None => {
+                headers.remove(TRACESTATE);
+            }
+        }
+    }
+}
+
+/// Sets the trace context headers of a subgraph request from the span of the request, with the
+/// configured propagators and the subgraph trace context options of the telemetry plugin
+pub(crate) fn inject_trace_context(headers: &mut HeaderMap, context: &Context, span: &Span) {
+    let (options, incoming) = {
+        let extensions = context.extensions().lock();
+        (
+            extensions.get::<SubgraphTraceContext>().copied(),
+            extensions.get::<IncomingTraceContext>().cloned(),
+        )
+    };
+    let options = options.unwrap_or_default();
+    // set before the request reaches the HTTP client, by header propagation or a coprocessor
+    let existing = (options.existing_traceparent == ExistingTraceParent::Keep)
+        .then(|| IncomingTraceContext::from_headers(headers))
+        .flatten();
+
+    let span_context = prepare_context(span.context());
+    let mut injector = opentelemetry_http::HeaderInjector(headers);
+    get_text_map_propagator(|propagator| propagator.inject_context(&span_context, &mut injector));
+    if options.enabled {
+        TraceContextPropagator::new().inject_context(&span_context, &mut injector);
+    }
+
+    if let Some(incoming) = incoming.filter(|_| options.forward_incoming) {
+        incoming.insert_into(headers);
+    }
+    if let Some(existing) = existing {
+        existing.insert_into(headers);
+    }
+}
+
+/// Span of a subgraph, returned in the `traceresponse` header of its response
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct TraceResponse {
+    /// trace the span belongs to, which differs from the router trace when the subgraph started
+    /// its own trace
+    pub(crate) trace_id: String,
+    pub(crate) span_id: String,
+    pub(crate) sampled: bool,
+}
+
+impl TraceResponse {
+    /// Parses a `traceresponse` header, in the format of `traceparent` (W3C Trace Context
+    /// Level 2), `None` if it is missing or invalid
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(TRACERESPONSE)?.to_str().ok()?;
+        let mut fields = value.trim().split('-');
+        let version = fields.next()?;
+        let trace_id = fields.next()?;
+        let span_id = fields.next()?;
+        let flags = fields.next()?;
+        // later versions may append fields, version 00 has exactly four
+        if !is_lowercase_hex(version, 2)
+            || version == "ff"
+            || (version == "00" && fields.next().is_some())
+            || !is_lowercase_hex(trace_id, 32)
+            || !is_lowercase_hex(span_id, 16)
+            || !is_lowercase_hex(flags, 2)
+        {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        let response = Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            sampled: TraceFlags::new(flags).is_sampled(),
+        };
+        // all zeros identifiers are invalid
+        response.span_context().is_valid().then_some(response)
+    }
+
+    fn span_context(&self) -> SpanContext {
+        SpanContext::new(
+            TraceId::from_hex(&self.trace_id).unwrap_or(TraceId::INVALID),
+            SpanId::from_hex(&self.span_id).unwrap_or(SpanId::INVALID),
+            if self.sampled {
+                TraceFlags::SAMPLED
+            } else {
+                TraceFlags::default()
+            },
+            true,
+            TraceState::default(),
+        )
+    }
+
+    /// Links the span of the subgraph request to the span of the subgraph, and records the
+    /// span id as an attribute
+    pub(crate) fn link_to(&self, span: &Span) {
+        span.add_link_with_attributes(
+            self.span_context(),
+            vec![KeyValue::new("apollo.subgraph.link", "traceresponse")],
+        );
+        span.set_attribute("apollo.subgraph.trace_id", self.trace_id.clone());
+        span.set_attribute("apollo.subgraph.span_id", self.span_id.clone());
+    }
+}
+
+fn is_lowercase_hex(field: &str, len: usize) -> bool {
+    field.len() == len
+        && field
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
@@ -0,0 +1,242 @@
+//! Connections opened to a subgraph before it receives traffic
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::join_all;
+use futures::future::BoxFuture;
+use http::Uri;
+use rand::Rng;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+pub(crate) const DEFAULT_RAMP: Duration = Duration::from_secs(1);
+
+/// Wraps the connector of a subgraph to hand out the connections opened by the warm-up
+///
+/// hyper does not let connections into its pool from outside the client, so the warm-up
+/// connections are kept by the connector, and returned when hyper connects to their host. They
+/// are closed once they stayed unused for the idle timeout of the pool, like idle pooled
+/// connections.
+pub(crate) struct WarmupConnector<C: Service<Uri>> {
+    inner: C,
+    idle_timeout: Option<Duration>,
+    warm: Arc<Mutex<Vec<WarmConnection<C::Response>>>>,
+}
+
+struct WarmConnection<S> {
+    key: String,
+    stream: S,
+    opened: Instant,
+}
+
+impl<C> Clone for WarmupConnector<C>
+where
+    C: Service<Uri> + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            idle_timeout: self.idle_timeout,
+            warm: self.warm.clone(),
+        }
+    }
+}
+
+impl<C: Service<Uri>> WarmupConnector<C> {
+    pub(crate) fn new(inner: C, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            idle_timeout,
+            warm: Default::default(),
+        }
+    }
+
+    /// Takes a warm connection to the host of the key, if one is left
+    fn take(&self, key: &str) -> Option<C::Response> {
+        let mut warm = self.warm.lock().expect("lock poisoned");
+        purge(&mut warm, self.idle_timeout);
+        let index = warm.iter().position(|connection| connection.key == key)?;
+        Some(warm.swap_remove(index).stream)
+    }
+}
+
+impl<C> WarmupConnector<C>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Future: Send,
+    C::Response: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    /// Opens connections to the host of the URI, each one after a random delay within the ramp,
+    /// and returns the errors of the ones that could not be opened
+    pub(crate) async fn warm_up(
+        &self,
+        uri: &Uri,
+        connections: usize,
+        ramp: Duration,
+    ) -> Vec<BoxError> {
+        let key = pool_key(uri);
+        let delays: Vec<Duration> = {
+            let mut rng = rand::thread_rng();
+            (0..connections)
+                .map(|_| rng.gen_range(Duration::ZERO..=ramp))
+                .collect()
+        };
+        let opening = delays.into_iter().map(|delay| {
+            let connector = self.inner.clone();
+            let uri = uri.clone();
+            let key = key.clone();
+            let warm = self.warm.clone();
+            async move {
+                tokio::time::sleep(delay).await;
+                let stream = connector.oneshot(uri).await.map_err(Into::into)?;
+                warm.lock().expect("lock poisoned").push(WarmConnection {
+                    key,
+                    stream,
+                    opened: Instant::now(),
+                });
+                Ok(())
+            }
+        });
+        let errors = join_all(opening)
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+
+        // the connections hyper did not need are closed even if it does not connect again
+        if let Some(idle_timeout) = self.idle_timeout {
+            let warm = Arc::downgrade(&self.warm);
+            tokio::spawn(purge_after(warm, idle_timeout));
+        }
+        errors
+    }
+}
+
+impl<C> Service<Uri> for WarmupConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Response: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if let Some(stream) = self.take(&pool_key(&uri)) {
+            return Box::pin(futures::future::ready(Ok(stream)));
+        }
+        let connecting = self.inner.call(uri);
+        Box::pin(async move { connecting.await.map_err(Into::into) })
+    }
+}
+
+/// Host of the connections, as hyper identifies them in its pool
+fn pool_key(uri: &Uri) -> String {
+    format!(
+        "{}://{}",
+        uri.scheme_str().unwrap_or_default(),
+        uri.authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or_default()
+    )
+}
+
+fn purge<S>(warm: &mut Vec<WarmConnection<S>>, idle_timeout: Option<Duration>) {
+    if let Some(idle_timeout) = idle_timeout {
+        warm.retain(|connection| connection.opened.elapsed() < idle_timeout);
+    }
+}
+
+async fn purge_after<S>(warm: Weak<Mutex<Vec<WarmConnection<S>>>>, idle_timeout: Duration) {
+    tokio::time::sleep(idle_timeout).await;
+    if let Some(warm) = warm.upgrade() {
+        purge(&mut warm.lock().expect("lock poisoned"), Some(idle_timeout));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    /// Connector returning the number of the connection instead of a stream
+    #[derive(Clone, Default)]
+    struct CountingConnector(Arc<AtomicUsize>);
+
+    impl Service<Uri> for CountingConnector {
+        type Response = usize;
+        type Error = BoxError;
+        type Future = futures::future::Ready<Result<usize, BoxError>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: Uri) -> Self::Future {
+            futures::future::ready(Ok(self.0.fetch_add(1, Ordering::SeqCst) + 1))
+        }
+    }
+
+    fn counting_connector() -> (CountingConnector, Arc<AtomicUsize>) {
+        let connector = CountingConnector::default();
+        let count = connector.0.clone();
+        (connector, count)
+    }
+
+    #[tokio::test]
+    async fn it_hands_out_the_warm_connections() {
+        let (inner, count) = counting_connector();
+        let mut connector = WarmupConnector::new(inner, Some(Duration::from_secs(60)));
+        let uri = Uri::from_static("https://products.example.com:4001/graphql");
+
+        let errors = connector.warm_up(&uri, 3, Duration::from_millis(20)).await;
+        assert!(errors.is_empty());
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // hyper connects with the scheme and authority of the request
+        let destination = Uri::from_static("https://products.example.com:4001/");
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            connections.push(connector.call(destination.clone()).await.unwrap());
+        }
+        connections.sort();
+        assert_eq!(connections, vec![1, 2, 3]);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // once the warm connections are taken, new ones are opened
+        assert_eq!(connector.call(destination).await.unwrap(), 4);
+        // and the warm connections are only used for their host
+        connector.warm_up(&uri, 1, Duration::ZERO).await;
+        let other = Uri::from_static("https://reviews.example.com:4001/");
+        assert_eq!(connector.call(other).await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn it_closes_the_unused_warm_connections() {
+        let (inner, count) = counting_connector();
+        let mut connector = WarmupConnector::new(inner, Some(Duration::from_millis(50)));
+        let uri = Uri::from_static("http://products.example.com/");
+
+        connector.warm_up(&uri, 2, Duration::ZERO).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(connector.warm.lock().unwrap().is_empty());
+        assert_eq!(connector.call(uri).await.unwrap(), 3);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+}
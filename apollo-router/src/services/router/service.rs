@@ -45,6 +45,8 @@ use crate::graphql;
 use crate::http_ext;
 #[cfg(test)]
 use crate::plugin::test::MockSupergraphService;
+use crate::plugins::traffic_shaping::TrafficShaping;
+use crate::plugins::traffic_shaping::APOLLO_TRAFFIC_SHAPING;
 use crate::protocols::multipart::Multipart;
 use crate::protocols::multipart::ProtocolMode;
 use crate::query_planner::InMemoryCachePlanner;
@@ -868,6 +870,17 @@ impl RouterFactory for RouterCreator {
             .for_each(|p| mm.extend(p.web_endpoints()));
         mm
     }
+
+    fn drain(&self) {
+        if let Some(shaping) = self
+            .supergraph_creator
+            .plugins()
+            .get(APOLLO_TRAFFIC_SHAPING)
+            .and_then(|plugin| (**plugin).as_any().downcast_ref::<TrafficShaping>())
+        {
+            shaping.drain_http_clients();
+        }
+    }
 }
 
 impl RouterCreator {
@@ -14,13 +14,18 @@ use http::header::ACCEPT;
 use http::header::CONTENT_TYPE;
 use http::header::{self};
 use http::response::Parts;
+use http::HeaderName;
 use http::HeaderValue;
+use http::Method;
 use http::Request;
 use hyper::Body;
 use hyper_rustls::ConfigBuilderExt;
 use itertools::Itertools;
 use mediatype::names::APPLICATION;
+use mediatype::names::BOUNDARY;
 use mediatype::names::JSON;
+use mediatype::names::MIXED;
+use mediatype::names::MULTIPART;
 use mediatype::MediaType;
 use mime::APPLICATION_JSON;
 use rustls::RootCertStore;
@@ -62,6 +67,7 @@ use crate::plugins::subscription::SUBSCRIPTION_WS_CUSTOM_CONNECTION_PARAMS;
 use crate::plugins::telemetry::config_new::events::log_event;
 use crate::plugins::telemetry::config_new::events::SubgraphEventRequestLevel;
 use crate::plugins::telemetry::config_new::events::SubgraphEventResponseLevel;
+use crate::plugins::telemetry::config_new::logging::RedactedHeaders;
 use crate::plugins::telemetry::LOGGING_DISPLAY_BODY;
 use crate::plugins::telemetry::LOGGING_DISPLAY_HEADERS;
 use crate::protocols::websocket::convert_websocket_stream;
@@ -74,6 +80,8 @@ use crate::Configuration;
 use crate::Context;
 use crate::Notify;
 
+mod multipart;
+
 const PERSISTED_QUERY_NOT_FOUND_EXTENSION_CODE: &str = "PERSISTED_QUERY_NOT_FOUND";
 const PERSISTED_QUERY_NOT_SUPPORTED_EXTENSION_CODE: &str = "PERSISTED_QUERY_NOT_SUPPORTED";
 const PERSISTED_QUERY_NOT_FOUND_MESSAGE: &str = "PersistedQueryNotFound";
@@ -83,6 +91,9 @@ const PERSISTED_QUERY_KEY: &str = "persistedQuery";
 const HASH_VERSION_KEY: &str = "version";
 const HASH_VERSION_VALUE: i32 = 1;
 const HASH_KEY: &str = "sha256Hash";
+/// Maximum size of the start of a response body kept when it cannot be parsed
+const BODY_SNIPPET_MAX_BYTES: usize = 256;
+static APOLLO_REQUIRE_PREFLIGHT: HeaderName = HeaderName::from_static("apollo-require-preflight");
 const GRAPHQL_RESPONSE: mediatype::Name = mediatype::Name::new_unchecked("graphql-response");
 
 #[allow(clippy::declare_interior_mutable_const)]
@@ -90,7 +101,7 @@ static CALLBACK_PROTOCOL_ACCEPT: HeaderValue =
     HeaderValue::from_static("application/json;callbackSpec=1.0");
 pub(crate) static APPLICATION_JSON_HEADER_VALUE: HeaderValue =
     HeaderValue::from_static("application/json");
-static ACCEPT_GRAPHQL_JSON: HeaderValue =
+pub(crate) static ACCEPT_GRAPHQL_JSON: HeaderValue =
     HeaderValue::from_static("application/json, application/graphql-response+json");
 
 enum APQError {
@@ -124,6 +135,8 @@ pub(crate) struct SubgraphService {
     /// If a subgraph sends the error message PERSISTED_QUERY_NOT_SUPPORTED,
     /// apq is set to false
     apq: Arc<AtomicBool>,
+    /// Whether the queries are sent with their hash only as GET requests
+    apq_use_get: bool,
     /// Subscription config if enabled
     subscription_config: Option<SubscriptionConfig>,
     notify: Notify<String, graphql::Response>,
@@ -145,14 +158,24 @@ impl SubgraphService {
             .get(&name)
             .map(|apq| apq.enabled)
             .unwrap_or(configuration.apq.subgraph.all.enabled);
+        let apq_use_get = configuration
+            .apq
+            .subgraph
+            .subgraphs
+            .get(&name)
+            .map(|apq| apq.use_get)
+            .unwrap_or(configuration.apq.subgraph.all.use_get);
 
-        SubgraphService::new(
-            name,
-            enable_apq,
-            subscription_config,
-            configuration.notify.clone(),
-            client_factory,
-        )
+        Ok(Self {
+            apq_use_get,
+            ..SubgraphService::new(
+                name,
+                enable_apq,
+                subscription_config,
+                configuration.notify.clone(),
+                client_factory,
+            )?
+        })
     }
 
     pub(crate) fn new(
@@ -166,6 +189,7 @@ impl SubgraphService {
             client_factory,
             service: Arc::new(service.into()),
             apq: Arc::new(<AtomicBool>::new(enable_apq)),
+            apq_use_get: false,
             subscription_config,
             notify,
         })
@@ -243,6 +267,7 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
         let client_factory = self.client_factory.clone();
 
         let arc_apq_enabled = self.apq.clone();
+        let apq_use_get = self.apq_use_get;
 
         let mut notify = self.notify.clone();
 
@@ -409,8 +434,14 @@ impl tower::Service<SubgraphRequest> for SubgraphService {
                 extensions: extensions_with_apq,
             };
 
+            // the responses to GET requests can be cached by HTTP intermediaries, and the
+            // fallback requests with the query are always sent as POST requests
+            let mut apq_request = request.clone();
+            if apq_use_get && request.operation_kind == OperationKind::Query {
+                *apq_request.subgraph_request.method_mut() = Method::GET;
+            }
             let response = call_http(
-                request.clone(),
+                apq_request,
                 apq_body.clone(),
                 context.clone(),
                 client_factory.clone(),
@@ -525,6 +556,7 @@ async fn call_websocket(
 
     let display_headers = context.contains_key(LOGGING_DISPLAY_HEADERS);
     let display_body = context.contains_key(LOGGING_DISPLAY_BODY);
+    let redacted_headers = RedactedHeaders::from_context(&context);
 
     let signing_params = context
         .extensions()
@@ -549,7 +581,7 @@ async fn call_websocket(
         let mut attrs = HashMap::with_capacity(5);
         attrs.insert(
             "http.request.headers".to_string(),
-            format!("{:?}", request.headers()),
+            format!("{:?}", redacted_headers.redacted(request.headers())),
         );
         attrs.insert(
             "http.request.method".to_string(),
@@ -573,7 +605,7 @@ async fn call_websocket(
     }
 
     if display_headers {
-        tracing::info!(http.request.headers = ?request.headers(), apollo.subgraph.name = %service_name, "Websocket request headers to subgraph {service_name:?}");
+        tracing::info!(http.request.headers = ?redacted_headers.redacted(request.headers()), apollo.subgraph.name = %service_name, "Websocket request headers to subgraph {service_name:?}");
     }
 
     if display_body {
@@ -626,7 +658,7 @@ async fn call_websocket(
     })?;
 
     if display_headers {
-        tracing::info!(response.headers = ?resp.headers(), apollo.subgraph.name = %service_name, "Websocket response headers to subgraph {service_name:?}");
+        tracing::info!(response.headers = ?redacted_headers.redacted(resp.headers()), apollo.subgraph.name = %service_name, "Websocket response headers to subgraph {service_name:?}");
     }
     if display_body {
         tracing::info!(
@@ -696,6 +728,7 @@ fn http_response_to_graphql_response(
 ) -> graphql::Response {
     let mut graphql_response = match (content_type, body, parts.status.is_success()) {
         (Ok(ContentType::ApplicationGraphqlResponseJson), Some(Ok(body)), _)
+        | (Ok(ContentType::MultipartMixed(_)), Some(Ok(body)), _)
         | (Ok(ContentType::ApplicationJson), Some(Ok(body)), true) => {
             // Application graphql json expects valid graphql response
             // Application json expects valid graphql response if 2xx
@@ -846,7 +879,10 @@ pub(crate) async fn process_batch(
         let mut attrs = HashMap::with_capacity(5);
         attrs.insert(
             "http.response.headers".to_string(),
-            format!("{:?}", parts.headers),
+            format!(
+                "{:?}",
+                RedactedHeaders::from_context(&batch_context).redacted(&parts.headers)
+            ),
         );
         attrs.insert(
             "http.response.status".to_string(),
@@ -1145,7 +1181,9 @@ pub(crate) async fn call_single_http(
     service_name: &str,
 ) -> Result<SubgraphResponse, BoxError> {
     let SubgraphRequest {
-        subgraph_request, ..
+        subgraph_request,
+        operation_kind,
+        ..
     } = request;
 
     let operation_name = subgraph_request
@@ -1155,13 +1193,20 @@ pub(crate) async fn call_single_http(
         .unwrap_or_default();
 
     let (parts, _) = subgraph_request.into_parts();
-    let body = serde_json::to_string(&body)?;
-    tracing::debug!("our JSON body: {body:?}");
-    let mut request = http::Request::from_parts(parts, Body::from(body));
+    let mut request = if parts.method == Method::GET {
+        get_request(parts, &body)?
+    } else {
+        let body = serde_json::to_string(&body)?;
+        tracing::debug!("our JSON body: {body:?}");
+        let mut request = http::Request::from_parts(parts, Body::from(body));
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE.clone());
+        request
+    };
+    // lets the HTTP client layers know whether the request is safe to retry
+    request.extensions_mut().insert(operation_kind);
 
-    request
-        .headers_mut()
-        .insert(CONTENT_TYPE, APPLICATION_JSON_HEADER_VALUE.clone());
     request
         .headers_mut()
         .append(ACCEPT, ACCEPT_GRAPHQL_JSON.clone());
@@ -1178,6 +1223,7 @@ pub(crate) async fn call_single_http(
         "net.transport" = "ip_tcp",
         "apollo.subgraph.name" = %service_name,
         "graphql.operation.name" = %operation_name,
+        "http.request.resend_count" = ::tracing::field::Empty,
     );
 
     // The graphql spec is lax about what strategy to use for processing responses: https://github.com/graphql/graphql-over-http/blob/main/spec/GraphQLOverHTTP.md#processing-the-response
@@ -1208,7 +1254,10 @@ pub(crate) async fn call_single_http(
         let mut attrs = HashMap::with_capacity(5);
         attrs.insert(
             "http.request.headers".to_string(),
-            format!("{:?}", request.headers()),
+            format!(
+                "{:?}",
+                RedactedHeaders::from_context(&context).redacted(request.headers())
+            ),
         );
         attrs.insert(
             "http.request.method".to_string(),
@@ -1247,7 +1296,10 @@ pub(crate) async fn call_single_http(
         let mut attrs = HashMap::with_capacity(5);
         attrs.insert(
             "http.response.headers".to_string(),
-            format!("{:?}", parts.headers),
+            format!(
+                "{:?}",
+                RedactedHeaders::from_context(&context).redacted(&parts.headers)
+            ),
         );
         attrs.insert(
             "http.response.status".to_string(),
@@ -1291,6 +1343,8 @@ pub(crate) async fn call_single_http(
 enum ContentType {
     ApplicationJson,
     ApplicationGraphqlResponseJson,
+    /// Incremental delivery, with the boundary of the parts
+    MultipartMixed(String),
 }
 
 fn get_graphql_content_type(service_name: &str, parts: &Parts) -> Result<ContentType, FetchError> {
@@ -1307,26 +1361,106 @@ fn get_graphql_content_type(service_name: &str, parts: &Parts) -> Result<Content
                 && content_type.suffix == Some(JSON)
             {
                 Ok(ContentType::ApplicationGraphqlResponseJson)
+            } else if content_type.ty == MULTIPART && content_type.subty == MIXED {
+                match content_type.get_param(BOUNDARY) {
+                    Some(boundary) => Ok(ContentType::MultipartMixed(
+                        boundary.unquoted_str().into_owned(),
+                    )),
+                    None => Err(FetchError::SubrequestHttpError {
+                        status_code: Some(parts.status.as_u16()),
+                        service: service_name.to_string(),
+                        reason: "multipart subgraph response without a boundary".to_string(),
+                    }),
+                }
             } else {
-                Err(FetchError::SubrequestHttpError {
-                    status_code: Some(parts.status.as_u16()),
+                Err(FetchError::SubrequestUnexpectedContentType {
+                    status_code: parts.status.as_u16(),
                     service: service_name.to_string(),
-                    reason: format!("subgraph didn't return JSON (expected content-type: {} or content-type: {}; found content-type: {content_type})", APPLICATION_JSON.essence_str(), GRAPHQL_JSON_RESPONSE_HEADER_VALUE),
+                    content_type: content_type.to_string(),
+                    body: String::new(),
                 })
             }
         }
-        None | Some(_) => Err(FetchError::SubrequestHttpError {
-            status_code: Some(parts.status.as_u16()),
+        Some(Ok(Err(_))) | Some(Err(_)) => Err(FetchError::SubrequestUnexpectedContentType {
+            status_code: parts.status.as_u16(),
             service: service_name.to_string(),
-            reason: format!(
-                "subgraph didn't return JSON (expected content-type: {} or content-type: {})",
-                APPLICATION_JSON.essence_str(),
-                GRAPHQL_JSON_RESPONSE_HEADER_VALUE
-            ),
+            content_type: String::from_utf8_lossy(parts.headers[header::CONTENT_TYPE].as_bytes())
+                .into_owned(),
+            body: String::new(),
         }),
+        None => Err(FetchError::SubrequestUnexpectedContentType {
+            status_code: parts.status.as_u16(),
+            service: service_name.to_string(),
+            content_type: "<none>".to_string(),
+            body: String::new(),
+        }),
+    }
+}
+
+/// Start of a response body that is not parsed, for diagnostics
+fn body_snippet(body: &[u8]) -> String {
+    match body.get(..BODY_SNIPPET_MAX_BYTES) {
+        Some(start) if body.len() > BODY_SNIPPET_MAX_BYTES => {
+            format!("{}...", String::from_utf8_lossy(start))
+        }
+        _ => String::from_utf8_lossy(body).into_owned(),
     }
 }
 
+/// Moves the GraphQL request to the query string of a GET request without body
+///
+/// The `apollo-require-preflight` header lets the request through the CSRF prevention of Apollo
+/// Server, which rejects GET requests without a `Content-Type`.
+fn get_request(
+    mut parts: http::request::Parts,
+    body: &graphql::Request,
+) -> Result<Request<Body>, BoxError> {
+    let to_json = |object: &Object| {
+        (!object.is_empty())
+            .then(|| serde_json::to_string(object))
+            .transpose()
+    };
+    let query = serde_urlencoded::to_string([
+        ("query", body.query.clone()),
+        ("operationName", body.operation_name.clone()),
+        ("variables", to_json(&body.variables)?),
+        ("extensions", to_json(&body.extensions)?),
+    ])?;
+    let mut uri = http::uri::Parts::from(parts.uri);
+    let path = uri
+        .path_and_query
+        .as_ref()
+        .map(|path_and_query| path_and_query.path())
+        .unwrap_or("/");
+    let path_and_query = match uri
+        .path_and_query
+        .as_ref()
+        .and_then(|path_and_query| path_and_query.query())
+    {
+        Some(existing) => format!("{path}?{existing}&{query}"),
+        None => format!("{path}?{query}"),
+    };
+    uri.path_and_query = Some(path_and_query.parse()?);
+    parts.uri = http::Uri::from_parts(uri)?;
+    parts.headers.remove(CONTENT_TYPE);
+    parts
+        .headers
+        .insert(APOLLO_REQUIRE_PREFLIGHT, HeaderValue::from_static("true"));
+    Ok(Request::from_parts(parts, Body::empty()))
+}
+
+/// Reads the start of a response body, without buffering the rest
+async fn read_body_snippet(mut body: Body) -> Result<Bytes, hyper::Error> {
+    let mut snippet = Vec::new();
+    while snippet.len() <= BODY_SNIPPET_MAX_BYTES {
+        match body.next().await {
+            Some(data) => snippet.extend_from_slice(&data?),
+            None => break,
+        }
+    }
+    Ok(snippet.into())
+}
+
 async fn do_fetch(
     mut client: crate::services::http::BoxService,
     context: &Context,
@@ -1349,29 +1483,55 @@ async fn do_fetch(
         })
         .map_err(|err| {
             tracing::error!(fetch_error = ?err);
-            FetchError::SubrequestHttpError {
+            subrequest_error(&*err).unwrap_or_else(|| FetchError::SubrequestHttpError {
                 status_code: None,
                 service: service_name.to_string(),
                 reason: err.to_string(),
-            }
+            })
         })
         .await?;
 
     let (parts, body) = response.http_response.into_parts();
 
-    let content_type = get_graphql_content_type(service_name, &parts);
+    let mut content_type = get_graphql_content_type(service_name, &parts);
 
-    let body = if content_type.is_ok() {
+    // the parts of incremental responses are merged into a single GraphQL response
+    let body = if let Ok(ContentType::MultipartMixed(boundary)) = &content_type {
+        let body = multipart::merge_incremental_responses(multipart::parse_multipart_response(
+            service_name,
+            body,
+            boundary.clone(),
+        ))
+        .instrument(tracing::debug_span!("aggregate_response_data"))
+        .await
+        .and_then(|response| {
+            serde_json::to_vec(&response)
+                .map(Bytes::from)
+                .map_err(|err| FetchError::SubrequestMalformedResponse {
+                    service: service_name.to_string(),
+                    reason: err.to_string(),
+                })
+        });
+        if let Ok(body) = &body {
+            if display_body {
+                tracing::info!(
+                    http.response.body = %String::from_utf8_lossy(body), apollo.subgraph.name = %service_name, "Raw response body from subgraph {service_name:?} received"
+                );
+            }
+        }
+        Some(body)
+    } else if content_type.is_ok() {
+        // the HTTP client streams the body, but a GraphQL response can only be parsed once complete
         let body = hyper::body::to_bytes(body)
             .instrument(tracing::debug_span!("aggregate_response_data"))
             .await
             .map_err(|err| {
                 tracing::error!(fetch_error = ?err);
-                FetchError::SubrequestHttpError {
+                subrequest_error(&err).unwrap_or_else(|| FetchError::SubrequestHttpError {
                     status_code: Some(parts.status.as_u16()),
                     service: service_name.to_string(),
                     reason: err.to_string(),
-                }
+                })
             });
         if let Ok(body) = &body {
             if display_body {
@@ -1382,22 +1542,28 @@ async fn do_fetch(
         }
         Some(body)
     } else {
-        if display_body {
-            let body = hyper::body::to_bytes(body)
+        let body = if display_body {
+            hyper::body::to_bytes(body)
                 .instrument(tracing::debug_span!("aggregate_response_data"))
                 .await
-                .map_err(|err| {
-                    tracing::error!(fetch_error = ?err);
-                    FetchError::SubrequestHttpError {
-                        status_code: Some(parts.status.as_u16()),
-                        service: service_name.to_string(),
-                        reason: err.to_string(),
-                    }
-                });
-            if let Ok(body) = &body {
-                tracing::info!(
-                    http.response.body = %String::from_utf8_lossy(body), apollo.subgraph.name = %service_name, "Raw response body from subgraph {service_name:?} received"
-                );
+        } else {
+            read_body_snippet(body).await
+        };
+        match &body {
+            Ok(body) => {
+                if display_body {
+                    tracing::info!(
+                        http.response.body = %String::from_utf8_lossy(body), apollo.subgraph.name = %service_name, "Raw response body from subgraph {service_name:?} received"
+                    );
+                }
+                if let Err(FetchError::SubrequestUnexpectedContentType { body: snippet, .. }) =
+                    &mut content_type
+                {
+                    *snippet = body_snippet(body);
+                }
+            }
+            Err(err) => {
+                tracing::error!(fetch_error = ?err);
             }
         }
         None
@@ -1405,6 +1571,34 @@ async fn do_fetch(
     Ok((parts, content_type, body))
 }
 
+// the HTTP client returns a typed error when the request_timeout of the subgraph elapses, either
+// directly or as the source of the error reading the response body, when no connection is
+// acquired within the pool_acquire_timeout of the subgraph, and when the circuit breaker,
+// the rate limit or the bulkhead of the subgraph rejects the request, and when all the endpoints
+// of the subgraph are ejected
+fn subrequest_error(err: &(dyn std::error::Error + 'static)) -> Option<FetchError> {
+    let mut error = Some(err);
+    while let Some(err) = error {
+        if let Some(
+            fetch_error @ (FetchError::SubrequestTimeout { .. }
+            | FetchError::SubrequestPoolTimeout { .. }
+            | FetchError::SubrequestCircuitOpen { .. }
+            | FetchError::SubrequestRateLimited { .. }
+            | FetchError::SubrequestOverloaded { .. }
+            | FetchError::SubrequestEndpointsEjected { .. }
+            | FetchError::SubrequestHttpError {
+                status_code: Some(_),
+                ..
+            }),
+        ) = err.downcast_ref()
+        {
+            return Some(fetch_error.clone());
+        }
+        error = err.source();
+    }
+    None
+}
+
 fn get_websocket_request(
     service_name: String,
     mut parts: http::request::Parts,
@@ -1698,6 +1892,28 @@ mod tests {
         server.await.unwrap();
     }
 
+    // starts a local server emulating a subgraph returning an incremental response
+    async fn emulate_subgraph_multipart_response(listener: TcpListener) {
+        async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            Ok(http::Response::builder()
+                .header(CONTENT_TYPE, "multipart/mixed;boundary=\"-\";deferSpec=20220824")
+                .status(StatusCode::OK)
+                .body(
+                    "\r\n---\r\ncontent-type: application/json\r\n\r\n\
+                    {\"data\":{\"me\":{\"id\":\"1\"}},\"hasNext\":true}\
+                    \r\n---\r\ncontent-type: application/json\r\n\r\n\
+                    {\"incremental\":[{\"data\":{\"name\":\"Ada\"},\"path\":[\"me\"]}],\"hasNext\":false}\
+                    \r\n-----\r\n"
+                        .into(),
+                )
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::from_tcp(listener).unwrap().serve(make_svc);
+        server.await.unwrap();
+    }
+
     // starts a local server emulating a subgraph returning bad response format
     async fn emulate_subgraph_bad_response_format(listener: TcpListener) {
         async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
@@ -1945,6 +2161,70 @@ mod tests {
         server.await.unwrap();
     }
 
+    // starts a local server emulating a subgraph that knows the persisted queries once they were
+    // sent with their query, and records the method of the requests and whether they had a query
+    async fn emulate_persisted_query_with_get(
+        listener: TcpListener,
+        requests: Arc<std::sync::Mutex<Vec<(Method, bool)>>>,
+    ) {
+        let known = Arc::new(AtomicBool::new(false));
+        let make_svc = make_service_fn(move |_conn| {
+            let requests = requests.clone();
+            let known = known.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: http::Request<Body>| {
+                    let requests = requests.clone();
+                    let known = known.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let request = if parts.method == Method::GET {
+                            assert_eq!(parts.headers[&APOLLO_REQUIRE_PREFLIGHT], "true");
+                            assert!(parts.headers.get(CONTENT_TYPE).is_none());
+                            Request::from_urlencoded_query(
+                                parts.uri.query().unwrap_or_default().to_string(),
+                            )
+                            .unwrap()
+                        } else {
+                            let body = hyper::body::to_bytes(body).await.unwrap();
+                            serde_json::from_slice::<Request>(&body).unwrap()
+                        };
+                        assert!(request.extensions.contains_key(PERSISTED_QUERY_KEY));
+                        assert_eq!(request.variables.get("first"), Some(&Value::from(2_usize)));
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push((parts.method, request.query.is_some()));
+
+                        let response = if request.query.is_some() {
+                            known.store(true, Relaxed);
+                            Response::builder().data(Value::from("test")).build()
+                        } else if known.load(Relaxed) {
+                            Response::builder().data(Value::from("cached")).build()
+                        } else {
+                            Response::builder()
+                                .error(
+                                    Error::builder()
+                                        .message(PERSISTED_QUERY_NOT_FOUND_MESSAGE)
+                                        .extension_code(PERSISTED_QUERY_NOT_FOUND_EXTENSION_CODE)
+                                        .build(),
+                                )
+                                .build()
+                        };
+                        Ok::<_, Infallible>(
+                            http::Response::builder()
+                                .header(CONTENT_TYPE, APPLICATION_JSON.essence_str())
+                                .status(StatusCode::OK)
+                                .body(Body::from(serde_json::to_string(&response).unwrap()))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener).unwrap().serve(make_svc);
+        server.await.unwrap();
+    }
+
     // starts a local server emulating a subgraph returning a response to request with apq
     // and panics if it does not find a persistedQuery.
     async fn emulate_expected_apq_enabled_configuration(listener: TcpListener) {
@@ -2238,6 +2518,44 @@ mod tests {
         spawned_task.abort();
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_subgraph_service_content_type_multipart_mixed() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::task::spawn(emulate_subgraph_multipart_response(listener));
+        let subgraph_service = SubgraphService::new(
+            "test",
+            true,
+            None,
+            Notify::default(),
+            HttpClientServiceFactory::from_config(
+                "test",
+                &Configuration::default(),
+                Http2Config::Enable,
+            ),
+        )
+        .expect("can create a SubgraphService");
+
+        let url = Uri::from_str(&format!("http://{socket_addr}")).unwrap();
+        let response = subgraph_service
+            .oneshot(
+                SubgraphRequest::builder()
+                    .supergraph_request(supergraph_request("query"))
+                    .subgraph_request(subgraph_http_request(url, "query"))
+                    .operation_kind(OperationKind::Query)
+                    .subgraph_name(String::from("test"))
+                    .context(Context::new())
+                    .build(),
+            )
+            .await
+            .unwrap();
+        assert!(response.response.body().errors.is_empty());
+        assert_eq!(
+            response.response.body().data,
+            Some(serde_json_bytes::json!({ "me": { "id": "1", "name": "Ada" } }))
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_subgraph_service_content_type_application_graphql() {
         let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
@@ -2599,10 +2917,28 @@ mod tests {
             )
             .await
             .unwrap();
+        let error = &response.response.body().errors[0];
         assert_eq!(
-            response.response.body().errors[0].message,
+            error.message,
             "HTTP fetch failed from 'test': subgraph didn't return JSON (expected content-type: application/json or content-type: application/graphql-response+json; found content-type: text/html)"
         );
+        assert_eq!(error.extensions["code"], "SUBREQUEST_HTTP_ERROR");
+        assert_eq!(error.extensions["content_type"], "text/html");
+        assert_eq!(error.extensions["body"], "TEST");
+        assert_eq!(
+            error.extensions["http"],
+            serde_json_bytes::json!({ "status": 200 })
+        );
+    }
+
+    #[test]
+    fn it_truncates_body_snippets() {
+        assert_eq!(super::body_snippet(b"<html></html>"), "<html></html>");
+        let body = "a".repeat(BODY_SNIPPET_MAX_BYTES + 1);
+        assert_eq!(
+            super::body_snippet(body.as_bytes()),
+            format!("{}...", &body[..BODY_SNIPPET_MAX_BYTES])
+        );
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -2814,6 +3150,69 @@ mod tests {
         assert_eq!(resp.response.body(), &expected_resp);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_persisted_query_with_get() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        tokio::task::spawn(emulate_persisted_query_with_get(listener, requests.clone()));
+        let configuration: Configuration = serde_yaml::from_str(
+            r#"
+            apq:
+              subgraph:
+                subgraphs:
+                  test:
+                    enabled: true
+                    use_get: true
+            "#,
+        )
+        .unwrap();
+        let subgraph_service = SubgraphService::from_config(
+            "test",
+            &configuration,
+            None,
+            HttpClientServiceFactory::from_config("test", &configuration, Http2Config::Enable),
+        )
+        .expect("can create a SubgraphService");
+
+        let url = Uri::from_str(&format!("http://{socket_addr}/graphql")).unwrap();
+        let call = |operation_kind: OperationKind| {
+            let mut subgraph_request = subgraph_http_request(url.clone(), "query");
+            subgraph_request
+                .body_mut()
+                .variables
+                .insert("first", Value::from(2_usize));
+            subgraph_service.clone().oneshot(
+                SubgraphRequest::builder()
+                    .supergraph_request(supergraph_request("query"))
+                    .subgraph_request(subgraph_request)
+                    .operation_kind(operation_kind)
+                    .subgraph_name(String::from("test"))
+                    .context(Context::new())
+                    .build(),
+            )
+        };
+
+        // the unknown hash is sent again with its query in a POST request
+        let response = call(OperationKind::Query).await.unwrap();
+        assert_eq!(response.response.body().data, Some(Value::from("test")));
+        let response = call(OperationKind::Query).await.unwrap();
+        assert_eq!(response.response.body().data, Some(Value::from("cached")));
+        // mutations are always sent as POST requests
+        let response = call(OperationKind::Mutation).await.unwrap();
+        assert_eq!(response.response.body().data, Some(Value::from("cached")));
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                (Method::GET, false),
+                (Method::POST, true),
+                (Method::GET, false),
+                (Method::POST, false),
+            ]
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_apq_enabled_subgraph_configuration() {
         let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
@@ -0,0 +1,175 @@
+//! Incremental delivery of subgraph responses
+//!
+//! Subgraphs supporting incremental delivery can answer with a `multipart/mixed` response, where
+//! each part is a GraphQL response: the first one is the initial payload, and the following ones
+//! carry the `incremental` payloads, until one of them sets `hasNext` to `false`.
+
+use futures::Stream;
+use futures::StreamExt;
+use hyper::Body;
+use multer::Multipart;
+
+use crate::error::FetchError;
+use crate::graphql;
+use crate::json_ext::Value;
+use crate::json_ext::ValueExt;
+
+/// Parses the parts of a `multipart/mixed` subgraph response
+///
+/// The stream ends after the first part without `hasNext: true`, or with an error if the body
+/// ends before it.
+pub(crate) fn parse_multipart_response(
+    service_name: &str,
+    body: Body,
+    boundary: String,
+) -> impl Stream<Item = Result<graphql::Response, FetchError>> {
+    let malformed = {
+        let service_name = service_name.to_string();
+        move |reason: String| FetchError::SubrequestMalformedResponse {
+            service: service_name.clone(),
+            reason,
+        }
+    };
+
+    futures::stream::unfold(Some(Multipart::new(body, boundary)), move |multipart| {
+        let malformed = malformed.clone();
+        async move {
+            let mut multipart = multipart?;
+            let part = match multipart.next_field().await {
+                Ok(Some(field)) => field.bytes().await,
+                Ok(None) => {
+                    return Some((
+                        Err(malformed(
+                            "multipart response ended before the last part".to_string(),
+                        )),
+                        None,
+                    ))
+                }
+                Err(err) => Err(err),
+            };
+            let response = part
+                .map_err(|err| malformed(format!("cannot read multipart response: {err}")))
+                .and_then(|bytes| {
+                    serde_json::from_slice::<graphql::Response>(&bytes)
+                        .map_err(|err| malformed(err.to_string()))
+                });
+            let has_next = matches!(&response, Ok(response) if response.has_next == Some(true));
+            Some((response, has_next.then_some(multipart)))
+        }
+    })
+}
+
+/// Merges the incremental payloads of a subgraph response into its initial payload
+///
+/// The data of each payload is inserted at its path, and its errors are appended to the errors
+/// of the initial payload.
+pub(crate) async fn merge_incremental_responses(
+    responses: impl Stream<Item = Result<graphql::Response, FetchError>>,
+) -> Result<graphql::Response, FetchError> {
+    let mut responses = Box::pin(responses);
+    let mut merged = match responses.next().await {
+        Some(response) => response?,
+        None => return Ok(graphql::Response::default()),
+    };
+
+    while let Some(response) = responses.next().await {
+        let response = response?;
+        merged.errors.extend(response.errors);
+        for incremental in response.incremental {
+            merged.errors.extend(incremental.errors);
+            if let (Some(data), Some(path)) = (incremental.data, incremental.path) {
+                merged
+                    .data
+                    .get_or_insert_with(Value::default)
+                    .deep_merge(Value::from_path(&path, data));
+            }
+        }
+    }
+    merged.has_next = None;
+    merged.incremental.clear();
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn multipart_body(parts: &[&str]) -> Body {
+        let mut body = String::new();
+        for part in parts {
+            body.push_str("\r\n--graphql\r\ncontent-type: application/json\r\n\r\n");
+            body.push_str(part);
+        }
+        body.push_str("\r\n--graphql--\r\n");
+        body.into()
+    }
+
+    #[tokio::test]
+    async fn it_merges_incremental_payloads() {
+        let body = multipart_body(&[
+            r#"{"data":{"me":{"id":"1","reviews":[{"id":"a"},{"id":"b"}]}},"hasNext":true}"#,
+            r#"{"incremental":[{"data":{"body":"great"},"path":["me","reviews",1]}],"hasNext":true}"#,
+            r#"{"incremental":[{"data":{"name":"Ada"},"path":["me"],"errors":[{"message":"partial"}]}],"hasNext":false}"#,
+        ]);
+
+        let response = merge_incremental_responses(parse_multipart_response(
+            "test",
+            body,
+            "graphql".to_string(),
+        ))
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "me": {
+                    "id": "1",
+                    "reviews": [{ "id": "a" }, { "id": "b", "body": "great" }],
+                    "name": "Ada"
+                }
+            }))
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].message, "partial");
+        assert_eq!(response.has_next, None);
+    }
+
+    #[tokio::test]
+    async fn it_stops_after_the_last_part() {
+        let body = multipart_body(&[
+            r#"{"data":{"me":{"id":"1"}},"hasNext":false}"#,
+            r#"{"incremental":[{"data":{"name":"Ada"},"path":["me"]}]}"#,
+        ]);
+
+        let responses: Vec<_> = parse_multipart_response("test", body, "graphql".to_string())
+            .collect()
+            .await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].as_ref().unwrap().data,
+            Some(json!({ "me": { "id": "1" } }))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_when_the_last_part_is_missing() {
+        let body = multipart_body(&[r#"{"data":{"me":{"id":"1"}},"hasNext":true}"#]);
+
+        let err = merge_incremental_responses(parse_multipart_response(
+            "test",
+            body,
+            "graphql".to_string(),
+        ))
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "service 'test' response was malformed: multipart response ended before the last part"
+        );
+    }
+}
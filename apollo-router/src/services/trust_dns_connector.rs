@@ -1,31 +1,57 @@
 use std::future::Future;
 use std::io;
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
 
 use hyper::client::connect::dns::Name;
 use hyper::client::HttpConnector;
 use hyper::service::Service;
+use trust_dns_resolver::config::LookupIpStrategy;
+use trust_dns_resolver::system_conf::read_system_conf;
 use trust_dns_resolver::TokioAsyncResolver;
 
+use crate::services::http::ipv6_zone;
+
 /// Wrapper around trust-dns-resolver's
 /// [`TokioAsyncResolver`](https://docs.rs/trust-dns-resolver/0.23.2/trust_dns_resolver/type.TokioAsyncResolver.html)
 ///
 /// The resolver runs a background Task which manages dns requests. When a new resolver is created,
 /// the background task is also created, it needs to be spawned on top of an executor before using the client,
 /// or dns requests will block.
+///
+/// When the connections are bound to a local address, only the addresses of its family are
+/// returned.
 #[derive(Debug, Clone)]
-pub(crate) struct AsyncHyperResolver(TokioAsyncResolver);
+pub(crate) struct AsyncHyperResolver {
+    resolver: TokioAsyncResolver,
+    local_address: Option<IpAddr>,
+}
 
 impl AsyncHyperResolver {
     /// constructs a new resolver from default configuration, uses the corresponding method of
     /// [`TokioAsyncResolver`](https://docs.rs/trust-dns-resolver/0.23.2/trust_dns_resolver/type.TokioAsyncResolver.html#method.new)
     pub(crate) fn new_from_system_conf() -> Result<Self, io::Error> {
         let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
-        Ok(Self(resolver))
+        Ok(Self {
+            resolver,
+            local_address: None,
+        })
+    }
+
+    /// constructs a new resolver from default configuration, returning both the IPv6 and the IPv4
+    /// addresses of a name, IPv6 first
+    pub(crate) fn new_dual_stack_from_system_conf() -> Result<Self, io::Error> {
+        let (config, mut options) = read_system_conf()?;
+        options.ip_strategy = LookupIpStrategy::Ipv6AndIpv4;
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio(config, options),
+            local_address: None,
+        })
     }
 }
 
@@ -39,19 +65,36 @@ impl Service<Name> for AsyncHyperResolver {
     }
 
     fn call(&mut self, name: Name) -> Self::Future {
-        let resolver = self.0.clone();
+        let resolver = self.resolver.clone();
+        let local_address = self.local_address;
 
         Box::pin(async move {
-            Ok(resolver
-                .lookup_ip(name.as_str())
-                .await?
-                .iter()
-                .map(|addr| (addr, 0_u16).to_socket_addrs())
-                .try_fold(Vec::new(), |mut acc, s_addr| {
-                    acc.extend(s_addr?);
-                    Ok::<_, io::Error>(acc)
-                })?
-                .into_iter())
+            // hyper only connects directly to the IP hosts without a zone identifier
+            let mut addrs = match ipv6_zone::socket_addr(name.as_str()) {
+                Some(addr) => vec![addr?],
+                None => resolver
+                    .lookup_ip(name.as_str())
+                    .await?
+                    .iter()
+                    .map(|addr| (addr, 0_u16).to_socket_addrs())
+                    .try_fold(Vec::new(), |mut acc, s_addr| {
+                        acc.extend(s_addr?);
+                        Ok::<_, io::Error>(acc)
+                    })?,
+            };
+            if let Some(local_address) = local_address {
+                addrs.retain(|addr| addr.is_ipv4() == local_address.is_ipv4());
+                if addrs.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!(
+                            "{name} has no {} address to connect to from local_address {local_address}",
+                            if local_address.is_ipv4() { "IPv4" } else { "IPv6" }
+                        ),
+                    ));
+                }
+            }
+            Ok(addrs.into_iter())
         })
     }
 }
@@ -61,3 +104,28 @@ pub(crate) fn new_async_http_connector() -> Result<HttpConnector<AsyncHyperResol
     let resolver = AsyncHyperResolver::new_from_system_conf()?;
     Ok(HttpConnector::new_with_resolver(resolver))
 }
+
+/// Creates an http connector attempting IPv6 and IPv4 connections concurrently (Happy Eyeballs,
+/// RFC 8305): the IPv4 attempt starts after the connection attempt delay if the IPv6 one has not
+/// succeeded yet, and the first established connection is used
+pub(crate) fn new_dual_stack_async_http_connector(
+    connection_attempt_delay: Duration,
+) -> Result<HttpConnector<AsyncHyperResolver>, io::Error> {
+    let resolver = AsyncHyperResolver::new_dual_stack_from_system_conf()?;
+    let mut connector = HttpConnector::new_with_resolver(resolver);
+    connector.set_happy_eyeballs_timeout(Some(connection_attempt_delay));
+    Ok(connector)
+}
+
+/// Creates an http connector binding its connections to a local address, and resolving names
+/// to the addresses of its family only
+pub(crate) fn new_bound_async_http_connector(
+    local_address: IpAddr,
+) -> Result<HttpConnector<AsyncHyperResolver>, io::Error> {
+    // both families are looked up, the default strategy skips IPv6 when there is an IPv4 address
+    let mut resolver = AsyncHyperResolver::new_dual_stack_from_system_conf()?;
+    resolver.local_address = Some(local_address);
+    let mut connector = HttpConnector::new_with_resolver(resolver);
+    connector.set_local_address(Some(local_address));
+    Ok(connector)
+}
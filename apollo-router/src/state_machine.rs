@@ -229,6 +229,9 @@ impl<FA: RouterSuperServiceFactory> State<FA> {
                     .await
                     {
                         Ok(new_state) => {
+                            // the new router is live, the operations of the previous one get the
+                            // drain timeout to complete
+                            router_service_factory.drain();
                             tracing::info!(
                                 new_schema = schema_reload,
                                 new_license = license_reload,
@@ -260,6 +260,17 @@ impl<'a> TestHarness<'a> {
         self
     }
 
+    /// Sends the subgraph requests to a mocked HTTP client instead of the network.
+    ///
+    /// Subgraph plugins, header propagation and traffic shaping still apply to the requests,
+    /// which can then be inspected with
+    /// [`MockSubgraphHttpClient::received_requests`][mocks::http_client::MockSubgraphHttpClient::received_requests].
+    #[cfg(any(test, feature = "testing"))]
+    pub fn http_client_mock(self, mock: mocks::http_client::MockSubgraphHttpClient) -> Self {
+        self.with_subgraph_network_requests()
+            .extra_private_plugin(mock)
+    }
+
     pub(crate) async fn build_common(
         self,
     ) -> Result<(Arc<Configuration>, SupergraphCreator), BoxError> {
@@ -345,6 +356,19 @@ impl<'a> TestHarness<'a> {
         .boxed_clone())
     }
 
+    /// Builds the router factory, to drive it like the state machine does
+    #[cfg(test)]
+    pub(crate) async fn build_router_creator(self) -> Result<RouterCreator, BoxError> {
+        let (config, supergraph_creator) = self.build_common().await?;
+        RouterCreator::new(
+            QueryAnalysisLayer::new(supergraph_creator.schema(), Arc::clone(&config)).await,
+            Arc::new(PersistedQueryLayer::new(&config).await.unwrap()),
+            Arc::new(supergraph_creator),
+            config,
+        )
+        .await
+    }
+
     #[cfg(test)]
     pub(crate) async fn build_http_service(self) -> Result<HttpService, BoxError> {
         use crate::axum_factory::tests::make_axum_router;
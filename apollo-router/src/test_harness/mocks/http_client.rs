@@ -0,0 +1,229 @@
+//! In-process replacement of the HTTP client sending requests to subgraphs
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::HeaderMap;
+use http::StatusCode;
+use http::Uri;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+use crate::plugin::PluginInit;
+use crate::plugin::PluginPrivate;
+use crate::services::http::HttpRequest;
+use crate::services::http::HttpResponse;
+
+type Matcher = Arc<dyn Fn(&http::Request<Bytes>) -> bool + Send + Sync>;
+
+struct MockResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Mock of the HTTP client of subgraphs, with canned responses
+///
+/// It receives the HTTP requests once the subgraph plugins, header propagation and traffic
+/// shaping are applied, without opening any connection. The first registered response matching
+/// a request is returned, and requests without a matching response fail. Use it with
+/// [`TestHarness::http_client_mock`][crate::TestHarness::http_client_mock].
+#[derive(Clone)]
+pub struct MockSubgraphHttpClient {
+    responses: Arc<Vec<(Matcher, MockResponse)>>,
+    received: Arc<Mutex<Vec<http::Request<Bytes>>>>,
+}
+
+/// Builder of a [`MockSubgraphHttpClient`]
+#[derive(Default)]
+pub struct MockSubgraphHttpClientBuilder {
+    responses: Vec<(Matcher, MockResponse)>,
+}
+
+impl MockSubgraphHttpClient {
+    pub fn builder() -> MockSubgraphHttpClientBuilder {
+        MockSubgraphHttpClientBuilder::default()
+    }
+
+    /// Requests received so far, in the order they were sent
+    pub fn received_requests(&self) -> Vec<http::Request<Bytes>> {
+        self.received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(clone_request)
+            .collect()
+    }
+}
+
+impl MockSubgraphHttpClientBuilder {
+    /// Responds to the requests sent to this URI
+    pub fn with_response(self, uri: &str, response: http::Response<String>) -> Self {
+        let uri: Uri = uri.parse().expect("invalid mocked URI");
+        self.with_matcher(move |request| request.uri() == &uri, response)
+    }
+
+    /// Responds to the requests accepted by the matcher
+    pub fn with_matcher(
+        mut self,
+        matcher: impl Fn(&http::Request<Bytes>) -> bool + Send + Sync + 'static,
+        response: http::Response<String>,
+    ) -> Self {
+        let (parts, body) = response.into_parts();
+        self.responses.push((
+            Arc::new(matcher),
+            MockResponse {
+                status: parts.status,
+                headers: parts.headers,
+                body: body.into(),
+            },
+        ));
+        self
+    }
+
+    pub fn build(self) -> MockSubgraphHttpClient {
+        MockSubgraphHttpClient {
+            responses: Arc::new(self.responses),
+            received: Default::default(),
+        }
+    }
+}
+
+// extensions are not cloned
+fn clone_request(request: &http::Request<Bytes>) -> http::Request<Bytes> {
+    let mut clone = http::Request::new(request.body().clone());
+    *clone.method_mut() = request.method().clone();
+    *clone.uri_mut() = request.uri().clone();
+    *clone.version_mut() = request.version();
+    *clone.headers_mut() = request.headers().clone();
+    clone
+}
+
+impl Service<HttpRequest> for MockSubgraphHttpClient {
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let HttpRequest {
+                http_request,
+                context,
+            } = request;
+            let (parts, body) = http_request.into_parts();
+            let http_request = http::Request::from_parts(parts, hyper::body::to_bytes(body).await?);
+
+            let response = this
+                .responses
+                .iter()
+                .find(|(matcher, _)| matcher(&http_request))
+                .map(|(_, response)| {
+                    let mut http_response =
+                        http::Response::new(hyper::Body::from(response.body.clone()));
+                    *http_response.status_mut() = response.status;
+                    *http_response.headers_mut() = response.headers.clone();
+                    http_response
+                });
+            let error = format!(
+                "no mocked response for {} {}",
+                http_request.method(),
+                http_request.uri()
+            );
+            this.received.lock().unwrap().push(http_request);
+
+            Ok(HttpResponse {
+                http_response: response.ok_or(error)?,
+                context,
+            })
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl PluginPrivate for MockSubgraphHttpClient {
+    type Config = ();
+
+    async fn new(_: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        unreachable!()
+    }
+
+    fn http_client_service(
+        &self,
+        _subgraph_name: &str,
+        _service: crate::services::http::BoxService,
+    ) -> crate::services::http::BoxService {
+        self.clone().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::services::supergraph;
+    use crate::TestHarness;
+
+    #[tokio::test]
+    async fn test_mock_http_client() {
+        let mock = MockSubgraphHttpClient::builder()
+            .with_response(
+                "http://localhost:4001",
+                http::Response::builder()
+                    .header("content-type", "application/json")
+                    .body(r#"{"data":{"me":{"name":"Ada Lovelace"}}}"#.to_string())
+                    .unwrap(),
+            )
+            .build();
+        let service = TestHarness::builder()
+            .schema(include_str!("../../../testing_schema.graphql"))
+            .configuration_yaml(
+                r#"
+            headers:
+              all:
+                request:
+                  - propagate:
+                      named: x-tenant
+            "#,
+            )
+            .unwrap()
+            .http_client_mock(mock.clone())
+            .build_supergraph()
+            .await
+            .unwrap();
+
+        let request = supergraph::Request::fake_builder()
+            .query("{ me { name } }")
+            .header("x-tenant", "acme")
+            .build()
+            .unwrap();
+        let response = service
+            .oneshot(request)
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.data,
+            Some(json!({ "me": { "name": "Ada Lovelace" } }))
+        );
+
+        let requests = mock.received_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers()["x-tenant"], "acme");
+        assert!(std::str::from_utf8(requests[0].body())
+            .unwrap()
+            .contains("me{name}"));
+    }
+}
@@ -1,2 +1,6 @@
 /// Mocks for the persisted queries uplink integration.
 pub mod persisted_queries;
+
+/// Mock of the HTTP client sending requests to subgraphs.
+#[cfg(any(test, feature = "testing"))]
+pub mod http_client;